@@ -0,0 +1,54 @@
+// Build metadata
+// Records the git commit, the build date, the enabled Cargo features and the target triple in the
+// environment of the compiler, so that gentup --version and the run reports can identify exactly
+// which binary is in use. Builds from a release tarball, which has no git history, say "unknown"
+
+use std::{env, process::Command};
+
+// Run a command and return the first line it prints, if it succeeds
+//
+fn first_line(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    text.lines().next().map(str::to_string)
+}
+
+fn main() {
+    let commit = first_line("git", &["rev-parse", "--short=12", "HEAD"])
+        .map(|commit| {
+            let dirty = first_line("git", &["status", "--porcelain", "--untracked-files=no"]);
+            if dirty.is_some() {
+                commit + "-dirty"
+            } else {
+                commit
+            }
+        })
+        .unwrap_or("unknown".to_string());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let date = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => first_line("date", &["-u", "-d", &["@", &epoch].concat(), "+%Y-%m-%d"]),
+        Err(_) => first_line("date", &["-u", "+%Y-%m-%d"]),
+    }
+    .unwrap_or("unknown".to_string());
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=GENTUP_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=GENTUP_BUILD_DATE={}", date);
+    println!("cargo:rustc-env=GENTUP_FEATURES={}", features.join(","));
+    println!(
+        "cargo:rustc-env=GENTUP_TARGET={}",
+        env::var("TARGET").unwrap_or("unknown".to_string())
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,263 @@
+// Post-update action checklist
+// Gathers everything the run found which needs the administrator to do something - the actions
+// packages asked for in their elog messages, news items which have not been read, libraries kept
+// for packages still to be rebuilt, configuration file updates still to be merged, and a reboot -
+// into one numbered checklist. It is printed when the run finishes, emailed, and included in the
+// run report, so that nothing is lost in the scrollback of a long update. How long each
+// configuration file update has waited to be merged is kept across runs, and the reminder
+// escalates the longer they wait
+
+use crate::{
+    config::state_dir,
+    events::{self, Event},
+    linux::OsCall,
+    portage, prompt, report, Config,
+};
+use crossterm::style::Color;
+use std::{fs, path::Path, sync::Mutex};
+
+static ACTIONS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub static PRESERVED_LIBS_REGISTRY: &str = "/var/lib/portage/preserved_libs_registry";
+
+// Add an action to the checklist
+//
+pub fn add(action: String) {
+    if let Ok(mut actions) = ACTIONS.lock() {
+        if !actions.contains(&action) {
+            actions.push(action);
+        }
+    }
+}
+
+// Format the checklist, numbering each action
+//
+pub fn checklist(actions: &[String]) -> String {
+    actions
+        .iter()
+        .enumerate()
+        .map(|(number, action)| format!("{:>3}. {}\n", number + 1, action))
+        .collect()
+}
+
+// Whether portage is keeping old libraries for packages which still link against them. The
+// registry holds a JSON object, which is empty when there are none
+//
+pub fn preserved_libs() -> bool {
+    fs::read_to_string(portage::target_path(PRESERVED_LIBS_REGISTRY))
+        .map(|registry| !matches!(registry.trim(), "" | "{}"))
+        .unwrap_or(false)
+}
+
+// Find the configuration file updates waiting to be merged, which portage installs beside the
+// file they update with a name such as ._cfg0000_make.conf
+//
+fn find_pending_configs(directory: &Path, found: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            find_pending_configs(&entry.path(), found);
+        } else if entry.file_name().to_string_lossy().starts_with("._cfg") {
+            found.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+}
+
+// The configuration file updates waiting to be merged in the CONFIG_PROTECT directories
+//
+pub fn pending_configs() -> Vec<String> {
+    let config_protect = OsCall::Quiet
+        .execute("portageq envvar CONFIG_PROTECT", "")
+        .map(|(output, _)| output.trim().to_string())
+        .unwrap_or_default();
+    let directories = if config_protect.is_empty() {
+        "/etc".to_string()
+    } else {
+        config_protect
+    };
+    let mut found = Vec::new();
+    for directory in directories.split_whitespace() {
+        find_pending_configs(Path::new(&portage::target_path(directory)), &mut found);
+    }
+    found.sort();
+    found
+}
+
+// Bring the backlog of configuration file updates up to date. The backlog holds one line for each
+// update, giving when it was first found pending in seconds since the epoch, e.g
+//
+//   1712350000 /etc/._cfg0000_hosts
+//
+// Updates which are still pending keep the time they were first found, new ones are added with
+// the current time, and merged ones are dropped
+//
+pub fn update_backlog(recorded: &str, pending: &[String], now: u64) -> Vec<(u64, String)> {
+    pending
+        .iter()
+        .map(|path| {
+            let first_seen = recorded
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|(_, recorded_path)| recorded_path == path)
+                .and_then(|(since, _)| since.parse().ok())
+                .unwrap_or(now);
+            (first_seen, path.to_string())
+        })
+        .collect()
+}
+
+// The checklist entry for the configuration file updates, which grows more insistent the longer
+// the oldest has waited, and whether it is overdue
+//
+pub fn backlog_action(count: usize, oldest_days: u64, deadline_days: u64) -> (String, bool) {
+    let merge = format!(
+        "Merge the {} pending configuration file update(s) with: dispatch-conf",
+        count
+    );
+    if deadline_days == 0 || oldest_days < deadline_days {
+        (merge, false)
+    } else if oldest_days < deadline_days * 2 {
+        (
+            format!("{} - the oldest has waited {} days", merge, oldest_days),
+            false,
+        )
+    } else {
+        (
+            format!(
+                "OVERDUE: {} - the oldest has waited {} days. Unmerged updates leave services running with \
+                configuration their new versions do not expect",
+                merge, oldest_days
+            ),
+            true,
+        )
+    }
+}
+
+fn backlog_path() -> String {
+    [state_dir(), "/config-backlog"].concat()
+}
+
+// Record the configuration file updates still pending, and add them to the checklist
+//
+fn track_pending_configs(running_config: &Config) {
+    let pending = pending_configs();
+    let now = report::now();
+    let recorded = fs::read_to_string(backlog_path()).unwrap_or_default();
+    let backlog = update_backlog(&recorded, &pending, now);
+    let contents: String = backlog
+        .iter()
+        .map(|(first_seen, path)| format!("{} {}\n", first_seen, path))
+        .collect();
+    let _ = fs::create_dir_all(state_dir()).and_then(|_| fs::write(backlog_path(), contents));
+    let Some(oldest) = backlog.iter().map(|(first_seen, _)| *first_seen).min() else {
+        return;
+    };
+    let oldest_days = now.saturating_sub(oldest) / (24 * 60 * 60);
+    let (action, overdue) = backlog_action(
+        backlog.len(),
+        oldest_days,
+        running_config.config_deadline_days.into(),
+    );
+    if overdue {
+        eprintln!("{} {}", prompt::revchevrons(Color::Red), action);
+    }
+    add(action);
+}
+
+// Check the state the update left behind, then display, email and report the whole checklist
+//
+#[cfg_attr(not(feature = "mail"), allow(unused_variables))]
+pub fn report(running_config: &Config) {
+    if preserved_libs() {
+        add(
+            "Rebuild the packages still using old libraries with: emerge @preserved-rebuild"
+                .to_string(),
+        );
+    }
+    track_pending_configs(running_config);
+    let actions = ACTIONS
+        .lock()
+        .map(|mut actions| std::mem::take(&mut *actions))
+        .unwrap_or_default();
+    events::emit(Event::ActionsRequired {
+        actions: actions.clone(),
+    });
+    if actions.is_empty() {
+        return;
+    }
+    let checklist = checklist(&actions);
+    prompt::console(&format!(
+        "{} Actions required after this update:\n\n{}\n",
+        prompt::revchevrons(Color::Yellow),
+        checklist
+    ));
+    #[cfg(feature = "mail")]
+    if let Err(error) =
+        crate::mail::send_email(running_config, String::from("gentup-actions"), checklist)
+    {
+        eprintln!(
+            "{} The checklist could not be emailed: {}",
+            prompt::revchevrons(Color::Yellow),
+            error
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_the_checklist() {
+        let actions = vec![
+            "sys-libs/glibc-2.39-r6: Please run locale-gen".to_string(),
+            "Reboot to use the updated kernel or system libraries".to_string(),
+        ];
+        assert_eq!(
+            checklist(&actions),
+            "  1. sys-libs/glibc-2.39-r6: Please run locale-gen\n  \
+            2. Reboot to use the updated kernel or system libraries\n"
+        );
+
+        let etc = std::env::temp_dir().join(format!("gentup-etc-{}", std::process::id()));
+        fs::create_dir_all(etc.join("portage")).unwrap();
+        fs::write(etc.join("._cfg0000_hosts"), "").unwrap();
+        fs::write(etc.join("portage/._cfg0001_make.conf"), "").unwrap();
+        fs::write(etc.join("portage/make.conf"), "").unwrap();
+        let mut found = Vec::new();
+        find_pending_configs(&etc, &mut found);
+        assert_eq!(found.len(), 2);
+
+        let pending = vec![
+            "/etc/._cfg0000_hosts".to_string(),
+            "/etc/portage/._cfg0001_make.conf".to_string(),
+        ];
+        let recorded = "1712350000 /etc/._cfg0000_hosts\n1712000000 /etc/._cfg0000_fstab\n";
+        assert_eq!(
+            update_backlog(recorded, &pending, 1713000000),
+            vec![
+                (1712350000, "/etc/._cfg0000_hosts".to_string()),
+                (1713000000, "/etc/portage/._cfg0001_make.conf".to_string())
+            ]
+        );
+        assert_eq!(
+            backlog_action(2, 3, 14),
+            (
+                "Merge the 2 pending configuration file update(s) with: dispatch-conf".to_string(),
+                false
+            )
+        );
+        assert!(backlog_action(2, 20, 14)
+            .0
+            .ends_with("the oldest has waited 20 days"));
+        let (action, overdue) = backlog_action(2, 40, 14);
+        assert!(action.starts_with("OVERDUE: Merge the 2"));
+        assert!(overdue);
+        let _ = fs::remove_dir_all(&etc);
+    }
+}
@@ -3,19 +3,33 @@
 // Supports clustered shorts like -obf
 // Supports long switches like --version
 // Supports mixed shorts and longs, like --optional -f -ob
+// Supports options followed by a value, like --max-packages 20, --max-packages=20 or -m 20
+// Supports checking each value given, so a bad one is reported with the usage rather than later
+// Supports options given more than once, like --exclude www-client/firefox --exclude dev-lang/rust
 
-use crate::version::VERSION;
-use std::env::{self, Args};
+use crate::{
+    exitcode::{self, ExitCode},
+    linux, prefix, version,
+};
+use std::env::Args;
 
 // Define a Struct to contain one single command line option definition
 //
 pub struct ArgumentStruct {
-    short: String, // Short command line options like -o
-    long: String,  // Long command line options like --optional
-    desc: String,  // A description so we can generate the -help output
-    switch: bool,  // Store the on/off state of the command line switch
+    short: String,                       // Short command line options like -o
+    long: String,                        // Long command line options like --optional
+    desc: String,                        // A description so we can generate the -help output
+    switch: bool,                        // Store the on/off state of the command line switch
+    takes_value: bool,                   // Whether the option is followed by a value
+    placeholder: String, // What the value is, shown in the -help output, e.g N for a number
+    values: Vec<String>, // The values supplied, if the option takes one, in the order given
+    check: Option<(ValueCheck, String)>, // Whether a value is valid, and what was expected
 }
 
+// A test of whether a value given for an option is valid
+//
+pub type ValueCheck = fn(&str) -> bool;
+
 // Define a vector of command line options
 //
 pub type ArgCheck = Vec<ArgumentStruct>;
@@ -28,6 +42,8 @@ pub trait Search {
     fn setflag(&mut self, flag: &char);
     fn setflag_from_long(&mut self, flag: String);
     fn get(&self, flag: &str) -> bool;
+    fn value(&self, flag: &str) -> Option<&str>;
+    fn values(&self, flag: &str) -> Vec<&str>;
     fn help(&self) -> String;
     fn usage(&self) -> String;
     fn version() -> String;
@@ -46,8 +62,103 @@ impl ArgumentStruct {
             long: long.to_string(),
             desc: desc.to_string(),
             switch: false,
+            takes_value: false,
+            placeholder: String::new(),
+            values: Vec::new(),
+            check: None,
+        }
+    }
+
+    // As from, for an option which is followed by a value, described in the help by the
+    // placeholder, e.g --max-packages N
+    //
+    pub fn with_value(short: &str, long: &str, placeholder: &str, desc: &str) -> Self {
+        ArgumentStruct {
+            takes_value: true,
+            placeholder: placeholder.to_string(),
+            ..ArgumentStruct::from(short, long, desc)
+        }
+    }
+
+    // Reject a value for which check returns false, explaining what was expected, e.g "a number
+    // of packages"
+    //
+    pub fn validated(self, check: ValueCheck, expected: &str) -> Self {
+        ArgumentStruct {
+            check: Some((check, expected.to_string())),
+            ..self
+        }
+    }
+
+    // The option as shown in the help and usage text, with the placeholder for its value
+    //
+    fn name(&self) -> String {
+        if self.takes_value {
+            format!("{} {}", self.long, self.placeholder)
+        } else {
+            self.long.clone()
+        }
+    }
+}
+
+// Split a long option given with its value, e.g --max-packages=20
+//
+fn split_long(supplied: &str) -> (&str, Option<&str>) {
+    match supplied.split_once('=') {
+        Some((option, value)) => (option, Some(value)),
+        None => (supplied, None),
+    }
+}
+
+// If the option just supplied takes a value, take the value given with it, or else the next
+// argument, as its value, and check it
+//
+fn take_value(
+    arguments: &mut ArgCheck,
+    matches: impl Fn(&ArgumentStruct) -> bool,
+    given: Option<&str>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(), String> {
+    let options: Vec<String> = arguments
+        .iter()
+        .flat_map(|argsearch| {
+            [
+                ["-", &argsearch.short].concat(),
+                ["--", &argsearch.long].concat(),
+            ]
+        })
+        .collect();
+    let Some(argsearch) = arguments.iter_mut().find(|argsearch| matches(argsearch)) else {
+        return Ok(());
+    };
+    if !argsearch.takes_value {
+        return match given {
+            Some(_) => Err(format!("Error: --{} does not take a value", argsearch.long)),
+            None => Ok(()),
+        };
+    }
+    // The next argument is not taken as the value if it is another option
+    let value = match given {
+        Some(value) => Some(value.to_string()),
+        None => args.next().filter(|value| !options.contains(value)),
+    };
+    let Some(value) = value.filter(|value| !value.is_empty()) else {
+        return Err(format!(
+            "Error: --{} needs a value, e.g --{}",
+            argsearch.long,
+            argsearch.name()
+        ));
+    };
+    if let Some((check, expected)) = &argsearch.check {
+        if !check(&value) {
+            return Err(format!(
+                "Error: --{} needs {}, not {}",
+                argsearch.long, expected, value
+            ));
         }
     }
+    argsearch.values.push(value);
+    Ok(())
 }
 
 impl Search for ArgCheck {
@@ -56,7 +167,7 @@ impl Search for ArgCheck {
     //
     fn contains(&self, supplied: &str) -> bool {
         for argsearch in self {
-            let stripped = supplied.replace('-', "");
+            let stripped = supplied.trim_start_matches('-');
             if argsearch.short.eq(&stripped) || argsearch.long.eq(&stripped) {
                 return true;
             }
@@ -77,9 +188,9 @@ impl Search for ArgCheck {
     // Set a command line switch for a particular long flag to true
     //
     fn setflag_from_long(&mut self, flag: String) {
-        let stripped = flag.replace('-', "");
+        let stripped = flag.trim_start_matches('-');
         for argsearch in self {
-            if argsearch.long.eq(&stripped) {
+            if argsearch.long.eq(stripped) {
                 argsearch.switch = true;
             }
         }
@@ -96,6 +207,31 @@ impl Search for ArgCheck {
         false
     }
 
+    // Get the value supplied for a named long flag, if it takes one and was given. If it was
+    // given more than once, the last value counts
+    //
+    fn value(&self, flag: &str) -> Option<&str> {
+        self.iter()
+            .find(|argsearch| argsearch.long.eq(&flag))
+            .and_then(|argsearch| argsearch.values.last())
+            .map(|value| value.as_str())
+    }
+
+    // Get every value supplied for a named long flag, for an option which can be given repeatedly
+    //
+    fn values(&self, flag: &str) -> Vec<&str> {
+        self.iter()
+            .find(|argsearch| argsearch.long.eq(&flag))
+            .map(|argsearch| {
+                argsearch
+                    .values
+                    .iter()
+                    .map(|value| value.as_str())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     // Display program help - the user asked for help
     //
     fn help(&self) -> String {
@@ -103,11 +239,13 @@ impl Search for ArgCheck {
         for eacharg in self {
             let line = format!(
                 "-{:1}, --{:15}\t{}\n",
-                eacharg.short, eacharg.long, eacharg.desc
+                eacharg.short,
+                eacharg.name(),
+                eacharg.desc
             );
             retval = retval + &line;
         }
-        retval
+        retval + &exitcode::help()
     }
 
     // Display usage. One of the command line arguments was incorrect
@@ -115,17 +253,17 @@ impl Search for ArgCheck {
     fn usage(&self) -> String {
         let mut retval = "Error: usage - gentup [".to_string();
         for eacharg in self {
-            let line = format!("--{}|", eacharg.long);
+            let line = format!("--{}|", eacharg.name());
             retval = retval + &line;
         }
         retval = format!("{}]", retval);
         retval
     }
 
-    // Display the program version.
+    // Display the program version, and the details of the build
     //
     fn version() -> String {
-        format!("gentup version {}", VERSION)
+        version::details()
     }
 
     // The parse function is public and exposed to the calling code. It takes a Vector of valid
@@ -137,40 +275,40 @@ impl Search for ArgCheck {
     // .get("--force") which will return true if the flag was set by the user.
     //
     fn parse(mut self, args: Args) -> Result<Self, String> {
-        // Check we are root
-        match env::var("USER") {
-            Ok(val) => {
-                if val != "root" {
-                    return Err("You need to be root to run this".to_string());
-                }
-            }
-            Err(_) => {
-                return Err("You need to be root to run this".to_string());
-            }
+        // Check we are root, unless updating a Gentoo Prefix, which belongs to its user
+        if !linux::is_root() && !prefix::active() {
+            return Err(linux::not_root_message());
         }
-        let mut first = true;
-        for arg in args {
-            // The first arg is the name of the binary e.g gentup, so we skip past onto the next argument
-            if first {
-                first = false;
-                continue;
-            }
+        // The first arg is the name of the binary e.g gentup, so we skip past onto the next argument
+        let mut args = args.skip(1);
+        while let Some(arg) = args.next() {
             match &arg[..] {
                 "-h" | "--help" => {
-                    return Err(Self::help(&self));
+                    print!("{}", Self::help(&self));
+                    ExitCode::NothingToDo.exit();
                 }
                 "-V" | "--version" => {
-                    return Err(Self::version());
+                    println!("{}", Self::version());
+                    ExitCode::NothingToDo.exit();
                 }
                 supplied => {
                     // Handle the long version of the options, which are prefixed with -- e.g
                     // --force
                     if supplied.contains("--") {
-                        // The long version of an option has been supplied
+                        // The long version of an option has been supplied, perhaps with its
+                        // value, e.g --max-packages=20
+                        let (supplied, given) = split_long(supplied);
                         if self.contains(supplied) {
                             // A valid long option was found
                             // Set the switch for that option to "true"
                             self.setflag_from_long(supplied.to_string());
+                            let stripped = supplied.trim_start_matches('-');
+                            take_value(
+                                &mut self,
+                                |argsearch| argsearch.long.eq(stripped),
+                                given,
+                                &mut args,
+                            )?;
                         } else {
                             // Syntax error, so return the usage text as part of the error
                             return Err(Self::usage(&self));
@@ -188,6 +326,13 @@ impl Search for ArgCheck {
                                 // A valid command line switch was found. Set the switch for the
                                 // option to "true"
                                 self.setflag(&individual);
+                                let short = individual.to_string();
+                                take_value(
+                                    &mut self,
+                                    |argsearch| argsearch.short.eq(&short),
+                                    None,
+                                    &mut args,
+                                )?;
                             } else {
                                 // Syntax error, so return the usage text as part of the error
                                 return Err(Self::usage(&self));
@@ -200,3 +345,63 @@ impl Search for ArgCheck {
         Ok(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax() -> ArgCheck {
+        vec![
+            ArgumentStruct::from("f", "force", "Force package tree sync"),
+            ArgumentStruct::with_value("m", "max-packages", "N", "Build only N updates")
+                .validated(|max| max.parse::<usize>().is_ok(), "a number of packages"),
+            ArgumentStruct::with_value("P", "package-env", "SETTINGS", "Build with extra settings"),
+        ]
+    }
+
+    #[test]
+    fn takes_and_checks_values() {
+        let mut arguments = syntax();
+        let mut args = ["20".to_string()].into_iter();
+        take_value(
+            &mut arguments,
+            |argsearch| argsearch.short == "m",
+            None,
+            &mut args,
+        )
+        .unwrap();
+        let (option, given) = split_long("--package-env=www-client/chromium MAKEOPTS=-j2");
+        assert_eq!(option, "--package-env");
+        take_value(
+            &mut arguments,
+            |argsearch| argsearch.long == "package-env",
+            given,
+            &mut args,
+        )
+        .unwrap();
+        assert_eq!(arguments.value("max-packages"), Some("20"));
+        assert_eq!(
+            arguments.value("package-env"),
+            Some("www-client/chromium MAKEOPTS=-j2")
+        );
+
+        let mut args = ["lots".to_string()].into_iter();
+        let bad = |argsearch: &ArgumentStruct| argsearch.long == "max-packages";
+        assert_eq!(
+            take_value(&mut arguments, bad, None, &mut args),
+            Err("Error: --max-packages needs a number of packages, not lots".to_string())
+        );
+        let mut args = ["--force".to_string()].into_iter();
+        assert!(take_value(&mut arguments, bad, None, &mut args).is_err());
+        let mut args = std::iter::empty();
+        let force = |argsearch: &ArgumentStruct| argsearch.long == "force";
+        assert!(take_value(&mut arguments, force, Some("yes"), &mut args).is_err());
+    }
+
+    #[test]
+    fn shows_value_placeholders() {
+        let help = syntax().help();
+        assert!(help.contains("-m, --max-packages N "));
+        assert!(syntax().usage().contains("--package-env SETTINGS|"));
+    }
+}
@@ -0,0 +1,325 @@
+// Package atoms and versions
+// Parses package names such as sys-devel/gcc-13.2.1_p20240113-r1:13::gentoo into their parts, and
+// compares versions following the rules in the Package Manager Specification (PMS), so that names
+// containing digits (e.g. dev-lang/python3-bin, media-libs/x264) are split correctly
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+// The version suffixes defined by PMS, in ascending order. A version with no suffix sorts between
+// _rc and _p
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Suffix {
+    Alpha,
+    Beta,
+    Pre,
+    Rc,
+    P,
+}
+
+impl Suffix {
+    fn from_name(name: &str) -> Option<Suffix> {
+        match name {
+            "alpha" => Some(Suffix::Alpha),
+            "beta" => Some(Suffix::Beta),
+            "pre" => Some(Suffix::Pre),
+            "rc" => Some(Suffix::Rc),
+            "p" => Some(Suffix::P),
+            _ => None,
+        }
+    }
+}
+
+// Define a struct to hold a package version, e.g 1.2.3b_rc4_p5-r6
+//
+#[derive(Clone, Debug)]
+pub struct Version {
+    numbers: Vec<String>,            // 1, 2, 3
+    letter: Option<char>,            // b
+    suffixes: Vec<(Suffix, String)>, // (Rc, 4), (P, 5)
+    revision: String,                // 6
+    text: String,                    // The version as it was written
+}
+
+// Strip leading zeros so that numeric strings of any length can be compared without overflow
+fn compare_integers(left: &str, right: &str) -> Ordering {
+    let left = left.trim_start_matches('0');
+    let right = right.trim_start_matches('0');
+    left.len().cmp(&right.len()).then_with(|| left.cmp(right))
+}
+
+impl Version {
+    // The version without its revision, e.g 13.2.1_p20240113 for 13.2.1_p20240113-r1
+    pub fn without_revision(&self) -> &str {
+        match self.text.rfind("-r") {
+            Some(position) if !self.revision.is_empty() => &self.text[..position],
+            _ => &self.text,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid version: {}", text);
+        let (rest, revision) = match text.rsplit_once("-r") {
+            Some((rest, revision))
+                if !revision.is_empty() && revision.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                (rest, revision.to_string())
+            }
+            _ => (text, String::new()),
+        };
+        let mut parts = rest.split('_');
+        let mut base = parts.next().ok_or_else(invalid)?;
+        let mut letter = None;
+        if let Some(last) = base.chars().last() {
+            if last.is_ascii_lowercase() {
+                letter = Some(last);
+                base = &base[..base.len() - 1];
+            }
+        }
+        let numbers: Vec<String> = base.split('.').map(|number| number.to_string()).collect();
+        if numbers
+            .iter()
+            .any(|number| number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()))
+        {
+            return Err(invalid());
+        }
+        let mut suffixes = Vec::new();
+        for part in parts {
+            let split = part
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(part.len());
+            let suffix = Suffix::from_name(&part[..split]).ok_or_else(invalid)?;
+            let number = &part[split..];
+            if !number.chars().all(|c| c.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            suffixes.push((suffix, number.to_string()));
+        }
+        Ok(Version {
+            numbers,
+            letter,
+            suffixes,
+            revision,
+            text: text.to_string(),
+        })
+    }
+}
+
+// Version comparison, following the algorithm in PMS section 3.3
+//
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // The first component is always compared as an integer
+        let ordering = compare_integers(&self.numbers[0], &other.numbers[0]);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        // Later components are compared as integers, unless either has a leading zero, in which
+        // case they are compared as strings with trailing zeros removed
+        for (left, right) in self.numbers.iter().zip(other.numbers.iter()).skip(1) {
+            let ordering = if left.starts_with('0') || right.starts_with('0') {
+                left.trim_end_matches('0').cmp(right.trim_end_matches('0'))
+            } else {
+                compare_integers(left, right)
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        let ordering = self
+            .numbers
+            .len()
+            .cmp(&other.numbers.len())
+            .then_with(|| self.letter.cmp(&other.letter));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        for (left, right) in self.suffixes.iter().zip(other.suffixes.iter()) {
+            let ordering = left
+                .0
+                .cmp(&right.0)
+                .then_with(|| compare_integers(&left.1, &right.1));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        // When one version has more suffixes, a further _p makes it greater, and any other suffix
+        // makes it lesser
+        let ordering = match self.suffixes.len().cmp(&other.suffixes.len()) {
+            Ordering::Greater if self.suffixes[other.suffixes.len()].0 == Suffix::P => {
+                Ordering::Greater
+            }
+            Ordering::Greater => Ordering::Less,
+            Ordering::Less if other.suffixes[self.suffixes.len()].0 == Suffix::P => Ordering::Less,
+            Ordering::Less => Ordering::Greater,
+            Ordering::Equal => Ordering::Equal,
+        };
+        ordering.then_with(|| compare_integers(&self.revision, &other.revision))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// Define a struct to hold a package, e.g sys-devel/gcc-13.2.1_p20240113-r1:13::gentoo. Only the
+// category and name are required, so that unversioned names like app-editors/vim also parse
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct Package {
+    pub category: String,
+    pub name: String,
+    pub version: Option<Version>,
+    pub slot: Option<String>, // Including any sub-slot, e.g 0/3
+    pub repo: Option<String>,
+}
+
+impl Package {
+    // The category and name, e.g sys-devel/gcc
+    pub fn cpn(&self) -> String {
+        [&self.category, "/", &self.name].concat()
+    }
+
+    // The category, name and version, e.g sys-devel/gcc-13.2.1_p20240113-r1
+    pub fn cpv(&self) -> String {
+        match &self.version {
+            Some(version) => [&self.cpn(), "-", &version.text].concat(),
+            None => self.cpn(),
+        }
+    }
+}
+
+impl FromStr for Package {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (rest, repo) = match text.split_once("::") {
+            Some((rest, repo)) => (rest, Some(repo.to_string())),
+            None => (text, None),
+        };
+        let (rest, slot) = match rest.split_once(':') {
+            Some((rest, slot)) => (rest, Some(slot.to_string())),
+            None => (rest, None),
+        };
+        let (category, name_version) = rest
+            .split_once('/')
+            .filter(|(category, name)| !category.is_empty() && !name.is_empty())
+            .ok_or_else(|| format!("Invalid package: {}", text))?;
+
+        // The version starts after the first hyphen which is followed by something that parses
+        // as a complete version. A package name may itself contain hyphens followed by digits, as
+        // in dev-python/python-dateutil or x11-libs/gtk+-3, so each candidate is tried in turn
+        let mut name = name_version.to_string();
+        let mut version = None;
+        for (position, _) in name_version.match_indices('-') {
+            let candidate = &name_version[position + 1..];
+            if !candidate.starts_with(|c: char| c.is_ascii_digit()) {
+                continue;
+            }
+            if let Ok(parsed) = candidate.parse::<Version>() {
+                name = name_version[..position].to_string();
+                version = Some(parsed);
+                break;
+            }
+        }
+        Ok(Package {
+            category: category.to_string(),
+            name,
+            version,
+            slot,
+            repo,
+        })
+    }
+}
+
+impl fmt::Display for Package {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.cpv())?;
+        if let Some(slot) = &self.slot {
+            write!(f, ":{}", slot)?;
+        }
+        if let Some(repo) = &self.repo {
+            write!(f, "::{}", repo)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_full_atom() {
+        let package: Package = "sys-devel/gcc-13.2.1_p20240113-r1:13::gentoo"
+            .parse()
+            .unwrap();
+        assert_eq!(package.cpn(), "sys-devel/gcc");
+        assert_eq!(package.cpv(), "sys-devel/gcc-13.2.1_p20240113-r1");
+        assert_eq!(package.slot.as_deref(), Some("13"));
+        assert_eq!(package.repo.as_deref(), Some("gentoo"));
+        assert_eq!(
+            package.to_string(),
+            "sys-devel/gcc-13.2.1_p20240113-r1:13::gentoo"
+        );
+        let version = package.version.unwrap();
+        assert_eq!(version.without_revision(), "13.2.1_p20240113");
+    }
+
+    #[test]
+    fn parses_names_containing_digits() {
+        let package: Package = "media-libs/x264-0.0.20231114".parse().unwrap();
+        assert_eq!(package.cpn(), "media-libs/x264");
+        let package: Package = "x11-libs/gtk+-3.24.41:3".parse().unwrap();
+        assert_eq!(package.cpn(), "x11-libs/gtk+");
+        let package: Package = "dev-lang/python-3.12.2_p1:3.12".parse().unwrap();
+        assert_eq!(package.cpn(), "dev-lang/python");
+        let package: Package = "net-misc/openssh-9.6_p1-r3".parse().unwrap();
+        assert_eq!(package.cpn(), "net-misc/openssh");
+        let package: Package = "app-editors/vim".parse().unwrap();
+        assert_eq!(package.cpn(), "app-editors/vim");
+        assert!(package.version.is_none());
+        assert!("notanatom".parse::<Package>().is_err());
+    }
+
+    #[test]
+    fn compares_versions_per_pms() {
+        assert!(version("1.10") > version("1.9"));
+        assert!(version("1.01") < version("1.1"));
+        assert!(version("1.2.3") > version("1.2"));
+        assert!(version("1.2a") > version("1.2"));
+        assert!(version("1.0_alpha1") < version("1.0_beta"));
+        assert!(version("1.0_rc2") < version("1.0"));
+        assert!(version("1.0") < version("1.0_p1"));
+        assert!(version("1.0_p1") < version("1.0_p1-r1"));
+        assert!(version("1.0_rc1_p1") > version("1.0_rc1"));
+        assert!(version("1.0_rc1_pre1") < version("1.0_rc1"));
+        assert!(version("13.2.1_p20240210") > version("13.2.1_p20240113-r1"));
+        assert_eq!(version("1.0-r0"), version("1.0"));
+        assert!("1.0_foo".parse::<Version>().is_err());
+    }
+}
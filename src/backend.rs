@@ -0,0 +1,174 @@
+// Package manager backends
+// The Backend trait abstracts the commands gentup runs against the system's package manager, so
+// that the code which parses their output and decides what to do next can be exercised against
+// canned output instead of a real Gentoo system
+
+use crate::{
+    changeddeps, eixdb,
+    linux::{OsCall, ShellOutResult},
+    parallel, portage,
+};
+
+// Define the operations gentup performs with the package manager. Each returns the captured
+// output (where there is any) and the exit status, exactly as OsCall does
+//
+pub trait Backend {
+    fn pretend_update(&self) -> ShellOutResult; // List pending updates of the @world set
+    fn update(&self) -> ShellOutResult; // Update the @world set
+    fn pretend_depclean(&self) -> ShellOutResult; // List orphaned dependencies
+    fn depclean(&self, preserve_kernel: bool) -> ShellOutResult; // Remove orphaned dependencies
+    fn pretend_revdep(&self) -> ShellOutResult; // List broken reverse dependencies
+    fn revdep_rebuild(&self) -> ShellOutResult; // Rebuild broken reverse dependencies
+    fn sync(&self) -> ShellOutResult; // Sync the package tree
+    fn webrsync(&self) -> ShellOutResult; // Sync the package tree from a daily snapshot over HTTP
+    fn query_installed(&self, package: &str) -> ShellOutResult; // Exit status 0 if installed
+    fn query_outdated(&self, package: &str) -> ShellOutResult; // Exit status 0 if outdated
+}
+
+// The real backend, which runs emerge and friends
+//
+pub struct Emerge;
+
+impl Backend for Emerge {
+    fn pretend_update(&self) -> ShellOutResult {
+        OsCall::Spinner.execute("emerge -puDv @world", "Checking for updates")
+    }
+
+    fn update(&self) -> ShellOutResult {
+        OsCall::Interactive.execute(
+            &[
+                "emerge",
+                portage::quiet_build(),
+                " -uNDv --autounmask n --with-bdeps y --changed-use --complete-graph",
+                parallel::update_options(),
+                changeddeps::update_option(),
+                &portage::user_excludes(),
+                " @world",
+            ]
+            .concat(),
+            "Updating world set",
+        )
+    }
+
+    fn pretend_depclean(&self) -> ShellOutResult {
+        OsCall::Spinner.execute("emerge -p --depclean", "Checking for orphaned dependencies")
+    }
+
+    fn depclean(&self, preserve_kernel: bool) -> ShellOutResult {
+        if preserve_kernel {
+            OsCall::Interactive.execute(
+                &[
+                    "emerge --depclean --exclude sys-kernel/gentoo-kernel-bin --exclude sys-kernel/gentoo-sources",
+                    &portage::toolchain_excludes(),
+                ]
+                .concat(),
+                "Removing orphaned dependencies",
+            )
+        } else {
+            OsCall::Interactive.execute(
+                &["emerge --depclean", &portage::toolchain_excludes()].concat(),
+                "Removing all orphaned dependencies",
+            )
+        }
+    }
+
+    fn pretend_revdep(&self) -> ShellOutResult {
+        OsCall::Spinner.execute("revdep-rebuild -ip", "Checking reverse dependencies")
+    }
+
+    fn revdep_rebuild(&self) -> ShellOutResult {
+        OsCall::Interactive.execute("revdep-rebuild", "Rebuilding reverse dependencies")
+    }
+
+    fn sync(&self) -> ShellOutResult {
+        OsCall::Spinner.execute("emerge --sync", "Syncing package tree")
+    }
+
+    fn webrsync(&self) -> ShellOutResult {
+        OsCall::Spinner.execute("emerge-webrsync", "Syncing package tree from a snapshot")
+    }
+
+    fn query_installed(&self, package: &str) -> ShellOutResult {
+        OsCall::Quiet.execute(&["equery l ", package].concat(), "")
+    }
+
+    fn query_outdated(&self, package: &str) -> ShellOutResult {
+        eixdb::query(&["eix -u ", package].concat())
+    }
+}
+
+// A fake backend driven by canned emerge output, which records the operations requested of it
+//
+#[cfg(test)]
+pub mod mock {
+    use super::Backend;
+    use crate::linux::ShellOutResult;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    pub struct MockBackend {
+        pub pretend_update_output: String,
+        pub pretend_depclean_output: String,
+        pub pretend_revdep_output: String,
+        pub installed: Vec<String>,
+        pub outdated: Vec<String>,
+        pub exit_status: i32,
+        pub calls: RefCell<Vec<String>>,
+    }
+
+    impl MockBackend {
+        fn record(&self, call: &str, output: &str) -> ShellOutResult {
+            self.calls.borrow_mut().push(call.to_string());
+            Ok((output.to_string(), self.exit_status))
+        }
+    }
+
+    impl Backend for MockBackend {
+        fn pretend_update(&self) -> ShellOutResult {
+            self.record("pretend_update", &self.pretend_update_output)
+        }
+        fn update(&self) -> ShellOutResult {
+            self.record("update", "")
+        }
+        fn pretend_depclean(&self) -> ShellOutResult {
+            self.record("pretend_depclean", &self.pretend_depclean_output)
+        }
+        fn depclean(&self, preserve_kernel: bool) -> ShellOutResult {
+            self.record(&format!("depclean preserve_kernel={}", preserve_kernel), "")
+        }
+        fn pretend_revdep(&self) -> ShellOutResult {
+            self.record("pretend_revdep", &self.pretend_revdep_output)
+        }
+        fn revdep_rebuild(&self) -> ShellOutResult {
+            self.record("revdep_rebuild", "")
+        }
+        fn sync(&self) -> ShellOutResult {
+            self.record("sync", "")
+        }
+        fn webrsync(&self) -> ShellOutResult {
+            self.record("webrsync", "")
+        }
+        fn query_installed(&self, package: &str) -> ShellOutResult {
+            self.calls
+                .borrow_mut()
+                .push(format!("query_installed {}", package));
+            let status = if self.installed.iter().any(|each| each == package) {
+                0
+            } else {
+                1
+            };
+            Ok((String::new(), status))
+        }
+        fn query_outdated(&self, package: &str) -> ShellOutResult {
+            self.calls
+                .borrow_mut()
+                .push(format!("query_outdated {}", package));
+            let status = if self.outdated.iter().any(|each| each == package) {
+                0
+            } else {
+                1
+            };
+            Ok((String::new(), status))
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Download bandwidth limiting
+// With bandwidth_limit set in the configuration file, the package tree sync and the source
+// downloads are held to that many kilobytes per second, so an update does not take all of a
+// shared connection. Portage reads its settings from the environment ahead of make.conf, so the
+// limit is passed to rsync with --bwlimit in PORTAGE_RSYNC_EXTRA_OPTS, and to the download program
+// by adding its rate limit option to FETCHCOMMAND and RESUMECOMMAND, e.g
+//
+//   wget -t 3 -T 60 --passive-ftp -O "${DISTDIR}/${FILE}" "${URI}"
+//   wget --limit-rate=500k -t 3 -T 60 --passive-ftp -O "${DISTDIR}/${FILE}" "${URI}"
+
+use crate::{portage, prompt, Config};
+use crossterm::style::Color;
+use std::{env, sync::Once};
+
+static APPLIED: Once = Once::new();
+
+// Add a rate limit to a FETCHCOMMAND or RESUMECOMMAND, for the download programs portage is
+// commonly set up with. A command which already has a limit is left alone
+//
+pub fn limit_command(command: &str, kilobytes: u32) -> Option<String> {
+    let (program, arguments) = command.trim().split_once(' ')?;
+    let option = match program.rsplit('/').next()? {
+        "wget" if command.contains("--limit-rate") => return Some(command.to_string()),
+        "curl" if command.contains("--limit-rate") => return Some(command.to_string()),
+        "aria2c" if command.contains("--max-download-limit") => return Some(command.to_string()),
+        "wget" => format!("--limit-rate={}k", kilobytes),
+        "curl" => format!("--limit-rate {}k", kilobytes),
+        "aria2c" => format!("--max-download-limit={}K", kilobytes),
+        _ => return None,
+    };
+    Some([program, " ", &option, " ", arguments].concat())
+}
+
+// Add a bandwidth limit to the extra options portage passes to rsync
+//
+pub fn rsync_options(existing: &str, kilobytes: u32) -> String {
+    let kept: Vec<&str> = existing
+        .split_whitespace()
+        .filter(|option| !option.starts_with("--bwlimit"))
+        .collect();
+    let mut options = kept.join(" ");
+    if !options.is_empty() {
+        options.push(' ');
+    }
+    options + &format!("--bwlimit={}", kilobytes)
+}
+
+// Limit the download bandwidth of the rest of this run, if the configuration asks for it. Only
+// the first call does anything, so each phase which downloads can ask
+//
+pub fn limit(running_config: &Config) {
+    if running_config.bandwidth_limit == 0 {
+        return;
+    }
+    APPLIED.call_once(|| {
+        let kilobytes = running_config.bandwidth_limit;
+        env::set_var(
+            "PORTAGE_RSYNC_EXTRA_OPTS",
+            rsync_options(
+                &portage::portage_variable("PORTAGE_RSYNC_EXTRA_OPTS"),
+                kilobytes,
+            ),
+        );
+        for variable in ["FETCHCOMMAND", "RESUMECOMMAND"] {
+            let command = portage::portage_variable(variable);
+            match limit_command(&command, kilobytes) {
+                Some(limited) => env::set_var(variable, limited),
+                None => eprintln!(
+                    "{} Cannot limit the bandwidth of {}=\"{}\" - downloads will not be limited",
+                    prompt::revchevrons(Color::Yellow),
+                    variable,
+                    command
+                ),
+            }
+        }
+        println!(
+            "{} Downloads are limited to {}KB/s",
+            prompt::revchevrons(Color::Green),
+            kilobytes
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_download_commands() {
+        assert_eq!(
+            limit_command(
+                "wget -t 3 -T 60 --passive-ftp -O \"${DISTDIR}/${FILE}\" \"${URI}\"",
+                500
+            )
+            .as_deref(),
+            Some("wget --limit-rate=500k -t 3 -T 60 --passive-ftp -O \"${DISTDIR}/${FILE}\" \"${URI}\"")
+        );
+        assert_eq!(
+            limit_command("/usr/bin/curl -f -o \"${DISTDIR}/${FILE}\" \"${URI}\"", 64).as_deref(),
+            Some("/usr/bin/curl --limit-rate 64k -f -o \"${DISTDIR}/${FILE}\" \"${URI}\"")
+        );
+        assert_eq!(
+            limit_command(
+                "wget --limit-rate=1m -O \"${DISTDIR}/${FILE}\" \"${URI}\"",
+                500
+            )
+            .as_deref(),
+            Some("wget --limit-rate=1m -O \"${DISTDIR}/${FILE}\" \"${URI}\"")
+        );
+        assert_eq!(limit_command("fetch-it \"${URI}\"", 500), None);
+        assert_eq!(limit_command("", 500), None);
+
+        assert_eq!(rsync_options("", 500), "--bwlimit=500");
+        assert_eq!(
+            rsync_options("--timeout=60 --bwlimit=100", 500),
+            "--timeout=60 --bwlimit=500"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// Binary-only updates
+// "gentup --usepkgonly" is for minimal hosts, such as small VMs with no compiler, which install
+// everything from a binhost and must never start building from source. Every emerge gentup runs
+// is given --usepkgonly --getbinpkg, by adding them to EMERGE_DEFAULT_OPTS, so portage refuses
+// anything it would have to compile. The pending updates are still worked out from the ebuilds,
+// and those with no binary package, locally or on the binhost, are listed and left for a later
+// run, as with --offline. Whether a package has a binary package is asked of portage itself:
+//
+//   emerge --pretend --nodeps --usepkgonly --getbinpkg =sys-libs/zlib-1.3.1
+
+use crate::{actions, atom::Package, emergeopts, offline, portage, prompt};
+use crossterm::style::Color;
+use std::{
+    env,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+// The options which stop emerge building from source
+static BINARY_ONLY: [&str; 2] = ["--usepkgonly", "--getbinpkg"];
+
+// EMERGE_DEFAULT_OPTS, with the binary-only options added
+//
+pub fn default_opts(existing: &str) -> String {
+    let mut options: Vec<&str> = existing.split_whitespace().collect();
+    for option in BINARY_ONLY {
+        if !options.contains(&option) {
+            options.push(option);
+        }
+    }
+    options.join(" ")
+}
+
+// Whether a binhost is configured, with PORTAGE_BINHOST or in binrepos.conf
+//
+pub fn binhost_configured() -> bool {
+    portage::make_conf_variable("PORTAGE_BINHOST").is_some_and(|binhost| !binhost.is_empty())
+        || Path::new(&portage::target_path("/etc/portage/binrepos.conf")).exists()
+}
+
+// Stop every emerge run from here on building from source
+//
+pub fn require_binaries() {
+    if !binhost_configured() {
+        println!(
+            "{} No binhost is configured, so only the binary packages already in PKGDIR can be installed",
+            prompt::revchevrons(Color::Yellow)
+        );
+    }
+    env::set_var("EMERGE_DEFAULT_OPTS", default_opts(&emergeopts::current()));
+}
+
+// Run a pretend with portage free to consider the ebuilds, so that updates with no binary package
+// are listed rather than silently passed over
+//
+pub fn allowing_sources<T>(pretend: impl FnOnce() -> T) -> T {
+    let required = emergeopts::current();
+    let allowing: Vec<&str> = required
+        .split_whitespace()
+        .filter(|option| !BINARY_ONLY.contains(option))
+        .collect();
+    env::set_var("EMERGE_DEFAULT_OPTS", allowing.join(" "));
+    let result = pretend();
+    env::set_var("EMERGE_DEFAULT_OPTS", required);
+    result
+}
+
+// Whether a binary package of the exact version is available, in PKGDIR or on the binhost
+//
+pub fn binpkg_available(package: &Package) -> bool {
+    Command::new("emerge")
+        .args(["--pretend", "--nodeps"])
+        .args(BINARY_ONLY)
+        .arg(["=", &package.to_string()].concat())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// Keep only the pending updates which can be installed from binary packages. Returns the number
+// left for a later run
+//
+pub fn select(pending_updates: &mut Vec<Package>) -> usize {
+    let (available, missing) =
+        offline::split_buildable(std::mem::take(pending_updates), binpkg_available);
+    *pending_updates = available;
+    if missing.is_empty() {
+        println!(
+            "{} Every pending update has a binary package",
+            prompt::revchevrons(Color::Blue)
+        );
+        return 0;
+    }
+    println!(
+        "{} Binary only: installing {} pending updates, and leaving {} which have no binary package:",
+        prompt::revchevrons(Color::Yellow),
+        pending_updates.len(),
+        missing.len()
+    );
+    for package in &missing {
+        println!("    {}", package);
+    }
+    actions::add(format!(
+        "No binary package is available for {}. Build them on the binhost",
+        missing
+            .iter()
+            .map(|package| package.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    ));
+    missing.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_binary_only_options() {
+        assert_eq!(default_opts(""), "--usepkgonly --getbinpkg");
+        assert_eq!(
+            default_opts("--jobs 4  --getbinpkg"),
+            "--jobs 4 --getbinpkg --usepkgonly"
+        );
+    }
+}
@@ -0,0 +1,250 @@
+// Phase time budgets
+// The longest each phase of an update may take can be set in the configuration file, so that an
+// update started at night is out of the way by the morning, e.g
+//
+//   phase_budget: sync 15m
+//   phase_budget: fetch 2h
+//   phase_budget: build 8h
+//
+// When a phase runs past its budget in unattended mode, the update stops cleanly and exits with
+// its own status, so the next window can carry on with gentup --continue. A build is stopped as
+// soon as no package is being merged onto the live filesystem, going by emerge.log, since
+// interrupting a compile loses nothing but time. The other phases are not interrupted, and the
+// update stops once they finish. At a terminal, the user is only told the budget has run out
+
+use crate::{linux, portage, prompt, stats};
+use crossterm::style::Color;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How often the budget of the running phase is checked
+static INTERVAL: Duration = Duration::from_secs(1);
+
+// Set once the running phase has run past its budget
+static EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+// Define a struct to hold the time budget of one phase
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct Budget {
+    pub phase: String, // The phase name, as in the checkpoint file, e.g build
+    pub seconds: u64,
+}
+
+// Parse a duration such as 15m, 2h, 1h30m or 90s. A number without a unit is in minutes
+//
+pub fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if let Ok(minutes) = text.parse::<u64>() {
+        return Some(minutes * 60);
+    }
+    let mut seconds = 0;
+    let mut number = String::new();
+    for character in text.chars() {
+        if character.is_ascii_digit() {
+            number.push(character);
+            continue;
+        }
+        let multiplier = match character {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return None,
+        };
+        seconds += number.parse::<u64>().ok()? * multiplier;
+        number.clear();
+    }
+    (number.is_empty() && seconds > 0).then_some(seconds)
+}
+
+impl Budget {
+    // Parse a phase name and its budget, e.g build 8h
+    //
+    pub fn parse(text: &str, phases: &[&str]) -> Option<Budget> {
+        let (phase, duration) = text.trim().split_once(char::is_whitespace)?;
+        if !phases.contains(&phase) {
+            return None;
+        }
+        Some(Budget {
+            phase: phase.to_string(),
+            seconds: parse_duration(duration)?,
+        })
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (hours, minutes, seconds) = (
+            self.seconds / 3600,
+            self.seconds % 3600 / 60,
+            self.seconds % 60,
+        );
+        write!(f, "{} ", self.phase)?;
+        if hours > 0 {
+            write!(f, "{}h", hours)?;
+        }
+        if minutes > 0 {
+            write!(f, "{}m", minutes)?;
+        }
+        if seconds > 0 {
+            write!(f, "{}s", seconds)?;
+        }
+        Ok(())
+    }
+}
+
+// Whether a package is being merged onto the live filesystem, from the last lines of emerge.log.
+// Each merge starts with "=== (3 of 10) Merging" and ends with "::: completed emerge (3 of 10)",
+// and with --jobs several may overlap
+//
+pub fn merging(log_lines: &[&str]) -> bool {
+    let mut merging = HashSet::new();
+    for line in log_lines {
+        let message = line.split_once(':').map(|(_, message)| message.trim());
+        let Some(message) = message else {
+            continue;
+        };
+        if let Some(rest) = message.strip_prefix("=== (") {
+            if let Some((position, step)) = rest.split_once(") ") {
+                if step.starts_with("Merging") {
+                    merging.insert(position.to_string());
+                }
+            }
+        } else if let Some(rest) = message.strip_prefix("::: completed emerge (") {
+            if let Some((position, _)) = rest.split_once(')') {
+                merging.remove(position);
+            }
+        }
+    }
+    !merging.is_empty()
+}
+
+// Whether the phase which just ran went past its budget
+//
+pub fn exceeded() -> bool {
+    EXCEEDED.load(Ordering::Relaxed)
+}
+
+// Stop the build processes once nothing is being merged, unless the build finishes first
+//
+fn stop_build(finished: &AtomicBool) {
+    loop {
+        if finished.load(Ordering::Relaxed) {
+            return;
+        }
+        let contents =
+            fs::read_to_string(portage::target_path(portage::EMERGE_LOG)).unwrap_or_default();
+        let lines: Vec<&str> = contents.lines().collect();
+        if !merging(&lines[lines.len().saturating_sub(200)..]) {
+            break;
+        }
+        thread::sleep(INTERVAL);
+    }
+    println!(
+        "{} Stopping the build, which will carry on with gentup --continue",
+        prompt::revchevrons(Color::Yellow)
+    );
+    for pid in crate::throttle::descendants() {
+        // SAFETY: kill only sends signals, to the processes of the build gentup started. A
+        // stopped build is continued first, so that it can act on the SIGTERM
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGCONT);
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+// Watches the running phase's budget, until stopped
+//
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Watchdog {
+    // Start watching a phase, if it has a budget
+    //
+    pub fn start(budgets: &[Budget], phase: &'static str) -> Option<Watchdog> {
+        EXCEEDED.store(false, Ordering::Relaxed);
+        let budget = budgets.iter().find(|budget| budget.phase == phase)?.seconds;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let started = Instant::now();
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                thread::sleep(INTERVAL);
+                if started.elapsed().as_secs() < budget {
+                    continue;
+                }
+                let unattended = !linux::is_a_tty();
+                println!(
+                    "{} The {} phase has run past its budget of {}{}",
+                    prompt::revchevrons(Color::Yellow),
+                    phase,
+                    stats::format_duration(budget),
+                    if unattended {
+                        ", so the update will stop"
+                    } else {
+                        ""
+                    }
+                );
+                if unattended {
+                    EXCEEDED.store(true, Ordering::Relaxed);
+                    if phase == "build" {
+                        stop_build(&stopped);
+                    }
+                }
+                break;
+            }
+        });
+        Some(Watchdog { stop, handle })
+    }
+
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_budgets_and_merges() {
+        let phases = ["sync", "fetch", "build"];
+        assert_eq!(parse_duration("15m"), Some(900));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("45"), Some(2700));
+        assert_eq!(parse_duration("2x"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        let build = Budget::parse("build 8h", &phases).unwrap();
+        assert_eq!(build.seconds, 28800);
+        assert_eq!(build.to_string(), "build 8h");
+        assert_eq!(
+            Budget::parse("fetch 90m", &phases).unwrap().to_string(),
+            "fetch 1h30m"
+        );
+        assert_eq!(Budget::parse("lunch 1h", &phases), None);
+
+        let log = [
+            "1712345600:  >>> emerge (1 of 2) sys-libs/zlib-1.3.1 to /",
+            "1712345601:  === (1 of 2) Cleaning (sys-libs/zlib-1.3.1::...)",
+            "1712345640:  === (1 of 2) Merging (sys-libs/zlib-1.3.1::...)",
+        ];
+        assert!(merging(&log));
+        let mut finished = log.to_vec();
+        finished.push("1712345650:  ::: completed emerge (1 of 2) sys-libs/zlib-1.3.1 to /");
+        finished.push("1712345651:  === (2 of 2) Compiling/Packaging (sys-devel/gcc-13.2.1::...)");
+        assert!(!merging(&finished));
+    }
+}
@@ -0,0 +1,190 @@
+// Bug report bundles
+// When a package fails to build, everything a Gentoo bug or a support request asks for is
+// gathered into one tarball in /var/lib/gentup/bugs, named after the package and the time, e.g
+// /var/lib/gentup/bugs/sys-devel_gcc-13.2.1_p20240113-r1-1712345678.tar.gz, holding:
+//
+//   emerge-info.txt     emerge --info for the failed package
+//   build.log           the build log of the failed package
+//   environment         the ebuild environment portage saved beside it
+//   transcript.txt      what this run did, from emerge.log, since it started
+//   report.json         the run report so far
+//
+// The path of the bundle goes into the post-update checklist, which is emailed, and the run
+// report, which is delivered to the webhook and MQTT broker
+
+use crate::{
+    config::state_dir,
+    events::{self, Event},
+    linux::OsCall,
+    portage, preflight, prompt, report,
+    tempfile::TempDir,
+};
+use crossterm::style::Color;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+// Define a struct to describe a package which failed to build
+//
+pub struct FailedBuild {
+    pub package: String, // e.g sys-devel/gcc-13.2.1_p20240113-r1
+    pub build_log: PathBuf,
+}
+
+// Portage leaves the build directory of a failed package in place, so the most recently modified
+// build.log under PORTAGE_TMPDIR belongs to the package which just failed
+//
+pub fn find_failed_build() -> Option<FailedBuild> {
+    let tmpdir = portage::portage_tmpdir();
+    let mut newest: Option<(SystemTime, FailedBuild)> = None;
+    for root in [tmpdir.as_str(), preflight::NOTMPFS_DIR] {
+        let Ok(categories) = fs::read_dir(Path::new(root).join("portage")) else {
+            continue;
+        };
+        for category in categories.flatten() {
+            let Ok(packages) = fs::read_dir(category.path()) else {
+                continue;
+            };
+            for package in packages.flatten() {
+                let build_log = package.path().join("temp/build.log");
+                let Ok(modified) = fs::metadata(&build_log).and_then(|m| m.modified()) else {
+                    continue;
+                };
+                let is_newer = match &newest {
+                    Some((newest, _)) => modified > *newest,
+                    None => true,
+                };
+                if is_newer {
+                    let name = [
+                        category.file_name().to_string_lossy(),
+                        package.file_name().to_string_lossy(),
+                    ]
+                    .join("/");
+                    newest = Some((
+                        modified,
+                        FailedBuild {
+                            package: name,
+                            build_log,
+                        },
+                    ));
+                }
+            }
+        }
+    }
+    newest.map(|(_, failed)| failed)
+}
+
+// The name of the bundle for a package which failed at the given time
+//
+pub fn bundle_name(package: &str, time: u64) -> String {
+    format!("{}-{}.tar.gz", package.replace('/', "_"), time)
+}
+
+// The lines of emerge.log written since the given time, in seconds since the epoch
+//
+pub fn log_since(contents: &str, since: u64) -> String {
+    contents
+        .lines()
+        .filter(|line| {
+            line.split_once(':')
+                .and_then(|(timestamp, _)| timestamp.trim().parse::<u64>().ok())
+                .is_some_and(|timestamp| timestamp >= since)
+        })
+        .map(|line| [line, "\n"].concat())
+        .collect()
+}
+
+// Gather the bundle for the package which just failed to build. Returns its path
+//
+pub fn create(run_started: u64) -> Option<String> {
+    let failed = find_failed_build()?;
+    let staging = TempDir::create().ok()?;
+    let staged = |name: &str| [staging.path(), "/", name].concat();
+    let emerge_info = OsCall::Spinner
+        .execute(
+            &["emerge --info =", &failed.package].concat(),
+            "Collecting emerge --info for the bug report",
+        )
+        .map(|(output, _)| output)
+        .unwrap_or_default();
+    let transcript = log_since(
+        &fs::read_to_string(portage::target_path(portage::EMERGE_LOG)).unwrap_or_default(),
+        run_started,
+    );
+    let written = fs::write(staged("emerge-info.txt"), emerge_info)
+        .and_then(|_| fs::copy(&failed.build_log, staged("build.log")))
+        .and_then(|_| fs::write(staged("transcript.txt"), transcript))
+        .and_then(|_| {
+            fs::write(
+                staged("report.json"),
+                report::snapshot().unwrap_or_default(),
+            )
+        });
+    if let Some(environment) = failed
+        .build_log
+        .parent()
+        .map(|temp| temp.join("environment"))
+    {
+        let _ = fs::copy(environment, staged("environment"));
+    }
+    let directory = [state_dir(), "/bugs"].concat();
+    let path = [
+        &directory,
+        "/",
+        &bundle_name(&failed.package, report::now()),
+    ]
+    .concat();
+    let bundled = written.is_ok()
+        && fs::create_dir_all(&directory).is_ok()
+        && matches!(
+            OsCall::Quiet.execute(
+                &["tar -czf ", &path, " -C ", staging.path(), " ."].concat(),
+                ""
+            ),
+            Ok((_, 0))
+        );
+    if !bundled {
+        eprintln!(
+            "{} Could not create the bug report bundle {}",
+            prompt::revchevrons(Color::Yellow),
+            path
+        );
+        return None;
+    }
+    println!(
+        "{} The build log, emerge --info and environment of {} are bundled in {}, ready to attach to a bug report",
+        prompt::revchevrons(Color::Yellow),
+        failed.package,
+        path
+    );
+    events::emit(Event::BugReport {
+        package: failed.package,
+        path: path.clone(),
+    });
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_failed_builds() {
+        assert_eq!(
+            bundle_name("sys-devel/gcc-13.2.1_p20240113-r1", 1712345678),
+            "sys-devel_gcc-13.2.1_p20240113-r1-1712345678.tar.gz"
+        );
+        let log = "\
+1712345000:  >>> emerge (1 of 2) sys-libs/zlib-1.3.1 to /
+1712345600: Started emerge on: Apr 05, 2024 10:40:00
+1712345601:  >>> emerge (1 of 1) sys-devel/gcc-13.2.1_p20240113-r1 to /
+";
+        assert_eq!(
+            log_since(log, 1712345600),
+            "1712345600: Started emerge on: Apr 05, 2024 10:40:00\n\
+            1712345601:  >>> emerge (1 of 1) sys-devel/gcc-13.2.1_p20240113-r1 to /\n"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// Stale build directories
+// When a build crashes, is killed, or the machine loses power, portage leaves the package's build
+// directory behind in PORTAGE_TMPDIR, e.g /var/tmp/portage/www-client/chromium-126.0.6478.126,
+// and nothing ever removes it. On a build machine these can quietly take tens of gigabytes. Before
+// the update, any build directory older than the run is listed with its size, and the user is
+// offered to delete them. After the update they are listed again, and the cleanup phase deletes
+// them. A directory whose build is still running, which portage marks with a lock file beside it,
+// e.g .chromium-126.0.6478.126.portage_lockfile, is left alone
+
+use crate::{cleanup, portage, preflight, prompt, Prompt};
+use crossterm::style::Color;
+use std::{fs, path::Path, time::UNIX_EPOCH};
+
+// The space used by a directory and everything in it, in bytes
+//
+fn directory_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| directory_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+// The build directories beneath a portage build root, e.g /var/tmp/portage, which were last
+// changed before the given time in seconds since the epoch and are not locked by a running build
+//
+pub fn scan(build_root: &Path, started: u64) -> Vec<(String, u64)> {
+    let mut stale = Vec::new();
+    let Ok(categories) = fs::read_dir(build_root) else {
+        return stale;
+    };
+    for category in categories.flatten() {
+        let Ok(entries) = fs::read_dir(category.path()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || !entry.path().is_dir() {
+                continue;
+            }
+            if category
+                .path()
+                .join(format!(".{}.portage_lockfile", name))
+                .exists()
+            {
+                continue;
+            }
+            let modified = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs())
+                .unwrap_or(u64::MAX);
+            if modified < started {
+                stale.push((
+                    entry.path().to_string_lossy().to_string(),
+                    directory_size(&entry.path()),
+                ));
+            }
+        }
+    }
+    stale.sort();
+    stale
+}
+
+// Find the stale build directories in PORTAGE_TMPDIR, and where huge packages are built on disk,
+// and list them
+//
+fn find(started: u64) -> Vec<(String, u64)> {
+    let tmpdir = portage::portage_tmpdir();
+    let mut stale = Vec::new();
+    for directory in [tmpdir.as_str(), preflight::NOTMPFS_DIR] {
+        let build_root = portage::target_path(&[directory, "/portage"].concat());
+        stale.extend(scan(Path::new(&build_root), started));
+    }
+    if !stale.is_empty() {
+        let total: u64 = stale.iter().map(|(_, bytes)| bytes).sum();
+        println!(
+            "{} {} build directories were left behind by earlier builds, using {}:",
+            prompt::revchevrons(Color::Yellow),
+            stale.len(),
+            cleanup::format_bytes(total)
+        );
+        for (path, bytes) in &stale {
+            println!("    {} ({})", path, cleanup::format_bytes(*bytes));
+        }
+    }
+    stale
+}
+
+fn remove(stale: &[(String, u64)]) {
+    for (path, _) in stale {
+        if let Err(error) = fs::remove_dir_all(path) {
+            eprintln!(
+                "{} Could not remove {} - {}",
+                prompt::revchevrons(Color::Yellow),
+                path,
+                error
+            );
+        }
+    }
+}
+
+// Before the update, list any stale build directories and offer to delete them
+//
+pub fn offer_removal(started: u64) {
+    let stale = find(started);
+    if !stale.is_empty()
+        && Prompt::AllowSkip
+            .askuser("stale-builds", "Delete these build directories")
+            .is_some()
+    {
+        remove(&stale);
+    }
+}
+
+// After the update, list any stale build directories, and delete them if cleaning up
+//
+pub fn after_update(started: u64, cleanup: bool) {
+    let stale = find(started);
+    if cleanup {
+        remove(&stale);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_stale_build_directories() {
+        let root = std::env::temp_dir().join(format!("gentup-builds-{}", std::process::id()));
+        let crashed = root.join("www-client/chromium-126.0.6478.126");
+        fs::create_dir_all(crashed.join("temp")).unwrap();
+        fs::write(crashed.join("temp/build.log"), vec![b'x'; 4096]).unwrap();
+        fs::create_dir_all(root.join("dev-libs/openssl-3.0.13")).unwrap();
+        fs::write(root.join("dev-libs/.openssl-3.0.13.portage_lockfile"), "").unwrap();
+
+        let later = crate::report::now() + 60;
+        let stale = scan(&root, later);
+        assert_eq!(stale.len(), 1);
+        assert!(stale[0].0.ends_with("www-client/chromium-126.0.6478.126"));
+        assert_eq!(stale[0].1, 4096);
+        assert!(scan(&root, 0).is_empty()); // Nothing is older than the epoch
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,218 @@
+// Per-run package.env overrides
+// Build settings for particular packages, for the updates of one run only, e.g fewer make jobs for
+// chromium on a machine short of memory, or ccache turned off for a package it breaks. They are
+// given in the config file, one package per line:
+//
+//   package_env: www-client/chromium MAKEOPTS="-j2 -l2"
+//   package_env: app-misc/flaky FEATURES=-ccache
+//
+// or for one run on the command line, separated by semicolons:
+//
+//   gentup --package-env "www-client/chromium MAKEOPTS=-j2; app-misc/flaky FEATURES=-ccache"
+//
+// Before building, gentup writes the settings to /etc/portage/env/gentup-run-<n>.conf, and lists
+// each package against its file in /etc/portage/package.env/gentup-run. Both are removed after
+// the build, and anything left behind by a run which did not finish is removed by the next
+
+use crate::{portage, prompt, Config};
+use crossterm::style::Color;
+use std::{fmt, fs, io::Write, path::Path};
+
+// The package.env file listing the overridden packages, and the prefix of their env files
+static PACKAGE_ENV_FILE: &str = "/etc/portage/package.env/gentup-run";
+static ENV_FILE_PREFIX: &str = "gentup-run-";
+
+// Define a struct to hold the build settings given to one package
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+    pub package: String,                 // An atom, e.g www-client/chromium
+    pub settings: Vec<(String, String)>, // Each variable and its value, e.g MAKEOPTS and -j2
+}
+
+// Split a line into words, keeping the spaces inside double quotes and removing the quotes
+//
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for character in line.chars() {
+        match character {
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            _ => word.push(character),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+impl Override {
+    // Parse a package followed by its settings, e.g www-client/chromium MAKEOPTS="-j2 -l2"
+    //
+    pub fn parse(line: &str) -> Option<Override> {
+        let mut words = words(line).into_iter();
+        let package = words.next().filter(|package| package.contains('/'))?;
+        let settings: Vec<(String, String)> = words
+            .map(|word| {
+                let (variable, value) = word.split_once('=')?;
+                let valid = !variable.is_empty()
+                    && variable
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+                valid.then(|| (variable.to_string(), value.to_string()))
+            })
+            .collect::<Option<_>>()?;
+        if settings.is_empty() {
+            return None;
+        }
+        Some(Override { package, settings })
+    }
+
+    // Parse the overrides given on the command line, separated by semicolons
+    //
+    pub fn parse_list(list: &str) -> Option<Vec<Override>> {
+        list.split(';')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(Override::parse)
+            .collect()
+    }
+
+    // The contents of the env file holding the settings
+    //
+    fn env_file(&self) -> String {
+        self.settings
+            .iter()
+            .map(|(variable, value)| format!("{}=\"{}\"\n", variable, value))
+            .collect()
+    }
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.package)?;
+        for (variable, value) in &self.settings {
+            if value.contains(' ') {
+                write!(f, " {}=\"{}\"", variable, value)?;
+            } else {
+                write!(f, " {}={}", variable, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Remove the overrides written for a run
+//
+pub fn remove() {
+    let _ = fs::remove_file(portage::target_path(PACKAGE_ENV_FILE));
+    if let Ok(entries) = fs::read_dir(portage::target_path("/etc/portage/env")) {
+        for entry in entries.flatten() {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(ENV_FILE_PREFIX)
+            {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+// Write the overrides from the config file and the command line for this run's build
+//
+pub fn apply(running_config: &Config, from_command_line: &[Override]) {
+    remove(); // Anything left over from a previous run which did not finish
+    let overrides: Vec<&Override> = running_config
+        .package_env
+        .iter()
+        .chain(from_command_line)
+        .collect();
+    if overrides.is_empty() {
+        return;
+    }
+    if !Path::new(&portage::target_path("/etc/portage/package.env")).is_dir() {
+        println!(
+            "{} /etc/portage/package.env is not a directory, so the package_env overrides cannot be applied",
+            prompt::revchevrons(Color::Yellow)
+        );
+        return;
+    }
+    let env_dir = portage::target_path("/etc/portage/env");
+    let written = fs::create_dir_all(&env_dir).and_then(|_| {
+        let mut package_env = fs::File::create(portage::target_path(PACKAGE_ENV_FILE))?;
+        for (number, each) in overrides.iter().enumerate() {
+            let name = format!("{}{}.conf", ENV_FILE_PREFIX, number + 1);
+            fs::write([&env_dir, "/", &name].concat(), each.env_file())?;
+            writeln!(package_env, "{} {}", each.package, name)?;
+        }
+        Ok(())
+    });
+    match written {
+        Ok(_) => {
+            for each in &overrides {
+                println!(
+                    "{} Building {} with {} for this update",
+                    prompt::revchevrons(Color::Green),
+                    each.package,
+                    &each.to_string()[each.package.len() + 1..]
+                );
+            }
+        }
+        Err(error) => {
+            eprintln!(
+                "{} Could not write the package_env overrides: {}",
+                prompt::revchevrons(Color::Red),
+                error
+            );
+            remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_overrides() {
+        let chromium =
+            Override::parse("www-client/chromium MAKEOPTS=\"-j2 -l2\" FEATURES=-ccache").unwrap();
+        assert_eq!(chromium.package, "www-client/chromium");
+        assert_eq!(
+            chromium.settings,
+            vec![
+                ("MAKEOPTS".to_string(), "-j2 -l2".to_string()),
+                ("FEATURES".to_string(), "-ccache".to_string())
+            ]
+        );
+        assert_eq!(
+            chromium.to_string(),
+            "www-client/chromium MAKEOPTS=\"-j2 -l2\" FEATURES=-ccache"
+        );
+        assert_eq!(
+            chromium.env_file(),
+            "MAKEOPTS=\"-j2 -l2\"\nFEATURES=\"-ccache\"\n"
+        );
+        assert_eq!(Override::parse("www-client/chromium"), None);
+        assert_eq!(Override::parse("chromium MAKEOPTS=-j2"), None);
+        assert_eq!(Override::parse("www-client/chromium makeopts=-j2"), None);
+
+        let list = Override::parse_list(
+            "www-client/chromium MAKEOPTS=-j2; app-misc/flaky FEATURES=-ccache;",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].package, "app-misc/flaky");
+        assert_eq!(
+            Override::parse_list("www-client/chromium; app-misc/flaky FEATURES=-ccache"),
+            None
+        );
+    }
+}
@@ -0,0 +1,102 @@
+// Changed dependency rebuilds
+// Ebuilds sometimes change their dependencies without a version bump, e.g to add a missing
+// dependency or a subslot operator, and packages built before the change keep the old ones until
+// something else rebuilds them. With changed_deps set in the configuration file, the update also
+// runs emerge --changed-deps, which replaces those packages, and reports which packages this added
+// to the update. Finding them means comparing the dependencies of every installed package with its
+// ebuild, which takes a while on a large system, so it is off by default. The additions show as
+// reinstalls of the same version, e.g
+//
+//   [ebuild   R    ] dev-python/setuptools-69.5.1:0::gentoo  USE="-test" PYTHON_TARGETS="python3_12"
+
+use crate::{
+    linux::OsCall,
+    portage::{self, Change},
+    prompt, Config,
+};
+use crossterm::style::Color;
+use std::sync::OnceLock;
+
+// The option to add to the world update command line, once the pass has found something
+static UPDATE_OPTION: OnceLock<&'static str> = OnceLock::new();
+
+// The changes which only the --changed-deps pass would make
+//
+pub fn added(without: &[Change], with: Vec<Change>) -> Vec<Change> {
+    with.into_iter()
+        .filter(|change| {
+            !without
+                .iter()
+                .any(|known| known.package.cpv() == change.package.cpv())
+        })
+        .collect()
+}
+
+// If the configuration asks for it, find the packages whose dependencies changed since they were
+// built, and add them to the pending updates
+//
+pub fn add_rebuilds(running_config: &Config, changes: &mut Vec<Change>) {
+    if !running_config.changed_deps {
+        return;
+    }
+    let output = match OsCall::Spinner.execute(
+        "emerge -puDv --changed-deps y @world",
+        "Checking for changed dependencies",
+    ) {
+        Ok((output, 0)) => output,
+        _ => {
+            eprintln!(
+                "{} Could not check for packages with changed dependencies",
+                prompt::revchevrons(Color::Yellow)
+            );
+            return;
+        }
+    };
+    let rebuilds = added(changes, portage::parse_changes(&output));
+    if rebuilds.is_empty() {
+        return;
+    }
+    println!(
+        "{} --changed-deps added {} package(s) whose dependencies changed without a version bump:",
+        prompt::revchevrons(Color::Yellow),
+        rebuilds.len()
+    );
+    for change in &rebuilds {
+        println!("    {}", change.package);
+    }
+    let _ = UPDATE_OPTION.set(" --changed-deps y");
+    changes.extend(rebuilds);
+}
+
+// The option to add to the world update command line
+//
+pub fn update_option() -> &'static str {
+    UPDATE_OPTION.get().copied().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_changed_dependency_rebuilds() {
+        let without = portage::parse_changes(
+            "[ebuild     U  ] sys-libs/zlib-1.3.1:0/1::gentoo [1.3:0/1::gentoo] 1,564 KiB\n",
+        );
+        let with = portage::parse_changes(
+            "\
+[ebuild   R    ] dev-python/setuptools-69.5.1:0::gentoo  USE=\"-test\" 0 KiB
+[ebuild     U  ] sys-libs/zlib-1.3.1:0/1::gentoo [1.3:0/1::gentoo] 1,564 KiB
+[ebuild   R    ] dev-libs/libxml2-2.12.7:2::gentoo  USE=\"python\" 0 KiB
+",
+        );
+        let rebuilds: Vec<String> = added(&without, with)
+            .iter()
+            .map(|change| change.package.cpv())
+            .collect();
+        assert_eq!(
+            rebuilds,
+            vec!["dev-python/setuptools-69.5.1", "dev-libs/libxml2-2.12.7"]
+        );
+    }
+}
@@ -0,0 +1,430 @@
+// Standalone cleanup
+// "gentup --clean" runs the cleanup stages of an update on their own, for use between updates. Each
+// stage is first run in its pretend mode, and the results are combined into one preview of exactly
+// what would be removed, rebuilt or trimmed, with the space each stage would free. Nothing is
+// changed until the preview has been accepted. The stages are:
+//
+//   orphaned dependencies           emerge -p --depclean
+//   broken reverse dependencies     revdep-rebuild -ip
+//   obsolete portage configuration  eix-test-obsolete, which is only reported
+//   unused source distfiles         eclean --pretend -d distfiles
+//   unused binary packages          eclean --pretend -d packages
+//   old kernels                     eclean-kernel -a -p
+//   filesystem trim                 the mounts on solid state storage, with --trim
+
+use crate::{
+    distclean, eixdb,
+    exitcode::ExitCode,
+    linux::{self, OsCall},
+    portage::{self, PackageManager},
+    prompt, rotational, Config, Prompt,
+};
+use crossterm::style::Color;
+use std::fs;
+
+// Define a struct to hold what one cleanup stage would do
+//
+#[derive(Debug, Default)]
+pub struct Stage {
+    pub name: &'static str,
+    pub items: Vec<String>,
+    pub bytes: u64, // The space which would be freed
+}
+
+// Format a number of bytes for display, e.g 1.5 GiB
+//
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+// Convert a size as eclean displays it, e.g 1.2 G or 345 K, to bytes
+//
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(size.len());
+    let number: f64 = size[..split].trim().parse().ok()?;
+    let multiplier = match size[split..].trim().chars().next() {
+        None | Some('B') => 1.0,
+        Some('K') => 1024.0,
+        Some('M') => 1024.0 * 1024.0,
+        Some('G') => 1024.0 * 1024.0 * 1024.0,
+        Some('T') => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((number * multiplier) as u64)
+}
+
+// Parse the output of emerge -p --depclean into the packages it would remove, e.g
+//
+//   >>> These are the packages that would be unmerged:
+//
+//    dev-python/six
+//       selected: 1.16.0
+//      protected: none
+//        omitted: none
+//
+pub fn parse_unmerge_list(output: &str) -> Vec<String> {
+    let mut packages = Vec::new();
+    let mut cpn = "";
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(versions) = line.strip_prefix("selected:") {
+            for version in versions
+                .split_whitespace()
+                .filter(|version| *version != "none")
+            {
+                packages.push([cpn, "-", version].concat());
+            }
+        } else if line.contains('/') && !line.contains(' ') {
+            cpn = line;
+        }
+    }
+    packages
+}
+
+// Parse the output of eclean --pretend into the files it would delete, each with its size, and
+// the space freed. The last line gives the total, e.g
+//
+//    [    4.3 M ] Python-3.11.9.tar.xz
+//    [    1.2 G ] Total space from 34 files that would be freed in distfiles directory
+//
+pub fn parse_eclean(output: &str) -> (Vec<(String, u64)>, u64) {
+    let mut files = Vec::new();
+    let mut listed = 0;
+    let mut total = None;
+    for line in output.lines() {
+        let Some((size, name)) = line
+            .trim()
+            .strip_prefix('[')
+            .and_then(|line| line.split_once(']'))
+        else {
+            continue;
+        };
+        let Some(bytes) = parse_size(size) else {
+            continue;
+        };
+        let name = name.trim();
+        if name.starts_with("Total space") {
+            total = Some(bytes);
+        } else {
+            files.push((name.to_string(), bytes));
+            listed += bytes;
+        }
+    }
+    (files, total.unwrap_or(listed))
+}
+
+// Parse the output of eclean-kernel -p into the files and directories it would remove, which are
+// listed one to a line after a dash, e.g
+//
+//   - vmlinuz: /boot/vmlinuz-6.6.13-gentoo
+//   - modules: /lib/modules/6.6.13-gentoo
+//
+pub fn parse_eclean_kernel(output: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for line in output.lines() {
+        let Some(rest) = line.trim().strip_prefix("- ") else {
+            continue;
+        };
+        let path = rest.rsplit_once(": ").map(|(_, path)| path).unwrap_or(rest);
+        if path.starts_with('/') && !paths.iter().any(|known| known == path) {
+            paths.push(path.to_string());
+        }
+    }
+    paths
+}
+
+// The space in bytes used by some files and directories
+//
+fn disk_usage(paths: &[String]) -> u64 {
+    if paths.is_empty() || paths.iter().any(|path| path.contains(char::is_whitespace)) {
+        return 0; // Cannot be passed through OsCall, which splits the command line on spaces
+    }
+    OsCall::Quiet
+        .execute(&["du -sbc ", &paths.join(" ")].concat(), "")
+        .ok()
+        .and_then(|(output, _)| {
+            output
+                .lines()
+                .last()?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        })
+        .unwrap_or(0)
+}
+
+// The output of a pretend command, or nothing if it could not be run
+//
+fn pretend(command: &str, status: &str) -> String {
+    OsCall::Spinner
+        .execute(command, status)
+        .map(|(output, _)| output)
+        .unwrap_or_default()
+}
+
+// Run each stage in its pretend mode, and collect what each would do
+//
+fn preview(running_config: &Config, trim: bool) -> (Vec<Stage>, String) {
+    let mut stages = Vec::new();
+
+    let depclean = pretend("emerge -p --depclean", "Checking for orphaned dependencies");
+    let orphans = parse_unmerge_list(&depclean);
+    let bytes = orphans
+        .iter()
+        .filter_map(|package| {
+            fs::read_to_string(portage::target_path(
+                &["/var/db/pkg/", package, "/SIZE"].concat(),
+            ))
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()
+        })
+        .sum();
+    stages.push(Stage {
+        name: "Orphaned dependencies",
+        items: orphans,
+        bytes,
+    });
+
+    let revdep = pretend("revdep-rebuild -ip", "Checking reverse dependencies");
+    stages.push(Stage {
+        name: "Reverse dependency rebuilds",
+        items: portage::parse_changes(&revdep)
+            .iter()
+            .map(|change| change.package.to_string())
+            .collect(),
+        bytes: 0,
+    });
+
+    let obsolete = eixdb::query("eix-test-obsolete")
+        .map(|(output, _)| output)
+        .unwrap_or_default();
+    stages.push(Stage {
+        name: "Obsolete configuration (to review)",
+        items: obsolete
+            .lines()
+            .filter(|line| line.starts_with([' ', '\t']) && !line.trim().is_empty())
+            .map(|line| line.trim().to_string())
+            .collect(),
+        bytes: 0,
+    });
+
+    // Distfiles and binary packages are listed by package, each with the files it would lose
+    //
+    for (action, name) in [
+        ("distfiles", "Unused distfiles"),
+        ("packages", "Unused binary packages"),
+    ] {
+        let groups = distclean::preview(running_config, action);
+        stages.push(Stage {
+            name,
+            items: groups
+                .iter()
+                .map(|group| {
+                    format!(
+                        "{} ({}): {}",
+                        group.package,
+                        format_bytes(group.bytes),
+                        group
+                            .files
+                            .iter()
+                            .map(|(file, _)| file.as_str())
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    )
+                })
+                .collect(),
+            bytes: groups.iter().map(|group| group.bytes).sum(),
+        });
+    }
+
+    if portage::running_system() {
+        let kernels = parse_eclean_kernel(&pretend("eclean-kernel -a -p", "Checking old kernels"));
+        let bytes = disk_usage(&kernels);
+        stages.push(Stage {
+            name: "Old kernels",
+            items: kernels,
+            bytes,
+        });
+    }
+
+    if trim {
+        stages.push(Stage {
+            name: "Filesystems to trim",
+            items: rotational::trimmable_mounts(),
+            bytes: 0,
+        });
+    }
+    (stages, depclean)
+}
+
+// Preview the cleanup, then if the user accepts it, carry it out
+//
+pub fn run(running_config: &Config, trim: bool) -> ExitCode {
+    let (stages, depclean) = preview(running_config, trim);
+    let total: u64 = stages.iter().map(|stage| stage.bytes).sum();
+    if stages.iter().all(|stage| stage.items.is_empty()) {
+        println!(
+            "{} There is nothing to clean up",
+            prompt::revchevrons(Color::Blue)
+        );
+        return ExitCode::NothingToDo;
+    }
+    println!(
+        "{} The cleanup would free {}:\n",
+        prompt::revchevrons(Color::Green),
+        format_bytes(total)
+    );
+    for stage in stages.iter().filter(|stage| !stage.items.is_empty()) {
+        println!(
+            "{} ({}{})",
+            stage.name,
+            stage.items.len(),
+            if stage.bytes > 0 {
+                [", ", &format_bytes(stage.bytes)].concat()
+            } else {
+                String::new()
+            }
+        );
+        for item in &stage.items {
+            println!("    {}", item);
+        }
+        println!();
+    }
+    if Prompt::AllowSkip
+        .askuser("cleanup", "Carry out this cleanup")
+        .is_none()
+    {
+        return ExitCode::NothingToDo;
+    }
+    let has_items = |name: &str| {
+        stages
+            .iter()
+            .any(|stage| stage.name == name && !stage.items.is_empty())
+    };
+
+    // Record the exact versions of the active toolchain, so that it can be restored if cleanup
+    // manages to break it
+    //
+    let toolchain: Vec<String> = portage::active_toolchain()
+        .iter()
+        .map(|atom| portage::installed_version(atom))
+        .collect();
+    if has_items("Orphaned dependencies") {
+        let kernels = portage::parse_depclean(&depclean)
+            .map(|(_, kernels)| kernels)
+            .unwrap_or_default();
+        if portage::running_system() && kernels.contains(&linux::running_kernel()) {
+            println!(
+                "{} Preserving currently running kernel",
+                prompt::chevrons(Color::Green)
+            );
+            PackageManager::PreserveKernel.depclean();
+        } else {
+            PackageManager::AllPackages.depclean();
+        }
+    }
+    if has_items("Reverse dependency rebuilds") {
+        PackageManager::NoDryRun.revdep_rebuild();
+    }
+    portage::verify_toolchain(&toolchain);
+    if has_items("Unused distfiles") {
+        distclean::remove(running_config, "distfiles");
+    }
+    if has_items("Unused binary packages") {
+        distclean::remove(running_config, "packages");
+    }
+    if has_items("Old kernels") {
+        portage::clean_old_kernels();
+    }
+    if has_items("Filesystems to trim") {
+        linux::call_fstrim();
+    }
+    println!("{} Cleanup complete", prompt::revchevrons(Color::Green));
+    ExitCode::NothingToDo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_the_cleanup() {
+        let depclean = "\
+>>> These are the packages that would be unmerged:
+
+ dev-python/six
+    selected: 1.16.0
+   protected: none
+     omitted: none
+
+ sys-kernel/gentoo-kernel-bin
+    selected: 6.6.13 6.6.21
+   protected: none
+     omitted: 6.6.30
+
+All selected packages: =dev-python/six-1.16.0 =sys-kernel/gentoo-kernel-bin-6.6.13
+";
+        assert_eq!(
+            parse_unmerge_list(depclean),
+            vec![
+                "dev-python/six-1.16.0",
+                "sys-kernel/gentoo-kernel-bin-6.6.13",
+                "sys-kernel/gentoo-kernel-bin-6.6.21"
+            ]
+        );
+
+        let eclean = "\
+ * Building file list for distfiles cleaning...
+ * These are the files that would be deleted from distfiles directory:
+
+ [    4.3 M ] Python-3.11.9.tar.xz
+ [  512.0 K ] zlib-1.3.tar.xz
+ ===========
+ [    4.8 M ] Total space from 2 files that would be freed in distfiles directory
+";
+        let (files, bytes) = parse_eclean(eclean);
+        assert_eq!(
+            files,
+            vec![
+                ("Python-3.11.9.tar.xz".to_string(), 4508876),
+                ("zlib-1.3.tar.xz".to_string(), 524288)
+            ]
+        );
+        assert_eq!(bytes, 5033164);
+
+        let kernel = "\
+Legend: [-] file being removed, [x] file being kept
+* Removing kernel 6.6.13-gentoo
+- vmlinuz: /boot/vmlinuz-6.6.13-gentoo
+- modules: /lib/modules/6.6.13-gentoo
+- /boot/System.map-6.6.13-gentoo
+";
+        assert_eq!(
+            parse_eclean_kernel(kernel),
+            vec![
+                "/boot/vmlinuz-6.6.13-gentoo",
+                "/lib/modules/6.6.13-gentoo",
+                "/boot/System.map-6.6.13-gentoo"
+            ]
+        );
+        assert_eq!(format_bytes(5033164), "4.8 MiB");
+        assert_eq!(format_bytes(512), "512 B");
+    }
+}
@@ -0,0 +1,252 @@
+// File collision analysis
+// When a package would overwrite files which are already on disk, portage refuses to merge it and
+// prints a long error, which ends up in the package's elog messages:
+//
+//   ERROR: preinst
+//   Detected file collision(s):
+//   /usr/bin/foo
+//   /usr/share/man/man1/foo.1.bz2
+//   Searching all installed packages for file collisions...
+//
+// When the update fails, the colliding files are picked out of the elog messages, and each is
+// looked up in the CONTENTS files of the package database to find the package it belongs to. The
+// failure is then explained in a few lines, with what can be done about it: a file which belongs to
+// another package means the two packages conflict, and one has to go, while a file which belongs
+// to no package was left behind by a manual install or an interrupted merge, and can be deleted
+
+use crate::{atom::Package, elog, portage, prompt};
+use crossterm::style::Color;
+use std::{collections::HashMap, fs};
+
+// How many files are listed for each package, or for no package
+static FILES_SHOWN: usize = 5;
+
+// The files a package could not merge because they already exist, from its elog messages
+//
+pub fn colliding_paths(messages: &[elog::Message]) -> Vec<String> {
+    let mut paths = Vec::new();
+    for message in messages.iter().filter(|message| message.class == "ERROR") {
+        let mut in_list = false;
+        for line in &message.lines {
+            if line.starts_with("Detected file collision") {
+                in_list = true;
+            } else if in_list && line.starts_with('/') {
+                if !paths.contains(line) {
+                    paths.push(line.to_string());
+                }
+            } else {
+                in_list = false;
+            }
+        }
+    }
+    paths
+}
+
+// The files and symbolic links listed in a package's CONTENTS file, whose lines look like
+//
+//   obj /usr/bin/foo 9e107d9d372bb6826bd81d3542a419d6 1712311200
+//   sym /usr/lib/libfoo.so -> libfoo.so.1 1712311200
+//   dir /usr/share/foo
+//
+pub fn contents_paths(contents: &str) -> Vec<&str> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            if let Some(entry) = line.strip_prefix("obj ") {
+                // The path may hold spaces, so the checksum and time are taken off the end
+                let mut fields = entry.rsplitn(3, ' ');
+                fields.nth(2)
+            } else if let Some(entry) = line.strip_prefix("sym ") {
+                entry.split_once(" -> ").map(|(path, _)| path)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// Find the installed package which owns each path, by reading every CONTENTS file in the package
+// database
+//
+pub fn owners(vdb: &str, paths: &[String]) -> Vec<(String, Option<String>)> {
+    let mut found: HashMap<&str, String> = HashMap::new();
+    if let Ok(categories) = fs::read_dir(vdb) {
+        for category in categories.flatten() {
+            let Ok(entries) = fs::read_dir(category.path()) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let Ok(contents) = fs::read_to_string(entry.path().join("CONTENTS")) else {
+                    continue;
+                };
+                for path in contents_paths(&contents) {
+                    if let Some(wanted) = paths.iter().find(|wanted| *wanted == path) {
+                        found.insert(
+                            wanted,
+                            [
+                                category.file_name().to_string_lossy(),
+                                "/".into(),
+                                entry.file_name().to_string_lossy(),
+                            ]
+                            .concat(),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    paths
+        .iter()
+        .map(|path| (path.clone(), found.get(path.as_str()).cloned()))
+        .collect()
+}
+
+// Explain a collision, and suggest how to resolve it
+//
+pub fn describe(package: &str, owned: &[(String, Option<String>)]) -> Vec<String> {
+    let mut groups: Vec<(Option<&String>, Vec<&str>)> = Vec::new();
+    for (path, owner) in owned {
+        match groups
+            .iter_mut()
+            .find(|(group, _)| *group == owner.as_ref())
+        {
+            Some((_, paths)) => paths.push(path),
+            None => groups.push((owner.as_ref(), vec![path])),
+        }
+    }
+    let mut lines = vec![format!(
+        "{} was not merged, because it would overwrite {} file(s) which are already installed",
+        package,
+        owned.len()
+    )];
+    let mut suggestions = Vec::new();
+    for (owner, paths) in &groups {
+        match owner {
+            Some(owner) => {
+                lines.push(format!("{} file(s) belong to {}:", paths.len(), owner));
+                let cpn = owner
+                    .parse::<Package>()
+                    .map(|owner| owner.cpn())
+                    .unwrap_or(owner.to_string());
+                suggestions.push(format!(
+                    "If {} replaces {}, remove it with emerge --deselect {} && emerge --unmerge ={}. Otherwise the two packages conflict, which is worth reporting at bugs.gentoo.org",
+                    package, owner, cpn, owner
+                ));
+            }
+            None => {
+                lines.push(format!(
+                    "{} file(s) belong to no installed package, so were left behind by a manual install or an interrupted merge:",
+                    paths.len()
+                ));
+                suggestions.push(format!(
+                    "Delete the {} file(s) which belong to no package, e.g rm {}",
+                    paths.len(),
+                    paths[0]
+                ));
+            }
+        }
+        for path in paths.iter().take(FILES_SHOWN) {
+            lines.push(["    ", path].concat());
+        }
+        if paths.len() > FILES_SHOWN {
+            lines.push(format!("    ... and {} more", paths.len() - FILES_SHOWN));
+        }
+    }
+    lines.push("To resolve this:".to_string());
+    for suggestion in suggestions {
+        lines.push(["    ", &suggestion].concat());
+    }
+    lines.push("    then resume the update with gentup --continue".to_string());
+    lines
+}
+
+// After a failed update, explain any file collisions reported by the packages merged since the
+// given time
+//
+pub fn explain(timestamp: u64) {
+    let root = portage::target_root().unwrap_or("");
+    for log in elog::since(timestamp) {
+        let paths = colliding_paths(&log.messages);
+        if paths.is_empty() {
+            continue;
+        }
+        // The package database lists paths relative to the root being updated
+        let relative: Vec<String> = paths
+            .iter()
+            .map(|path| path.strip_prefix(root).unwrap_or(path).to_string())
+            .collect();
+        let owned: Vec<(String, Option<String>)> =
+            owners(&portage::target_path("/var/db/pkg"), &relative)
+                .into_iter()
+                .zip(paths)
+                .map(|((_, owner), path)| (path, owner))
+                .collect();
+        let lines = describe(&log.package, &owned);
+        if let Some((first, rest)) = lines.split_first() {
+            eprintln!("{} {}", prompt::revchevrons(Color::Red), first);
+            for line in rest {
+                eprintln!("    {}", line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explains_collisions() {
+        let messages = elog::parse(
+            "\
+ERROR: preinst
+This package will overwrite one or more files that may belong to other
+packages (see list below).
+Detected file collision(s):
+
+\t/usr/bin/foo
+\t/usr/lib/libfoo.so
+\t/usr/share/foo/data file
+
+Searching all installed packages for file collisions...
+Press Ctrl-C to Stop
+app-misc/bar-1.0:0::gentoo
+\t/usr/bin/foo
+Package 'app-misc/foo-2.0' NOT merged due to file collisions.
+",
+        );
+        let paths = colliding_paths(&messages);
+        assert_eq!(
+            paths,
+            vec![
+                "/usr/bin/foo",
+                "/usr/lib/libfoo.so",
+                "/usr/share/foo/data file"
+            ]
+        );
+
+        let vdb = std::env::temp_dir().join(format!("gentup-collisions-{}", std::process::id()));
+        fs::create_dir_all(vdb.join("app-misc/bar-1.0")).unwrap();
+        fs::write(
+            vdb.join("app-misc/bar-1.0/CONTENTS"),
+            "dir /usr/bin\n\
+            obj /usr/bin/foo 9e107d9d372bb6826bd81d3542a419d6 1712311200\n\
+            obj /usr/share/foo/data file d41d8cd98f00b204e9800998ecf8427e 1712311200\n\
+            sym /usr/lib/libbar.so -> libbar.so.1 1712311200\n",
+        )
+        .unwrap();
+        let owned = owners(&vdb.to_string_lossy(), &paths);
+        let _ = fs::remove_dir_all(&vdb);
+        assert_eq!(owned[0].1.as_deref(), Some("app-misc/bar-1.0"));
+        assert_eq!(owned[1].1, None);
+        assert_eq!(owned[2].1.as_deref(), Some("app-misc/bar-1.0"));
+
+        let lines = describe("app-misc/foo-2.0", &owned);
+        assert_eq!(lines[1], "2 file(s) belong to app-misc/bar-1.0:");
+        assert!(lines.iter().any(|line| line
+            .contains("emerge --deselect app-misc/bar && emerge --unmerge =app-misc/bar-1.0")));
+        assert!(lines
+            .iter()
+            .any(|line| line.ends_with("e.g rm /usr/lib/libfoo.so")));
+    }
+}
@@ -0,0 +1,431 @@
+// Compiler cache and distributed compile statistics
+// When FEATURES includes ccache or distcc, gentup measures how much use the build phase made of
+// them, to show whether a ccache or distcc setup is actually paying off. ccache keeps counters,
+// which are read before and after the build to give the hit rate. distcc keeps no history on the
+// client, so distccmon-text is sampled while the build runs and the percentage of the compile
+// jobs seen running on other hosts is reported
+//
+// A ccache which is misconfigured costs the time of every rebuild without any error, so the cache
+// directory is checked before the build, and a cache too small to keep what is built is pointed
+// out after it. "gentup --setup" can install and configure ccache for those who want it
+
+use crate::{
+    actions,
+    events::{self, Event},
+    linux::OsCall,
+    makeconf, portage, prompt, Prompt,
+};
+use crossterm::style::Color;
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// Parse the counters from "ccache --print-stats", which are tab separated names and values, into
+// the number of cache hits and misses
+//
+pub fn parse_ccache_stats(output: &str) -> Option<(u64, u64)> {
+    let mut hits = 0;
+    let mut misses = None;
+    for line in output.lines() {
+        let Some((name, value)) = line.split_once('\t') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<u64>() else {
+            continue;
+        };
+        match name {
+            "direct_cache_hit" | "preprocessed_cache_hit" => hits += value,
+            "cache_miss" => misses = Some(value),
+            _ => {}
+        }
+    }
+    Some((hits, misses?))
+}
+
+// Count the compile jobs in the output of distccmon-text which are running on this host and on
+// other hosts. Each job is listed with the host running it last, e.g
+//   3117  Compile     unix.c                                 192.168.1.20[2]
+//
+pub fn parse_distccmon(output: &str) -> (u64, u64) {
+    let mut local = 0;
+    let mut remote = 0;
+    for line in output.lines() {
+        let Some(host) = line.split_whitespace().last() else {
+            continue;
+        };
+        let Some((host, _slot)) = host.split_once('[') else {
+            continue;
+        };
+        if host == "localhost" || host.starts_with("127.") || host == "::1" {
+            local += 1;
+        } else {
+            remote += 1;
+        }
+    }
+    (local, remote)
+}
+
+// The size of the cache in kibibytes, from "ccache --print-stats"
+//
+pub fn parse_ccache_size(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("cache_size_kibibyte\t"))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+// Convert a ccache size setting, such as 5G, 500M or 20Gi, to kibibytes. Plain suffixes are
+// decimal and those ending in i are binary, as ccache takes them
+//
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, suffix) = size.split_at(
+        size.find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(size.len()),
+    );
+    let number: f64 = number.trim().parse().ok()?;
+    let bytes = match suffix {
+        "" | "G" => number * 1e9,
+        "K" => number * 1e3,
+        "M" => number * 1e6,
+        "T" => number * 1e12,
+        "Ki" => number * 1024.0,
+        "Mi" => number * 1024.0 * 1024.0,
+        "Gi" => number * 1024.0 * 1024.0 * 1024.0,
+        "Ti" => number * 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((bytes / 1024.0) as u64)
+}
+
+// Advise on a ccache which made the given hits and misses over a build. A cache which is close to
+// its size limit is evicting objects before they can be used again
+//
+pub fn ccache_advice(hits: u64, misses: u64, used_kib: u64, max_kib: u64) -> Option<String> {
+    if hits + misses == 0 || max_kib == 0 {
+        return None;
+    }
+    if used_kib * 100 >= max_kib * 90 && percentage(hits, hits + misses) < 30 {
+        return Some(format!(
+            "ccache is {}% full with a hit rate of {}%, so it is discarding objects before they are \
+            reused - raise max_size in its ccache.conf",
+            percentage(used_kib, max_kib),
+            percentage(hits, hits + misses)
+        ));
+    }
+    None
+}
+
+fn ccache_stats(ccache_dir: &str) -> Option<(u64, u64)> {
+    let (output, status) = OsCall::Quiet
+        .execute(&["ccache -d ", ccache_dir, " --print-stats"].concat(), "")
+        .ok()?;
+    if status != 0 {
+        return None;
+    }
+    parse_ccache_stats(&output)
+}
+
+// Check that ccache can work as FEATURES asks it to, returning the problem if it cannot. Portage
+// compiles as the portage user, which must be able to write to the cache
+//
+fn ccache_problem(ccache_dir: &str) -> Option<String> {
+    if portage::package_is_missing("dev-util/ccache") {
+        return Some("FEATURES includes ccache, but ccache is not installed".to_string());
+    }
+    if ccache_dir.is_empty() {
+        return Some(
+            "FEATURES includes ccache, but CCACHE_DIR is not set in make.conf".to_string(),
+        );
+    }
+    let Ok(metadata) = fs::metadata(ccache_dir) else {
+        return Some(format!(
+            "The ccache directory {} does not exist",
+            ccache_dir
+        ));
+    };
+    let portage_uid = OsCall::Quiet
+        .execute("id -u portage", "")
+        .ok()
+        .and_then(|(output, _)| output.trim().parse::<u32>().ok());
+    let group_writable = metadata.mode() & 0o020 != 0;
+    if portage_uid.is_some_and(|uid| uid != metadata.uid()) && !group_writable {
+        return Some(format!(
+            "The ccache directory {} cannot be written by the portage user",
+            ccache_dir
+        ));
+    }
+    None
+}
+
+// Measures compiler cache and distcc use over the build phase
+//
+pub struct Monitor {
+    ccache: Option<(String, (u64, u64))>, // The cache directory, and its counters at the start
+    distcc: Option<JoinHandle<(u64, u64)>>, // Samples distccmon-text until stopped
+    stop: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    // Take the starting ccache counters and start sampling distcc, for those which are enabled
+    //
+    pub fn start() -> Monitor {
+        let features = portage::portage_variable("FEATURES");
+        let features: Vec<&str> = features.split_whitespace().collect();
+        let ccache = if features.contains(&"ccache") {
+            let ccache_dir = portage::portage_variable("CCACHE_DIR");
+            if let Some(problem) = ccache_problem(&ccache_dir) {
+                eprintln!(
+                    "{} {} - the build will not be cached",
+                    prompt::revchevrons(Color::Yellow),
+                    problem
+                );
+                actions::add([&problem, " - run gentup --setup to configure ccache"].concat());
+                None
+            } else {
+                ccache_stats(&ccache_dir).map(|counters| (ccache_dir, counters))
+            }
+        } else {
+            None
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let distcc = if features.contains(&"distcc") {
+            let stopped = Arc::clone(&stop);
+            let handle = thread::spawn(move || {
+                let mut totals = (0, 0);
+                while !stopped.load(Ordering::Relaxed) {
+                    if let Ok((output, 0)) = OsCall::Quiet.execute("distccmon-text", "") {
+                        let (local, remote) = parse_distccmon(&output);
+                        totals = (totals.0 + local, totals.1 + remote);
+                    }
+                    thread::sleep(Duration::from_secs(2));
+                }
+                totals
+            });
+            Some(handle)
+        } else {
+            None
+        };
+        Monitor {
+            ccache,
+            distcc,
+            stop,
+        }
+    }
+
+    // Stop measuring, then display and report the results
+    //
+    pub fn finish(self) {
+        let ccache_dir = self
+            .ccache
+            .as_ref()
+            .map(|(ccache_dir, _)| ccache_dir.clone());
+        let ccache = self.ccache.and_then(|(ccache_dir, (hits, misses))| {
+            ccache_stats(&ccache_dir).map(|(hits_after, misses_after)| {
+                (
+                    hits_after.saturating_sub(hits),
+                    misses_after.saturating_sub(misses),
+                )
+            })
+        });
+        self.stop.store(true, Ordering::Relaxed);
+        let distcc = self.distcc.and_then(|handle| handle.join().ok());
+        if ccache.is_none() && distcc.is_none() {
+            return;
+        }
+        if let Some((hits, misses)) = ccache {
+            println!(
+                "{} ccache: {} hits and {} misses, a hit rate of {}%",
+                prompt::revchevrons(Color::Green),
+                hits,
+                misses,
+                percentage(hits, hits + misses)
+            );
+            if let Some(advice) = ccache_dir.and_then(|ccache_dir| {
+                let used = OsCall::Quiet
+                    .execute(&["ccache -d ", &ccache_dir, " --print-stats"].concat(), "")
+                    .ok()
+                    .and_then(|(output, _)| parse_ccache_size(&output))?;
+                let max = OsCall::Quiet
+                    .execute(
+                        &["ccache -d ", &ccache_dir, " --get-config max_size"].concat(),
+                        "",
+                    )
+                    .ok()
+                    .and_then(|(output, _)| parse_size(&output))?;
+                ccache_advice(hits, misses, used, max)
+            }) {
+                eprintln!("{} {}", prompt::revchevrons(Color::Yellow), advice);
+                actions::add(advice);
+            }
+        }
+        if let Some((local, remote)) = distcc {
+            println!(
+                "{} distcc: {}% of the compile jobs sampled ran on other hosts",
+                prompt::revchevrons(Color::Green),
+                percentage(remote, local + remote)
+            );
+        }
+        events::emit(Event::CompilerStats { ccache, distcc });
+    }
+}
+
+pub fn percentage(part: u64, whole: u64) -> u64 {
+    (part * 100).checked_div(whole).unwrap_or(0)
+}
+
+// The contents of ccache.conf for portage's cache, as the Gentoo handbook suggests. umask 002
+// lets the portage group share the cache, and the compiler is checked by version rather than by
+// modification time, which every rebuild of gcc would change
+//
+pub fn ccache_conf(max_size: &str) -> String {
+    format!(
+        "max_size = {}\numask = 002\nhash_dir = false\ncompiler_check = %compiler% -dumpversion\ncache_dir_levels = 3\n",
+        max_size
+    )
+}
+
+// Ask for a setting, offering a default which is taken if the answer is left empty
+//
+fn ask_with_default(name: &str, question: &str, default: &str) -> String {
+    Prompt::Options
+        .askuser(name, &[question, " [", default, "]"].concat())
+        .map(|answer| answer.trim().to_string())
+        .filter(|answer| !answer.is_empty())
+        .unwrap_or(default.to_string())
+}
+
+// Install and configure ccache for portage, asking for the size and location of the cache
+//
+pub fn setup_ccache() {
+    let features = portage::portage_variable("FEATURES");
+    let enabled = features
+        .split_whitespace()
+        .any(|feature| feature == "ccache");
+    let current_dir = portage::portage_variable("CCACHE_DIR");
+    println!(
+        "{} ccache is {}installed, and {} in FEATURES{}",
+        prompt::revchevrons(Color::Green),
+        if portage::package_is_missing("dev-util/ccache") {
+            "not "
+        } else {
+            ""
+        },
+        if enabled { "enabled" } else { "not enabled" },
+        if current_dir.is_empty() {
+            String::new()
+        } else {
+            [" with the cache in ", &current_dir].concat()
+        }
+    );
+    let max_size = ask_with_default("ccache-size", "Cache size", "20G");
+    if parse_size(&max_size).is_none() {
+        eprintln!(
+            "{} {} is not a size ccache understands, such as 20G or 500M",
+            prompt::revchevrons(Color::Red),
+            max_size
+        );
+        return;
+    }
+    let ccache_dir = ask_with_default(
+        "ccache-dir",
+        "Cache directory",
+        if current_dir.is_empty() {
+            "/var/cache/ccache"
+        } else {
+            &current_dir
+        },
+    );
+    if portage::package_is_missing("dev-util/ccache") {
+        let _ =
+            OsCall::Interactive.execute("emerge --quiet -v dev-util/ccache", "Installing ccache");
+    }
+    let configured = fs::create_dir_all(&ccache_dir)
+        .and_then(|_| fs::set_permissions(&ccache_dir, fs::Permissions::from_mode(0o2775)))
+        .and_then(|_| {
+            fs::write(
+                [&ccache_dir, "/ccache.conf"].concat(),
+                ccache_conf(&max_size),
+            )
+        });
+    if let Err(error) = configured {
+        eprintln!(
+            "{} Could not configure {} - {}",
+            prompt::revchevrons(Color::Red),
+            ccache_dir,
+            error
+        );
+        return;
+    }
+    let _ = OsCall::Quiet.execute(&["chown -R portage:portage ", &ccache_dir].concat(), "");
+    if !enabled || current_dir != ccache_dir {
+        let mut settings = String::from("# Compiler cache\n");
+        if !enabled {
+            settings.push_str("FEATURES=\"${FEATURES} ccache\"\n");
+        }
+        settings.push_str(&format!("CCACHE_DIR=\"{}\"\n", ccache_dir));
+        let written = makeconf::append(&settings);
+        if let Err(error) = written {
+            eprintln!(
+                "{} Could not add ccache to make.conf - {}",
+                prompt::revchevrons(Color::Red),
+                error
+            );
+            return;
+        }
+    }
+    println!(
+        "{} ccache is configured with a {} cache in {}. The hit rate is shown after each update",
+        prompt::revchevrons(Color::Green),
+        max_size,
+        ccache_dir
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compiler_statistics() {
+        let ccache = "stats_updated_timestamp\t1712350000\n\
+            direct_cache_hit\t1200\n\
+            preprocessed_cache_hit\t300\n\
+            cache_miss\t500\n\
+            files_in_cache\t40000\n";
+        assert_eq!(parse_ccache_stats(ccache), Some((1500, 500)));
+        assert_eq!(parse_ccache_stats("unrelated output"), None);
+        let distccmon =
+            "  3117  Compile     unix.c                                 192.168.1.20[2]\n  \
+            3118  Preprocess  ssl.c                                  localhost[0]\n  \
+            3120  Compile     tls.c                                  builder[1]\n";
+        assert_eq!(parse_distccmon(distccmon), (1, 2));
+        assert_eq!(percentage(2, 3), 66);
+    }
+
+    #[test]
+    fn advises_on_ccache() {
+        assert_eq!(
+            parse_ccache_size("cache_miss\t500\ncache_size_kibibyte\t4800000\n"),
+            Some(4800000)
+        );
+        assert_eq!(parse_size("5G"), Some(4882812));
+        assert_eq!(parse_size("20Gi"), Some(20971520));
+        assert_eq!(parse_size("500M"), Some(488281));
+        assert_eq!(parse_size("lots"), None);
+        // Nearly full and missing: too small
+        assert!(ccache_advice(10, 990, 4800000, 4882812)
+            .is_some_and(|advice| advice.starts_with("ccache is 98% full with a hit rate of 1%")));
+        // Nearly full but hitting well, or room to spare
+        assert_eq!(ccache_advice(800, 200, 4800000, 4882812), None);
+        assert_eq!(ccache_advice(10, 990, 1000000, 4882812), None);
+        assert!(ccache_conf("20G").starts_with("max_size = 20G\n"));
+    }
+}
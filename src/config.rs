@@ -1,6 +1,12 @@
+#[cfg(feature = "mail")]
+use crate::mail;
 use crate::{
+    budget, buildenv, compiler,
+    exitcode::ExitCode,
+    integrity,
     linux::{self, OsCall},
-    mail, prompt, Prompt,
+    orchestrator::Phase,
+    prefix, prompt, throttle, Prompt,
 };
 use crossterm::style::Color;
 use std::{
@@ -8,19 +14,114 @@ use std::{
     fs::{self, File},
     io::Write,
     path::Path,
-    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
 };
 
-pub static CONFIG_FILE_PATH: &str = "/etc/conf.d/gentup";
-pub static PACKAGE_FILE_PATH: &str = "/etc/default/gentup";
+static CONFIG_FILE_PATH: &str = "/etc/conf.d/gentup";
+static PACKAGE_FILE_PATH: &str = "/etc/default/gentup";
+static STATE_DIR_PATH: &str = "/var/lib/gentup";
+
+// gentup's own files, which are within the prefix in a Gentoo Prefix
+static LOCATIONS: OnceLock<[String; 3]> = OnceLock::new();
+
+fn locations() -> &'static [String; 3] {
+    LOCATIONS
+        .get_or_init(|| [CONFIG_FILE_PATH, PACKAGE_FILE_PATH, STATE_DIR_PATH].map(prefix::path))
+}
+
+// The configuration file
+//
+pub fn config_file() -> &'static str {
+    &locations()[0]
+}
+
+// The list of optional packages
+//
+pub fn package_file() -> &'static str {
+    &locations()[1]
+}
+
+// The directory holding gentup's state between runs
+//
+pub fn state_dir() -> &'static str {
+    &locations()[2]
+}
+
+// The lines with syntax errors found by the parse in progress
+static SYNTAX_ERRORS: AtomicUsize = AtomicUsize::new(0);
 
 // Define a struct to hold the configuration options
 //
+#[derive(Clone)]
 pub struct Config {
     pub cleanup_default: bool,
     pub trim_default: bool,
     pub background_default: bool,
+    pub group_by_category: bool,
+    pub sync_method: String, // rsync or webrsync
+    pub require_signed_tree: bool,
+    pub changed_deps: bool, // Rebuild packages whose dependencies changed without a version bump
+    pub bandwidth_limit: u32, // Kilobytes per second, 0 for no limit
     pub email_address: String,
+    pub load_limit: f32,
+    pub temperature_limit: u32,
+    pub wait_when_busy: bool,
+    pub battery_minimum: u32,
+    pub tmpfs_redirect: bool,
+    pub auto_parallelism: bool,
+    pub critical_first: bool, // Merge the toolchain, security fixes and small packages first
+    pub storage_health: String, // off, warn or abort on a failing disk
+    pub mount_thresholds: Vec<MountThreshold>,
+    pub custom_phases: Vec<CustomPhaseEntry>,
+    pub answers: Vec<(String, String)>, // The name of a prompt, and the answer to give it
+    pub webhook_url: String,
+    pub webhook_auth: String,
+    pub http_status: String,
+    pub mqtt_broker: String,
+    pub mqtt_topic: String,
+    pub temp_dir: String,          // Where temporary files are created
+    pub config_deadline_days: u32, // Days configuration file updates may wait before nagging
+    pub log_compress_days: u32,    // Days before saved logs and run reports are compressed
+    pub throttle_windows: Vec<throttle::Window>,
+    pub cross_targets: Vec<String>, // crossdev targets updated after the host
+    pub package_env: Vec<buildenv::Override>, // Build settings for particular packages
+    pub protect_sources: Vec<String>, // Packages whose distfiles and binary packages are kept
+    pub phase_budgets: Vec<budget::Budget>, // The longest each phase may take
+    pub fingerprint: String,        // The MD5 of the file the settings were loaded from
+}
+
+// Define a struct to hold a custom phase registered in the config file. The named built-in runs
+// after the named update phase, and is passed the rest of the line as its argument
+//
+#[derive(Clone)]
+pub struct CustomPhaseEntry {
+    pub after: String,
+    pub name: String,
+    pub argument: String,
+}
+
+// Define a struct to hold the minimum free space and free inodes required on a mount point before
+// an update is allowed to start
+//
+#[derive(Clone)]
+pub struct MountThreshold {
+    pub path: String,
+    pub min_free_mb: u64,
+    pub min_free_inodes: u64,
+}
+
+impl MountThreshold {
+    pub fn from(path: &str, min_free_mb: u64, min_free_inodes: u64) -> Self {
+        MountThreshold {
+            path: path.to_string(),
+            min_free_mb,
+            min_free_inodes,
+        }
+    }
 }
 
 // Implement a formatter for Config so we can display the contents
@@ -32,9 +133,87 @@ impl fmt::Display for Config {
             "cleanup_default: {}\n\
             trim_default: {}\n\
             background_default: {}\n\
-            email_address: {}\n",
-            self.cleanup_default, self.trim_default, self.background_default, self.email_address,
-        )
+            group_by_category: {}\n\
+            sync_method: {}\n\
+            require_signed_tree: {}\n\
+            changed_deps: {}\n\
+            bandwidth_limit: {}\n\
+            email_address: {}\n\
+            load_limit: {}\n\
+            temperature_limit: {}\n\
+            wait_when_busy: {}\n\
+            battery_minimum: {}\n\
+            tmpfs_redirect: {}\n\
+            auto_parallelism: {}\n\
+            critical_first: {}\n\
+            storage_health: {}\n\
+            webhook_url: {}\n\
+            webhook_auth: {}\n\
+            http_status: {}\n\
+            mqtt_broker: {}\n\
+            mqtt_topic: {}\n\
+            temp_dir: {}\n\
+            config_deadline_days: {}\n\
+            log_compress_days: {}\n",
+            self.cleanup_default,
+            self.trim_default,
+            self.background_default,
+            self.group_by_category,
+            self.sync_method,
+            self.require_signed_tree,
+            self.changed_deps,
+            self.bandwidth_limit,
+            self.email_address,
+            self.load_limit,
+            self.temperature_limit,
+            self.wait_when_busy,
+            self.battery_minimum,
+            self.tmpfs_redirect,
+            self.auto_parallelism,
+            self.critical_first,
+            self.storage_health,
+            self.webhook_url,
+            self.webhook_auth,
+            self.http_status,
+            self.mqtt_broker,
+            self.mqtt_topic,
+            self.temp_dir,
+            self.config_deadline_days,
+            self.log_compress_days,
+        )?;
+        for threshold in &self.mount_thresholds {
+            writeln!(
+                f,
+                "mount_threshold: {} {} {}",
+                threshold.path, threshold.min_free_mb, threshold.min_free_inodes
+            )?;
+        }
+        for custom_phase in &self.custom_phases {
+            writeln!(
+                f,
+                "custom_phase: {} {} {}",
+                custom_phase.after, custom_phase.name, custom_phase.argument
+            )?;
+        }
+        for window in &self.throttle_windows {
+            writeln!(f, "throttle: {}", window)?;
+        }
+        for target in &self.cross_targets {
+            writeln!(f, "cross_target: {}", target)?;
+        }
+        for package_env in &self.package_env {
+            writeln!(f, "package_env: {}", package_env)?;
+        }
+        for package in &self.protect_sources {
+            writeln!(f, "protect_sources: {}", package)?;
+        }
+        for budget in &self.phase_budgets {
+            writeln!(f, "phase_budget: {}", budget)?;
+        }
+        for (prompt, answer) in &self.answers {
+            writeln!(f, "answer: {} {}", prompt, answer)?;
+        }
+        Ok(())
     }
 }
 
@@ -46,19 +225,55 @@ impl Config {
             cleanup_default: false,
             trim_default: false,
             background_default: false,
+            group_by_category: false,
+            sync_method: "rsync".to_string(),
+            require_signed_tree: false,
+            changed_deps: false,
+            bandwidth_limit: 0,
             email_address: "root@localhost".to_string(),
+            load_limit: 0.0,
+            temperature_limit: 0,
+            wait_when_busy: true,
+            battery_minimum: 50,
+            tmpfs_redirect: true,
+            auto_parallelism: false,
+            critical_first: false,
+            storage_health: "warn".to_string(),
+            mount_thresholds: vec![
+                MountThreshold::from("/", 2048, 10000),
+                MountThreshold::from("/usr", 2048, 10000),
+                MountThreshold::from("/var", 4096, 10000),
+                MountThreshold::from("/var/tmp", 8192, 10000),
+                MountThreshold::from("/boot", 64, 100),
+            ],
+            custom_phases: Vec::new(),
+            answers: Vec::new(),
+            webhook_url: String::new(),
+            webhook_auth: String::new(),
+            http_status: String::new(),
+            mqtt_broker: String::new(),
+            mqtt_topic: "gentup".to_string(),
+            temp_dir: "/tmp".to_string(),
+            config_deadline_days: 14,
+            log_compress_days: 7,
+            throttle_windows: Vec::new(),
+            cross_targets: Vec::new(),
+            package_env: Vec::new(),
+            protect_sources: Vec::new(),
+            phase_budgets: Vec::new(),
+            fingerprint: String::new(),
         }
     }
 
     // Save the running config out to the config file
     //
     pub fn save(self) -> Self {
-        let path = Path::new(&CONFIG_FILE_PATH);
+        let path = Path::new(config_file());
         let display = path.display();
         let mut config_file = match File::create(path) {
             Err(error) => {
                 eprintln!("Could not create {} - {}", display, error);
-                process::exit(1);
+                ExitCode::ConfigError.exit();
             }
             Ok(config_file) => config_file,
         };
@@ -68,8 +283,38 @@ impl Config {
             # post-update cleanup, true or false\n\
             # post-update trim, true or false\n\
             # background package downloads, true or false\n\
+            # list pending updates in groups by category, true or false\n\
+            # sync the package tree with rsync, or with webrsync where rsync is blocked\n\
+            # refuse to update from a package tree whose signatures cannot be verified, true or false\n\
+            # rebuild packages whose dependencies changed without a version bump, slow, true or false\n\
+            # download bandwidth for syncing and fetching sources in kilobytes per second, 0 for no limit\n\
             # email address to send update reports to\n\
-            "
+            # maximum 1-minute load average before building, 0 to disable\n\
+            # maximum CPU temperature in Celsius before building, 0 to disable\n\
+            # wait for the system to calm down rather than abort, true or false\n\
+            # minimum battery charge percentage to build on battery power, 0 to disable\n\
+            # build packages too large for a tmpfs PORTAGE_TMPDIR on disk instead, true or false\n\
+            # set the make and emerge jobs from the CPUs and memory, overriding make.conf, true or false\n\
+            # merge the toolchain, security fixes and small packages before large ones, in batches, true or false\n\
+            # check the SMART health of the disks under /, /usr and /var before updating, off, warn or abort\n\
+            # HTTPS endpoint to POST the JSON run report to, blank to disable\n\
+            # Authorization header value for the endpoint, e.g Bearer and a token, blank for none\n\
+            # address such as 127.0.0.1:8080 to serve the progress page on during updates, blank to disable\n\
+            # MQTT broker to publish progress and results to, as host or host:port, blank to disable\n\
+            # MQTT topic prefix, followed by the host name\n\
+            # directory for temporary files, such as emails being sent\n\
+            # days configuration file updates may wait for dispatch-conf before the reminders escalate, 0 to disable\n\
+            # days before build logs, elog messages and run reports are compressed, 0 to disable\n\
+            # per-mount minimum free space, as path, free MB and free inodes, one line per mount\n\
+            # custom phases, as the phase to run after, the built-in name and its argument\n\
+            # build throttling by time of day, as the days, the times and either pause or the niceness and make jobs\n\
+            # crossdev targets to update after the host, e.g armv7a-unknown-linux-gnueabihf, one line per target\n\
+            # build settings for a package during updates, e.g www-client/chromium MAKEOPTS=-j2, one line per package\n\
+            # packages whose distfiles and binary packages cleanup keeps, e.g sys-kernel/gentoo-sources, one line per package\n\
+            # the longest a phase may run unattended before the update stops, e.g build 8h, one line per phase\n\
+            # answers to give prompts without asking, as the prompt ({}) and the reply\n\
+            ",
+            prompt::answerable()
         );
         let _ = writeln!(config_file, "{}", self);
         self
@@ -78,6 +323,25 @@ impl Config {
     // Load the config file into the running config
     //
     pub fn load() -> Self {
+        match fs::read_to_string(config_file()) {
+            Ok(contents) => Config::parse(&contents).0,
+            Err(error) => {
+                println!(
+                    "{} Could not read {} - {}",
+                    prompt::revchevrons(Color::Red),
+                    config_file(),
+                    error
+                );
+                ExitCode::ConfigError.exit();
+            }
+        }
+    }
+
+    // Parse the contents of a config file, returning the settings and the number of lines which
+    // had syntax errors
+    //
+    pub fn parse(contents: &str) -> (Self, usize) {
+        SYNTAX_ERRORS.store(0, Ordering::Relaxed);
         let getswitch = move |p, l: &str| -> Option<bool> {
             let mut c = None;
             let value = l.replace(p, "").to_string();
@@ -87,11 +351,7 @@ impl Config {
                     "true" => c = Some(true),
                     "false" => c = Some(false),
                     _ => {
-                        println!(
-                            "{} Syntax error in the config file: {}",
-                            prompt::revchevrons(Color::Red),
-                            l
-                        );
+                        syntax_error(l);
                         c = None;
                     }
                 }
@@ -109,39 +369,287 @@ impl Config {
             }
             _c
         };
+        fn getnumber<T: FromStr>(p: &str, l: &str) -> Option<T> {
+            if !l.contains(p) {
+                return None;
+            }
+            let value = l.replace(p, "");
+            match value.trim().parse() {
+                Ok(number) => Some(number),
+                Err(_) => {
+                    syntax_error(l);
+                    None
+                }
+            }
+        }
+        let getthreshold = move |p, l: &str| -> Option<MountThreshold> {
+            if !l.contains(p) {
+                return None;
+            }
+            let value = l.replace(p, "").to_string();
+            let fields: Vec<&str> = value.split_whitespace().collect();
+            if fields.len() == 3 {
+                if let (Ok(min_free_mb), Ok(min_free_inodes)) =
+                    (fields[1].parse(), fields[2].parse())
+                {
+                    return Some(MountThreshold::from(
+                        fields[0],
+                        min_free_mb,
+                        min_free_inodes,
+                    ));
+                }
+            }
+            syntax_error(l);
+            None
+        };
+        let getcustomphase = move |p, l: &str| -> Option<CustomPhaseEntry> {
+            if !l.contains(p) {
+                return None;
+            }
+            let value = l.replace(p, "").to_string();
+            let mut fields = value.trim().splitn(3, char::is_whitespace);
+            match (fields.next(), fields.next()) {
+                (Some(after), Some(name)) if !after.is_empty() => Some(CustomPhaseEntry {
+                    after: after.to_string(),
+                    name: name.to_string(),
+                    argument: fields.next().unwrap_or("").trim().to_string(),
+                }),
+                _ => {
+                    syntax_error(l);
+                    None
+                }
+            }
+        };
         let mut running_config = Config::build_default();
-        let fileopt = fs::read_to_string(CONFIG_FILE_PATH);
-        match fileopt {
-            Ok(contents) => {
-                for line in contents.lines() {
-                    if let Some(switch) = getswitch("cleanup_default:", line) {
-                        running_config.cleanup_default = switch;
-                    }
-                    if let Some(switch) = getswitch("trim_default:", line) {
-                        running_config.trim_default = switch;
-                    }
-                    if let Some(switch) = getswitch("background_default:", line) {
-                        running_config.background_default = switch;
-                    }
-                    if let Some(param) = getparam("email_address:", line) {
-                        running_config.email_address = param;
-                    }
+        let mut mount_thresholds = Vec::new();
+        running_config.fingerprint = integrity::md5_hex(contents.as_bytes());
+        for line in contents.lines() {
+            if let Some(switch) = getswitch("cleanup_default:", line) {
+                running_config.cleanup_default = switch;
+            }
+            if let Some(switch) = getswitch("trim_default:", line) {
+                running_config.trim_default = switch;
+            }
+            if let Some(switch) = getswitch("background_default:", line) {
+                running_config.background_default = switch;
+            }
+            if let Some(switch) = getswitch("group_by_category:", line) {
+                running_config.group_by_category = switch;
+            }
+            if let Some(param) = getparam("sync_method:", line) {
+                if param == "rsync" || param == "webrsync" {
+                    running_config.sync_method = param;
+                } else {
+                    syntax_error(line);
                 }
             }
-            Err(error) => {
+            if let Some(switch) = getswitch("require_signed_tree:", line) {
+                running_config.require_signed_tree = switch;
+            }
+            if let Some(switch) = getswitch("changed_deps:", line) {
+                running_config.changed_deps = switch;
+            }
+            if let Some(number) = getnumber("bandwidth_limit:", line) {
+                running_config.bandwidth_limit = number;
+            }
+            if let Some(param) = getparam("email_address:", line) {
+                running_config.email_address = param;
+            }
+            if let Some(number) = getnumber("load_limit:", line) {
+                running_config.load_limit = number;
+            }
+            if let Some(number) = getnumber("temperature_limit:", line) {
+                running_config.temperature_limit = number;
+            }
+            if let Some(switch) = getswitch("wait_when_busy:", line) {
+                running_config.wait_when_busy = switch;
+            }
+            if let Some(number) = getnumber("battery_minimum:", line) {
+                running_config.battery_minimum = number;
+            }
+            if let Some(switch) = getswitch("tmpfs_redirect:", line) {
+                running_config.tmpfs_redirect = switch;
+            }
+            if let Some(switch) = getswitch("auto_parallelism:", line) {
+                running_config.auto_parallelism = switch;
+            }
+            if let Some(switch) = getswitch("critical_first:", line) {
+                running_config.critical_first = switch;
+            }
+            if let Some(param) = getparam("storage_health:", line) {
+                if ["off", "warn", "abort"].contains(&param.as_str()) {
+                    running_config.storage_health = param;
+                } else {
+                    syntax_error(line);
+                }
+            }
+            if let Some(param) = getparam("webhook_url:", line) {
+                running_config.webhook_url = param;
+            }
+            if let Some(param) = getparam("webhook_auth:", line) {
+                running_config.webhook_auth = param;
+            }
+            if let Some(param) = getparam("http_status:", line) {
+                running_config.http_status = param;
+            }
+            if let Some(param) = getparam("mqtt_broker:", line) {
+                running_config.mqtt_broker = param;
+            }
+            if let Some(param) = getparam("mqtt_topic:", line) {
+                running_config.mqtt_topic = param;
+            }
+            if let Some(param) = getparam("temp_dir:", line) {
+                running_config.temp_dir = param;
+            }
+            if let Some(number) = getnumber("config_deadline_days:", line) {
+                running_config.config_deadline_days = number;
+            }
+            if let Some(number) = getnumber("log_compress_days:", line) {
+                running_config.log_compress_days = number;
+            }
+            if let Some(threshold) = getthreshold("mount_threshold:", line) {
+                mount_thresholds.push(threshold);
+            }
+            if let Some(custom_phase) = getcustomphase("custom_phase:", line) {
+                running_config.custom_phases.push(custom_phase);
+            }
+            if let Some(param) = getparam("cross_target:", line) {
+                if !param.is_empty() {
+                    running_config.cross_targets.push(param);
+                }
+            }
+            if let Some(param) = getparam("package_env:", line) {
+                match buildenv::Override::parse(&param) {
+                    Some(package_env) => running_config.package_env.push(package_env),
+                    None => syntax_error(line),
+                }
+            }
+            if let Some(param) = getparam("protect_sources:", line) {
+                if !param.is_empty() {
+                    running_config.protect_sources.push(param);
+                }
+            }
+            if let Some(param) = getparam("phase_budget:", line) {
+                let phases = Phase::ALL.map(|phase| phase.name());
+                match budget::Budget::parse(&param, &phases) {
+                    Some(budget) => running_config.phase_budgets.push(budget),
+                    None => syntax_error(line),
+                }
+            }
+            if let Some(param) = getparam("throttle:", line) {
+                match throttle::Window::parse(&param) {
+                    Some(window) => running_config.throttle_windows.push(window),
+                    None => syntax_error(line),
+                }
+            }
+            if let Some(param) = getparam("answer:", line) {
+                let (name, answer) = param.split_once(' ').unwrap_or((&param, ""));
+                if prompt::accepts(name, answer.trim()) {
+                    running_config
+                        .answers
+                        .push((name.to_string(), answer.trim().to_string()));
+                } else {
+                    syntax_error(line);
+                }
+            }
+        }
+        // Thresholds in the config file replace the built-in defaults entirely
+        if !mount_thresholds.is_empty() {
+            running_config.mount_thresholds = mount_thresholds;
+        }
+        (running_config, SYNTAX_ERRORS.load(Ordering::Relaxed))
+    }
+
+    // Reload the config file if it was edited since it was loaded. An edit with syntax errors is
+    // not applied, so a half-finished edit cannot break a run. Returns true if settings changed
+    //
+    pub fn reload_if_changed(&mut self) -> bool {
+        let Ok(contents) = fs::read_to_string(config_file()) else {
+            return false;
+        };
+        if integrity::md5_hex(contents.as_bytes()) == self.fingerprint {
+            return false;
+        }
+        let (reloaded, errors) = Config::parse(&contents);
+        if errors > 0 {
+            eprintln!(
+                "{} {} was edited, but has {} syntax error(s), so the previous settings are kept",
+                prompt::revchevrons(Color::Yellow),
+                config_file(),
+                errors
+            );
+            // Only warn once about the same edit
+            self.fingerprint = reloaded.fingerprint;
+            return false;
+        }
+        let changes = changed_settings(&self.to_string(), &reloaded.to_string());
+        *self = reloaded;
+        if changes.is_empty() {
+            return false;
+        }
+        println!(
+            "{} {} was edited, and the new settings apply from now on:",
+            prompt::revchevrons(Color::Yellow),
+            config_file()
+        );
+        for change in &changes {
+            println!("    {}", change);
+        }
+        true
+    }
+
+    // Log the settings which changed since the last update run, then remember these for the next
+    //
+    pub fn note_changes_since_last_run(&self) {
+        let path = [state_dir(), "/last-run-config"].concat();
+        let settings = self.to_string();
+        if let Ok(previous) = fs::read_to_string(&path) {
+            let changes = changed_settings(&previous, &settings);
+            if !changes.is_empty() {
                 println!(
-                    "{} Could not read {} - {}",
-                    prompt::revchevrons(Color::Red),
-                    CONFIG_FILE_PATH,
-                    error
+                    "{} The configuration changed since the last update run:",
+                    prompt::revchevrons(Color::Blue)
                 );
-                process::exit(1);
+                for change in &changes {
+                    println!("    {}", change);
+                }
             }
         }
-        running_config
+        let _ = fs::create_dir_all(state_dir()).and_then(|_| fs::write(&path, settings));
     }
 }
 
+// Count and report a line of the config file which could not be understood
+//
+fn syntax_error(line: &str) {
+    SYNTAX_ERRORS.fetch_add(1, Ordering::Relaxed);
+    println!(
+        "{} Syntax error in the config file: {}",
+        prompt::revchevrons(Color::Red),
+        line
+    );
+}
+
+// The settings removed and added between two configurations, each as written to the config file,
+// e.g "- load_limit: 4" and "+ load_limit: 8"
+//
+pub fn changed_settings(old: &str, new: &str) -> Vec<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let mut changes: Vec<String> = old_lines
+        .iter()
+        .filter(|line| !new_lines.contains(line))
+        .map(|line| ["- ", line].concat())
+        .collect();
+    changes.extend(
+        new_lines
+            .iter()
+            .filter(|line| !old_lines.contains(line))
+            .map(|line| ["+ ", line].concat()),
+    );
+    changes
+}
+
 // Interactive setup
 //
 pub fn setup() {
@@ -149,7 +657,7 @@ pub fn setup() {
         //
         // Load or create the configuration file
         //
-        let mut running_config: Config = if !Path::new(&CONFIG_FILE_PATH).exists() {
+        let mut running_config: Config = if !Path::new(config_file()).exists() {
             Config::build_default().save()
         } else {
             Config::load()
@@ -169,7 +677,7 @@ pub fn setup() {
         // Display the list of optional packages
         //
 
-        let optlist = fs::read_to_string(PACKAGE_FILE_PATH);
+        let optlist = fs::read_to_string(package_file());
         if let Ok(plist) = optlist {
             println!(
                 "{} Optional package list contains\n\n{}",
@@ -178,25 +686,62 @@ pub fn setup() {
             );
         }
 
-        let optans = Prompt::Options.askuser("Select c to edit the configuration, p to edit the package list, t to send a test email, or q to quit [c|p|t|q]");
+        let optans = Prompt::Options.askuser("setup", "Select c to edit the configuration, p to edit the package list, x to set up ccache, t to send a test email, or q to quit [c|p|x|t|q]");
 
         if let Some(answer) = optans {
             if answer.eq("c\n") {
                 let _ = OsCall::Interactive
-                    .execute(&["vi ", CONFIG_FILE_PATH].concat(), "Launching editor");
+                    .execute(&["vi ", config_file()].concat(), "Launching editor");
                 running_config = Config::load();
             }
             if answer.eq("p\n") {
                 let _ = OsCall::Interactive
-                    .execute(&["vi ", PACKAGE_FILE_PATH].concat(), "Launching editor");
+                    .execute(&["vi ", package_file()].concat(), "Launching editor");
+            }
+            if answer.eq("x\n") {
+                linux::clearscreen();
+                compiler::setup_ccache();
+                let _ = Prompt::PressReturn.askuser("setup", "ccache setup finished");
             }
+            #[cfg(feature = "mail")]
             if answer.eq("t\n") {
-                mail::test_mail(&running_config);
+                let sent = mail::test_mail(&running_config);
                 linux::clearscreen();
-                println!("{} Test email sent", prompt::revchevrons(Color::Green));
+                match sent {
+                    Ok(_) => println!("{} Test email sent", prompt::revchevrons(Color::Green)),
+                    Err(error) => println!(
+                        "{} The test email could not be sent: {}",
+                        prompt::revchevrons(Color::Red),
+                        error
+                    ),
+                }
                 continue;
             }
         }
         linux::clearscreen();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloads_changed_settings() {
+        let (config, errors) = Config::parse("load_limit: 4\ntrim_default: true\n");
+        assert_eq!(errors, 0);
+        assert!(config.trim_default);
+        assert_eq!(config.load_limit, 4.0);
+        let (edited, errors) = Config::parse("load_limit: 8\ntrim_default: true\n");
+        assert_eq!(errors, 0);
+        assert_ne!(config.fingerprint, edited.fingerprint);
+        assert_eq!(
+            changed_settings(&config.to_string(), &edited.to_string()),
+            vec!["- load_limit: 4", "+ load_limit: 8"]
+        );
+        let (_, errors) = Config::parse(
+            "load_limit: lots\ntrim_default: maybe\nanswer: recovery b\nanswer: recovery s\n",
+        );
+        assert_eq!(errors, 3);
+    }
+}
@@ -0,0 +1,178 @@
+// Trivial configuration updates
+// Before dispatch-conf is run, the configuration file updates which need no decision are merged
+// without asking, as dispatch-conf's replace-unmodified does. An update such as
+// /etc/._cfg0000_hosts is merged into /etc/hosts when:
+//
+//   there is no /etc/hosts
+//   /etc/hosts is the previous default, i.e it was never edited, going by the copy of the previous
+//       default dispatch-conf keeps in its archive, /etc/config-archive/etc/hosts.dist
+//   the two differ only in comments, blank lines and whitespace
+//
+// and is discarded when it is identical to /etc/hosts. Everything else, including a file with
+// more than one update waiting, is left for dispatch-conf. Each merged update becomes the previous
+// default in the archive, so the next update to an unedited file is merged too
+
+use crate::{actions, prompt};
+use crossterm::style::Color;
+use std::{fs, path::Path};
+
+static ARCHIVE_DIR: &str = "/etc/config-archive";
+
+// What to do with one configuration file update
+//
+#[derive(Debug, PartialEq)]
+pub enum Merge {
+    Discard,               // The update is identical to the installed file
+    Replace(&'static str), // Install the update, for the reason given
+    Conflict,              // The installed file was edited, so a person needs to decide
+}
+
+// The lines of a configuration file which mean something, with comments, blank lines and
+// surrounding whitespace removed
+//
+fn significant_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+// Decide what to do with an update, given the installed file and the previous default, if known
+//
+pub fn classify(installed: Option<&str>, update: &str, previous_default: Option<&str>) -> Merge {
+    let Some(installed) = installed else {
+        return Merge::Replace("the file did not exist");
+    };
+    if installed == update {
+        Merge::Discard
+    } else if previous_default == Some(installed) {
+        Merge::Replace("the file was never edited")
+    } else if significant_lines(installed) == significant_lines(update) {
+        Merge::Replace("only comments or whitespace differ")
+    } else {
+        Merge::Conflict
+    }
+}
+
+// The file an update is for, e.g /etc/hosts for /etc/._cfg0000_hosts
+//
+pub fn target_of(update_path: &str) -> Option<String> {
+    let (directory, name) = update_path.rsplit_once('/')?;
+    let name = name.strip_prefix("._cfg")?;
+    let (number, target) = name.split_once('_')?;
+    if number.len() != 4 || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some([directory, "/", target].concat())
+}
+
+fn archive_path(target: &str) -> String {
+    [ARCHIVE_DIR, target, ".dist"].concat()
+}
+
+// Merge the pending configuration file updates which need no decision, and return how many are
+// left for dispatch-conf
+//
+pub fn merge_trivial() -> usize {
+    let pending = actions::pending_configs();
+    let targets: Vec<Option<String>> = pending.iter().map(|path| target_of(path)).collect();
+    let mut merged = 0;
+    for (update_path, target) in pending.iter().zip(&targets) {
+        let Some(target) = target else {
+            continue;
+        };
+        if targets
+            .iter()
+            .filter(|other| other.as_ref() == Some(target))
+            .count()
+            > 1
+        {
+            continue; // Several updates to choose between
+        }
+        let Ok(update) = fs::read_to_string(update_path) else {
+            continue; // Not text
+        };
+        let installed = fs::read_to_string(target).ok();
+        if installed.is_none() && Path::new(target).exists() {
+            continue;
+        }
+        let previous_default = fs::read_to_string(archive_path(target)).ok();
+        let result = match classify(installed.as_deref(), &update, previous_default.as_deref()) {
+            Merge::Conflict => continue,
+            Merge::Discard => fs::remove_file(update_path),
+            Merge::Replace(reason) => {
+                println!(
+                    "{} Merging {} into {}, as {}",
+                    prompt::chevrons(Color::Green),
+                    update_path,
+                    target,
+                    reason
+                );
+                fs::rename(update_path, target)
+            }
+        };
+        match result {
+            Ok(_) => {
+                merged += 1;
+                let archive = archive_path(target);
+                if let Some(directory) = Path::new(&archive).parent() {
+                    let _ =
+                        fs::create_dir_all(directory).and_then(|_| fs::write(&archive, &update));
+                }
+            }
+            Err(error) => eprintln!(
+                "{} Could not merge {} - {}",
+                prompt::revchevrons(Color::Yellow),
+                update_path,
+                error
+            ),
+        }
+    }
+    if merged > 0 {
+        println!(
+            "{} Merged {} configuration file update(s) which needed no decision",
+            prompt::revchevrons(Color::Green),
+            merged
+        );
+    }
+    pending.len() - merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_trivial_updates() {
+        assert_eq!(
+            target_of("/etc/ssh/._cfg0000_sshd_config").as_deref(),
+            Some("/etc/ssh/sshd_config")
+        );
+        assert_eq!(target_of("/etc/ssh/sshd_config"), None);
+
+        let update = "# Default hosts\n127.0.0.1 localhost\n::1 localhost\n";
+        assert_eq!(
+            classify(None, update, None),
+            Merge::Replace("the file did not exist")
+        );
+        assert_eq!(classify(Some(update), update, None), Merge::Discard);
+        let old_default = "# Default hosts\n127.0.0.1 localhost\n";
+        assert_eq!(
+            classify(Some(old_default), update, Some(old_default)),
+            Merge::Replace("the file was never edited")
+        );
+        assert_eq!(
+            classify(
+                Some("127.0.0.1   localhost\n\n# IPv6\n::1 localhost\n"),
+                update,
+                None
+            ),
+            Merge::Replace("only comments or whitespace differ")
+        );
+        let edited = "127.0.0.1 localhost\n10.0.0.5 build1\n";
+        assert_eq!(
+            classify(Some(edited), update, Some(old_default)),
+            Merge::Conflict
+        );
+    }
+}
@@ -0,0 +1,174 @@
+// Container integration tests
+// These run gentup's package manager phases end-to-end against a real Gentoo stage3 container,
+// with the package tree supplied by a gentoo/portage snapshot image, so that changes in emerge's
+// output which break the parsers are caught before a release. They are opt-in, as they need
+// podman (or docker) and download several hundred MB of images:
+//
+//   GENTUP_CONTAINER_TESTS=1 cargo test container -- --nocapture
+//
+// GENTUP_CONTAINER_RUNTIME selects docker instead of podman, and GENTUP_STAGE3_IMAGE and
+// GENTUP_PORTAGE_IMAGE override the images used
+
+use crate::{
+    backend::Backend,
+    linux::ShellOutResult,
+    portage::{self, PackageManager},
+};
+use std::{env, process::Command};
+
+// A Backend which runs each command inside the test container
+//
+struct Container {
+    runtime: String,
+    name: String,
+}
+
+impl Container {
+    // Start a stage3 container with the package tree mounted from a snapshot container
+    //
+    fn start() -> Container {
+        let runtime = env::var("GENTUP_CONTAINER_RUNTIME").unwrap_or("podman".to_string());
+        let stage3 =
+            env::var("GENTUP_STAGE3_IMAGE").unwrap_or("docker.io/gentoo/stage3".to_string());
+        let snapshot =
+            env::var("GENTUP_PORTAGE_IMAGE").unwrap_or("docker.io/gentoo/portage".to_string());
+        let name = format!("gentup-test-{}", std::process::id());
+        let tree = [&name, "-tree"].concat();
+        let status = Command::new(&runtime)
+            .args(["create", "--name", &tree, &snapshot])
+            .status()
+            .expect("the container runtime should be installed");
+        assert!(status.success(), "could not create the snapshot container");
+        let status = Command::new(&runtime)
+            .args(["run", "-d", "--name", &name, "--volumes-from", &tree])
+            .args([&stage3, "sleep", "infinity"])
+            .status()
+            .expect("the container runtime should be installed");
+        assert!(status.success(), "could not start the stage3 container");
+        Container { runtime, name }
+    }
+
+    fn exec(&self, command_line: &str) -> ShellOutResult {
+        let output = Command::new(&self.runtime)
+            .args(["exec", &self.name, "sh", "-c", command_line])
+            .output()?;
+        Ok((
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            output.status.code().unwrap_or(1),
+        ))
+    }
+}
+
+// Remove the containers however the test ends
+impl Drop for Container {
+    fn drop(&mut self) {
+        let tree = [&self.name, "-tree"].concat();
+        let _ = Command::new(&self.runtime)
+            .args(["rm", "-f", &self.name, &tree])
+            .output();
+    }
+}
+
+impl Backend for Container {
+    fn pretend_update(&self) -> ShellOutResult {
+        self.exec("emerge -puDv @world")
+    }
+    fn update(&self) -> ShellOutResult {
+        self.exec("emerge --quiet-build y -uNDv --autounmask n --with-bdeps y @world")
+    }
+    fn pretend_depclean(&self) -> ShellOutResult {
+        self.exec("emerge -p --depclean")
+    }
+    fn depclean(&self, _preserve_kernel: bool) -> ShellOutResult {
+        self.exec("emerge --depclean")
+    }
+    fn pretend_revdep(&self) -> ShellOutResult {
+        self.exec("revdep-rebuild -ip")
+    }
+    fn revdep_rebuild(&self) -> ShellOutResult {
+        self.exec("revdep-rebuild")
+    }
+    fn sync(&self) -> ShellOutResult {
+        self.exec("emaint sync --auto")
+    }
+    fn webrsync(&self) -> ShellOutResult {
+        self.exec("emerge-webrsync")
+    }
+    fn query_installed(&self, package: &str) -> ShellOutResult {
+        self.exec(&["portageq has_version / ", package].concat())
+    }
+    fn query_outdated(&self, package: &str) -> ShellOutResult {
+        // Exit status 0 when the best visible version is not the one installed
+        self.exec(
+            &[
+                "test \"$(portageq best_visible / ",
+                package,
+                ")\" != \"$(portageq best_version / ",
+                package,
+                ")\"",
+            ]
+            .concat(),
+        )
+    }
+}
+
+fn enabled() -> bool {
+    env::var("GENTUP_CONTAINER_TESTS").is_ok_and(|value| value == "1")
+}
+
+// The steps share one container, as starting it dominates the run time
+//
+#[test]
+fn container_end_to_end() {
+    if !enabled() {
+        eprintln!("Skipping container tests: set GENTUP_CONTAINER_TESTS=1 to run them");
+        return;
+    }
+    let container = Container::start();
+
+    // The snapshot tree is mounted, so portage itself should be installed and queryable
+    assert!(matches!(
+        container.query_installed("sys-apps/portage"),
+        Ok((_, 0))
+    ));
+
+    // Pretend: the stage3 is older than the snapshot, so there should be updates, and every
+    // [ebuild line should parse into a versioned package
+    let (output, status) = container.pretend_update().unwrap();
+    assert_eq!(status, 0, "emerge -puDv @world failed:\n{}", output);
+    let pending_updates = portage::parse_pending_updates(&output);
+    let ebuild_lines = output
+        .lines()
+        .filter(|line| line.starts_with("[ebuild"))
+        .count();
+    assert_eq!(pending_updates.len(), ebuild_lines);
+    assert!(pending_updates
+        .iter()
+        .all(|package| package.version.is_some()));
+
+    // Fetch: the first pending package's sources should download
+    if let Some(package) = pending_updates.first() {
+        let (output, status) = container
+            .exec(&["emerge --fetchonly --nodeps =", &package.to_string()].concat())
+            .unwrap();
+        assert_eq!(status, 0, "fetch of {} failed:\n{}", package, output);
+    }
+
+    // Cleanup: a fresh stage3 reports a depclean count which the parser must find
+    let (output, _) = container.pretend_depclean().unwrap();
+    assert!(
+        portage::parse_depclean(&output).is_some(),
+        "could not parse emerge -p --depclean output:\n{}",
+        output
+    );
+    let (orphans, _) = PackageManager::DryRun.depclean_with(&container);
+    assert!(orphans >= 0);
+
+    // Sync: refresh the tree in the container and check the pretend still parses
+    if env::var("GENTUP_CONTAINER_SYNC").is_ok_and(|value| value == "1") {
+        let (output, status) = container.sync().unwrap();
+        assert_eq!(status, 0, "sync failed:\n{}", output);
+        let (output, _) = container.pretend_update().unwrap();
+        portage::parse_pending_updates(&output);
+    }
+}
@@ -0,0 +1,142 @@
+// Cross-compilation targets
+// crossdev keeps a Gentoo installation for each cross toolchain it builds, in /usr/<target>, e.g
+// /usr/armv7a-unknown-linux-gnueabihf, with its own make.conf, profile and world file, and
+// installs a <target>-emerge wrapper which builds packages for it. Each target listed with a
+// cross_target: line in the configuration file is updated after the host, with its own pretend,
+// build and report:
+//
+//   cross_target: armv7a-unknown-linux-gnueabihf
+//
+// A target which cannot be updated is reported and added to the checklist, and the other targets
+// still get their turn
+
+use crate::{
+    actions,
+    events::{self, Event},
+    linux::OsCall,
+    portage, preflight, prompt, requirements, Config,
+};
+use crossterm::style::Color;
+
+// The directory holding a target's installation, e.g /usr/armv7a-unknown-linux-gnueabihf
+//
+pub fn root_of(target: &str) -> String {
+    ["/usr/", target].concat()
+}
+
+// The emerge wrapper crossdev installs for a target
+//
+pub fn wrapper(target: &str) -> String {
+    [target, "-emerge"].concat()
+}
+
+// Check that a target has an installation and an emerge wrapper
+//
+fn check(target: &str) -> Result<(), String> {
+    portage::check_target_root(&root_of(target))?;
+    if !requirements::on_path(&wrapper(target)) {
+        return Err([
+            &wrapper(target),
+            " was not found. Set up the target with crossdev --target ",
+            target,
+        ]
+        .concat());
+    }
+    Ok(())
+}
+
+// Update one target, returning the updates it had pending and whether they were all applied
+//
+fn update(running_config: &Config, target: &str) -> (Vec<String>, bool) {
+    if let Err(error) = check(target) {
+        eprintln!("{} {}", prompt::revchevrons(Color::Red), error);
+        return (Vec::new(), false);
+    }
+    let emerge = wrapper(target);
+    let output = match OsCall::Spinner.execute(
+        &[&emerge, " -puDv @world"].concat(),
+        &["Checking for updates to ", target].concat(),
+    ) {
+        Ok((output, 0)) => output,
+        _ => {
+            eprintln!(
+                "{} {} could not calculate the pending updates",
+                prompt::revchevrons(Color::Red),
+                emerge
+            );
+            return (Vec::new(), false);
+        }
+    };
+    let pending: Vec<String> = portage::parse_changes(&output)
+        .iter()
+        .map(|change| change.package.to_string())
+        .collect();
+    if pending.is_empty() {
+        println!(
+            "{} There are no pending updates for {}",
+            prompt::revchevrons(Color::Blue),
+            target
+        );
+        return (pending, true);
+    }
+    println!(
+        "{} {} package(s) pending an update for {}:",
+        prompt::revchevrons(Color::Yellow),
+        pending.len(),
+        target
+    );
+    for package in &pending {
+        println!("    {}", package);
+    }
+    preflight::before_build(running_config);
+    let result = OsCall::Interactive.execute(
+        &[
+            &emerge,
+            portage::quiet_build(),
+            " -uDNv --with-bdeps n --autounmask n @world",
+        ]
+        .concat(),
+        &["Updating ", target].concat(),
+    );
+    (pending, matches!(result, Ok((_, 0))))
+}
+
+// Update each configured crossdev target after the host. Returns false if any could not be updated
+//
+pub fn update_targets(running_config: &Config) -> bool {
+    let mut all_updated = true;
+    for target in &running_config.cross_targets {
+        println!(
+            "{} Updating the cross-compilation target {} in {}",
+            prompt::chevrons(Color::Green),
+            target,
+            root_of(target)
+        );
+        let (pending, updated) = update(running_config, target);
+        if updated {
+            println!(
+                "{} {} is up to date",
+                prompt::revchevrons(Color::Green),
+                target
+            );
+        } else {
+            all_updated = false;
+            let action = [
+                "The cross-compilation target ",
+                target,
+                " was not updated. Fix the problem, then run ",
+                &wrapper(target),
+                " -uDN @world",
+            ]
+            .concat();
+            eprintln!("{} {}", prompt::revchevrons(Color::Red), action);
+            actions::add(action);
+        }
+        events::emit(Event::CrossTarget {
+            target: target.clone(),
+            packages: pending,
+            succeeded: updated,
+        });
+    }
+    all_updated
+}
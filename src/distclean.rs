@@ -0,0 +1,237 @@
+// Distfile and binary package cleaning
+// eclean removes the source tarballs in DISTDIR, and the binary packages in PKGDIR, which no
+// installed package needs. Before it does, the files it would remove are listed, grouped by the
+// package they belong to with their sizes and a total, so that the cleanup can be audited. The
+// sources and binary packages of some packages can be kept, e.g the kernel sources, with
+// protect_sources: lines in the configuration file:
+//
+//   protect_sources: sys-kernel/gentoo-sources
+//   protect_sources: sys-kernel/gentoo-kernel
+//
+// These are given to eclean in an exclude file, together with anything already listed in
+// /etc/eclean/distfiles.exclude or /etc/eclean/packages.exclude, which eclean would otherwise
+// stop reading
+
+use crate::{
+    atom::Package,
+    cleanup::{self, format_bytes},
+    linux::{CouldFail, OsCall},
+    prompt,
+    tempfile::TempFile,
+    Config,
+};
+use crossterm::style::Color;
+use std::fs;
+
+// Define a struct to hold the files of one package which would be removed
+//
+#[derive(Debug, PartialEq)]
+pub struct Group {
+    pub package: String,
+    pub files: Vec<(String, u64)>, // Each file and its size in bytes
+    pub bytes: u64,
+}
+
+// The package a file listed by eclean belongs to. Binary packages are listed by their full
+// version, e.g dev-libs/openssl-3.0.13, and distfiles by their file name, which mostly starts
+// with the package name, e.g Python-3.11.9.tar.xz
+//
+pub fn package_of(file: &str) -> String {
+    if let Ok(package) = file.parse::<Package>() {
+        return package.cpn();
+    }
+    let bytes = file.as_bytes();
+    let version = (1..bytes.len())
+        .find(|&at| matches!(bytes[at - 1], b'-' | b'_') && bytes[at].is_ascii_digit());
+    match version {
+        Some(at) => file[..at - 1].to_string(),
+        None => file.split('.').next().unwrap_or(file).to_string(),
+    }
+}
+
+// Group the files eclean would remove by their package, the largest first
+//
+pub fn group(files: Vec<(String, u64)>) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+    for (file, bytes) in files {
+        let package = package_of(&file);
+        match groups.iter_mut().find(|group| group.package == package) {
+            Some(group) => {
+                group.files.push((file, bytes));
+                group.bytes += bytes;
+            }
+            None => groups.push(Group {
+                package,
+                files: vec![(file, bytes)],
+                bytes,
+            }),
+        }
+    }
+    groups.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.package.cmp(&b.package)));
+    groups
+}
+
+// The contents of the exclude file given to eclean: the protected packages from the config file,
+// then the exclusions the user already keeps for eclean
+//
+pub fn exclude_list(protected: &[String], existing: &str) -> String {
+    let mut list: String = protected
+        .iter()
+        .map(|package| [package, "\n"].concat())
+        .collect();
+    list.push_str(existing);
+    list
+}
+
+// The eclean command for distfiles or packages, with the exclude file, if any
+//
+fn command(action: &str, pretend: bool, exclude: &Option<TempFile>) -> String {
+    let mut command = [
+        "eclean ",
+        if pretend { "--pretend " } else { "" },
+        "-d ",
+        action,
+    ]
+    .concat();
+    if let Some(exclude) = exclude {
+        command = command + " --exclude-file=" + exclude.path();
+    }
+    command
+}
+
+// Write the exclude file for distfiles or packages, if anything is to be kept
+//
+fn exclude_file(running_config: &Config, action: &str) -> Option<TempFile> {
+    let existing =
+        fs::read_to_string(["/etc/eclean/", action, ".exclude"].concat()).unwrap_or_default();
+    if running_config.protect_sources.is_empty() && existing.is_empty() {
+        return None;
+    }
+    match TempFile::create(
+        ".exclude",
+        &exclude_list(&running_config.protect_sources, &existing),
+    ) {
+        Ok(file) => Some(file),
+        Err(error) => {
+            eprintln!(
+                "{} Could not write the eclean exclude file: {}",
+                prompt::revchevrons(Color::Red),
+                error
+            );
+            None
+        }
+    }
+}
+
+// The files eclean would remove from DISTDIR (action distfiles) or PKGDIR (action packages),
+// grouped by package
+//
+pub fn preview(running_config: &Config, action: &str) -> Vec<Group> {
+    let exclude = exclude_file(running_config, action);
+    let output = OsCall::Spinner
+        .execute(
+            &command(action, true, &exclude),
+            &["Checking unused ", action].concat(),
+        )
+        .map(|(output, _)| output)
+        .unwrap_or_default();
+    group(cleanup::parse_eclean(&output).0)
+}
+
+// Display the files which would be removed, grouped by package
+//
+pub fn show(title: &str, groups: &[Group]) {
+    let total: u64 = groups.iter().map(|group| group.bytes).sum();
+    println!(
+        "{} {} to be removed, {} in total:",
+        prompt::revchevrons(Color::Yellow),
+        title,
+        format_bytes(total)
+    );
+    for group in groups {
+        println!(
+            "    {} ({} file(s), {})",
+            group.package,
+            group.files.len(),
+            format_bytes(group.bytes)
+        );
+        for (file, bytes) in &group.files {
+            println!("        {} ({})", file, format_bytes(*bytes));
+        }
+    }
+}
+
+// Remove the unused files, for distfiles or packages, which were previewed
+//
+pub fn remove(running_config: &Config, action: &str) {
+    let exclude = exclude_file(running_config, action);
+    let _ = OsCall::Interactive
+        .execute(
+            &command(action, false, &exclude),
+            &["Cleaning unused ", action].concat(),
+        )
+        .exit_if_failed();
+}
+
+// Preview, then remove, the unused distfiles and binary packages
+//
+pub fn clean(running_config: &Config) {
+    for package in &running_config.protect_sources {
+        println!(
+            "{} Keeping the sources and binary packages of {}",
+            prompt::revchevrons(Color::Blue),
+            package
+        );
+    }
+    for (action, title) in [
+        ("distfiles", "Unused distfiles"),
+        ("packages", "Unused binary packages"),
+    ] {
+        let groups = preview(running_config, action);
+        if groups.is_empty() {
+            continue;
+        }
+        show(title, &groups);
+        remove(running_config, action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_files_by_package() {
+        assert_eq!(package_of("Python-3.11.9.tar.xz"), "Python");
+        assert_eq!(package_of("linux-6.6.tar.xz"), "linux");
+        assert_eq!(package_of("genpatches-6.6-30.base.tar.xz"), "genpatches");
+        assert_eq!(package_of("go1.22.3.src.tar.gz"), "go1");
+        assert_eq!(package_of("dev-libs/openssl-3.0.13-r1"), "dev-libs/openssl");
+
+        let groups = group(vec![
+            ("zlib-1.3.tar.xz".to_string(), 1_000),
+            ("linux-6.6.tar.xz".to_string(), 140_000_000),
+            ("patch-6.6.13.xz".to_string(), 500_000),
+            ("zlib-1.3.1.tar.xz".to_string(), 1_100),
+        ]);
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| (group.package.as_str(), group.files.len(), group.bytes))
+                .collect::<Vec<_>>(),
+            vec![
+                ("linux", 1, 140_000_000),
+                ("patch", 1, 500_000),
+                ("zlib", 2, 2_100)
+            ]
+        );
+
+        assert_eq!(
+            exclude_list(
+                &["sys-kernel/gentoo-sources".to_string()],
+                "# kept by hand\nwww-client/firefox\n"
+            ),
+            "sys-kernel/gentoo-sources\n# kept by hand\nwww-client/firefox\n"
+        );
+    }
+}
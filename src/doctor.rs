@@ -0,0 +1,569 @@
+// System health audit
+// "gentup --doctor" runs every check gentup can make without changing anything, in one pass, and
+// grades each one as ok, a warning, or failed, with what to do about it:
+//
+//   [ ok ] Distribution: Gentoo Base System release 2.17
+//   [warn] World file: 2 problem(s)
+//            app-misc/foo is in the world file but not installed
+//              -> emerge --deselect app-misc/foo
+//
+// The checks are the distribution, the portage profile and configuration, make.conf, FEATURES,
+// the world file, the eix database against the package database, orphaned dependencies, preserved
+// libraries, obsolete entries in /etc/portage, free disk space, the SMART health of the disks,
+// configuration file updates waiting to be merged, and whether the running kernel is the newest
+// installed. gentup exits with the failure status if any check failed, so --doctor can be run
+// from monitoring
+
+use crate::{
+    actions,
+    atom::Package,
+    backend::{Backend, Emerge},
+    eixdb,
+    exitcode::ExitCode,
+    features,
+    linux::{self, OsCall},
+    makeconf, portage, preflight, prompt, smart,
+    treestate::EIX_CACHE,
+    Config,
+};
+use crossterm::style::Color;
+use std::{collections::HashSet, fs, path::Path, time::UNIX_EPOCH};
+
+// How a check came out
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Grade {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl Grade {
+    fn label(self) -> &'static str {
+        match self {
+            Grade::Ok => "[ ok ]",
+            Grade::Warning => "[warn]",
+            Grade::Failed => "[FAIL]",
+        }
+    }
+
+    fn colour(self) -> Color {
+        match self {
+            Grade::Ok => Color::Green,
+            Grade::Warning => Color::Yellow,
+            Grade::Failed => Color::Red,
+        }
+    }
+}
+
+// Define a struct to hold the outcome of one check, and the lines explaining what to do about it
+//
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub grade: Grade,
+    pub summary: String,
+    pub hints: Vec<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, summary: impl Into<String>) -> Check {
+        Check {
+            name,
+            grade: Grade::Ok,
+            summary: summary.into(),
+            hints: Vec::new(),
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        grade: Grade,
+        summary: impl Into<String>,
+        hints: Vec<String>,
+    ) -> Check {
+        Check {
+            name,
+            grade,
+            summary: summary.into(),
+            hints,
+        }
+    }
+}
+
+// The names of the installed packages, e.g sys-libs/zlib, from the package database
+//
+pub fn installed_packages() -> HashSet<String> {
+    portage::installed_packages()
+        .iter()
+        .map(Package::cpn)
+        .collect()
+}
+
+// Find what is wrong with the world file: entries for packages which are not installed, entries
+// listed more than once, and entries pinned to a version, which are never updated
+//
+pub fn world_problems(world: &str, installed: &HashSet<String>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut seen: Vec<&str> = Vec::new();
+    for entry in world.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if seen.contains(&entry) {
+            problems.push(format!(
+                "{} is listed more than once\n  -> remove the repeated lines from the world file",
+                entry
+            ));
+            continue;
+        }
+        seen.push(entry);
+        let atom = entry.trim_start_matches(['=', '<', '>', '~']);
+        if atom.len() != entry.len() {
+            problems.push(format!(
+                "{} is pinned to a version, so it is never updated\n  -> emerge --deselect {} && emerge --noreplace {}",
+                entry,
+                entry,
+                atom.parse::<Package>()
+                    .map(|package| package.cpn())
+                    .unwrap_or(atom.to_string())
+            ));
+            continue;
+        }
+        let Ok(package) = atom.parse::<Package>() else {
+            problems.push(format!(
+                "{} is not a package name\n  -> remove it from the world file",
+                entry
+            ));
+            continue;
+        };
+        if !installed.contains(&package.cpn()) {
+            problems.push(format!(
+                "{} is in the world file but not installed\n  -> emerge --deselect {}",
+                entry, entry
+            ));
+        }
+    }
+    problems
+}
+
+// The sections of eix-test-obsolete's report which list something, e.g
+//
+//   Non-matching entries in /etc/portage/package.accept_keywords:
+//   app-misc/foo ~amd64
+//
+// together with the number of entries in each. The sections with nothing to report begin "No"
+//
+pub fn obsolete_sections(output: &str) -> Vec<(String, usize)> {
+    let mut sections: Vec<(String, usize)> = Vec::new();
+    let mut listing = false;
+    for line in output.lines() {
+        let line = line.trim();
+        if line.ends_with(':') && !line.starts_with("No ") {
+            sections.push((line.trim_end_matches(':').to_string(), 0));
+            listing = true;
+        } else if line.is_empty() || line.starts_with("No ") || line.ends_with('.') {
+            listing = false;
+        } else if listing {
+            if let Some((_, count)) = sections.last_mut() {
+                *count += 1;
+            }
+        }
+    }
+    sections.retain(|(_, count)| *count > 0);
+    sections
+}
+
+fn check_distribution() -> Check {
+    match linux::check_distro("Gentoo") {
+        Ok(distro) => Check::ok(
+            "Distribution",
+            fs::read_to_string(portage::target_path("/etc/gentoo-release"))
+                .map(|release| release.trim().to_string())
+                .unwrap_or(distro),
+        ),
+        Err(error) => Check::problem("Distribution", Grade::Failed, error, Vec::new()),
+    }
+}
+
+fn check_portage() -> Check {
+    let profile = portage::target_path("/etc/portage/make.profile");
+    let Ok(resolved) = fs::canonicalize(&profile) else {
+        return Check::problem(
+            "Portage configuration",
+            Grade::Failed,
+            [&profile, " does not point to a profile"].concat(),
+            vec!["-> choose one with eselect profile list and eselect profile set".to_string()],
+        );
+    };
+    if !matches!(OsCall::Quiet.execute("emerge --info", ""), Ok((_, 0))) {
+        return Check::problem(
+            "Portage configuration",
+            Grade::Failed,
+            "emerge cannot read its configuration",
+            vec!["-> run emerge --info to see the error".to_string()],
+        );
+    }
+    Check::ok(
+        "Portage configuration",
+        ["profile ", &resolved.to_string_lossy()].concat(),
+    )
+}
+
+fn check_make_conf(running_config: &Config) -> Check {
+    let findings = makeconf::findings(running_config);
+    if findings.is_empty() {
+        return Check::ok("make.conf", "no problems found");
+    }
+    let hints = findings
+        .iter()
+        .map(|finding| [&finding.problem, "\n  -> ", &finding.suggestion].concat())
+        .collect();
+    Check::problem(
+        "make.conf",
+        Grade::Warning,
+        format!("{} problem(s)", findings.len()),
+        hints,
+    )
+}
+
+fn check_features() -> Check {
+    let findings = features::findings();
+    if findings.is_empty() {
+        return Check::ok("FEATURES", "as recommended");
+    }
+    let hints = findings
+        .iter()
+        .map(|finding| [&finding.problem, "\n  -> ", &finding.suggestion].concat())
+        .collect();
+    Check::problem(
+        "FEATURES",
+        Grade::Warning,
+        format!("{} recommendation(s)", findings.len()),
+        hints,
+    )
+}
+
+fn check_world(installed: &HashSet<String>) -> Check {
+    let Ok(world) = fs::read_to_string(portage::target_path("/var/lib/portage/world")) else {
+        return Check::problem(
+            "World file",
+            Grade::Failed,
+            "/var/lib/portage/world could not be read",
+            Vec::new(),
+        );
+    };
+    let problems = world_problems(&world, installed);
+    if problems.is_empty() {
+        return Check::ok(
+            "World file",
+            format!("{} entries, all installed", world.lines().count()),
+        );
+    }
+    Check::problem(
+        "World file",
+        Grade::Warning,
+        format!("{} problem(s)", problems.len()),
+        problems,
+    )
+}
+
+fn check_eix(installed: &HashSet<String>) -> Check {
+    if !Path::new(&portage::target_path(EIX_CACHE)).exists() {
+        return Check::problem(
+            "eix database",
+            Grade::Warning,
+            [EIX_CACHE, " does not exist"].concat(),
+            vec!["-> build it with eix-update".to_string()],
+        );
+    }
+    let listed: HashSet<String> = match eixdb::query("eix -I# --only-names") {
+        Ok((output, _)) => output.lines().map(|line| line.trim().to_string()).collect(),
+        Err(error) => {
+            return Check::problem(
+                "eix database",
+                Grade::Failed,
+                error.to_string(),
+                vec!["-> rebuild it with eix-update".to_string()],
+            )
+        }
+    };
+    let differing = installed.symmetric_difference(&listed).count();
+    if differing == 0 {
+        return Check::ok(
+            "eix database",
+            format!("agrees with the {} installed packages", installed.len()),
+        );
+    }
+    Check::problem(
+        "eix database",
+        Grade::Warning,
+        format!(
+            "{} package(s) differ between eix and the package database",
+            differing
+        ),
+        vec!["-> bring it up to date with eix-update".to_string()],
+    )
+}
+
+fn check_orphans() -> Check {
+    match Emerge.pretend_depclean() {
+        Ok((output, 0)) => match portage::parse_depclean(&output) {
+            Some((0, _)) => Check::ok("Orphaned dependencies", "none"),
+            Some((count, _)) => Check::problem(
+                "Orphaned dependencies",
+                Grade::Warning,
+                format!("{} package(s) could be removed", count),
+                vec!["-> review them with emerge -p --depclean, then gentup --clean".to_string()],
+            ),
+            None => Check::ok("Orphaned dependencies", "none"),
+        },
+        _ => Check::problem(
+            "Orphaned dependencies",
+            Grade::Warning,
+            "emerge -p --depclean could not calculate the orphans",
+            vec!["-> this usually clears once the system is updated with gentup".to_string()],
+        ),
+    }
+}
+
+fn check_preserved_libs() -> Check {
+    if !actions::preserved_libs() {
+        return Check::ok("Preserved libraries", "none");
+    }
+    Check::problem(
+        "Preserved libraries",
+        Grade::Warning,
+        "old libraries are kept for packages still linked against them",
+        vec!["-> emerge @preserved-rebuild".to_string()],
+    )
+}
+
+fn check_obsolete_configs() -> Check {
+    let output = match eixdb::query("eix-test-obsolete") {
+        Ok((output, _)) => output,
+        Err(error) => {
+            return Check::problem(
+                "Obsolete configuration",
+                Grade::Warning,
+                ["eix-test-obsolete did not run - ", &error.to_string()].concat(),
+                Vec::new(),
+            )
+        }
+    };
+    let sections = obsolete_sections(&output);
+    if sections.is_empty() {
+        return Check::ok("Obsolete configuration", "none in /etc/portage");
+    }
+    let mut hints: Vec<String> = sections
+        .iter()
+        .map(|(section, count)| format!("{} ({})", section, count))
+        .collect();
+    hints.push("-> run eix-test-obsolete for the entries, and remove them".to_string());
+    Check::problem(
+        "Obsolete configuration",
+        Grade::Warning,
+        format!("{} kind(s) of obsolete entry", sections.len()),
+        hints,
+    )
+}
+
+fn check_disk_space(running_config: &Config) -> Check {
+    let offenders = preflight::short_of_space(running_config);
+    if offenders.is_empty() {
+        return Check::ok("Disk space", "enough on every mount_threshold path");
+    }
+    let mut hints: Vec<String> = offenders
+        .iter()
+        .map(|(threshold, measured)| {
+            format!(
+                "{} has {}MB free, and needs {}MB",
+                measured.path, measured.free_mb, threshold.min_free_mb
+            )
+        })
+        .collect();
+    hints.push("-> free up some space, or adjust the mount_threshold entries".to_string());
+    Check::problem(
+        "Disk space",
+        Grade::Failed,
+        format!("{} path(s) short of space or inodes", offenders.len()),
+        hints,
+    )
+}
+
+fn check_storage_health() -> Check {
+    let unhealthy = smart::unhealthy_disks();
+    if unhealthy.is_empty() {
+        return Check::ok(
+            "Storage health",
+            "no disk under /, /usr or /var reports problems",
+        );
+    }
+    let mut hints: Vec<String> = unhealthy
+        .iter()
+        .map(|(disk, problems)| format!("{}: {}", disk, problems.join(", ")))
+        .collect();
+    hints.push("-> back up this system and replace the failing disk(s)".to_string());
+    Check::problem(
+        "Storage health",
+        Grade::Failed,
+        format!("{} disk(s) failing", unhealthy.len()),
+        hints,
+    )
+}
+
+fn check_pending_configs() -> Check {
+    let pending = actions::pending_configs();
+    if pending.is_empty() {
+        return Check::ok("Configuration updates", "none waiting");
+    }
+    Check::problem(
+        "Configuration updates",
+        Grade::Warning,
+        format!("{} waiting to be merged", pending.len()),
+        vec!["-> dispatch-conf".to_string()],
+    )
+}
+
+// The most recently installed kernel, going by the modules directories in /lib/modules
+//
+fn newest_kernel() -> Option<String> {
+    fs::read_dir("/lib/modules")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            let since = modified.duration_since(UNIX_EPOCH).ok()?;
+            Some((since, entry.file_name().to_string_lossy().to_string()))
+        })
+        .max()
+        .map(|(_, name)| name)
+}
+
+fn check_reboot() -> Check {
+    if !portage::running_system() {
+        return Check::ok(
+            "Reboot",
+            "not applicable to a target root or a Gentoo Prefix",
+        );
+    }
+    let running = OsCall::Quiet
+        .execute("uname -r", "")
+        .map(|(output, _)| output.trim().to_string())
+        .unwrap_or_default();
+    match newest_kernel() {
+        Some(newest) if newest != running => Check::problem(
+            "Reboot",
+            Grade::Warning,
+            format!("kernel {} is installed, but {} is running", newest, running),
+            vec!["-> reboot into the new kernel".to_string()],
+        ),
+        _ => Check::ok("Reboot", ["not needed, running ", &running].concat()),
+    }
+}
+
+// Run every check, display the graded report, and return the exit status
+//
+pub fn run(running_config: &Config) -> ExitCode {
+    println!(
+        "{} Checking the health of this system",
+        prompt::chevrons(Color::Green)
+    );
+    let installed = installed_packages();
+    let checks = [
+        check_distribution(),
+        check_portage(),
+        check_make_conf(running_config),
+        check_features(),
+        check_world(&installed),
+        check_eix(&installed),
+        check_orphans(),
+        check_preserved_libs(),
+        check_obsolete_configs(),
+        check_disk_space(running_config),
+        check_storage_health(),
+        check_pending_configs(),
+        check_reboot(),
+    ];
+    println!();
+    for check in &checks {
+        println!(
+            "{}{}{} {}: {}",
+            crossterm::style::SetForegroundColor(check.grade.colour()),
+            check.grade.label(),
+            crossterm::style::SetForegroundColor(Color::Grey),
+            check.name,
+            check.summary
+        );
+        for hint in &check.hints {
+            for line in hint.lines() {
+                println!("         {}", line);
+            }
+        }
+    }
+    let count = |grade| checks.iter().filter(|check| check.grade == grade).count();
+    println!(
+        "\n{} {} ok, {} warning(s), {} failed",
+        prompt::revchevrons(if count(Grade::Failed) > 0 {
+            Color::Red
+        } else if count(Grade::Warning) > 0 {
+            Color::Yellow
+        } else {
+            Color::Green
+        }),
+        count(Grade::Ok),
+        count(Grade::Warning),
+        count(Grade::Failed)
+    );
+    makeconf::offer_cpu_flags();
+    features::offer_changes();
+    if count(Grade::Failed) > 0 {
+        ExitCode::Failed
+    } else {
+        ExitCode::NothingToDo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audits_system_health() {
+        let installed: HashSet<String> = ["app-editors/vim", "sys-libs/zlib", "dev-lang/rust"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let world = "app-editors/vim\nsys-libs/zlib\napp-misc/foo\n\napp-editors/vim\n\
+            =dev-lang/rust-1.77.1\ndev-lang/rust:stable\n";
+        let problems = world_problems(world, &installed);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("app-misc/foo is in the world file but not installed"));
+        assert!(problems[1].starts_with("app-editors/vim is listed more than once"));
+        assert!(problems[2].ends_with("emerge --noreplace dev-lang/rust"));
+
+        let output = "\
+Non-matching entries in /etc/portage/package.accept_keywords:
+app-misc/foo ~amd64
+=dev-lang/rust-1.70.0 ~amd64
+
+No non-matching or empty entries in /etc/portage/package.use.
+No redundant entries in /etc/portage/package.mask.
+Installed packages with a version not in the database (or masked):
+[I] app-misc/bar (1.0@01/01/2024): an old package
+";
+        assert_eq!(
+            obsolete_sections(output),
+            vec![
+                (
+                    "Non-matching entries in /etc/portage/package.accept_keywords".to_string(),
+                    2
+                ),
+                (
+                    "Installed packages with a version not in the database (or masked)".to_string(),
+                    1
+                ),
+            ]
+        );
+        assert!(
+            obsolete_sections("No non-matching entries in /etc/portage/package.mask.\n").is_empty()
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// Dry run
+// "gentup --dry-run" goes through an update as it would really run, but prints each command which
+// would change the system, with the phase it belongs to, rather than running it, e.g
+//
+//   >>> [dry run] build - Updating @world: emerge --quiet-build y -uNDv ... @world
+//
+// so the user can audit exactly what gentup would do before trusting it unattended. The commands
+// which only look at the system, such as portageq queries and emerge --pretend, still run, since
+// the pending updates and what the later phases would do are worked out from their output.
+// Commands which are held back are taken to have succeeded. The checkpoint is neither saved nor
+// cleared, so a dry run cannot disturb an interrupted update waiting to be continued
+
+use crate::prompt;
+use crossterm::style::{Color, SetForegroundColor};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+// The phase the update is in, to label the commands printed
+static PHASE: Mutex<String> = Mutex::new(String::new());
+
+// Commands which only report on the system, whatever their arguments
+static QUERIES: [&str; 12] = [
+    "cat",
+    "cpuid2cpuflags",
+    "df",
+    "distccmon-text",
+    "du",
+    "eix",
+    "equery",
+    "gemato",
+    "id",
+    "portageq",
+    "smartctl",
+    "uname",
+];
+
+// Hold back, from here on, every command which would change the system
+//
+pub fn enable() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+pub fn active() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// Record the phase the update has entered
+//
+pub fn set_phase(phase: &str) {
+    if let Ok(mut current) = PHASE.lock() {
+        *current = phase.to_string();
+    }
+}
+
+// Whether a short option, or a bundle of them such as -puDv, includes a letter
+fn has_short(words: &[&str], letter: char) -> bool {
+    words
+        .iter()
+        .any(|word| !word.starts_with("--") && word.starts_with('-') && word.contains(letter))
+}
+
+// Whether a command line only looks at the system, and can run during a dry run
+//
+pub fn read_only(command_line: &str) -> bool {
+    let words: Vec<&str> = command_line.split_whitespace().collect();
+    let Some(program) = words.first() else {
+        return true;
+    };
+    let program = Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let arguments = &words[1..];
+    let given = |option: &str| arguments.contains(&option);
+    match program.as_str() {
+        "emerge" => {
+            given("--pretend")
+                || given("--info")
+                || given("--version")
+                || given("--search")
+                || has_short(arguments, 'p')
+        }
+        "eclean" | "revdep-rebuild" => given("--pretend") || has_short(arguments, 'p'),
+        "gcc-config" => given("-c") || given("-l"),
+        "eselect" => given("show") || given("list"),
+        "ccache" => given("--print-stats") || given("--get-config") || given("-s"),
+        "snapper" => given("list"),
+        "nvme" => given("smart-log"),
+        "make" => given("listnewconfig"),
+        "btrfs" => given("stats") && !has_short(arguments, 'z'),
+        "gzip" | "xz" | "zstd" => has_short(arguments, 'd') && has_short(arguments, 'c'),
+        _ => QUERIES.contains(&program.as_str()),
+    }
+}
+
+// Print a command which is held back, labelled with its phase and what it is for
+//
+pub fn show(command_line: &str, status: &str) {
+    let phase = PHASE.lock().map(|phase| phase.clone()).unwrap_or_default();
+    let label = match (phase.is_empty(), status.is_empty()) {
+        (true, true) => String::new(),
+        (true, false) => [status, ": "].concat(),
+        (false, true) => [&phase, ": "].concat(),
+        (false, false) => [&phase, " - ", status, ": "].concat(),
+    };
+    println!(
+        "{} [dry run] {}{}{}{}",
+        prompt::chevrons(Color::Yellow),
+        label,
+        SetForegroundColor(Color::Cyan),
+        command_line,
+        SetForegroundColor(Color::Grey)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_queries_from_changes() {
+        for query in [
+            "emerge -puDv @world",
+            "emerge -p --depclean",
+            "emerge --info =sys-libs/zlib-1.3.1",
+            "revdep-rebuild -ip",
+            "eclean --pretend -d distfiles",
+            "portageq envvar ARCH",
+            "/usr/bin/uname -r",
+            "eselect python show",
+            "snapper --csvout --iso list --columns number,date",
+            "zstd -dcq /var/lib/gentup/runs/1712345600.zst",
+        ] {
+            assert!(read_only(query), "{}", query);
+        }
+        for change in [
+            "emerge --sync",
+            "emerge --quiet-build y -uNDv --autounmask n @world",
+            "emerge --depclean --exclude sys-kernel/gentoo-sources",
+            "emerge --quiet -1v app-portage/gentup",
+            "eclean -d distfiles",
+            "fstrim -v /",
+            "dispatch-conf",
+            "snapper rollback 2",
+            "zstd -q /var/log/portage/build.log",
+        ] {
+            assert!(!read_only(change), "{}", change);
+        }
+    }
+}
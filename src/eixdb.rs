@@ -0,0 +1,119 @@
+// eix database repair
+// eix answers its queries from its own database, /var/cache/eix/portage.eix, rather than from the
+// package tree. When the database is missing, was cut short by a full disk or an interrupted
+// eix-update, or was written by an older eix before an upgrade, every eix query fails, and gentup
+// used to exit on the first one. eix queries are now run through query(), which recognises this
+// kind of failure from eix's error message, e.g
+//
+//   Can't open the database file /var/cache/eix/portage.eix for reading (mode = 'rb')
+//   Did you forget to create it with 'eix-update'?
+//
+// then rebuilds the database and retries the query once. The database is rebuilt with eix-update,
+// and if eix-update fails too, the database is removed and eix-update is run again from scratch
+
+use crate::{
+    linux::{self, OsCall, ShellOutResult},
+    portage, prompt,
+    treestate::EIX_CACHE,
+};
+use crossterm::style::Color;
+use std::{fs, process::Command};
+
+// Phrases in eix's error messages which mean the database, rather than the query, is at fault
+static DATABASE_ERRORS: [&str; 6] = [
+    "database file",
+    "eix-update",
+    "incompatible version",
+    "wrong format",
+    "corrupt",
+    "premature end of file",
+];
+
+// Whether an eix error message says the database cannot be used
+//
+pub fn is_database_error(stderr: &str) -> bool {
+    let lowercase = stderr.to_lowercase();
+    DATABASE_ERRORS
+        .iter()
+        .any(|phrase| lowercase.contains(phrase))
+}
+
+// Run an eix command, returning what it wrote to stdout and to stderr, and its exit status.
+// OsCall::Quiet returns stdout alone, and the error message is needed to tell the failures apart
+//
+fn run(command_line: &str) -> Result<(String, String, i32), std::io::Error> {
+    let mut words = command_line.split_whitespace();
+    let mut command = Command::new(words.next().unwrap_or("eix"));
+    let output = linux::untranslated(&mut command).args(words).output()?;
+    Ok((
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+        output.status.code().unwrap_or(-1),
+    ))
+}
+
+// Rebuild the eix database, removing it first if eix-update cannot bring it up to date
+//
+pub fn rebuild() -> bool {
+    if let Ok((_, 0)) = OsCall::Spinner.execute("eix-update", "Initialising package database") {
+        return true;
+    }
+    let cache = portage::target_path(EIX_CACHE);
+    println!(
+        "{} eix-update failed. Removing {} and building it again",
+        prompt::revchevrons(Color::Yellow),
+        cache
+    );
+    let _ = fs::remove_file(&cache);
+    matches!(
+        OsCall::Spinner.execute("eix-update", "Rebuilding package database"),
+        Ok((_, 0))
+    )
+}
+
+// Run an eix query, rebuilding the database and retrying once if the database is at fault
+//
+pub fn query(command_line: &str) -> ShellOutResult {
+    let (stdout, stderr, status) = run(command_line)?;
+    if status == 0 || !is_database_error(&stderr) {
+        return Ok((stdout, status));
+    }
+    println!(
+        "{} The eix database could not be used: {}",
+        prompt::revchevrons(Color::Yellow),
+        stderr.lines().next().unwrap_or_default()
+    );
+    if !rebuild() {
+        return Err(["The eix database could not be rebuilt - ", stderr.trim()]
+            .concat()
+            .into());
+    }
+    let (stdout, stderr, status) = run(command_line)?;
+    if status != 0 && is_database_error(&stderr) {
+        return Err([
+            "eix still fails after rebuilding its database - ",
+            stderr.trim(),
+        ]
+        .concat()
+        .into());
+    }
+    Ok((stdout, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_database_errors() {
+        assert!(is_database_error(
+            "Can't open the database file /var/cache/eix/portage.eix for reading (mode = 'rb')\n\
+            Did you forget to create it with 'eix-update'?\n"
+        ));
+        assert!(is_database_error(
+            "/var/cache/eix/portage.eix was created with an incompatible version (expected 39, got 38)"
+        ));
+        assert!(!is_database_error(""));
+        assert!(!is_database_error("No matches found"));
+    }
+}
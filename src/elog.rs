@@ -0,0 +1,218 @@
+// Package build logs
+// Reads the elog messages which portage saves for each package it merges, rather than relying on
+// elogv, and picks out the messages asking the administrator to do something, so that they can be
+// added to the post-update checklist with the package which asked. Portage saves the messages when
+// PORTAGE_ELOG_SYSTEM includes "save", in files named after the package and time, e.g
+// /var/log/portage/elog/sys-libs:glibc-2.39-r6:20240405-101500.log, holding a header line for
+// each message giving its class and the phase which logged it:
+//
+//   WARN: postinst
+//   You must restart any services linked against glibc.
+
+use crate::{portage, prompt};
+use crossterm::style::Color;
+use std::{fs, time::UNIX_EPOCH};
+
+// Phrases which mark a message line as something the administrator has to act on
+static ACTION_PHRASES: [&str; 12] = [
+    "you must",
+    "you should",
+    "you need to",
+    "please run",
+    "please restart",
+    "restart",
+    "reboot",
+    "rebuild",
+    "re-emerge",
+    "dispatch-conf",
+    "etc-update",
+    "manually",
+];
+
+// Define a struct to hold one elog message
+//
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub class: String, // INFO, LOG, WARN, ERROR or QA
+    pub phase: String,
+    pub lines: Vec<String>,
+}
+
+// Define a struct to hold the messages logged by one package
+//
+pub struct PackageLog {
+    pub package: String,
+    pub messages: Vec<Message>,
+}
+
+impl PackageLog {
+    // The message lines which ask for something to be done, from the log, warn and error classes
+    //
+    pub fn actions(&self) -> Vec<&str> {
+        self.messages
+            .iter()
+            .filter(|message| matches!(message.class.as_str(), "LOG" | "WARN" | "ERROR"))
+            .flat_map(|message| message.lines.iter())
+            .filter(|line| {
+                let lowercase = line.to_lowercase();
+                ACTION_PHRASES
+                    .iter()
+                    .any(|phrase| lowercase.contains(phrase))
+            })
+            .map(|line| line.as_str())
+            .collect()
+    }
+}
+
+// Parse the contents of an elog file into its messages
+//
+pub fn parse(contents: &str) -> Vec<Message> {
+    let mut messages: Vec<Message> = Vec::new();
+    for line in contents.lines() {
+        if let Some((class, phase)) = line.split_once(": ") {
+            if matches!(class, "INFO" | "LOG" | "WARN" | "ERROR" | "QA")
+                && !phase.contains(char::is_whitespace)
+            {
+                messages.push(Message {
+                    class: class.to_string(),
+                    phase: phase.to_string(),
+                    lines: Vec::new(),
+                });
+                continue;
+            }
+        }
+        if let Some(message) = messages.last_mut() {
+            if !line.trim().is_empty() {
+                message.lines.push(line.trim().to_string());
+            }
+        }
+    }
+    messages
+}
+
+// The package from the name of an elog file, e.g sys-libs/glibc-2.39-r6 from
+// sys-libs:glibc-2.39-r6:20240405-101500.log
+//
+pub fn package_from_file_name(name: &str) -> Option<String> {
+    let mut fields = name.split(':');
+    Some([fields.next()?, "/", fields.next()?].concat())
+}
+
+fn elog_dir() -> String {
+    portage::port_logdir() + "/elog"
+}
+
+// Read the elog files written since the given time, in the order the packages were merged
+//
+pub fn since(timestamp: u64) -> Vec<PackageLog> {
+    let mut files: Vec<(u64, String, String)> = Vec::new();
+    let Ok(entries) = fs::read_dir(elog_dir()) else {
+        return Vec::new();
+    };
+    for entry in entries.flatten() {
+        let modified = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or(0);
+        let name = entry.file_name().to_string_lossy().to_string();
+        if modified >= timestamp && name.ends_with(".log") {
+            files.push((modified, name, entry.path().to_string_lossy().to_string()));
+        }
+    }
+    files.sort();
+    files
+        .into_iter()
+        .filter_map(|(_, name, path)| {
+            Some(PackageLog {
+                package: package_from_file_name(&name)?,
+                messages: parse(&fs::read_to_string(path).ok()?),
+            })
+        })
+        .collect()
+}
+
+// The actions asked for, each with the package which asked for it
+//
+pub fn actions(logs: &[PackageLog]) -> Vec<String> {
+    logs.iter()
+        .flat_map(|log| {
+            log.actions()
+                .into_iter()
+                .map(|action| [&log.package, ": ", action].concat())
+        })
+        .collect()
+}
+
+// Add the actions asked for by the packages merged since the given time to the post-update
+// checklist
+//
+pub fn report(timestamp: u64) {
+    let logs = since(timestamp);
+    let warnings: usize = logs
+        .iter()
+        .flat_map(|log| log.messages.iter())
+        .filter(|message| matches!(message.class.as_str(), "WARN" | "ERROR"))
+        .count();
+    let actions = actions(&logs);
+    if !logs.is_empty() {
+        println!(
+            "{} {} package(s) logged messages, with {} warning(s) and {} action(s) for the checklist",
+            prompt::revchevrons(if actions.is_empty() {
+                Color::Green
+            } else {
+                Color::Yellow
+            }),
+            logs.len(),
+            warnings,
+            actions.len()
+        );
+    }
+    actions.into_iter().for_each(crate::actions::add);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_out_actions() {
+        let contents = "\
+INFO: setup
+Package:    sys-libs/glibc-2.39-r6
+Repository: gentoo
+
+LOG: postinst
+Please run locale-gen after editing /etc/locale.gen
+Locales are unchanged.
+
+WARN: postinst
+You must restart any running services linked against glibc
+for them to pick up the new version.
+Run: dispatch-conf to update /etc/locale.gen
+";
+        let messages = parse(contents);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2].class, "WARN");
+        assert_eq!(messages[2].phase, "postinst");
+        let log = PackageLog {
+            package: package_from_file_name("sys-libs:glibc-2.39-r6:20240405-101500.log").unwrap(),
+            messages,
+        };
+        assert_eq!(log.package, "sys-libs/glibc-2.39-r6");
+        assert_eq!(
+            log.actions(),
+            vec![
+                "Please run locale-gen after editing /etc/locale.gen",
+                "You must restart any running services linked against glibc",
+                "Run: dispatch-conf to update /etc/locale.gen"
+            ]
+        );
+        assert_eq!(
+            actions(&[log])[0],
+            "sys-libs/glibc-2.39-r6: Please run locale-gen after editing /etc/locale.gen"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+// emerge default options
+// EMERGE_DEFAULT_OPTS, from make.conf or the environment, is added to every emerge command gentup
+// runs, and some options fight gentup's own. --ask leaves emerge waiting for an answer behind
+// gentup's spinner, where nobody sees the question, and --pretend or --tree stop the pending
+// updates being read. Before the update, these are taken out of EMERGE_DEFAULT_OPTS for gentup's
+// own emerge commands, by setting the cleaned value in the environment, which portage prefers to
+// make.conf, e.g
+//
+//   EMERGE_DEFAULT_OPTS="-av --jobs 4 --quiet-build n"  runs emerge with  -v --jobs=4
+//
+// make.conf itself is left alone, and gentup --doctor suggests removing them from it for good.
+// --jobs and --load-average are kept, but with auto_parallelism: true in the configuration file
+// gentup's own take their place for the world update, which is pointed out
+
+use crate::{makeconf, prompt, Config};
+use crossterm::style::Color;
+use std::env;
+
+// EMERGE_DEFAULT_OPTS which stop gentup's emerge commands working unattended, or undo the options
+// it passes, with the reason
+pub static CONFLICTS: [(&str, &str); 5] = [
+    (
+        "--ask",
+        "emerge waits for an answer behind gentup's spinner",
+    ),
+    (
+        "--autounmask-write",
+        "gentup runs emerge with --autounmask n",
+    ),
+    ("--pretend", "nothing would ever be updated"),
+    (
+        "--quiet-build=n",
+        "build output floods the terminal and the logs",
+    ),
+    ("--tree", "the pending update list can no longer be read"),
+];
+
+// The short form of a conflicting option, which may be bundled, e.g -av for --ask --verbose
+fn short(option: &str) -> Option<char> {
+    match option {
+        "--ask" => Some('a'),
+        "--pretend" => Some('p'),
+        "--tree" => Some('t'),
+        _ => None,
+    }
+}
+
+// Split EMERGE_DEFAULT_OPTS into options, joining each to its value, e.g --quiet-build n becomes
+// --quiet-build=n
+//
+pub fn split(default_opts: &str) -> Vec<String> {
+    let mut options: Vec<String> = Vec::new();
+    for word in default_opts.split_whitespace() {
+        match options.last_mut() {
+            Some(last)
+                if last.starts_with("--") && !last.contains('=') && !word.starts_with('-') =>
+            {
+                last.push('=');
+                last.push_str(word);
+            }
+            _ => options.push(word.to_string()),
+        }
+    }
+    options
+}
+
+// The conflicting options among those given, with the reason each conflicts
+//
+pub fn conflicting(options: &[String]) -> Vec<(&'static str, &'static str)> {
+    CONFLICTS
+        .into_iter()
+        .filter(|(option, _)| {
+            options.iter().any(|each| {
+                *each == *option
+                    || *each == [option, "=y"].concat()
+                    || short(option).is_some_and(|short| bundled(each).contains(short))
+            })
+        })
+        .collect()
+}
+
+// The letters of a bundle of short options, up to the value of any which takes one, e.g av from
+// -avj4
+fn bundled(option: &str) -> &str {
+    match option.strip_prefix('-') {
+        Some(letters) if !letters.starts_with('-') => letters
+            .split(|character: char| character.is_ascii_digit())
+            .next()
+            .unwrap_or_default(),
+        _ => "",
+    }
+}
+
+// The options with the conflicting ones taken out
+//
+pub fn neutralised(options: &[String]) -> Vec<String> {
+    let conflicts = conflicting(options);
+    let shorts: Vec<char> = conflicts
+        .iter()
+        .filter_map(|(option, _)| short(option))
+        .collect();
+    options
+        .iter()
+        .filter(|each| {
+            !conflicts
+                .iter()
+                .any(|(option, _)| *each == option || **each == [option, "=y"].concat())
+        })
+        .filter_map(|each| {
+            let letters = bundled(each);
+            if letters.is_empty() {
+                return Some(each.clone());
+            }
+            let kept: String = letters
+                .chars()
+                .filter(|letter| !shorts.contains(letter))
+                .collect();
+            let rest = &each[1 + letters.len()..];
+            (!kept.is_empty() || !rest.is_empty()).then(|| ["-", &kept, rest].concat())
+        })
+        .collect()
+}
+
+// EMERGE_DEFAULT_OPTS as emerge will see it: from the environment if set there, otherwise from
+// make.conf
+//
+pub fn current() -> String {
+    env::var("EMERGE_DEFAULT_OPTS").unwrap_or_else(|_| {
+        makeconf::variable(&makeconf::read_make_conf(), "EMERGE_DEFAULT_OPTS").unwrap_or_default()
+    })
+}
+
+// Take the conflicting options out of EMERGE_DEFAULT_OPTS for the rest of this run
+//
+pub fn neutralise(running_config: &Config) {
+    let options = split(&current());
+    let conflicts = conflicting(&options);
+    for (option, reason) in &conflicts {
+        println!(
+            "{} Ignoring {} in EMERGE_DEFAULT_OPTS, as {}. gentup --doctor suggests how to fix this",
+            prompt::revchevrons(Color::Yellow),
+            option,
+            reason
+        );
+    }
+    if running_config.auto_parallelism
+        && options
+            .iter()
+            .any(|option| option.starts_with("--jobs") || bundled(option).ends_with('j'))
+    {
+        println!(
+            "{} EMERGE_DEFAULT_OPTS sets --jobs, which auto_parallelism overrides for the world update",
+            prompt::revchevrons(Color::Blue)
+        );
+    }
+    if !conflicts.is_empty() {
+        env::set_var("EMERGE_DEFAULT_OPTS", neutralised(&options).join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutralises_conflicting_options() {
+        let options = split("-av --jobs 4 --quiet-build n --autounmask-write=y");
+        assert_eq!(
+            options,
+            vec!["-av", "--jobs=4", "--quiet-build=n", "--autounmask-write=y"]
+        );
+        assert_eq!(
+            conflicting(&options)
+                .iter()
+                .map(|(option, _)| *option)
+                .collect::<Vec<_>>(),
+            vec!["--ask", "--autounmask-write", "--quiet-build=n"]
+        );
+        assert_eq!(neutralised(&options), vec!["-v", "--jobs=4"]);
+
+        let options = split("-aj4 --tree --ask n --keep-going");
+        assert_eq!(
+            neutralised(&options),
+            vec!["-j4", "--ask=n", "--keep-going"]
+        );
+        assert!(conflicting(&split("-v --quiet-build y")).is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+// Update estimate
+// "gentup --estimate" predicts what an update would involve, without syncing or changing anything,
+// to help decide whether to run one now: the number of packages pending, how much would be
+// downloaded, how long building would take going by earlier builds in emerge.log, how much disk
+// space is needed, and anything likely to make the update fail or need attention. The pending
+// updates are calculated as the update itself calculates them, and the result is kept for the
+// update to reuse if it follows soon after, e.g
+//
+//   [ebuild     U  ] dev-libs/openssl-3.0.13:0/3::gentoo [3.0.12:0/3::gentoo] USE="asm" 15,470 KiB
+//   [blocks B      ] <sys-apps/util-linux-2.39 ("<sys-apps/util-linux-2.39" is soft blocking ...)
+//
+//   Total: 1 package (1 upgrade), Size of downloads: 15,470 KiB
+
+use crate::{
+    exitcode::ExitCode,
+    glsa,
+    portage::{self, Change, PackageManager},
+    preflight, prompt,
+    stats::{self, History},
+};
+use crossterm::style::Color;
+
+// The space assumed to build a package which is not known to be huge
+static DEFAULT_BUILD_SPACE_MB: u64 = 1024;
+
+// Define a struct to hold the prediction for an update
+//
+#[derive(Debug, Default)]
+pub struct Estimate {
+    pub changes: Vec<Change>,
+    pub download_kib: u64,
+    pub build_seconds: u64, // For the packages which have been built before
+    pub unknown_build_times: usize, // Packages which have never been built here
+    pub build_space_mb: u64, // The most any one package needs in PORTAGE_TMPDIR
+    pub risks: Vec<String>,
+}
+
+// Parse the total download size from the last line of emerge's pretend output, in KiB
+//
+pub fn parse_download_size(output: &str) -> Option<u64> {
+    let size = output
+        .lines()
+        .find_map(|line| line.split_once("Size of downloads: "))?
+        .1
+        .trim();
+    let (number, unit) = size.split_once(' ')?;
+    let number: u64 = number.replace(',', "").parse().ok()?;
+    match unit.trim() {
+        "KiB" => Some(number),
+        "MiB" => Some(number * 1024),
+        "GiB" => Some(number * 1024 * 1024),
+        _ => None,
+    }
+}
+
+// Predict an update from emerge's pretend output and the merge history, given the free space in
+// MB where the sources are downloaded and the packages are built
+//
+pub fn predict(
+    output: &str,
+    history: &History,
+    distdir_free_mb: Option<u64>,
+    tmpdir_free_mb: Option<u64>,
+) -> Estimate {
+    let mut estimate = Estimate {
+        changes: portage::parse_changes(output),
+        download_kib: parse_download_size(output).unwrap_or(0),
+        ..Default::default()
+    };
+    for change in &estimate.changes {
+        let cpn = change.package.cpn();
+        match history.average_build_time(&cpn) {
+            Some(seconds) => estimate.build_seconds += seconds,
+            None => estimate.unknown_build_times += 1,
+        }
+        let needed_mb = preflight::build_space_mb(&cpn);
+        if let Some(needed_mb) = needed_mb {
+            estimate.risks.push(format!(
+                "{} is a huge package, needing around {} MB to build",
+                cpn, needed_mb
+            ));
+        }
+        estimate.build_space_mb = estimate
+            .build_space_mb
+            .max(needed_mb.unwrap_or(DEFAULT_BUILD_SPACE_MB));
+    }
+    for line in output.lines() {
+        if line.starts_with("[blocks B") {
+            estimate.risks.push(format!(
+                "Hard blocker, which needs resolving by hand: {}",
+                line.split_once(']')
+                    .map(|(_, rest)| rest)
+                    .unwrap_or("")
+                    .trim()
+            ));
+        }
+    }
+    if output.contains("slot conflict") {
+        estimate
+            .risks
+            .push("emerge reports a slot conflict, which needs resolving by hand".to_string());
+    }
+    let download_mb = estimate.download_kib / 1024;
+    if distdir_free_mb.is_some_and(|free_mb| free_mb < download_mb) {
+        estimate.risks.push(format!(
+            "The {} MB of downloads will not fit in the {} MB free in DISTDIR",
+            download_mb,
+            distdir_free_mb.unwrap_or(0)
+        ));
+    }
+    if !estimate.changes.is_empty()
+        && tmpdir_free_mb.is_some_and(|free_mb| free_mb < estimate.build_space_mb)
+    {
+        estimate.risks.push(format!(
+            "Building needs around {} MB, but PORTAGE_TMPDIR has {} MB free",
+            estimate.build_space_mb,
+            tmpdir_free_mb.unwrap_or(0)
+        ));
+    }
+    estimate
+}
+
+// Predict the update and display the prediction on one screen, for gentup --estimate
+//
+pub fn show() -> ExitCode {
+    let (output, status) = match PackageManager::DryRun.update_all_packages() {
+        Ok(result) => result,
+        Err(_) => {
+            eprintln!("{} Error calling emerge", prompt::revchevrons(Color::Red));
+            return ExitCode::Failed;
+        }
+    };
+    let free_mb = |variable: &str, default: &str| {
+        let path = portage::make_conf_variable(variable).unwrap_or(default.to_string());
+        preflight::filesystem_of(&path).map(|(_, free_mb)| free_mb)
+    };
+    let mut estimate = predict(
+        &output,
+        &History::load(),
+        free_mb("DISTDIR", "/var/cache/distfiles"),
+        free_mb("PORTAGE_TMPDIR", "/var/tmp"),
+    );
+    if estimate.changes.is_empty() && status != 0 {
+        // Nothing could be calculated, so show why
+        eprintln!(
+            "{} emerge could not calculate the update:\n",
+            prompt::revchevrons(Color::Red)
+        );
+        for line in output
+            .lines()
+            .filter(|line| line.starts_with("!!!"))
+            .take(10)
+        {
+            eprintln!("  {}", line);
+        }
+        return ExitCode::Failed;
+    }
+    if estimate.changes.is_empty() {
+        println!(
+            "{} There are no pending updates",
+            prompt::revchevrons(Color::Blue)
+        );
+        return ExitCode::NothingToDo;
+    }
+    glsa::tag(&glsa::load(), &mut estimate.changes);
+    let security = estimate
+        .changes
+        .iter()
+        .filter(|change| !change.security.is_empty())
+        .count();
+    println!(
+        "{} Estimate for the update, from the package tree as last synced:\n",
+        prompt::revchevrons(Color::Green)
+    );
+    println!(
+        "  Pending updates   {} ({} fixing security advisories)",
+        estimate.changes.len(),
+        security
+    );
+    println!(
+        "  Downloads         {} MB",
+        estimate.download_kib.div_ceil(1024)
+    );
+    println!(
+        "  Build time        {}{}",
+        stats::format_duration(estimate.build_seconds),
+        match estimate.unknown_build_times {
+            0 => String::new(),
+            unknown => format!(", plus {} package(s) never built here before", unknown),
+        }
+    );
+    println!(
+        "  Disk space        {} MB of downloads, and around {} MB to build\n",
+        estimate.download_kib.div_ceil(1024),
+        estimate.build_space_mb
+    );
+    if estimate.risks.is_empty() {
+        println!("{} No risks found", prompt::revchevrons(Color::Green));
+    } else {
+        println!("{} Risks:", prompt::revchevrons(Color::Yellow));
+        for risk in &estimate.risks {
+            println!("  - {}", risk);
+        }
+    }
+    ExitCode::UpdatesPending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predicts_the_update() {
+        let output = "\
+[ebuild     U  ] sys-libs/zlib-1.3.1:0/1::gentoo [1.3:0/1::gentoo] USE=\"-minizip\" 1,564 KiB
+[ebuild  N     ] www-client/chromium-126.0.6478.126:0/stable::gentoo 1,234,567 KiB
+[blocks B      ] <sys-apps/util-linux-2.39 (\"<sys-apps/util-linux-2.39\" is blocking sys-apps/shadow-4.14)
+
+Total: 2 packages (1 upgrade, 1 new), Size of downloads: 1,236,131 KiB
+";
+        assert_eq!(parse_download_size(output), Some(1236131));
+        assert_eq!(
+            parse_download_size("Total: 1 package, Size of downloads: 2 MiB"),
+            Some(2048)
+        );
+        let history = History::parse(
+            "1712350000:  >>> emerge (1 of 1) sys-libs/zlib-1.3 to /\n\
+            1712350090:  ::: completed emerge (1 of 1) sys-libs/zlib-1.3 to /\n",
+        );
+        let estimate = predict(output, &history, Some(512), Some(8192));
+        assert_eq!(estimate.changes.len(), 2);
+        assert_eq!(estimate.build_seconds, 90);
+        assert_eq!(estimate.unknown_build_times, 1);
+        assert_eq!(estimate.build_space_mb, 16384);
+        assert_eq!(estimate.risks.len(), 4);
+        assert!(estimate.risks[0].starts_with("www-client/chromium is a huge package"));
+        assert!(estimate.risks[1].starts_with("Hard blocker"));
+        assert!(estimate.risks[2].starts_with("The 1207 MB of downloads will not fit"));
+        assert!(estimate.risks[3].starts_with("Building needs around 16384 MB"));
+    }
+}
@@ -0,0 +1,397 @@
+// Machine readable output
+// With --json, gentup writes newline-delimited JSON events to stdout as the update progresses, one
+// object per line, so that orchestration tools can follow it without parsing text. Everything
+// else, including the output of emerge and the other commands gentup runs, goes to stderr
+// instead, leaving stdout carrying nothing but events:
+//
+//   {"event":"phase_start","phase":"pretend"}
+//   {"event":"pending_updates","count":1,"security_count":0,"packages":["sys-libs/zlib-1.3.1"],"security":[]}
+//   {"event":"package_started","package":"sys-libs/zlib-1.3.1","number":1,"total":1}
+//   {"event":"exit","code":1,"description":"Updates were applied"}
+
+use crate::{
+    portage::{self, EmergeLogEntry},
+    report,
+};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    os::fd::FromRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+static OUTPUT: Mutex<Option<File>> = Mutex::new(None); // The original stdout, once redirected
+
+// Define the events reported in JSON mode
+//
+pub enum Event {
+    PhaseStart {
+        phase: &'static str,
+    },
+    PhaseEnd {
+        phase: &'static str,
+        seconds: u64,
+    },
+    TreeVerification {
+        status: &'static str, // verified, unverified or failed
+    },
+    PendingUpdates {
+        packages: Vec<String>,
+        security: Vec<String>, // Those of the packages which fix a security advisory
+    },
+    PackageStarted {
+        package: String,
+        number: usize,
+        total: usize,
+    },
+    PackageFinished {
+        package: String,
+        number: usize,
+        total: usize,
+    },
+    PackageFailed {
+        package: String,
+    },
+    Orphans {
+        count: i32,
+    },
+    CompilerStats {
+        ccache: Option<(u64, u64)>, // Cache hits and misses during the build
+        distcc: Option<(u64, u64)>, // Compile jobs sampled running locally and remotely
+    },
+    ActionsRequired {
+        actions: Vec<String>, // The post-update checklist
+    },
+    CrossTarget {
+        target: String, // A crossdev target, e.g armv7a-unknown-linux-gnueabihf
+        packages: Vec<String>,
+        succeeded: bool,
+    },
+    BugReport {
+        package: String, // The package which failed to build
+        path: String,    // The bundle gathered for a bug report
+    },
+    RemovedPackages {
+        packages: Vec<(String, String)>, // Installed packages in no repository, and why
+    },
+    Exit {
+        code: i32,
+        description: &'static str,
+    },
+}
+
+// Format the packages removed from the repositories as the members of a JSON array
+//
+pub fn removed_json(packages: &[(String, String)]) -> String {
+    packages
+        .iter()
+        .map(|(package, reason)| {
+            format!(
+                "{{\"package\":{},\"reason\":{}}}",
+                json_string(package),
+                json_string(reason)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// Quote a string for inclusion in JSON output
+//
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Event {
+    pub fn to_json(&self) -> String {
+        match self {
+            Event::PhaseStart { phase } => format!(
+                "{{\"event\":\"phase_start\",\"phase\":{}}}",
+                json_string(phase)
+            ),
+            Event::PhaseEnd { phase, seconds } => format!(
+                "{{\"event\":\"phase_end\",\"phase\":{},\"seconds\":{}}}",
+                json_string(phase),
+                seconds
+            ),
+            Event::TreeVerification { status } => format!(
+                "{{\"event\":\"tree_verification\",\"status\":{}}}",
+                json_string(status)
+            ),
+            Event::PendingUpdates { packages, security } => {
+                let list = |items: &[String]| {
+                    let quoted: Vec<String> = items.iter().map(|each| json_string(each)).collect();
+                    quoted.join(",")
+                };
+                format!(
+                    "{{\"event\":\"pending_updates\",\"count\":{},\"security_count\":{},\"packages\":[{}],\"security\":[{}]}}",
+                    packages.len(),
+                    security.len(),
+                    list(packages),
+                    list(security)
+                )
+            }
+            Event::PackageStarted {
+                package,
+                number,
+                total,
+            } => format!(
+                "{{\"event\":\"package_started\",\"package\":{},\"number\":{},\"total\":{}}}",
+                json_string(package),
+                number,
+                total
+            ),
+            Event::PackageFinished {
+                package,
+                number,
+                total,
+            } => format!(
+                "{{\"event\":\"package_finished\",\"package\":{},\"number\":{},\"total\":{}}}",
+                json_string(package),
+                number,
+                total
+            ),
+            Event::PackageFailed { package } => format!(
+                "{{\"event\":\"package_failed\",\"package\":{}}}",
+                json_string(package)
+            ),
+            Event::Orphans { count } => format!("{{\"event\":\"orphans\",\"count\":{}}}", count),
+            Event::BugReport { package, path } => format!(
+                "{{\"event\":\"bug_report\",\"package\":{},\"path\":{}}}",
+                json_string(package),
+                json_string(path)
+            ),
+            Event::RemovedPackages { packages } => format!(
+                "{{\"event\":\"removed_packages\",\"packages\":[{}]}}",
+                removed_json(packages)
+            ),
+            Event::CompilerStats { ccache, distcc } => format!(
+                "{{\"event\":\"compiler_stats\",\"ccache\":{},\"distcc\":{}}}",
+                ccache
+                    .map(|(hits, misses)| format!("{{\"hits\":{},\"misses\":{}}}", hits, misses))
+                    .unwrap_or("null".to_string()),
+                distcc
+                    .map(|(local, remote)| format!("{{\"local\":{},\"remote\":{}}}", local, remote))
+                    .unwrap_or("null".to_string())
+            ),
+            Event::ActionsRequired { actions } => {
+                let quoted: Vec<String> = actions.iter().map(|each| json_string(each)).collect();
+                format!(
+                    "{{\"event\":\"actions_required\",\"count\":{},\"actions\":[{}]}}",
+                    actions.len(),
+                    quoted.join(",")
+                )
+            }
+            Event::CrossTarget {
+                target,
+                packages,
+                succeeded,
+            } => {
+                let quoted: Vec<String> = packages.iter().map(|each| json_string(each)).collect();
+                format!(
+                    "{{\"event\":\"cross_target\",\"target\":{},\"count\":{},\"packages\":[{}],\"succeeded\":{}}}",
+                    json_string(target),
+                    packages.len(),
+                    quoted.join(","),
+                    succeeded
+                )
+            }
+            Event::Exit { code, description } => format!(
+                "{{\"event\":\"exit\",\"code\":{},\"description\":{}}}",
+                code,
+                json_string(description)
+            ),
+        }
+    }
+}
+
+// Extract the value of a field from one of the JSON events written above, e.g the code from
+// {"event":"exit","code":1,...}. Strings are returned unquoted, and anything else as written
+//
+pub fn field(event: &str, name: &str) -> Option<String> {
+    let start = event.find(&format!("\"{}\":", name))? + name.len() + 3;
+    let rest = &event[start..];
+    if let Some(rest) = rest.strip_prefix('"') {
+        let mut value = String::new();
+        let mut characters = rest.chars();
+        while let Some(character) = characters.next() {
+            match character {
+                '"' => return Some(value),
+                '\\' => match characters.next()? {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    escaped => value.push(escaped),
+                },
+                c => value.push(c),
+            }
+        }
+        None
+    } else {
+        let end = rest.find([',', '}', ']']).unwrap_or(rest.len());
+        Some(rest[..end].to_string())
+    }
+}
+
+// Switch to JSON mode: keep the original stdout for events, and point stdout at stderr so that
+// all other output, ours and that of child processes, goes there
+//
+pub fn enable_json() -> io::Result<()> {
+    let _ = io::stdout().flush();
+    // SAFETY: dup and dup2 only manipulate the process's file descriptor table, and the
+    // descriptor returned by dup is owned by nothing else
+    let events = unsafe {
+        let saved = libc::dup(libc::STDOUT_FILENO);
+        if saved < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        File::from_raw_fd(saved)
+    };
+    if let Ok(mut output) = OUTPUT.lock() {
+        *output = Some(events);
+    }
+    JSON_MODE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn json_mode() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+// Record an event in the run report, and write it out if in JSON mode
+//
+pub fn emit(event: Event) {
+    report::record(&event);
+    if !json_mode() {
+        return;
+    }
+    if let Ok(mut output) = OUTPUT.lock() {
+        if let Some(events) = output.as_mut() {
+            let _ = writeln!(events, "{}", event.to_json());
+        }
+    }
+}
+
+// Follows emerge.log while the world update runs, reporting each package as it starts and
+// finishes building
+//
+pub struct LogWatcher {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Option<String>>, // Returns the package which started but did not finish
+}
+
+impl LogWatcher {
+    // Start following emerge.log from its current end. Returns None unless in JSON mode
+    //
+    pub fn start() -> Option<LogWatcher> {
+        if !json_mode() {
+            return None;
+        }
+        let mut log = File::open(portage::target_path(portage::EMERGE_LOG)).ok()?;
+        log.seek(SeekFrom::End(0)).ok()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            let mut reader = BufReader::new(log);
+            let mut line = String::new();
+            let mut building = None;
+            loop {
+                match reader.read_line(&mut line) {
+                    Ok(0) | Err(_) => {
+                        // Read whatever emerge wrote before it exited, then stop
+                        if stopped.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        thread::sleep(Duration::from_millis(500));
+                        continue;
+                    }
+                    Ok(_) if !line.ends_with('\n') => continue, // Part of a line so far
+                    Ok(_) => {}
+                }
+                if let Some(EmergeLogEntry {
+                    completed,
+                    number,
+                    total,
+                    package,
+                    ..
+                }) = portage::parse_emerge_log_line(&line)
+                {
+                    if completed {
+                        building = None;
+                        emit(Event::PackageFinished {
+                            package,
+                            number,
+                            total,
+                        });
+                    } else {
+                        building = Some(package.clone());
+                        emit(Event::PackageStarted {
+                            package,
+                            number,
+                            total,
+                        });
+                    }
+                }
+                line.clear();
+            }
+            building
+        });
+        Some(LogWatcher { stop, handle })
+    }
+
+    // Stop following emerge.log once the update has exited. If it failed, report the package
+    // which was being built at the time
+    //
+    pub fn finish(self, succeeded: bool) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Ok(Some(package)) = self.handle.join() {
+            if !succeeded {
+                emit(Event::PackageFailed { package });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_events_as_json() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        let event = Event::PendingUpdates {
+            packages: vec![
+                "sys-libs/zlib-1.3.1".to_string(),
+                "x11-libs/gtk+-3.24.41".to_string(),
+            ],
+            security: vec!["sys-libs/zlib-1.3.1".to_string()],
+        };
+        assert_eq!(
+            event.to_json(),
+            "{\"event\":\"pending_updates\",\"count\":2,\"security_count\":1,\"packages\":[\"sys-libs/zlib-1.3.1\",\"x11-libs/gtk+-3.24.41\"],\"security\":[\"sys-libs/zlib-1.3.1\"]}"
+        );
+        assert_eq!(field(&event.to_json(), "count").as_deref(), Some("2"));
+        assert_eq!(
+            field(&event.to_json(), "event").as_deref(),
+            Some("pending_updates")
+        );
+        assert_eq!(field(&event.to_json(), "missing"), None);
+    }
+}
@@ -0,0 +1,81 @@
+// Exit codes
+// gentup exits with a distinct status for each outcome, so that wrapper scripts, cron jobs and
+// monitoring can react without parsing its output. The codes are listed in the --help output
+
+use crate::{
+    events::{self, Event},
+    report, stalerun, tempfile,
+};
+use std::process;
+
+// Define the exit statuses of the program
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitCode {
+    NothingToDo = 0,     // There were no updates to apply, or the command completed
+    UpdatesApplied = 1,  // Packages were updated
+    SyncFailed = 2,      // The package tree could not be synced
+    BuildFailed = 3,     // A package failed to build
+    ConfigError = 4,     // The configuration file or command line is invalid
+    RebootRequired = 5,  // Packages were updated, and a reboot is needed to use them
+    PreflightFailed = 6, // Not enough disk space, an unhealthy filesystem, or a busy system
+    Aborted = 7,         // The user quit at a prompt, or aborted the update
+    Failed = 8,          // Any other failure
+    UpdatesPending = 9,  // --check or --estimate found updates pending
+    OutOfTime = 10,      // A phase ran past its budget, and the update stopped to carry on later
+}
+
+impl ExitCode {
+    // Every exit code, in numeric order
+    pub const ALL: [ExitCode; 11] = [
+        ExitCode::NothingToDo,
+        ExitCode::UpdatesApplied,
+        ExitCode::SyncFailed,
+        ExitCode::BuildFailed,
+        ExitCode::ConfigError,
+        ExitCode::RebootRequired,
+        ExitCode::PreflightFailed,
+        ExitCode::Aborted,
+        ExitCode::Failed,
+        ExitCode::UpdatesPending,
+        ExitCode::OutOfTime,
+    ];
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ExitCode::NothingToDo => "Nothing to update, or the command completed",
+            ExitCode::UpdatesApplied => "Updates were applied",
+            ExitCode::SyncFailed => "The package tree sync failed",
+            ExitCode::BuildFailed => "A package failed to build",
+            ExitCode::ConfigError => "Configuration or command line error",
+            ExitCode::RebootRequired => "Updates were applied and a reboot is required",
+            ExitCode::PreflightFailed => "A preflight check failed (disk space, filesystems, load)",
+            ExitCode::Aborted => "Quit or aborted by the user",
+            ExitCode::Failed => "Any other failure",
+            ExitCode::UpdatesPending => "Updates are pending (--check or --estimate)",
+            ExitCode::OutOfTime => "A phase ran past its time budget; carry on with --continue",
+        }
+    }
+
+    // Exit the program with this status, reporting it first in JSON mode and the run report
+    pub fn exit(self) -> ! {
+        events::emit(Event::Exit {
+            code: self as i32,
+            description: self.description(),
+        });
+        report::finish();
+        stalerun::release();
+        tempfile::remove_all();
+        process::exit(self as i32)
+    }
+}
+
+// The table of exit codes appended to the --help output
+//
+pub fn help() -> String {
+    let mut retval = "\nExit status:\n".to_string();
+    for code in ExitCode::ALL {
+        retval = retval + &format!("{:>3}  {}\n", code as i32, code.description());
+    }
+    retval
+}
@@ -0,0 +1,149 @@
+// Portage FEATURES audit
+// As part of "gentup --doctor", the FEATURES portage runs with are compared with those which make
+// unattended updates safer or quicker, each with why it is recommended:
+//
+//   - parallel-fetch, so sources download while earlier packages build
+//   - no candy, whose spinner fills build logs and captured output with escape codes
+//   - unmerge-orphans, so files a package no longer installs are removed when it is updated
+//   - collision-protect, so a package cannot overwrite files another package, or nobody, owns
+//
+// At the terminal, gentup offers to add those missing to make.conf, as one FEATURES line which
+// extends the existing setting
+
+use crate::{
+    linux,
+    makeconf::{self, Finding},
+    portage, prompt, Prompt,
+};
+use crossterm::style::Color;
+
+// The recommended FEATURES, whether each should be on, and why
+static RECOMMENDED: [(&str, bool, &str); 4] = [
+    (
+        "parallel-fetch",
+        true,
+        "sources are downloaded in the background while earlier packages build",
+    ),
+    (
+        "candy",
+        false,
+        "its spinner fills build logs and gentup's captured output with escape codes",
+    ),
+    (
+        "unmerge-orphans",
+        true,
+        "files a package no longer installs are removed when it is updated",
+    ),
+    (
+        "collision-protect",
+        true,
+        "a package cannot overwrite files owned by another package, or by no package",
+    ),
+];
+
+// The recommended FEATURES which are not as recommended, given the FEATURES portage runs with
+//
+pub fn audit(features: &str) -> Vec<Finding> {
+    let enabled: Vec<&str> = features.split_whitespace().collect();
+    RECOMMENDED
+        .iter()
+        .filter(|(feature, wanted, _)| enabled.contains(feature) != *wanted)
+        .map(|(feature, wanted, reason)| {
+            if *wanted {
+                Finding {
+                    problem: format!("FEATURES does not include {}", feature),
+                    suggestion: format!("Add {} to FEATURES, so {}", feature, reason),
+                }
+            } else {
+                Finding {
+                    problem: format!("FEATURES includes {}", feature),
+                    suggestion: format!("Add -{} to FEATURES, as {}", feature, reason),
+                }
+            }
+        })
+        .collect()
+}
+
+// The make.conf line which brings FEATURES in line with the recommendations
+//
+pub fn recommended_line(features: &str) -> Option<String> {
+    let enabled: Vec<&str> = features.split_whitespace().collect();
+    let changes: Vec<String> = RECOMMENDED
+        .iter()
+        .filter(|(feature, wanted, _)| enabled.contains(feature) != *wanted)
+        .map(|(feature, wanted, _)| {
+            if *wanted {
+                feature.to_string()
+            } else {
+                ["-", feature].concat()
+            }
+        })
+        .collect();
+    if changes.is_empty() {
+        return None;
+    }
+    Some(format!("FEATURES=\"${{FEATURES}} {}\"", changes.join(" ")))
+}
+
+// Audit this machine's FEATURES
+//
+pub fn findings() -> Vec<Finding> {
+    audit(&portage::portage_variable("FEATURES"))
+}
+
+// Offer to add the recommended FEATURES to make.conf, when any are missing
+//
+pub fn offer_changes() {
+    let Some(line) = recommended_line(&portage::portage_variable("FEATURES")) else {
+        return;
+    };
+    if (!linux::is_a_tty() && !prompt::answered("features"))
+        || Prompt::AllowSkip
+            .askuser("features", &["Add ", &line, " to make.conf"].concat())
+            .is_none()
+    {
+        return;
+    }
+    match makeconf::append(&[&line, "\n"].concat()) {
+        Ok(_) => println!(
+            "{} Added {} to make.conf",
+            prompt::revchevrons(Color::Green),
+            line
+        ),
+        Err(error) => eprintln!(
+            "{} Could not write make.conf - {}",
+            prompt::revchevrons(Color::Red),
+            error
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recommends_features() {
+        let features = "assume-digests binpkg-logs candy config-protect-if-modified distlocks \
+            parallel-fetch protect-owned sandbox sfperms strict unmerge-orphans";
+        let problems: Vec<String> = audit(features)
+            .into_iter()
+            .map(|finding| finding.problem)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "FEATURES includes candy",
+                "FEATURES does not include collision-protect"
+            ]
+        );
+        assert_eq!(
+            recommended_line(features).as_deref(),
+            Some("FEATURES=\"${FEATURES} -candy collision-protect\"")
+        );
+        assert_eq!(
+            recommended_line("parallel-fetch unmerge-orphans collision-protect"),
+            None
+        );
+    }
+}
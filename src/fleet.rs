@@ -0,0 +1,223 @@
+// Fleet mode
+// gentup --fleet updates several Gentoo hosts at once. Each host listed in the hosts file is
+// reached over SSH, where gentup is run in --json mode, and the events it reports are collected
+// into a per-host result. Once every host has finished, a consolidated report is displayed and
+// emailed. The hosts file holds one SSH destination per line, e.g root@web1.example.com, and
+// lines starting with # are ignored. SSH must be able to log in as root without a password
+
+use crate::{events, exitcode::ExitCode, linux::OsCall, prompt, Config};
+use crossterm::style::Color;
+use std::{fs, thread};
+
+pub static HOSTS_FILE_PATH: &str = "/etc/default/gentup-hosts";
+
+// Define a struct to hold the outcome of the update on one host
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct HostResult {
+    pub host: String,
+    pub exit_code: Option<i32>, // None if gentup did not report an exit status
+    pub description: String,
+    pub updates: usize,
+    pub security_updates: usize, // Those of the updates which fix a security advisory
+    pub failed: Vec<String>,     // Packages which failed to build
+    pub orphans: Option<i32>,
+}
+
+impl HostResult {
+    fn summary(&self) -> String {
+        let mut summary = match self.exit_code {
+            Some(_) => self.description.clone(),
+            None => "No result - could not connect, or gentup is not installed".to_string(),
+        };
+        if self.updates > 0 {
+            summary = summary + &format!(", {} package(s) to update", self.updates);
+        }
+        if self.security_updates > 0 {
+            summary = summary + &format!(" (SECURITY: {})", self.security_updates);
+        }
+        if !self.failed.is_empty() {
+            summary = summary + ", failed: " + &self.failed.join(" ");
+        }
+        if let Some(orphans) = self.orphans.filter(|orphans| *orphans > 0) {
+            summary = summary + &format!(", {} orphan(s)", orphans);
+        }
+        summary
+    }
+}
+
+// Build a host's result from the JSON events gentup wrote on it
+//
+pub fn parse_events(host: &str, output: &str) -> HostResult {
+    let mut result = HostResult {
+        host: host.to_string(),
+        ..HostResult::default()
+    };
+    for line in output.lines() {
+        match events::field(line, "event").as_deref() {
+            Some("pending_updates") => {
+                result.updates = events::field(line, "count")
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0);
+                result.security_updates = events::field(line, "security_count")
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0);
+            }
+            Some("package_failed") => {
+                if let Some(package) = events::field(line, "package") {
+                    result.failed.push(package);
+                }
+            }
+            Some("orphans") => {
+                result.orphans = events::field(line, "count").and_then(|count| count.parse().ok());
+            }
+            Some("exit") => {
+                result.exit_code = events::field(line, "code").and_then(|code| code.parse().ok());
+                result.description = events::field(line, "description").unwrap_or_default();
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+// Read the hosts file
+//
+fn hosts() -> Vec<String> {
+    match fs::read_to_string(HOSTS_FILE_PATH) {
+        Ok(contents) => contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect(),
+        Err(error) => {
+            eprintln!(
+                "{} Could not read the fleet hosts file {} - {}",
+                prompt::revchevrons(Color::Red),
+                HOSTS_FILE_PATH,
+                error
+            );
+            ExitCode::ConfigError.exit();
+        }
+    }
+}
+
+// Update one host, returning its result
+//
+fn update_host(host: &str) -> HostResult {
+    let command_line = [
+        "ssh -n -o BatchMode=yes -o ConnectTimeout=30 ",
+        host,
+        " gentup --json",
+    ]
+    .concat();
+    match OsCall::Quiet.execute(&command_line, "") {
+        Ok((output, _)) => parse_events(host, &output),
+        Err(_) => HostResult {
+            host: host.to_string(),
+            ..HostResult::default()
+        },
+    }
+}
+
+// Update every host in the fleet in parallel, then report. Returns the most serious exit status
+// of any host
+//
+pub fn run(running_config: &Config) -> ExitCode {
+    let hosts = hosts();
+    if hosts.is_empty() {
+        eprintln!(
+            "{} There are no hosts listed in {}",
+            prompt::revchevrons(Color::Red),
+            HOSTS_FILE_PATH
+        );
+        return ExitCode::ConfigError;
+    }
+    println!(
+        "{} Updating {} host(s) from {}",
+        prompt::chevrons(Color::Green),
+        hosts.len(),
+        HOSTS_FILE_PATH
+    );
+    let handles: Vec<_> = hosts
+        .into_iter()
+        .map(|host| {
+            thread::spawn(move || {
+                let result = update_host(&host);
+                println!(
+                    "{} {}: {}",
+                    prompt::revchevrons(Color::Green),
+                    result.host,
+                    result.summary()
+                );
+                result
+            })
+        })
+        .collect();
+    let results: Vec<HostResult> = handles
+        .into_iter()
+        .filter_map(|handle| handle.join().ok())
+        .collect();
+
+    let mut report = String::from("Gentoo Linux Updater fleet report\n\n");
+    for result in &results {
+        report = report + &format!("{}: {}\n", result.host, result.summary());
+    }
+    println!("\n{}", report);
+    #[cfg(feature = "mail")]
+    if let Err(error) =
+        crate::mail::send_email(running_config, String::from("gentup-fleet-report"), report)
+    {
+        eprintln!(
+            "{} The fleet report could not be emailed: {}",
+            prompt::revchevrons(Color::Yellow),
+            error
+        );
+    }
+    #[cfg(not(feature = "mail"))]
+    let _ = (running_config, report);
+
+    // A failure on any host outranks a reboot, which outranks updates having been applied
+    let codes: Vec<i32> = results
+        .iter()
+        .map(|result| result.exit_code.unwrap_or(ExitCode::Failed as i32))
+        .collect();
+    if codes
+        .iter()
+        .any(|code| ![0, 1, ExitCode::RebootRequired as i32].contains(code))
+    {
+        ExitCode::Failed
+    } else if codes.contains(&(ExitCode::RebootRequired as i32)) {
+        ExitCode::RebootRequired
+    } else if codes.contains(&(ExitCode::UpdatesApplied as i32)) {
+        ExitCode::UpdatesApplied
+    } else {
+        ExitCode::NothingToDo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_host_events() {
+        let output = "\
+{\"event\":\"phase_start\",\"phase\":\"pretend\"}
+{\"event\":\"pending_updates\",\"count\":2,\"security_count\":1,\"packages\":[\"sys-libs/zlib-1.3.1\",\"dev-lang/rust-1.77.1\"],\"security\":[\"sys-libs/zlib-1.3.1\"]}
+{\"event\":\"package_started\",\"package\":\"dev-lang/rust-1.77.1\",\"number\":2,\"total\":2}
+{\"event\":\"package_failed\",\"package\":\"dev-lang/rust-1.77.1\"}
+{\"event\":\"exit\",\"code\":3,\"description\":\"A package failed to build\"}
+";
+        let result = parse_events("root@web1", output);
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.updates, 2);
+        assert_eq!(result.security_updates, 1);
+        assert_eq!(result.failed, vec!["dev-lang/rust-1.77.1".to_string()]);
+        assert_eq!(
+            result.summary(),
+            "A package failed to build, 2 package(s) to update (SECURITY: 1), failed: dev-lang/rust-1.77.1"
+        );
+    }
+}
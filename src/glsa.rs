@@ -0,0 +1,432 @@
+// Gentoo Linux Security Advisories
+// Reads the GLSAs shipped in the Gentoo repository's metadata/glsa directory, so that pending
+// updates which fix a vulnerability in the installed version can be tagged as security updates,
+// and installed packages which are vulnerable with no fix pending can be pointed out. Each
+// advisory is an XML file listing the affected packages, each with the ranges of versions which
+// are vulnerable and those which are not, e.g
+//
+//   <glsa id="202401-01">
+//     <title>OpenSSL: Multiple Vulnerabilities</title>
+//     <affected>
+//       <package name="dev-libs/openssl" auto="yes" arch="*">
+//         <unaffected range="ge">3.0.13</unaffected>
+//         <vulnerable range="lt">3.0.13</vulnerable>
+//       </package>
+//     </affected>
+
+use crate::{
+    atom::Version,
+    config::state_dir,
+    exitcode::ExitCode,
+    linux::OsCall,
+    portage::{self, Change},
+    prefix, prompt, Config,
+};
+use crossterm::style::Color;
+use std::{cmp::Ordering, fs};
+
+pub static GLSA_PATH: &str = "/var/db/repos/gentoo/metadata/glsa";
+static GLSA_MIRROR: &str = "rsync://rsync.gentoo.org/gentoo-portage/metadata/glsa/";
+
+// Define a struct to hold a version range, e.g lt 3.0.13. The operators starting with r only
+// compare the revision, matching versions which differ from the given one by revision alone
+//
+#[derive(Debug)]
+pub struct Range {
+    pub operator: String, // lt, le, gt, ge, eq, rlt, rle, rgt or rge
+    pub version: Version,
+}
+
+impl Range {
+    pub fn matches(&self, version: &Version) -> bool {
+        let operator = match self.operator.strip_prefix('r') {
+            Some(operator) => {
+                if version.without_revision() != self.version.without_revision() {
+                    return false;
+                }
+                operator
+            }
+            None => self.operator.as_str(),
+        };
+        let ordering = version.cmp(&self.version);
+        match operator {
+            "lt" => ordering == Ordering::Less,
+            "le" => ordering != Ordering::Greater,
+            "gt" => ordering == Ordering::Greater,
+            "ge" => ordering != Ordering::Less,
+            "eq" => ordering == Ordering::Equal,
+            _ => false,
+        }
+    }
+}
+
+// Define a struct to hold one package named by an advisory
+//
+#[derive(Debug)]
+pub struct Affected {
+    pub package: String, // The category and name
+    pub vulnerable: Vec<Range>,
+    pub unaffected: Vec<Range>,
+}
+
+// Define a struct to hold an advisory
+//
+#[derive(Debug)]
+pub struct Advisory {
+    pub id: String, // e.g 202401-01
+    pub title: String,
+    pub affected: Vec<Affected>,
+}
+
+// The value of an attribute in the opening tag of an element, e.g name from <package name="...">
+//
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&[" ", name, "=\""].concat())? + name.len() + 3;
+    let length = tag[start..].find('"')?;
+    Some(tag[start..start + length].to_string())
+}
+
+// Each element with the given name in the text, as its opening tag and its contents
+//
+fn elements<'a>(text: &'a str, name: &str) -> Vec<(&'a str, &'a str)> {
+    let open = ["<", name].concat();
+    let close = ["</", name, ">"].concat();
+    let mut found = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start..];
+        // Skip elements whose names merely start with this one
+        if !rest[open.len()..].starts_with([' ', '>']) {
+            rest = &rest[open.len()..];
+            continue;
+        }
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push((&rest[..tag_end], &rest[tag_end + 1..end.max(tag_end + 1)]));
+        rest = &rest[end.max(tag_end)..];
+    }
+    found
+}
+
+fn unescape(text: &str) -> String {
+    text.trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+impl Advisory {
+    // Parse the XML of an advisory. Ranges whose versions do not parse, such as those with
+    // wildcards, are left out
+    //
+    pub fn parse(contents: &str) -> Option<Advisory> {
+        let (glsa_tag, glsa) = elements(contents, "glsa").into_iter().next()?;
+        let title = elements(glsa, "title")
+            .first()
+            .map(|(_, title)| unescape(title))
+            .unwrap_or_default();
+        let ranges = |text: &str, name: &str| {
+            elements(text, name)
+                .into_iter()
+                .filter_map(|(tag, version)| {
+                    Some(Range {
+                        operator: attribute(tag, "range")?,
+                        version: unescape(version).parse().ok()?,
+                    })
+                })
+                .collect()
+        };
+        let affected = elements(glsa, "affected")
+            .into_iter()
+            .flat_map(|(_, affected)| elements(affected, "package"))
+            .filter_map(|(tag, package)| {
+                Some(Affected {
+                    package: attribute(tag, "name")?,
+                    vulnerable: ranges(package, "vulnerable"),
+                    unaffected: ranges(package, "unaffected"),
+                })
+            })
+            .collect();
+        Some(Advisory {
+            id: attribute(glsa_tag, "id")?,
+            title,
+            affected,
+        })
+    }
+
+    // A version is affected if it is in a vulnerable range, and not in an unaffected one
+    //
+    pub fn affects(&self, package: &str, version: &Version) -> bool {
+        self.affected.iter().any(|affected| {
+            affected.package == package
+                && affected
+                    .vulnerable
+                    .iter()
+                    .any(|range| range.matches(version))
+                && !affected
+                    .unaffected
+                    .iter()
+                    .any(|range| range.matches(version))
+        })
+    }
+}
+
+// Read every advisory in the repository
+//
+pub fn load() -> Vec<Advisory> {
+    load_from(&prefix::path(GLSA_PATH))
+}
+
+// Read every advisory in a directory
+//
+pub fn load_from(directory: &str) -> Vec<Advisory> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.starts_with("glsa-") && name.ends_with(".xml")
+        })
+        .filter_map(|entry| Advisory::parse(&fs::read_to_string(entry.path()).ok()?))
+        .collect()
+}
+
+// The ids of the advisories affecting a version of a package
+//
+pub fn advisories_for(advisories: &[Advisory], package: &str, version: &Version) -> Vec<String> {
+    advisories
+        .iter()
+        .filter(|advisory| advisory.affects(package, version))
+        .map(|advisory| advisory.id.clone())
+        .collect()
+}
+
+// Tag each pending change with the advisories which affect the installed version but not the new
+// one, being those the update fixes
+//
+pub fn tag(advisories: &[Advisory], changes: &mut [Change]) {
+    for change in changes {
+        let (Some(installed), Some(new)) = (&change.installed, &change.package.version) else {
+            continue;
+        };
+        let package = change.package.cpn();
+        let fixed: Vec<String> = advisories_for(advisories, &package, installed)
+            .into_iter()
+            .filter(|id| {
+                advisories
+                    .iter()
+                    .filter(|advisory| advisory.id == *id)
+                    .all(|advisory| !advisory.affects(&package, new))
+            })
+            .collect();
+        change.security = fixed;
+    }
+}
+
+// Warn about installed packages which are vulnerable, and which none of the pending changes fix
+//
+pub fn report_unfixed(advisories: &[Advisory], changes: &[Change]) {
+    for package in portage::installed_packages() {
+        let Some(version) = &package.version else {
+            continue;
+        };
+        let cpn = package.cpn();
+        let ids = advisories_for(advisories, &cpn, version);
+        let pending_fixes: Vec<&String> = changes
+            .iter()
+            .filter(|change| change.package.cpn() == cpn)
+            .flat_map(|change| change.security.iter())
+            .collect();
+        let unfixed: Vec<String> = ids
+            .into_iter()
+            .filter(|id| !pending_fixes.contains(&id))
+            .map(|id| ["GLSA ", &id].concat())
+            .collect();
+        if !unfixed.is_empty() {
+            eprintln!(
+                "{} {} is vulnerable ({}) and no update fixing it is pending",
+                prompt::revchevrons(Color::Red),
+                package.cpv(),
+                unfixed.join(", ")
+            );
+        }
+    }
+}
+
+// The advisories affecting each installed package, as lines such as
+// dev-libs/openssl-3.0.12 GLSA 202401-01: OpenSSL: Multiple Vulnerabilities
+//
+pub fn affected_installed(advisories: &[Advisory]) -> Vec<String> {
+    let mut affected = Vec::new();
+    for package in portage::installed_packages() {
+        let Some(version) = &package.version else {
+            continue;
+        };
+        for advisory in advisories {
+            if advisory.affects(&package.cpn(), version) {
+                affected.push(format!(
+                    "{} GLSA {}: {}",
+                    package.cpv(),
+                    advisory.id,
+                    advisory.title
+                ));
+            }
+        }
+    }
+    affected.sort();
+    affected
+}
+
+// The affected packages which have not been notified already, given the list notified last time
+//
+pub fn newly_affected(affected: &[String], notified: &str) -> Vec<String> {
+    affected
+        .iter()
+        .filter(|line| !notified.lines().any(|each| each == line.as_str()))
+        .cloned()
+        .collect()
+}
+
+// Security watch mode, for running from a frequent timer between updates. Fetches only the
+// advisories, into gentup's state directory so that the signed repository is left untouched, and
+// notifies about installed packages which have become affected since the last watch. Each is
+// notified once, and again only if it stops being affected and later becomes affected again
+//
+#[cfg_attr(not(feature = "mail"), allow(unused_variables))]
+pub fn watch(running_config: &Config) -> ExitCode {
+    let directory = [state_dir(), "/glsa"].concat();
+    let notified_path = [state_dir(), "/glsa-notified"].concat();
+    let synced = fs::create_dir_all(&directory).is_ok()
+        && matches!(
+            OsCall::Quiet.execute(
+                &[
+                    "rsync -rt --delete --include=glsa-*.xml --exclude=* ",
+                    GLSA_MIRROR,
+                    " ",
+                    &directory,
+                    "/",
+                ]
+                .concat(),
+                "",
+            ),
+            Ok((_, 0))
+        );
+    let advisories = if synced {
+        load_from(&directory)
+    } else {
+        eprintln!(
+            "{} Could not fetch the advisories from {} - using those from the last sync",
+            prompt::revchevrons(Color::Yellow),
+            GLSA_MIRROR
+        );
+        load()
+    };
+    if advisories.is_empty() {
+        eprintln!(
+            "{} There are no security advisories to check against",
+            prompt::revchevrons(Color::Red)
+        );
+        return ExitCode::Failed;
+    }
+    let affected = affected_installed(&advisories);
+    let notified = fs::read_to_string(&notified_path).unwrap_or_default();
+    let new = newly_affected(&affected, &notified);
+    if new.is_empty() {
+        println!(
+            "{} Checked {} advisories: {} installed package advisory(s), none new",
+            prompt::revchevrons(Color::Green),
+            advisories.len(),
+            affected.len()
+        );
+    } else {
+        let text = new.join("\n");
+        println!(
+            "{} Installed packages newly affected by security advisories:\n\n{}\n",
+            prompt::revchevrons(Color::Red),
+            text
+        );
+        // Leave them to be notified again next time if the email could not be sent
+        #[cfg(feature = "mail")]
+        if let Err(error) = crate::mail::send_email(
+            running_config,
+            String::from("gentup-security"),
+            [&text, "\n\nUpdate with: gentup"].concat(),
+        ) {
+            eprintln!(
+                "{} The advisories could not be emailed: {}",
+                prompt::revchevrons(Color::Yellow),
+                error
+            );
+            return ExitCode::Failed;
+        }
+    }
+    let _ = fs::write(&notified_path, affected.join("\n"));
+    ExitCode::NothingToDo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ADVISORY: &str = "\
+<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!DOCTYPE glsa SYSTEM \"http://www.gentoo.org/dtd/glsa.dtd\">
+<glsa id=\"202401-01\">
+  <title>OpenSSL: Multiple Vulnerabilities</title>
+  <synopsis>Multiple vulnerabilities &amp; more</synopsis>
+  <affected>
+    <package name=\"dev-libs/openssl\" auto=\"yes\" arch=\"*\">
+      <unaffected range=\"ge\">3.0.13</unaffected>
+      <unaffected range=\"rge\">1.1.1w-r1</unaffected>
+      <vulnerable range=\"lt\">3.0.13</vulnerable>
+    </package>
+  </affected>
+</glsa>
+";
+
+    #[test]
+    fn tags_security_updates() {
+        let advisory = Advisory::parse(ADVISORY).unwrap();
+        assert_eq!(advisory.id, "202401-01");
+        assert_eq!(advisory.title, "OpenSSL: Multiple Vulnerabilities");
+        assert_eq!(advisory.affected.len(), 1);
+        let affects =
+            |version: &str| advisory.affects("dev-libs/openssl", &version.parse().unwrap());
+        assert!(affects("3.0.12"));
+        assert!(!affects("3.0.13"));
+        assert!(affects("1.1.1w"));
+        assert!(!affects("1.1.1w-r2"));
+        assert!(!advisory.affects("dev-libs/libressl", &"3.0.12".parse().unwrap()));
+
+        let mut changes = portage::parse_changes(
+            "\
+[ebuild     U  ] dev-libs/openssl-3.0.13:0/3::gentoo [3.0.12:0/3::gentoo] 15,470 KiB
+[ebuild     U  ] sys-libs/zlib-1.3.1:0/1::gentoo [1.3:0/1::gentoo] 0 KiB
+[ebuild  N     ] dev-libs/openssl-compat-1.1.1w::gentoo 0 KiB
+",
+        );
+        tag(&[advisory], &mut changes);
+        assert_eq!(changes[0].security, vec!["202401-01"]);
+        assert!(changes[1].security.is_empty());
+        assert!(changes[2].security.is_empty());
+        let affected = vec![
+            "dev-libs/openssl-3.0.12 GLSA 202401-01: OpenSSL".to_string(),
+            "sys-libs/zlib-1.3 GLSA 202402-01: zlib".to_string(),
+        ];
+        assert_eq!(
+            newly_affected(&affected, "dev-libs/openssl-3.0.12 GLSA 202401-01: OpenSSL"),
+            vec!["sys-libs/zlib-1.3 GLSA 202402-01: zlib"]
+        );
+        let table = portage::package_table(&changes, false, 80);
+        assert!(table[0].contains("SECURITY"));
+        assert!(!table[1].contains("SECURITY"));
+    }
+}
@@ -0,0 +1,165 @@
+// HTTP status endpoint
+// When http_status is set in the config file to an address such as 127.0.0.1:8080, a running
+// update serves its progress over HTTP, to be checked on from a browser without attaching to the
+// terminal it runs in. / is a page which refreshes itself, and /status.json returns the same JSON
+// as the status socket. The page has no authentication, so rather than listening on 0.0.0.0,
+// prefer a localhost address reached through an SSH tunnel
+
+use crate::{
+    portage, prompt,
+    stats::format_duration,
+    status::{self, Snapshot},
+};
+use crossterm::style::Color;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+// The number of lines of emerge.log shown on the page
+static LOG_LINES: usize = 20;
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The last lines of emerge.log, without their timestamps
+//
+fn recent_log_lines() -> Vec<String> {
+    let contents =
+        fs::read_to_string(portage::target_path(portage::EMERGE_LOG)).unwrap_or_default();
+    let lines: Vec<&str> = contents.lines().collect();
+    lines[lines.len().saturating_sub(LOG_LINES)..]
+        .iter()
+        .map(|line| match line.split_once(':') {
+            Some((_, message)) => message.trim().to_string(),
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+pub fn page(snapshot: &Snapshot, log_lines: &[String]) -> String {
+    let package = if snapshot.package.is_empty() {
+        "-".to_string()
+    } else {
+        html_escape(&snapshot.package)
+    };
+    let compile = if snapshot.compile.is_empty() {
+        "-".to_string()
+    } else {
+        html_escape(&snapshot.compile)
+    };
+    let eta = snapshot.eta.map(format_duration).unwrap_or("-".to_string());
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"10\">\
+        <title>gentup: {phase}</title></head>\n<body><h1>Gentoo Linux Updater</h1>\n<table>\n\
+        <tr><th align=\"left\">Phase</th><td>{phase}{paused}</td></tr>\n\
+        <tr><th align=\"left\">Building</th><td>{package}</td></tr>\n\
+        <tr><th align=\"left\">Compiling</th><td>{compile}</td></tr>\n\
+        <tr><th align=\"left\">Progress</th><td>{completed} of {total}</td></tr>\n\
+        <tr><th align=\"left\">Time remaining</th><td>{eta}</td></tr>\n</table>\n\
+        <h2>emerge.log</h2>\n<pre>{log}</pre>\n</body></html>\n",
+        phase = html_escape(&snapshot.phase),
+        paused = if snapshot.paused { " (paused)" } else { "" },
+        package = package,
+        compile = compile,
+        completed = snapshot.completed,
+        total = snapshot.total,
+        eta = eta,
+        log = html_escape(&log_lines.join("\n")),
+    )
+}
+
+fn respond(mut stream: TcpStream) {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    if reader.read_line(&mut request).is_err() {
+        return;
+    }
+    // Read past the headers, which are not needed
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|read| read > 2) {
+        header.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) = match (path, status::snapshot()) {
+        ("/", Some(snapshot)) => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            page(&snapshot, &recent_log_lines()),
+        ),
+        ("/status.json", Some(snapshot)) => ("200 OK", "application/json", snapshot.to_json()),
+        (_, None) => (
+            "503 Service Unavailable",
+            "text/plain",
+            "Status unavailable\n".to_string(),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+}
+
+// Start serving the status page in the background. The update carries on without it if the
+// address cannot be listened on
+//
+pub fn serve(address: &str) {
+    match TcpListener::bind(address) {
+        Ok(listener) => {
+            println!(
+                "{} Serving update status at http://{}/",
+                prompt::revchevrons(Color::Green),
+                address
+            );
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    respond(stream);
+                }
+            });
+        }
+        Err(error) => eprintln!(
+            "{} Could not serve the update status on {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            address,
+            error
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_status_page() {
+        let snapshot = Snapshot {
+            phase: "build".to_string(),
+            package: "x11-libs/gtk+-3.24.41".to_string(),
+            compile: "x11-libs/gtk+ compiling object 1234/5678 (21%)".to_string(),
+            completed: 3,
+            total: 10,
+            eta: Some(3725),
+            paused: false,
+        };
+        let page = page(
+            &snapshot,
+            &[">>> emerge (4 of 10) <x11-libs/gtk+-3.24.41> to /".to_string()],
+        );
+        assert!(page.contains("<td>x11-libs/gtk+-3.24.41</td>"));
+        assert!(page.contains("<td>x11-libs/gtk+ compiling object 1234/5678 (21%)</td>"));
+        assert!(page.contains("<td>3 of 10</td>"));
+        assert!(page.contains("<td>1h 02m 05s</td>"));
+        assert!(page.contains("&lt;x11-libs/gtk+-3.24.41&gt;"));
+    }
+}
@@ -0,0 +1,264 @@
+// Installed file integrity
+// After the build phase, checks the files of each package just merged against the record portage
+// keeps of them in the package database, as qcheck does, to catch installs left truncated by a
+// full filesystem or a crashed merge. The CONTENTS file of each package lists the directories,
+// files with their MD5 checksum and modification time, and symbolic links with their target, e.g
+//
+//   dir /usr/lib64
+//   obj /usr/lib64/libz.so.1.3.1 6f2d6a8b21c7cbc4ba18d5e6b3a8e07c 1712350000
+//   sym /usr/lib64/libz.so.1 -> libz.so.1.3.1 1712350000
+
+use crate::{actions, linux::OsCall, portage, prompt, stats::History};
+use crossterm::style::Color;
+use std::fs;
+
+// Define an enum to hold one entry of a CONTENTS file
+//
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    Dir { path: String },
+    Obj { path: String, md5: String },
+    Sym { path: String, target: String },
+}
+
+// Parse a CONTENTS file. Paths may contain spaces, so the fields after the path are taken from
+// the end of the line
+//
+pub fn parse_contents(contents: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        let Some((kind, rest)) = line.split_once(' ') else {
+            continue;
+        };
+        match kind {
+            "dir" => entries.push(Entry::Dir {
+                path: rest.to_string(),
+            }),
+            "obj" => {
+                let mut fields = rest.rsplitn(3, ' ');
+                if let (Some(_mtime), Some(md5), Some(path)) =
+                    (fields.next(), fields.next(), fields.next())
+                {
+                    entries.push(Entry::Obj {
+                        path: path.to_string(),
+                        md5: md5.to_string(),
+                    });
+                }
+            }
+            "sym" => {
+                let Some((rest, _mtime)) = rest.rsplit_once(' ') else {
+                    continue;
+                };
+                if let Some((path, target)) = rest.split_once(" -> ") {
+                    entries.push(Entry::Sym {
+                        path: path.to_string(),
+                        target: target.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    entries
+}
+
+// The MD5 digest of some data, as a lowercase hex string, following RFC 1321
+//
+pub fn md5_hex(data: &[u8]) -> String {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    state
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Define a struct to hold the discrepancies found in one package's files
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct Discrepancies {
+    pub missing: Vec<String>,
+    pub modified: Vec<String>, // Files whose checksum differs, or links pointing elsewhere
+}
+
+// Check the entries of a package against the files installed in the given root. Configuration
+// files are expected to be edited, so only their presence is checked
+//
+pub fn verify(entries: &[Entry], root: &str, config_protect: &[String]) -> Discrepancies {
+    let mut found = Discrepancies::default();
+    let protected = |path: &str| {
+        config_protect
+            .iter()
+            .any(|directory| path.starts_with(&[directory.trim_end_matches('/'), "/"].concat()))
+    };
+    for entry in entries {
+        match entry {
+            Entry::Dir { path } => {
+                if !fs::metadata([root, path].concat()).is_ok_and(|metadata| metadata.is_dir()) {
+                    found.missing.push(path.clone());
+                }
+            }
+            Entry::Obj { path, md5 } => match fs::read([root, path].concat()) {
+                Err(_) => found.missing.push(path.clone()),
+                Ok(data) => {
+                    if !protected(path) && md5_hex(&data) != *md5 {
+                        found.modified.push(path.clone());
+                    }
+                }
+            },
+            Entry::Sym { path, target } => match fs::read_link([root, path].concat()) {
+                Err(_) => found.missing.push(path.clone()),
+                Ok(link) => {
+                    if link.to_string_lossy() != *target {
+                        found.modified.push(path.clone());
+                    }
+                }
+            },
+        }
+    }
+    found
+}
+
+// Verify the files of each package merged since the given time, reporting any which are damaged
+// and adding them to the post-update checklist
+//
+pub fn verify_merged(since: u64) {
+    let merged: Vec<String> = History::load()
+        .merges
+        .into_iter()
+        .filter(|merge| merge.started >= since)
+        .map(|merge| merge.package)
+        .collect();
+    if merged.is_empty() {
+        return;
+    }
+    let config_protect: Vec<String> = OsCall::Quiet
+        .execute("portageq envvar CONFIG_PROTECT", "")
+        .map(|(output, _)| output.split_whitespace().map(String::from).collect())
+        .unwrap_or_default();
+    let root = portage::target_root().unwrap_or("");
+    let mut damaged = 0;
+    for package in &merged {
+        let Ok(contents) = fs::read_to_string(portage::target_path(
+            &["/var/db/pkg/", package, "/CONTENTS"].concat(),
+        )) else {
+            continue;
+        };
+        let found = verify(&parse_contents(&contents), root, &config_protect);
+        if found == Discrepancies::default() {
+            continue;
+        }
+        damaged += 1;
+        eprintln!(
+            "{} {} has {} missing and {} modified file(s):",
+            prompt::revchevrons(Color::Red),
+            package,
+            found.missing.len(),
+            found.modified.len()
+        );
+        for path in found.missing.iter().take(10) {
+            eprintln!("    missing   {}", path);
+        }
+        for path in found.modified.iter().take(10) {
+            eprintln!("    modified  {}", path);
+        }
+        actions::add(format!(
+            "Reinstall {} with: emerge --oneshot ={} ({} missing and {} modified file(s))",
+            package,
+            package,
+            found.missing.len(),
+            found.modified.len()
+        ));
+    }
+    if damaged == 0 {
+        println!(
+            "{} The files of the {} package(s) merged are intact",
+            prompt::revchevrons(Color::Green),
+            merged.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_installed_files() {
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            md5_hex(b"The quick brown fox jumps over the lazy dog"),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(md5_hex(&[b'a'; 64]), "014842d480b571495a4a0363793f7367");
+
+        let root = std::env::temp_dir().join(format!("gentup-root-{}", std::process::id()));
+        fs::create_dir_all(root.join("usr/lib64")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("usr/lib64/lib one.so"), "").unwrap();
+        fs::write(root.join("usr/lib64/libtwo.so"), "truncated").unwrap();
+        fs::write(root.join("etc/two.conf"), "edited").unwrap();
+        std::os::unix::fs::symlink("lib one.so", root.join("usr/lib64/libone.so")).unwrap();
+        let contents = "\
+dir /usr/lib64
+obj /usr/lib64/lib one.so d41d8cd98f00b204e9800998ecf8427e 1712350000
+obj /usr/lib64/libtwo.so d41d8cd98f00b204e9800998ecf8427e 1712350000
+obj /usr/lib64/libthree.so d41d8cd98f00b204e9800998ecf8427e 1712350000
+obj /etc/two.conf d41d8cd98f00b204e9800998ecf8427e 1712350000
+sym /usr/lib64/libone.so -> lib one.so 1712350000
+";
+        let entries = parse_contents(contents);
+        assert_eq!(
+            entries[1],
+            Entry::Obj {
+                path: "/usr/lib64/lib one.so".to_string(),
+                md5: "d41d8cd98f00b204e9800998ecf8427e".to_string()
+            }
+        );
+        let found = verify(&entries, &root.to_string_lossy(), &["/etc".to_string()]);
+        assert_eq!(found.missing, vec!["/usr/lib64/libthree.so"]);
+        assert_eq!(found.modified, vec!["/usr/lib64/libtwo.so"]);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
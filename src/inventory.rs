@@ -0,0 +1,224 @@
+// Package inventories
+// gentup --export writes a machine readable list of the packages installed on the system, or with
+// --pending those due an update, for compliance and auditing. The output is CSV, or JSON when
+// combined with --json, with the package, version, slot, repository, license and installed size.
+// --license lists only the packages under the given licenses, so that an update can be reviewed
+// for what it would introduce before it is approved. A license such as GPL-3 also matches GPL-3+,
+// and the licenses of a package count whichever USE flags or || groups they fall under
+//
+//   gentup --export > inventory.csv
+//   gentup --export --pending --json > pending.json
+//   gentup --export --pending --license GPL-3,AGPL-3
+
+use crate::{atom::Package, events::json_string, exitcode::ExitCode, linux::OsCall, portage};
+use std::fs;
+
+pub static VDB_PATH: &str = "/var/db/pkg";
+
+// Define a struct to hold one package in the inventory
+//
+pub struct Item {
+    pub package: Package,
+    pub license: String,
+    pub size: Option<u64>, // Installed size in bytes, unknown for packages not yet installed
+}
+
+// Read a metadata file of an installed package from the package database
+fn vdb_entry(directory: &str, name: &str) -> Option<String> {
+    fs::read_to_string([directory, "/", name].concat())
+        .ok()
+        .map(|contents| contents.trim().to_string())
+}
+
+// List the installed packages from the package database, where each is a directory such as
+// /var/db/pkg/sys-libs/zlib-1.3.1 holding files named after its metadata
+//
+pub fn installed() -> Vec<Item> {
+    let mut items = Vec::new();
+    let Ok(categories) = fs::read_dir(portage::target_path(VDB_PATH)) else {
+        return items;
+    };
+    for category in categories.flatten() {
+        let Ok(packages) = fs::read_dir(category.path()) else {
+            continue;
+        };
+        for package in packages.flatten() {
+            let directory = package.path().to_string_lossy().to_string();
+            let name = [
+                &category.file_name().to_string_lossy(),
+                "/",
+                &package.file_name().to_string_lossy(),
+            ]
+            .concat();
+            let Ok(mut parsed) = name.parse::<Package>() else {
+                continue;
+            };
+            if parsed.version.is_none() {
+                continue; // Not a package, e.g a leftover -MERGING- directory
+            }
+            parsed.slot = vdb_entry(&directory, "SLOT");
+            parsed.repo = vdb_entry(&directory, "repository");
+            items.push(Item {
+                package: parsed,
+                license: vdb_entry(&directory, "LICENSE").unwrap_or_default(),
+                size: vdb_entry(&directory, "SIZE").and_then(|size| size.parse().ok()),
+            });
+        }
+    }
+    items.sort_by_key(|item| item.package.cpv());
+    items
+}
+
+// List the packages pending an update, looking up the license of each new version
+//
+pub fn pending() -> Vec<Item> {
+    let output = match OsCall::Quiet.execute("emerge -puDv @world", "") {
+        Ok((output, 0)) => output,
+        _ => {
+            eprintln!("Could not calculate the pending updates");
+            ExitCode::Failed.exit();
+        }
+    };
+    let root = portage::portageq_root();
+    portage::parse_pending_updates(&output)
+        .into_iter()
+        .map(|package| {
+            let license = OsCall::Quiet
+                .execute(
+                    &[
+                        "portageq metadata ",
+                        &root,
+                        " ebuild ",
+                        &package.cpv(),
+                        " LICENSE",
+                    ]
+                    .concat(),
+                    "",
+                )
+                .map(|(output, _)| output.trim().to_string())
+                .unwrap_or_default();
+            Item {
+                package,
+                license,
+                size: None,
+            }
+        })
+        .collect()
+}
+
+// Whether a package's LICENSE, e.g "GPL-2+ ssl? ( openssl )", includes any of the wanted licenses
+//
+pub fn license_matches(license: &str, wanted: &[&str]) -> bool {
+    license
+        .split(|character: char| character.is_whitespace() || "()".contains(character))
+        .filter(|name| !name.is_empty() && *name != "||" && !name.ends_with('?'))
+        .any(|name| {
+            wanted
+                .iter()
+                .any(|wanted| name == *wanted || name.strip_suffix('+') == Some(*wanted))
+        })
+}
+
+// Quote a field for CSV output, if it needs it
+//
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        ["\"", &text.replace('"', "\"\""), "\""].concat()
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn to_csv(items: &[Item]) -> String {
+    let mut csv = String::from("package,version,slot,repository,license,size\n");
+    for item in items {
+        let fields = [
+            item.package.cpn(),
+            item.package
+                .version
+                .as_ref()
+                .map(|version| version.to_string())
+                .unwrap_or_default(),
+            item.package.slot.clone().unwrap_or_default(),
+            item.package.repo.clone().unwrap_or_default(),
+            item.license.clone(),
+            item.size.map(|size| size.to_string()).unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv = csv + &fields.join(",") + "\n";
+    }
+    csv
+}
+
+pub fn to_json(items: &[Item]) -> String {
+    let optional = |value: &Option<String>| match value {
+        Some(value) => json_string(value),
+        None => "null".to_string(),
+    };
+    let objects: Vec<String> = items
+        .iter()
+        .map(|item| {
+            format!(
+                "{{\"package\":{},\"version\":{},\"slot\":{},\"repository\":{},\"license\":{},\"size\":{}}}",
+                json_string(&item.package.cpn()),
+                optional(&item.package.version.as_ref().map(|version| version.to_string())),
+                optional(&item.package.slot),
+                optional(&item.package.repo),
+                json_string(&item.license),
+                item.size
+                    .map(|size| size.to_string())
+                    .unwrap_or("null".to_string())
+            )
+        })
+        .collect();
+    ["[", &objects.join(",\n "), "]\n"].concat()
+}
+
+// Write the inventory to stdout, for gentup --export, of the packages under the given licenses, a
+// comma separated list, if any
+//
+pub fn export(pending_only: bool, json: bool, licenses: Option<&str>) {
+    let mut items = if pending_only { pending() } else { installed() };
+    if let Some(licenses) = licenses {
+        let wanted: Vec<&str> = licenses
+            .split(',')
+            .map(str::trim)
+            .filter(|license| !license.is_empty())
+            .collect();
+        items.retain(|item| license_matches(&item.license, &wanted));
+    }
+    if json {
+        print!("{}", to_json(&items));
+    } else {
+        print!("{}", to_csv(&items));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_inventory() {
+        let items = vec![Item {
+            package: "sys-libs/zlib-1.3.1:0/1::gentoo".parse().unwrap(),
+            license: "ZLIB || ( GPL-2 \"quoted\" )".to_string(),
+            size: Some(389120),
+        }];
+        assert_eq!(
+            to_csv(&items),
+            "package,version,slot,repository,license,size\n\
+            sys-libs/zlib,1.3.1,0/1,gentoo,\"ZLIB || ( GPL-2 \"\"quoted\"\" )\",389120\n"
+        );
+        assert_eq!(
+            to_json(&items),
+            "[{\"package\":\"sys-libs/zlib\",\"version\":\"1.3.1\",\"slot\":\"0/1\",\"repository\":\"gentoo\",\
+            \"license\":\"ZLIB || ( GPL-2 \\\"quoted\\\" )\",\"size\":389120}]\n"
+        );
+        let license = "GPL-3+ || ( LGPL-2.1 MIT ) ssl? ( openssl )";
+        assert!(license_matches(license, &["GPL-3"]));
+        assert!(license_matches(license, &["BSD", "MIT"]));
+        assert!(license_matches(license, &["openssl"]));
+        assert!(!license_matches(license, &["GPL-2", "ssl"]));
+    }
+}
@@ -0,0 +1,357 @@
+// Kernel config drift
+// Each kernel release brings new configuration options, and make olddefconfig quietly gives each
+// one its default, so options are switched on or off in a kernel upgrade without anyone having
+// looked at them. When an update installs new kernel sources, still unconfigured, the running
+// kernel's configuration is compared with them, and the options it does not mention are listed
+// with the default each would take, e.g
+//
+//   CONFIG_ZRAM_TRACK_ENTRY_ACTIME=n
+//   CONFIG_RUST_FW_LOADER_ABSTRACTIONS=y
+//
+// The user can accept the defaults, review each option with make oldconfig, or leave the new
+// sources unconfigured for now. New kernel sources are merged ahead of the rest of the update, so
+// this happens as soon as they are installed rather than after the whole build. The value each
+// new option ends up with is kept in /var/lib/gentup/kernel-answers, and given to the same option
+// in later kernel upgrades, so only the options never seen before are asked about. Without a
+// terminal, the options are listed, and reviewing them is added to the actions checklist, unless
+// "answer: kernel-config a" accepts the defaults unattended
+
+use crate::{
+    actions,
+    atom::Package,
+    config::state_dir,
+    dryrun,
+    linux::{self, OsCall},
+    portage, prompt, Prompt,
+};
+use crossterm::style::Color;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+static SOURCES_DIR: &str = "/usr/src";
+
+// Define a struct to hold a configuration option, and its value as written to a .config, "n"
+// for an option which is not set
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct KernelOption {
+    pub name: String,
+    pub value: String,
+}
+
+impl KernelOption {
+    // The option as a line of a .config
+    //
+    pub fn config_line(&self) -> String {
+        if self.value == "n" {
+            format!("# {} is not set", self.name)
+        } else {
+            format!("{}={}", self.name, self.value)
+        }
+    }
+}
+
+// The options introduced in new sources, from the output of make listnewconfig, which lists each
+// as CONFIG_FOO=y, or just CONFIG_FOO in older kernels
+//
+pub fn parse_new_options(output: &str) -> Vec<KernelOption> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("CONFIG_"))
+        .map(|line| {
+            let (name, value) = line.split_once('=').unwrap_or((line, ""));
+            KernelOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            }
+        })
+        .collect()
+}
+
+// The options set in a .config, or in the saved answers, including those not set
+//
+pub fn parse_config(contents: &str) -> Vec<KernelOption> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            if let Some(name) = line
+                .strip_prefix("# ")
+                .and_then(|line| line.strip_suffix(" is not set"))
+            {
+                return Some((name, "n"));
+            }
+            line.split_once('=')
+        })
+        .filter(|(name, _)| name.starts_with("CONFIG_"))
+        .map(|(name, value)| KernelOption {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+        .collect()
+}
+
+// Where the value given to each new option is kept
+//
+fn answers_path() -> String {
+    [state_dir(), "/kernel-answers"].concat()
+}
+
+// The saved answers which settle any of the new options
+//
+pub fn saved_answers<'a>(
+    answers: &'a [KernelOption],
+    new_options: &[KernelOption],
+) -> Vec<&'a KernelOption> {
+    answers
+        .iter()
+        .filter(|answer| new_options.iter().any(|option| option.name == answer.name))
+        .collect()
+}
+
+// Whether a package installs kernel sources to be configured and built by hand, such as
+// sys-kernel/gentoo-sources
+//
+pub fn is_kernel_sources(package: &Package) -> bool {
+    package.category == "sys-kernel" && package.name.ends_with("-sources")
+}
+
+// The newest kernel sources in /usr/src which have not been configured
+//
+fn unconfigured_sources() -> Option<PathBuf> {
+    fs::read_dir(SOURCES_DIR)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("linux-"))
+        .map(|entry| entry.path())
+        .filter(|path| path.join("Kconfig").exists() && !path.join(".config").exists())
+        .filter_map(|path| Some((path.metadata().ok()?.modified().ok()?, path)))
+        .max()
+        .map(|(_, path)| path)
+}
+
+// The configuration of the running kernel, from its sources, the kernel itself, or /boot
+//
+fn running_config() -> Option<String> {
+    let current = [SOURCES_DIR, "/linux/.config"].concat();
+    if let Ok(contents) = fs::read_to_string(current) {
+        return Some(contents);
+    }
+    if Path::new("/proc/config.gz").exists() {
+        if let Ok((contents, 0)) = OsCall::Quiet.execute("gzip -dc /proc/config.gz", "") {
+            return Some(contents);
+        }
+    }
+    fs::read_to_string(["/boot/config-", &linux::running_kernel()].concat()).ok()
+}
+
+// The saved answers with the value each new option was given in the configured .config, replacing
+// any answer saved before for it
+//
+pub fn updated_answers(
+    answers: &[KernelOption],
+    new_options: &[KernelOption],
+    configured: &str,
+) -> String {
+    let chosen = parse_config(configured);
+    let mut kept: Vec<KernelOption> = answers
+        .iter()
+        .filter(|answer| !new_options.iter().any(|option| option.name == answer.name))
+        .cloned()
+        .collect();
+    for option in new_options {
+        let value = chosen
+            .iter()
+            .find(|chosen| chosen.name == option.name)
+            .map(|chosen| chosen.value.clone())
+            .unwrap_or_else(|| "n".to_string());
+        kept.push(KernelOption {
+            name: option.name.clone(),
+            value,
+        });
+    }
+    let lines: Vec<String> = kept.iter().map(KernelOption::config_line).collect();
+    lines.join("\n") + "\n"
+}
+
+// Save the value each new option was given
+//
+fn save_answers(answers: &[KernelOption], new_options: &[KernelOption], configured: &str) {
+    let contents = updated_answers(answers, new_options, configured);
+    let _ = fs::create_dir_all(state_dir()).and_then(|_| fs::write(answers_path(), contents));
+}
+
+// List the options new kernel sources introduce, and have the user settle them before the kernel
+// is built
+//
+pub fn review_new_sources() {
+    if !portage::running_system() {
+        return; // The kernel belongs to the running system
+    }
+    let (Some(sources), Some(old_config)) = (unconfigured_sources(), running_config()) else {
+        return;
+    };
+    let answers = parse_config(&fs::read_to_string(answers_path()).unwrap_or_default());
+
+    // Work out the new options from a copy of the old configuration, leaving the sources
+    // unconfigured until the user decides
+    //
+    let candidate = [state_dir(), "/kernel-config"].concat();
+    let with_answers = |new_options: &[KernelOption]| {
+        let mut contents = old_config.clone();
+        for answer in saved_answers(&answers, new_options) {
+            contents.push_str(&answer.config_line());
+            contents.push('\n');
+        }
+        contents
+    };
+    if fs::create_dir_all(state_dir())
+        .and_then(|_| fs::write(&candidate, &old_config))
+        .is_err()
+    {
+        return;
+    }
+    let listnewconfig = format!(
+        "make -s -C {} KCONFIG_CONFIG={} listnewconfig",
+        sources.display(),
+        candidate
+    );
+    let new_options = match OsCall::Quiet.execute(&listnewconfig, "") {
+        Ok((output, 0)) => parse_new_options(&output),
+        _ => Vec::new(),
+    };
+    let _ = fs::remove_file(&candidate);
+    if new_options.is_empty() {
+        return;
+    }
+    let remembered = saved_answers(&answers, &new_options);
+    println!(
+        "{} {} introduces {} kernel option(s) the running kernel's configuration does not mention:",
+        prompt::revchevrons(Color::Yellow),
+        sources.display(),
+        new_options.len()
+    );
+    for option in &new_options {
+        match remembered.iter().find(|answer| answer.name == option.name) {
+            Some(answer) => println!(
+                "    {} (answered before: {})",
+                option.config_line(),
+                answer.value
+            ),
+            None => println!("    {} (default)", option.config_line()),
+        }
+    }
+    if dryrun::active() {
+        return;
+    }
+    let review = format!(
+        "Review the new kernel options with make oldconfig in {}",
+        sources.display()
+    );
+    if !linux::is_a_tty() && !prompt::answered("kernel-config") {
+        actions::add(review);
+        return;
+    }
+    let make = loop {
+        let answer = Prompt::Options.askuser(
+            "kernel-config",
+            "Select a to accept these values, r to review each with make oldconfig, or l to leave the sources unconfigured for now [a|r|l]",
+        );
+        match answer.as_deref() {
+            Some("a\n") => break "olddefconfig",
+            Some("r\n") => break "oldconfig",
+            Some("l\n") | None => {
+                actions::add(review);
+                return;
+            }
+            // Anything else typed at the terminal is asked again. Answers from the config file
+            // are one of the above, as they are checked when it is read
+            _ => continue,
+        }
+    };
+    let config = sources.join(".config");
+    if fs::write(&config, with_answers(&new_options)).is_err() {
+        return;
+    }
+    let command = format!("make -C {} {}", sources.display(), make);
+    match OsCall::Interactive.execute(&command, "Configuring the new kernel") {
+        Ok((_, 0)) => {
+            save_answers(
+                &answers,
+                &new_options,
+                &fs::read_to_string(&config).unwrap_or_default(),
+            );
+            println!(
+                "{} {} is configured, and the values of its new options are saved for later kernels",
+                prompt::revchevrons(Color::Green),
+                sources.display()
+            );
+        }
+        _ => {
+            let _ = fs::remove_file(&config);
+            eprintln!(
+                "{} The new kernel could not be configured",
+                prompt::revchevrons(Color::Red)
+            );
+            actions::add(review);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_new_kernel_options() {
+        let output = "\
+CONFIG_ZRAM_TRACK_ENTRY_ACTIME=n
+CONFIG_RUST_FW_LOADER_ABSTRACTIONS=y
+CONFIG_LOCALVERSION=\"\"
+CONFIG_NEW_STYLELESS
+";
+        let new_options = parse_new_options(output);
+        assert_eq!(new_options.len(), 4);
+        assert_eq!(
+            new_options[0].config_line(),
+            "# CONFIG_ZRAM_TRACK_ENTRY_ACTIME is not set"
+        );
+        assert_eq!(new_options[2].value, "\"\"");
+
+        let answers =
+            parse_config("# CONFIG_RUST_FW_LOADER_ABSTRACTIONS is not set\nCONFIG_OLD_OPTION=m\n");
+        let remembered = saved_answers(&answers, &new_options);
+        assert_eq!(remembered.len(), 1);
+        assert_eq!(remembered[0].name, "CONFIG_RUST_FW_LOADER_ABSTRACTIONS");
+        assert_eq!(remembered[0].value, "n");
+    }
+
+    #[test]
+    fn reuses_saved_kernel_answers() {
+        let saved = "CONFIG_OLD_OPTION=m\nCONFIG_ZRAM_TRACK_ENTRY_ACTIME=y\n";
+        let listnewconfig = "CONFIG_ZRAM_TRACK_ENTRY_ACTIME=n\nCONFIG_NTSYNC=m\n";
+        let answers = parse_config(saved);
+        let new_options = parse_new_options(listnewconfig);
+        let remembered = saved_answers(&answers, &new_options);
+        assert_eq!(remembered.len(), 1);
+        assert_eq!(
+            remembered[0].config_line(),
+            "CONFIG_ZRAM_TRACK_ENTRY_ACTIME=y"
+        );
+
+        // The answers saved after configuring keep the old ones, and take each new option's value
+        // from the .config, including those left unset
+        let configured = "CONFIG_ZRAM_TRACK_ENTRY_ACTIME=y\n# CONFIG_NTSYNC is not set\n";
+        assert_eq!(
+            updated_answers(&answers, &new_options, configured),
+            "CONFIG_OLD_OPTION=m\nCONFIG_ZRAM_TRACK_ENTRY_ACTIME=y\n# CONFIG_NTSYNC is not set\n"
+        );
+
+        let sources: Package = "sys-kernel/gentoo-sources-6.6.30".parse().unwrap();
+        assert!(is_kernel_sources(&sources));
+        let kernel: Package = "sys-kernel/gentoo-kernel-bin-6.6.30".parse().unwrap();
+        assert!(!is_kernel_sources(&kernel));
+    }
+}
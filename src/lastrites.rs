@@ -0,0 +1,225 @@
+// Last rites
+// Packages due to be removed from the Gentoo repository are first masked in profiles/package.mask,
+// with a comment giving the reason and the date of removal, e.g
+//
+//   # Jane Doe <jane@gentoo.org> (2024-04-01)
+//   # Unmaintained upstream, many open bugs. Use app-misc/bar instead.
+//   # Removal on 2024-05-01.  Bugs #923456, #923457.
+//   app-misc/foo
+//   <dev-python/baz-2
+//
+// After each sync, the installed packages are checked against these entries, and any which are
+// going to vanish from the tree are added to the action checklist, so that a replacement can be
+// planned before they stop receiving updates. The reasons are kept until the packages are
+// uninstalled, so that removed.rs can say why a package vanished once its entry has gone
+
+use crate::{actions, atom::Package, config::state_dir, linux::OsCall, portage, prompt, removed};
+use chrono::NaiveDate;
+use crossterm::style::Color;
+use std::fs;
+
+// Define a struct to hold one package.mask entry which announces a removal
+//
+#[derive(Debug, PartialEq)]
+pub struct LastRite {
+    pub atoms: Vec<String>,
+    pub reason: String,
+    pub removal: Option<NaiveDate>, // None if the entry gives no date
+}
+
+// Find the first date written as YYYY-MM-DD in some text
+//
+fn find_date(text: &str) -> Option<NaiveDate> {
+    text.split(|c: char| !c.is_ascii_digit() && c != '-')
+        .find_map(|word| NaiveDate::parse_from_str(word, "%Y-%m-%d").ok())
+}
+
+// Parse package.mask into the entries which announce that packages are to be removed. Entries are
+// separated by blank lines, and each is a comment followed by the atoms it masks
+//
+pub fn parse_package_mask(contents: &str) -> Vec<LastRite> {
+    let mut rites = Vec::new();
+    for entry in contents.split("\n\n") {
+        let mut comment = Vec::new();
+        let mut atoms = Vec::new();
+        for line in entry.lines().map(|line| line.trim()) {
+            if let Some(text) = line.strip_prefix('#') {
+                comment.push(text.trim());
+            } else if !line.is_empty() {
+                atoms.push(line.to_string());
+            }
+        }
+        let Some(removal_line) = comment
+            .iter()
+            .find(|line| line.to_lowercase().contains("removal"))
+        else {
+            continue;
+        };
+        if atoms.is_empty() {
+            continue;
+        }
+        let reason = comment
+            .iter()
+            .skip(1) // The author and the date of the mask
+            .filter(|line| !line.to_lowercase().starts_with("removal"))
+            .copied()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        rites.push(LastRite {
+            atoms,
+            reason,
+            removal: find_date(removal_line),
+        });
+    }
+    rites
+}
+
+// Whether an installed package, e.g dev-python/baz-1.4, is matched by a package.mask atom such as
+// dev-python/baz, <dev-python/baz-2, =dev-python/baz-1.4* or ~dev-python/baz-1.4
+//
+pub fn matches(atom: &str, installed: &Package) -> bool {
+    let atom = atom.split(['[', ':']).next().unwrap_or(atom);
+    let operator_end = atom.find(|c: char| !"<>=~!".contains(c)).unwrap_or(0);
+    let (operator, rest) = atom.split_at(operator_end);
+    let (rest, glob) = match rest.strip_suffix('*') {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    let Ok(masked) = rest.parse::<Package>() else {
+        return false;
+    };
+    if masked.cpn() != installed.cpn() {
+        return false;
+    }
+    let (Some(masked_version), Some(installed_version)) = (&masked.version, &installed.version)
+    else {
+        return operator.is_empty();
+    };
+    match operator {
+        "<" => installed_version < masked_version,
+        "<=" => installed_version <= masked_version,
+        ">" => installed_version > masked_version,
+        ">=" => installed_version >= masked_version,
+        "~" => installed_version.without_revision() == masked_version.without_revision(),
+        "=" if glob => installed_version
+            .to_string()
+            .starts_with(&masked_version.to_string()),
+        "=" => installed_version == masked_version,
+        _ => false,
+    }
+}
+
+// Describe when a removal is due, relative to today
+//
+pub fn describe_removal(removal: Option<NaiveDate>, today: NaiveDate) -> String {
+    match removal {
+        None => "soon".to_string(),
+        Some(date) if date < today => format!("on {} (overdue)", date),
+        Some(date) => format!("on {} (in {} days)", date, (date - today).num_days()),
+    }
+}
+
+// Warn about installed packages which are masked for removal from the Gentoo repository
+//
+pub fn check() {
+    let repository = OsCall::Quiet
+        .execute("portageq get_repo_path / gentoo", "")
+        .map(|(output, _)| output.trim().to_string())
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or("/var/db/repos/gentoo".to_string());
+    let Ok(contents) = fs::read_to_string([&repository, "/profiles/package.mask"].concat()) else {
+        return;
+    };
+    let rites = parse_package_mask(&contents);
+    let today = chrono::Local::now().date_naive();
+    let installed = portage::installed_packages();
+    let mut kept: Vec<(String, String)> = removed::parse_last_rites(
+        &fs::read_to_string(removed::last_rites_path()).unwrap_or_default(),
+    )
+    .into_iter()
+    .filter(|(cpn, _)| installed.iter().any(|package| package.cpn() == *cpn))
+    .collect();
+    for package in installed {
+        let Some(rite) = rites
+            .iter()
+            .find(|rite| rite.atoms.iter().any(|atom| matches(atom, &package)))
+        else {
+            continue;
+        };
+        let warning = format!(
+            "{} is installed, but will be removed from the Gentoo repository {} - {}",
+            package.cpv(),
+            describe_removal(rite.removal, today),
+            rite.reason
+        );
+        println!("{} {}", prompt::revchevrons(Color::Yellow), warning);
+        actions::add(["Plan a replacement: ", &warning].concat());
+        kept.retain(|(cpn, _)| *cpn != package.cpn());
+        kept.push((package.cpn(), rite.reason.clone()));
+    }
+    let contents: String = kept
+        .iter()
+        .map(|(cpn, reason)| [cpn, "\t", reason, "\n"].concat())
+        .collect();
+    let _ = fs::create_dir_all(state_dir())
+        .and_then(|_| fs::write(removed::last_rites_path(), contents));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_last_rites() {
+        let mask = "\
+# Jane Doe <jane@gentoo.org> (2024-04-01)
+# Unmaintained upstream, many open bugs.
+# Use app-misc/bar instead.
+# Removal on 2024-05-01.  Bugs #923456, #923457.
+app-misc/foo
+<dev-python/baz-2
+
+# John Doe <john@gentoo.org> (2024-03-01)
+# Breaks the build of everything, masked for testing.
+=sys-devel/gcc-15*
+
+# Jane Doe <jane@gentoo.org> (2024-04-02)
+# Dead upstream. Removal: 2024-05-02
+~net-misc/qux-1.0
+";
+        let rites = parse_package_mask(mask);
+        assert_eq!(rites.len(), 2);
+        assert_eq!(rites[0].atoms, vec!["app-misc/foo", "<dev-python/baz-2"]);
+        assert_eq!(
+            rites[0].reason,
+            "Unmaintained upstream, many open bugs. Use app-misc/bar instead."
+        );
+        assert_eq!(rites[0].removal, NaiveDate::from_ymd_opt(2024, 5, 1));
+        assert_eq!(rites[1].removal, NaiveDate::from_ymd_opt(2024, 5, 2));
+
+        let package = |cpv: &str| cpv.parse::<Package>().unwrap();
+        assert!(matches("app-misc/foo", &package("app-misc/foo-1.2")));
+        assert!(matches("<dev-python/baz-2", &package("dev-python/baz-1.4")));
+        assert!(!matches(
+            "<dev-python/baz-2",
+            &package("dev-python/baz-2.1")
+        ));
+        assert!(matches(
+            "=sys-devel/gcc-15*",
+            &package("sys-devel/gcc-15.1.0")
+        ));
+        assert!(matches(
+            "~net-misc/qux-1.0",
+            &package("net-misc/qux-1.0-r3")
+        ));
+        assert!(!matches("app-misc/foo", &package("app-misc/foobar-1.0")));
+
+        let today = NaiveDate::from_ymd_opt(2024, 4, 16).unwrap();
+        assert_eq!(
+            describe_removal(rites[0].removal, today),
+            "on 2024-05-01 (in 15 days)"
+        );
+        assert_eq!(describe_removal(None, today), "soon");
+    }
+}
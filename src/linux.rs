@@ -1,4 +1,10 @@
-use crate::prompt;
+use crate::{
+    cleanup, dryrun,
+    exitcode::ExitCode,
+    portage,
+    prompt::{self, Verbosity},
+    rotational,
+};
 use crossterm::{
     cursor, execute,
     style::{Color, SetForegroundColor},
@@ -7,12 +13,28 @@ use crossterm::{
 };
 use execute::Execute;
 use std::{
+    env,
     error::Error,
-    fs::File,
-    io::{self, BufRead, BufReader},
-    process::{self, Command, Stdio},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, IsTerminal},
+    path::Path,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
-use terminal_spinners::{SpinnerBuilder, LINE};
+use terminal_spinners::{SpinnerBuilder, SpinnerHandle, LINE};
+
+// Set by the SIGWINCH handler when the terminal is resized
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+// Variables given to the commands gentup runs, on top of its own environment, which is not
+// changed once other threads may be reading it
+static COMMAND_VARIABLES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
 
 // Define a new type, OsCall which executes an external OS command
 pub enum OsCall {
@@ -24,6 +46,36 @@ pub enum OsCall {
 
 pub type ShellOutResult = Result<(String, i32), Box<dyn Error>>; // ShellOutResult is returned from an OsCall
 
+// Commands whose output gentup reads are run in the C locale, since portage, eselect and friends
+// translate messages such as "Number to remove" or "Your system is consistent" on systems set to
+// another language, and the output would no longer be understood. Commands whose output goes
+// straight to the terminal keep the user's locale
+//
+pub fn untranslated(command: &mut Command) -> &mut Command {
+    command.env("LC_ALL", "C").env_remove("LANGUAGE")
+}
+
+// Give the commands run from now on a variable, or with None, stop giving it to them
+//
+pub fn set_command_variable(name: &str, value: Option<String>) {
+    let Ok(mut variables) = COMMAND_VARIABLES.lock() else {
+        return;
+    };
+    variables.retain(|(given, _)| given != name);
+    if let Some(value) = value {
+        variables.push((name.to_string(), value));
+    }
+}
+
+// Add the variables given to every command to a command's environment
+//
+fn with_command_variables(command: &mut Command) -> &mut Command {
+    if let Ok(variables) = COMMAND_VARIABLES.lock() {
+        command.envs(variables.iter().cloned());
+    }
+    command
+}
+
 pub trait CouldFail {
     // OsCalls could fail, and the failures need to be handled
     fn exit_if_failed(self) -> ShellOutResult;
@@ -39,7 +91,7 @@ impl CouldFail for ShellOutResult {
                         "{} The command had a non zero exit status. Please check.\n",
                         prompt::revchevrons(Color::Red)
                     );
-                    process::exit(1);
+                    ExitCode::Failed.exit();
                 }
             }
             Err(errors) => {
@@ -48,7 +100,7 @@ impl CouldFail for ShellOutResult {
                     prompt::revchevrons(Color::Red),
                     errors
                 );
-                process::exit(1);
+                ExitCode::Failed.exit();
             }
         }
         self
@@ -58,6 +110,11 @@ impl CouldFail for ShellOutResult {
 impl OsCall {
     // Fork and exec an external command. Waits for completion
     pub fn execute(self, command_line: &str, status: &str) -> ShellOutResult {
+        // In a dry run, a command which would change the system is printed and taken to succeed
+        if dryrun::active() && !dryrun::read_only(command_line) {
+            dryrun::show(command_line, status);
+            return Ok((String::new(), 0));
+        }
         let mut command_words = Vec::new();
         for word in command_line.split_whitespace() {
             command_words.push(word);
@@ -66,26 +123,46 @@ impl OsCall {
         for argument in command_words.iter().skip(1) {
             command.arg(argument);
         }
+        with_command_variables(&mut command);
         let results = {
             match self {
                 // Spinner - executes a command via the OS with a progress spinner, returns
                 // stdout to the calling function
+                // With --verbose, the output is streamed to the terminal as it is captured
+                OsCall::Spinner if prompt::verbosity() == Verbosity::Verbose => {
+                    println!(
+                        "{} {}: {}{}{}",
+                        prompt::chevrons(Color::Green),
+                        status,
+                        &SetForegroundColor(Color::Cyan),
+                        command_line,
+                        &SetForegroundColor(Color::Grey)
+                    );
+                    untranslated(&mut command).stdout(Stdio::piped());
+                    stream_output(&mut command)
+                }
                 OsCall::Spinner => {
-                    command.stdout(Stdio::piped());
-                    let text = prompt::chevrons(Color::Green)
-                        + " "
-                        + status
-                        + ": "
-                        + &SetForegroundColor(Color::Cyan).to_string()
-                        + command_line
-                        + &SetForegroundColor(Color::Grey).to_string()
-                        + " ";
-                    let handle = SpinnerBuilder::new()
-                        .spinner(&LINE)
-                        .prefix(text)
-                        .text(" ")
-                        .start();
-                    let result = command.execute_output();
+                    untranslated(&mut command).stdout(Stdio::piped());
+                    let (sender, receiver) = mpsc::channel();
+                    thread::spawn(move || {
+                        let _ = sender.send(command.execute_output());
+                    });
+                    let mut handle = start_spinner(status, command_line);
+                    let result = loop {
+                        match receiver.recv_timeout(Duration::from_millis(100)) {
+                            Ok(result) => break result,
+                            // The spinner is redrawn to fit the terminal's new width
+                            Err(RecvTimeoutError::Timeout) => {
+                                if resized() {
+                                    handle.stop_and_clear();
+                                    handle = start_spinner(status, command_line);
+                                }
+                            }
+                            Err(RecvTimeoutError::Disconnected) => {
+                                break Err(io::Error::other("the command could not be waited for"))
+                            }
+                        }
+                    };
                     handle.done();
                     result
                 }
@@ -105,7 +182,7 @@ impl OsCall {
                 // Quiet - executes a command via the OS returning stdout and stderr to the calling
                 // function
                 OsCall::Quiet => {
-                    command.stdout(Stdio::piped());
+                    untranslated(&mut command).stdout(Stdio::piped());
                     command.stderr(Stdio::piped());
                     command.execute_output()
                 }
@@ -125,6 +202,10 @@ impl OsCall {
 
     // Pipe the stdout from one command into another
     pub fn piped(self, pipe_from: &str, pipe_to: &str) -> ShellOutResult {
+        if dryrun::active() && !(dryrun::read_only(pipe_from) && dryrun::read_only(pipe_to)) {
+            dryrun::show(&[pipe_from, " | ", pipe_to].concat(), "");
+            return Ok((String::new(), 0));
+        }
         match self {
             OsCall::Quiet => {
                 // build command 1
@@ -146,7 +227,8 @@ impl OsCall {
                     to_command.arg(argument);
                 }
                 //pipe them
-                to_command.stdout(Stdio::piped());
+                untranslated(with_command_variables(&mut from_command));
+                untranslated(with_command_variables(&mut to_command)).stdout(Stdio::piped());
                 let results = from_command.execute_multiple_output(&mut [&mut to_command]);
                 match results {
                     Ok(output) => Ok((
@@ -161,18 +243,183 @@ impl OsCall {
             }
             _ => {
                 println!("Internal Error: piped() only supports Quiet");
-                process::exit(1);
+                ExitCode::Failed.exit();
             }
         }
     }
 }
 
+// The text shown before a spinner, with the command line shortened so that the whole line fits the
+// width of the terminal. The spinner redraws its line in place, which a line too long for the
+// terminal defeats, leaving a trail of partial copies behind
+//
+pub fn spinner_prefix(status: &str, command_line: &str, width: usize) -> String {
+    // >>> status: command line, then the spinner, the space after it and room for the cursor
+    let around = 3 + 1 + status.chars().count() + 2 + 1 + 2 + 1;
+    prompt::chevrons(Color::Green)
+        + " "
+        + status
+        + ": "
+        + &SetForegroundColor(Color::Cyan).to_string()
+        + &portage::ellipsise(command_line, width.saturating_sub(around))
+        + &SetForegroundColor(Color::Grey).to_string()
+        + " "
+}
+
+// Run a command, echoing each line of its output as it is written, and capturing it as well
+//
+fn stream_output(command: &mut Command) -> io::Result<Output> {
+    let mut child = command.spawn()?;
+    let mut captured = Vec::new();
+    if let Some(output) = child.stdout.take() {
+        for line in BufReader::new(output).split(b'\n').map_while(Result::ok) {
+            println!("{}", String::from_utf8_lossy(&line));
+            captured.extend(line);
+            captured.push(b'\n');
+        }
+    }
+    Ok(Output {
+        status: child.wait()?,
+        stdout: captured,
+        stderr: Vec::new(),
+    })
+}
+
+fn start_spinner(status: &str, command_line: &str) -> SpinnerHandle {
+    let (width, _height) = termsize();
+    SpinnerBuilder::new()
+        .spinner(&LINE)
+        .prefix(spinner_prefix(status, command_line, width))
+        .text(" ")
+        .start()
+}
+
+extern "C" fn on_resize(_signal: libc::c_int) {
+    RESIZED.store(true, Ordering::Relaxed);
+}
+
+// Notice from here on when the terminal is resized, so that spinners can be redrawn to fit
+//
+pub fn watch_resize() {
+    // SAFETY: the handler only stores to an atomic, which is safe to do in a signal handler
+    unsafe {
+        libc::signal(
+            libc::SIGWINCH,
+            on_resize as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+// Whether the terminal has been resized since this was last asked
+//
+pub fn resized() -> bool {
+    RESIZED.swap(false, Ordering::Relaxed)
+}
+
+// Shorten plain text to fit the rest of a terminal line, after the columns already used, so that
+// the line can be overwritten in place
+//
+pub fn fit_line(text: &str, used: usize) -> String {
+    let (width, _height) = termsize();
+    portage::ellipsise(text, width.saturating_sub(used + 1))
+}
+
+// Define a struct to hold one entry from the kernel's mount table
+pub struct MountEntry {
+    pub device: String,
+    pub mount_point: String,
+    pub fstype: String,
+    pub options: Vec<String>,
+}
+
+impl MountEntry {
+    // Returns true if the filesystem is mounted with the named option, e.g "ro"
+    pub fn has_option(&self, option: &str) -> bool {
+        self.options.iter().any(|each| each == option)
+    }
+}
+
+// Returns the mounted filesystems listed in /proc/mounts, in mount order
+pub fn mounts() -> Vec<MountEntry> {
+    let mut entries = Vec::new();
+    if let Ok(contents) = fs::read_to_string("/proc/mounts") {
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 {
+                continue;
+            }
+            entries.push(MountEntry {
+                device: fields[0].to_string(),
+                mount_point: fields[1].replace("\\040", " "),
+                fstype: fields[2].to_string(),
+                options: fields[3]
+                    .split(',')
+                    .map(|option| option.to_string())
+                    .collect(),
+            });
+        }
+    }
+    entries
+}
+
+// Returns the mounted filesystem which holds a path. This is the mount with the longest mount
+// point that is a prefix of the path, and the most recent mount wins if a mount point is mounted
+// over
+pub fn mount_for(path: &str) -> Option<MountEntry> {
+    let mut found: Option<MountEntry> = None;
+    for entry in mounts() {
+        let contains_path = entry.mount_point == "/"
+            || path == entry.mount_point
+            || path.starts_with(&[&entry.mount_point, "/"].concat());
+        let longer = match &found {
+            Some(found) => entry.mount_point.len() >= found.mount_point.len(),
+            None => true,
+        };
+        if contains_path && longer {
+            found = Some(entry);
+        }
+    }
+    found
+}
+
+// Trim the filesystems on solid state storage which accepts discards, one at a time, reporting how
+// much each trimmed. Trimming a spinning disk does nothing useful, so they are left alone, as are
+// network and virtual filesystems, and on a system with nothing to trim the trim is skipped
+//
 pub fn call_fstrim() {
-    // A good example of how to use OsCall with the .execute and .exit_if_failed methods we defined
-    // above
-    let _ = OsCall::Spinner
-        .execute("fstrim -a", "Trimming filesystems")
-        .exit_if_failed();
+    let mount_points = rotational::trimmable_mounts();
+    if mount_points.is_empty() {
+        println!(
+            "{} There are no filesystems on solid state storage which accepts discards. Skipping trim",
+            prompt::revchevrons(Color::Yellow)
+        );
+        return;
+    }
+    for mount_point in mount_points {
+        if mount_point.contains(char::is_whitespace) {
+            continue; // Cannot be passed through OsCall, which splits the command line on spaces
+        }
+        match OsCall::Spinner.execute(
+            &["fstrim -v ", &mount_point].concat(),
+            &["Trimming ", &mount_point].concat(),
+        ) {
+            Ok((output, 0)) => {
+                if let Some(bytes) = rotational::trimmed_bytes(&output) {
+                    println!(
+                        "{} Trimmed {} on {}",
+                        prompt::chevrons(Color::Green),
+                        cleanup::format_bytes(bytes),
+                        mount_point
+                    );
+                }
+            }
+            _ => eprintln!(
+                "{} Could not trim {}",
+                prompt::revchevrons(Color::Yellow),
+                mount_point
+            ),
+        }
+    }
 }
 
 // Returns the name of the Linux distro we are running on. Returns a failure if it isn't the distro
@@ -208,19 +455,24 @@ pub fn stripchar(devicename: String) -> String {
 
 // Gets the current terminal size
 pub fn termsize() -> (usize, usize) {
-    let mut session_width: usize = 0;
-    let mut session_height: usize = 0;
-    if let Ok((w, h)) = size() {
-        session_width = w as usize;
-        session_height = h as usize;
-    } else {
-        eprintln!(
-            "Unable to get terminal size {} {}",
-            session_width, session_height
-        );
-        process::exit(1);
+    if let Ok((width, height)) = size() {
+        if width > 0 && height > 0 {
+            return (width as usize, height as usize);
+        }
     }
-    (session_width, session_height)
+    // There is no terminal, e.g when run from cron or with output to a pipe, so use the size the
+    // shell exported, or failing that the traditional 80x24
+    let from_environment = |variable: &str, default: usize| {
+        env::var(variable)
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(default)
+    };
+    (
+        from_environment("COLUMNS", 80),
+        from_environment("LINES", 24),
+    )
 }
 
 // Returns the running kernel version
@@ -241,6 +493,55 @@ pub fn clearscreen() {
     );
 }
 
+// Returns true if stdin is attached to a terminal, meaning there is a user present to answer prompts
 pub fn is_a_tty() -> bool {
-    true // TODO fully implement this function
+    io::stdin().is_terminal()
+}
+
+// Returns true if running as root. The effective user ID is checked rather than $USER, which
+// sudo -E, su and cron all leave set to something else
+pub fn is_root() -> bool {
+    // SAFETY: geteuid cannot fail and only reads the process credentials
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Explain that root is needed, suggesting how to run this command line again with sudo or doas
+// when either is installed
+pub fn not_root_message() -> String {
+    let command_line = env::args().collect::<Vec<String>>().join(" ");
+    match ["sudo", "doas"]
+        .iter()
+        .find(|tool| Path::new(&["/usr/bin/", tool].concat()).exists())
+    {
+        Some(tool) => format!(
+            "You need to be root to run this. Try again with: {} {}",
+            tool, command_line
+        ),
+        None => "You need to be root to run this. Log in as root, or use su -".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_spinner_lines_to_the_terminal() {
+        let visible = |text: &str| {
+            [Color::Green, Color::Cyan, Color::Grey]
+                .iter()
+                .fold(text.to_string(), |text, colour| {
+                    text.replace(&SetForegroundColor(*colour).to_string(), "")
+                })
+        };
+        let prefix = spinner_prefix("Syncing package tree", "emerge --sync", 80);
+        assert_eq!(visible(&prefix), ">>> Syncing package tree: emerge --sync ");
+
+        // The spinner and the space after it take two more columns, leaving one for the cursor
+        let prefix = spinner_prefix("Checking for updates", "emerge -puDv @world", 40);
+        assert_eq!(visible(&prefix), ">>> Checking for updates: emerge -p… ");
+        assert_eq!(visible(&prefix).chars().count() + 2, 39);
+        let prefix = spinner_prefix("Checking for updates", "emerge -puDv @world", 0);
+        assert_eq!(visible(&prefix), ">>> Checking for updates: … ");
+    }
 }
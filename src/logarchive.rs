@@ -0,0 +1,232 @@
+// Log archiving
+// Portage keeps a build log for every package it merges, when PORT_LOGDIR is set, and the elog
+// messages of each, and gentup keeps the JSON report of every update run in /var/lib/gentup/runs.
+// None of these are ever removed, and on a small root filesystem they add up. As part of the
+// cleanup phase, the logs and reports older than log_compress_days are compressed, with zstd if it
+// is installed and gzip otherwise. Whatever reads them back, such as the list of recent runs shown
+// by gentup --stats, decompresses them transparently, going by the file name:
+//
+//   /var/log/portage/sys-libs:glibc-2.39-r6:20240405-101500.log.zst
+//   /var/lib/gentup/runs/1712311200.json.gz
+
+use crate::{
+    cleanup, config::state_dir, linux::OsCall, portage, prompt, report, requirements, Config,
+};
+use crossterm::style::Color;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+// Where the report of each update run is kept, named after the time the run started
+//
+pub fn runs_dir() -> String {
+    [state_dir(), "/runs"].concat()
+}
+
+// Keep the JSON report of an update run
+//
+pub fn save_run(started: u64, json: &str) {
+    let path = format!("{}/{}.json", runs_dir(), started);
+    if let Err(error) = fs::create_dir_all(runs_dir()).and_then(|_| fs::write(&path, json)) {
+        eprintln!(
+            "{} Could not save the run report {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            path,
+            error
+        );
+    }
+}
+
+// The logs and reports beneath a directory which were last changed before the cutoff, in seconds
+// since the epoch, and are not compressed yet
+//
+pub fn due(directory: &Path, cutoff: u64) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let Ok(entries) = fs::read_dir(directory) else {
+        return found;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            found.extend(due(&path, cutoff));
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !(name.ends_with(".log") || name.ends_with(".json")) {
+            continue;
+        }
+        let modified = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs())
+            .unwrap_or(u64::MAX);
+        if modified < cutoff {
+            found.push(path);
+        }
+    }
+    found.sort();
+    found
+}
+
+// The command which compresses a file in place, and the suffix it adds
+//
+fn compressor() -> (&'static str, &'static str) {
+    if requirements::on_path("zstd") {
+        ("zstd -q --rm -19", ".zst")
+    } else {
+        ("gzip -9", ".gz")
+    }
+}
+
+// Compress the logs and reports older than log_compress_days
+//
+pub fn compress_old(running_config: &Config) {
+    if running_config.log_compress_days == 0 {
+        return;
+    }
+    let cutoff = report::now().saturating_sub(u64::from(running_config.log_compress_days) * 86400);
+    let mut files = due(Path::new(&portage::port_logdir()), cutoff);
+    files.extend(due(Path::new(&runs_dir()), cutoff));
+    if files.is_empty() {
+        return;
+    }
+    let (command, suffix) = compressor();
+    let (mut compressed, mut saved) = (0, 0);
+    for file in &files {
+        let before = fs::metadata(file)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let path = file.to_string_lossy();
+        if let Ok((_, 0)) = OsCall::Quiet.execute(&[command, " ", &path].concat(), "") {
+            let after = fs::metadata([&path, suffix].concat())
+                .map(|metadata| metadata.len())
+                .unwrap_or(before);
+            compressed += 1;
+            saved += before.saturating_sub(after);
+        }
+    }
+    println!(
+        "{} Compressed {} logs and reports older than {} days, saving {}",
+        prompt::chevrons(Color::Green),
+        compressed,
+        running_config.log_compress_days,
+        cleanup::format_bytes(saved)
+    );
+}
+
+// Read a log or report, decompressing it if it was compressed
+//
+pub fn read(path: &Path) -> Option<String> {
+    let name = path.to_string_lossy();
+    let command = if name.ends_with(".zst") {
+        "zstd -dcq "
+    } else if name.ends_with(".gz") {
+        "gzip -dc "
+    } else {
+        return fs::read_to_string(path).ok();
+    };
+    match OsCall::Quiet.execute(&[command, &name].concat(), "") {
+        Ok((contents, 0)) => Some(contents),
+        _ => None,
+    }
+}
+
+// Define a struct to hold the outline of a saved run report
+//
+#[derive(Debug, PartialEq)]
+pub struct RunSummary {
+    pub started: u64,
+    pub finished: u64,
+    pub exit_code: i32,
+    pub result: String,
+}
+
+// The value of a top level field of a run report: a string without its quotes, or anything else
+// up to the next comma or closing brace
+//
+fn field<'a>(json: &'a str, name: &str) -> Option<&'a str> {
+    let start = json.find(&format!("\"{}\":", name))? + name.len() + 3;
+    let rest = &json[start..];
+    match rest.strip_prefix('"') {
+        Some(text) => Some(&text[..text.find('"')?]),
+        None => Some(&rest[..rest.find([',', '}'])?]),
+    }
+}
+
+// Parse the outline of a run report, as written by report::to_json
+//
+pub fn parse_run(json: &str) -> Option<RunSummary> {
+    Some(RunSummary {
+        started: field(json, "started")?.parse().ok()?,
+        finished: field(json, "finished")?.parse().ok()?,
+        exit_code: field(json, "exit_code")?.parse().ok()?,
+        result: field(json, "result")?.to_string(),
+    })
+}
+
+// The most recent saved run reports, newest first
+//
+pub fn recent_runs(count: usize) -> Vec<RunSummary> {
+    let Ok(entries) = fs::read_dir(runs_dir()) else {
+        return Vec::new();
+    };
+    let mut runs: Vec<(u64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let started = name.split('.').next()?.parse().ok()?;
+            Some((started, entry.path()))
+        })
+        .collect();
+    runs.sort();
+    runs.iter()
+        .rev()
+        .take(count)
+        .filter_map(|(_, path)| parse_run(&read(path)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_logs() {
+        let json = "{\"host\":\"build1\",\"version\":\"0.5.1\",\"started\":1712311200,\
+            \"finished\":1712318400,\"exit_code\":0,\"result\":\"Nothing to update, or the command completed\",\
+            \"pending_updates\":[\"sys-libs/zlib-1.3.1\"],\"phases\":[]}";
+        assert_eq!(
+            parse_run(json),
+            Some(RunSummary {
+                started: 1712311200,
+                finished: 1712318400,
+                exit_code: 0,
+                result: "Nothing to update, or the command completed".to_string(),
+            })
+        );
+        assert_eq!(parse_run("{\"host\":\"build1\"}"), None);
+
+        let root = std::env::temp_dir().join(format!("gentup-logs-{}", std::process::id()));
+        fs::create_dir_all(root.join("build/sys-libs")).unwrap();
+        fs::create_dir_all(root.join("elog")).unwrap();
+        for name in [
+            "build/sys-libs/glibc-2.39-r6:20240405-101500.log",
+            "elog/sys-libs:glibc-2.39-r6:20240405-101500.log",
+            "elog/sys-libs:zlib-1.3.1:20240301-090000.log.zst",
+            "summary.txt",
+        ] {
+            fs::write(root.join(name), "log").unwrap();
+        }
+        let later = report::now() + 60;
+        let files = due(&root, later);
+        assert_eq!(files.len(), 2);
+        assert!(files[0].ends_with("build/sys-libs/glibc-2.39-r6:20240405-101500.log"));
+        assert!(due(&root, 0).is_empty());
+        assert_eq!(read(&files[1]).as_deref(), Some("log"));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
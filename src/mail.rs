@@ -1,39 +1,141 @@
-use crate::{linux::CouldFail, linux::OsCall, prompt, Config};
+// Email
+// News, the action checklist, security notices and fleet reports are emailed with the mail
+// command, which hands them to the local mail transport through /usr/sbin/sendmail, or to an SMTP
+// server configured for mailx, e.g
+//
+//   set mta=smtps://gentup@mail.example.com:465
+//
+// in /etc/mail.rc. Before the first email of a run, gentup checks that there is a way to deliver
+// it. When there is none, a warning explains how to set one up, and each email is written to
+// /var/lib/gentup/outbox instead, so nothing is lost and the update carries on
+
+use crate::{
+    config::state_dir, linux::OsCall, prompt, report, requirements, tempfile::TempFile, Config,
+};
 use crossterm::style::Color;
 use gethostname::gethostname;
-use std::{
-    fs::{self, File},
-    io::Write,
-    process,
-};
+use std::{fs, path::Path, sync::OnceLock};
 
-pub fn send_email(running_config: &Config, subject: String, email_body: String) {
-    let temp_file_name = format!("/tmp/gentup.{}.eml", process::id());
-    {
-        let mut temp_file = match File::create(&temp_file_name) {
-            Ok(temp_file) => temp_file,
-            Err(error) => {
-                println!(
-                    "{} Error creating email {}",
-                    prompt::revchevrons(Color::Red),
-                    error
-                );
-                process::exit(1);
-            }
-        };
-        let _ = writeln!(temp_file, "{email_body}");
+// The configuration files of the mailx variants, which can name an SMTP server to send through
+static MAILRC_PATHS: [&str; 4] = [
+    "/etc/mail.rc",
+    "/etc/nail.rc",
+    "/etc/s-nail.rc",
+    "/root/.mailrc",
+];
 
-        let _ = OsCall::Quiet
-            .piped(
-                &["cat ", &temp_file_name].concat(),
-                &["mail -s ", &subject, " ", &running_config.email_address].concat(),
-            )
-            .exit_if_failed();
+// Whether email can be delivered, checked once per run
+static TRANSPORT: OnceLock<Result<(), String>> = OnceLock::new();
+
+// Where emails are kept when they cannot be delivered
+//
+pub fn outbox_path() -> String {
+    [state_dir(), "/outbox"].concat()
+}
+
+// Whether a mailx configuration file sends through an SMTP server, with set smtp=... or
+// set mta=smtp://...
+//
+pub fn smtp_configured(mailrc: &str) -> bool {
+    mailrc
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("set "))
+        .flat_map(str::split_whitespace)
+        .any(|setting| {
+            setting.starts_with("smtp=")
+                || setting.starts_with("mta=smtp://")
+                || setting.starts_with("mta=smtps://")
+        })
+}
+
+// Check for a way to deliver email: the mail command, and a mail transport or an SMTP server for
+// it to hand the email to. Returns what is missing, and how to set it up
+//
+pub fn check_transport() -> Result<(), String> {
+    if !requirements::on_path("mail") {
+        return Err(
+            "the mail command is not installed. Install one with emerge mail-client/mailx"
+                .to_string(),
+        );
+    }
+    let smtp = MAILRC_PATHS
+        .iter()
+        .any(|path| fs::read_to_string(path).is_ok_and(|mailrc| smtp_configured(&mailrc)));
+    if !smtp && !Path::new("/usr/sbin/sendmail").exists() {
+        return Err(
+            "no mail transport is installed. Install one with emerge mail-mta/nullmailer and name your mail server in /etc/nullmailer/remotes, or set mta=smtp://<server> in /etc/mail.rc"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+// Whether email can be delivered this run, warning the first time it cannot
+//
+fn transport() -> &'static Result<(), String> {
+    TRANSPORT.get_or_init(|| {
+        let transport = check_transport();
+        if let Err(missing) = &transport {
+            eprintln!(
+                "{} Email cannot be delivered: {}. Until then, emails are saved in {}",
+                prompt::revchevrons(Color::Yellow),
+                missing,
+                outbox_path()
+            );
+        }
+        transport
+    })
+}
+
+// Save an email which cannot be delivered in the outbox. Returns where it was saved
+//
+fn save_to_outbox(
+    running_config: &Config,
+    subject: &str,
+    email_body: &str,
+) -> Result<String, String> {
+    let path = format!("{}/{}-{}.eml", outbox_path(), report::now(), subject);
+    let email = format!(
+        "To: {}\nSubject: {}\n\n{}\n",
+        running_config.email_address, subject, email_body
+    );
+    fs::create_dir_all(outbox_path())
+        .and_then(|_| fs::write(&path, email))
+        .map(|_| path.clone())
+        .map_err(|error| format!("Could not save the email in {} - {}", path, error))
+}
+
+// Send an email to the configured address. Returns an error describing the failure if the email
+// could not be handed to the mail command, or was saved in the outbox for want of a way to
+// deliver it, so the caller can decide what is lost
+//
+pub fn send_email(
+    running_config: &Config,
+    subject: String,
+    email_body: String,
+) -> Result<(), String> {
+    if transport().is_err() {
+        return Err(format!(
+            "there is no way to deliver email, so it was saved in {}",
+            save_to_outbox(running_config, &subject, &email_body)?
+        ));
     }
-    let _ = fs::remove_file(&temp_file_name);
+    TempFile::create("eml", &[&email_body, "\n"].concat())
+        .map_err(|error| format!("Error creating email {}", error))
+        .and_then(|temp_file| {
+            match OsCall::Quiet.piped(
+                &["cat ", temp_file.path()].concat(),
+                &["mail -s ", &subject, " ", &running_config.email_address].concat(),
+            ) {
+                Ok((_, 0)) => Ok(()),
+                Ok((_, status)) => Err(format!("mail exited with status {}", status)),
+                Err(error) => Err(format!("Could not run mail - {}", error)),
+            }
+        })
 }
 
-pub fn test_mail(running_config: &Config) {
+pub fn test_mail(running_config: &Config) -> Result<(), String> {
     send_email(
         running_config,
         String::from("Test_email"),
@@ -46,5 +148,22 @@ pub fn test_mail(running_config: &Config) {
                 .into_string()
                 .unwrap_or("localhost".to_string()),
         ),
-    );
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_smtp_settings() {
+        assert!(smtp_configured(
+            "# Send through the ISP\nset mta=smtps://gentup@mail.example.com:465\n"
+        ));
+        assert!(smtp_configured("set hold smtp=mail.example.com:25\n"));
+        assert!(!smtp_configured(
+            "set ask askcc append dot\n# set smtp=mail:25\n"
+        ));
+        assert!(!smtp_configured("set mta=/usr/sbin/sendmail\n"));
+    }
 }
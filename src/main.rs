@@ -18,24 +18,99 @@
 
 // Declare the modules used by the project
 //
+pub mod actions;
 pub mod args;
+pub mod atom;
+pub mod backend;
+pub mod bandwidth;
+pub mod binonly;
+pub mod budget;
+pub mod bugreport;
+pub mod builddirs;
+pub mod buildenv;
+pub mod changeddeps;
+pub mod cleanup;
+pub mod collisions;
+pub mod compiler;
 pub mod config;
+pub mod configmerge;
+#[cfg(test)]
+mod container_tests;
+pub mod crossdev;
+pub mod distclean;
+pub mod doctor;
+pub mod dryrun;
+pub mod eixdb;
+pub mod elog;
+pub mod emergeopts;
+pub mod estimate;
+pub mod events;
+pub mod exitcode;
+pub mod features;
+#[cfg(feature = "fleet")]
+pub mod fleet;
+pub mod glsa;
+#[cfg(feature = "http-status")]
+pub mod http;
+pub mod integrity;
+pub mod inventory;
+pub mod kconfig;
+pub mod lastrites;
 pub mod linux;
+pub mod logarchive;
+#[cfg(feature = "mail")]
 pub mod mail;
+pub mod makeconf;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod news;
+pub mod newsarchive;
+pub mod offline;
+pub mod onboarding;
+pub mod options;
+pub mod orchestrator;
+pub mod ordering;
+pub mod overlays;
+pub mod parallel;
+#[cfg(feature = "custom-phases")]
+pub mod plugin;
 pub mod portage;
+pub mod prefix;
+pub mod preflight;
+pub mod progress;
 pub mod prompt;
+pub mod rebuild;
+#[cfg(feature = "recovery")]
+pub mod recovery;
+pub mod removed;
+pub mod report;
+pub mod requirements;
+pub mod resume;
+pub mod rotational;
+pub mod selfupgrade;
+pub mod signature;
+pub mod smart;
+pub mod stalerun;
+pub mod stats;
+#[cfg(feature = "status-socket")]
+pub mod status;
+pub mod tempfile;
+pub mod throttle;
+pub mod treestate;
 pub mod version;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
 use crate::{
     args::{ArgCheck, ArgumentStruct, Search},
-    config::{Config, CONFIG_FILE_PATH, PACKAGE_FILE_PATH},
-    linux::CouldFail,
-    portage::PackageManager,
+    config::{config_file, package_file, Config},
+    exitcode::ExitCode,
+    options::RuntimeOptions,
     prompt::Prompt,
     version::VERSION,
 };
 use crossterm::style::Color;
-use std::{env, path::Path, process};
+use std::{env, path::Path};
 
 // main is the entry point for the compiled binary executable
 //
@@ -50,61 +125,228 @@ fn main() {
         "background",
         "Perform source fetching in the background during update",
     )];
+    arg_syntax.push(ArgumentStruct::from(
+        "B",
+        "since-boot",
+        "List the packages changed since the system booted, then exit",
+    ));
     arg_syntax.push(ArgumentStruct::from(
         "c",
         "cleanup",
         "Perform cleanup tasks after a successful upgrade",
     ));
+    arg_syntax.push(ArgumentStruct::from(
+        "C",
+        "continue",
+        "Continue an interrupted update from its last completed phase",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "d",
+        "doctor",
+        "Audit the health of the system and its portage configuration, with suggested fixes, then exit",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "D",
+        "dry-run",
+        "Print every command which would change the system, with its phase, rather than running it",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "e",
+        "export",
+        "Write an inventory of the installed packages as CSV (or JSON with --json), then exit",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "E",
+        "estimate",
+        "Predict the downloads, build time, disk space and risks of an update, without syncing, then exit",
+    ));
     arg_syntax.push(ArgumentStruct::from(
         "f",
         "force",
         "Force package tree sync, bypassing the timestamp check",
     ));
+    #[cfg(feature = "fleet")]
+    arg_syntax.push(ArgumentStruct::from(
+        "F",
+        "fleet",
+        &[
+            "Update each host listed in ",
+            fleet::HOSTS_FILE_PATH,
+            " over SSH, then exit",
+        ]
+        .concat(),
+    ));
     arg_syntax.push(ArgumentStruct::from(
         "h",
         "help",
         "Display this help text, then exit",
     ));
+    arg_syntax.push(ArgumentStruct::from(
+        "H",
+        "stats",
+        "Display merge history and build time statistics from emerge.log, then exit",
+    ));
+    arg_syntax.push(
+        ArgumentStruct::with_value(
+            "i",
+            "since",
+            "DATE",
+            "List the packages changed since a date, e.g --since 2024-04-05 or 2024-04-05T10:15, then exit",
+        )
+        .validated(
+            |date| stats::parse_since(date).is_some(),
+            "a date such as 2024-04-05 or 2024-04-05T10:15",
+        ),
+    );
+    arg_syntax.push(ArgumentStruct::from(
+        "j",
+        "json",
+        "Write progress to stdout as JSON events, one per line, and all other output to stderr",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "k",
+        "check",
+        "Report whether updates are pending, changing nothing, then exit",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "l",
+        "clean",
+        "Preview, then carry out, the cleanup stages on their own between updates, then exit",
+    ));
+    arg_syntax.push(ArgumentStruct::with_value(
+        "L",
+        "license",
+        "LICENSES",
+        "With --export, list only the packages under these licenses, e.g --license GPL-3,AGPL-3",
+    ));
+    arg_syntax.push(
+        ArgumentStruct::with_value(
+            "m",
+            "max-packages",
+            "N",
+            "Build only the first N pending updates, e.g --max-packages 20, leaving the rest for later runs",
+        )
+        .validated(
+            |max| max.parse::<usize>().is_ok_and(|max| max > 0),
+            "a number of packages",
+        ),
+    );
+    arg_syntax.push(ArgumentStruct::from(
+        "n",
+        "news-history",
+        "List the news items delivered, with when and to whom, then exit",
+    ));
     arg_syntax.push(ArgumentStruct::from(
         "o",
         "optional",
-        &["Install optional packages listed in ", PACKAGE_FILE_PATH].concat(),
+        &["Install optional packages listed in ", package_file()].concat(),
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "O",
+        "offline",
+        "Skip the sync and all downloads, building only the updates whose sources are already downloaded",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "p",
+        "pending",
+        "With --export, list the packages pending an update instead",
+    ));
+    arg_syntax.push(
+        ArgumentStruct::with_value(
+            "P",
+            "package-env",
+            "SETTINGS",
+            "Build packages with extra settings for this run, e.g --package-env \"www-client/chromium MAKEOPTS=-j2\". Can be given more than once",
+        )
+        .validated(
+            |overrides| buildenv::Override::parse_list(overrides).is_some(),
+            "a package and its settings, e.g \"www-client/chromium MAKEOPTS=-j2\"",
+        ),
+    );
+    arg_syntax.push(ArgumentStruct::from(
+        "q",
+        "quiet",
+        "Show only errors, prompts and the summary at the end",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "r",
+        "resume",
+        "Carry on with an emerge which stopped part way through the update, then the phases after it",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "R",
+        "rebuild-world",
+        "Rebuild every installed package in resumable chunks, e.g after a profile change, then exit",
     ));
     arg_syntax.push(ArgumentStruct::from(
         "s",
         "setup",
         "Set configuration options",
     ));
+    #[cfg(feature = "status-socket")]
+    arg_syntax.push(ArgumentStruct::from(
+        "S",
+        "status",
+        "Display the progress of a running update as JSON, then exit",
+    ));
     arg_syntax.push(ArgumentStruct::from(
         "t",
         "trim",
         "Perform an fstrim after the upgrade",
     ));
+    arg_syntax.push(ArgumentStruct::from(
+        "u",
+        "usepkgonly",
+        "Install only from binary packages, never building from source, and list the updates which have none",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "v",
+        "verbose",
+        "Show the full output of every command, including emerge's build output",
+    ));
+    arg_syntax.push(ArgumentStruct::from(
+        "w",
+        "watch-security",
+        "Fetch the security advisories and notify of newly affected installed packages, then exit",
+    ));
+    arg_syntax.push(ArgumentStruct::with_value(
+        "x",
+        "exclude",
+        "PACKAGE",
+        "Leave a package out of the update, e.g --exclude www-client/firefox. Can be given more than once",
+    ));
     arg_syntax.push(ArgumentStruct::from(
         "V",
         "version",
         "Display the program version",
     ));
 
-    // If this is not Gentoo Linux, exit with an error message
-    if let Err(error) = linux::check_distro("Gentoo") {
-        eprintln!("{error}");
-        process::exit(1);
+    // If this is not Gentoo Linux, exit with an error message. A Gentoo Prefix can be on any
+    // operating system
+    if !prefix::active() {
+        if let Err(error) = linux::check_distro("Gentoo") {
+            eprintln!("{error}");
+            ExitCode::Failed.exit();
+        }
+    } else if let Some(eprefix) = prefix::eprefix() {
+        println!(
+            "{} Updating the Gentoo Prefix in {}",
+            prompt::revchevrons(Color::Blue),
+            eprefix
+        );
     }
 
     // There is a configuration file for this program, by default in /etc/conf.d/gentup
-    // Load the saved config (or if no config file, request the user perform setup)
+    // Load the saved config (or if no config file, propose one to suit this system)
     //
-    let running_config = if Path::new(&CONFIG_FILE_PATH).exists() {
+    let running_config = if Path::new(config_file()).exists() {
         Config::load()
     } else {
-        println!(
-            "{} No configuration file found.",
-            prompt::revchevrons(Color::Yellow)
-        );
-        config::setup();
-        process::exit(1);
+        onboarding::run()
     };
+    prompt::set_answers(&running_config.answers);
+    tempfile::set_directory(&running_config.temp_dir);
+    tempfile::remove_on_signals();
 
     // Parse the command line arguments supplied by the user
     // The Result is either Ok or Err to indicate if the arguments were parsable according to the
@@ -114,165 +356,285 @@ fn main() {
         Err(error) => {
             // Command line arguments are incorrect - inform the user and exit
             eprintln!("{}", error);
-            process::exit(1);
+            ExitCode::ConfigError.exit();
         }
         Ok(arguments) => {
-            linux::clearscreen();
+            // Report on an update already in progress, if the user selected the --status option
+            #[cfg(feature = "status-socket")]
+            if arguments.get("status") {
+                status::query();
+                ExitCode::NothingToDo.exit();
+            }
+
+            // Display the merge statistics, if the user selected the --stats option
+            if arguments.get("stats") {
+                stats::show();
+                ExitCode::NothingToDo.exit();
+            }
+
+            // List the archived news items and where each was delivered, if the user selected
+            // the --news-history option
+            if arguments.get("news-history") {
+                newsarchive::show().exit();
+            }
+
+            // List what has changed since the system booted, or since a date, if the user
+            // selected the --since-boot or --since option
+            //
+            if arguments.get("since-boot") {
+                match stats::boot_time() {
+                    Some(booted) => stats::show_changes_since(booted, "the last boot"),
+                    None => {
+                        eprintln!(
+                            "{} Could not read the boot time from /proc/stat",
+                            prompt::revchevrons(Color::Red)
+                        );
+                        ExitCode::Failed.exit();
+                    }
+                }
+                ExitCode::NothingToDo.exit();
+            }
+            if let Some(date) = arguments.value("since") {
+                if let Some(since) = stats::parse_since(date) {
+                    stats::show_changes_since(since, date);
+                }
+                ExitCode::NothingToDo.exit();
+            }
+
+            // Audit the system's health, if the user selected the --doctor option
+            if arguments.get("doctor") {
+                doctor::run(&running_config).exit();
+            }
+
+            // Write a package inventory, if the user selected the --export option
+            if arguments.get("export") {
+                inventory::export(
+                    arguments.get("pending"),
+                    arguments.get("json"),
+                    arguments.value("license"),
+                );
+                ExitCode::NothingToDo.exit();
+            }
+
+            // Check for newly published security advisories affecting this system, without
+            // running an update, if the user selected the --watch-security option
+            //
+            if arguments.get("watch-security") {
+                glsa::watch(&running_config).exit();
+            }
+
+            // Predict what an update would involve, without changing anything, if the user
+            // selected the --estimate option
+            //
+            if arguments.get("estimate") {
+                estimate::show().exit();
+            }
+
+            // Report whether updates are pending, without changing anything, if the user selected
+            // the --check option
+            //
+            if arguments.get("check") {
+                portage::check_pending_updates().exit();
+            }
+
+            // --continue carries on from gentup's checkpoint, and --resume from emerge's own
+            // resume list, so only one of them makes sense
+            //
+            if arguments.get("continue") && arguments.get("resume") {
+                eprintln!(
+                    "{} --continue and --resume cannot be used together",
+                    prompt::revchevrons(Color::Red)
+                );
+                ExitCode::ConfigError.exit();
+            }
+
+            // Only one of --quiet and --verbose makes sense
+            //
+            if arguments.get("quiet") && arguments.get("verbose") {
+                eprintln!(
+                    "{} --quiet and --verbose cannot be used together",
+                    prompt::revchevrons(Color::Red)
+                );
+                ExitCode::ConfigError.exit();
+            }
+
+            // In JSON mode, stdout carries only events, so the screen is left alone
+            //
+            let options = RuntimeOptions::resolve(&running_config, &arguments);
+            if options.json {
+                if let Err(error) = events::enable_json() {
+                    eprintln!("Could not switch to JSON output - {}", error);
+                    ExitCode::Failed.exit();
+                }
+            }
+            if let Err(error) = prompt::set_verbosity(options.verbosity) {
+                eprintln!("Could not silence the output - {}", error);
+                ExitCode::Failed.exit();
+            }
+            if !options.json {
+                linux::clearscreen();
+                if linux::is_a_tty() {
+                    linux::watch_resize();
+                }
+            }
             println!("\nWelcome to the Gentoo Linux Updater v{}\n", VERSION);
 
             // Handle configuration setup if the user selected the --setup option
             if arguments.get("setup") {
                 config::setup();
-                process::exit(0);
+                ExitCode::NothingToDo.exit();
             }
 
-            // Inform the user of the behaviours read from the config file
-            if running_config.cleanup_default || arguments.get("cleanup") {
+            // Inform the user of the behaviours in effect, from the command line, environment and
+            // config file
+            //
+            if options.cleanup {
                 println!(
                     "{} Post-update cleanup is enabled",
                     prompt::revchevrons(Color::Green)
                 );
-                if running_config.trim_default || arguments.get("trim") {
+                if options.trim {
                     println!(
                         "{} Post-update filesystem trim is enabled",
                         prompt::revchevrons(Color::Green)
                     );
                 }
-            } else if running_config.trim_default || arguments.get("trim") {
+            } else if options.trim {
                 println!(
                     "{} Post-update filesystem trim is pending cleanup",
                     prompt::revchevrons(Color::Yellow)
                 );
             }
-            if running_config.background_default || arguments.get("background") {
+            if options.background {
                 println!(
                     "{} Background package downloading is enabled",
                     prompt::revchevrons(Color::Green)
                 );
             }
+            if options.offline {
+                println!(
+                    "{} Offline: nothing will be synced or downloaded",
+                    prompt::revchevrons(Color::Yellow)
+                );
+            }
+            if options.binary_only {
+                println!(
+                    "{} Binary only: nothing will be built from source",
+                    prompt::revchevrons(Color::Yellow)
+                );
+            }
+            if !options.exclude.is_empty() {
+                println!(
+                    "{} Excluded from the update: {}",
+                    prompt::revchevrons(Color::Yellow),
+                    options.exclude.join(" ")
+                );
+                portage::set_excluded(&options.exclude);
+            }
+            if options.dry_run {
+                dryrun::enable();
+                println!(
+                    "{} Dry run: the commands which would change the system are printed, not run",
+                    prompt::revchevrons(Color::Yellow)
+                );
+            }
+
+            // Update the hosts of a fleet over SSH, rather than this machine, if the user selected
+            // the --fleet option
+            //
+            #[cfg(feature = "fleet")]
+            if arguments.get("fleet") {
+                fleet::run(&running_config).exit();
+            }
+
+            // ==========
+            // PREFLIGHT
+            // ==========
+
+            // Check there is enough free disk space and inodes for the update to complete, rather
+            // than running out of space part way through a long build
+            //
+            preflight::check_disk_space(&running_config);
+
+            // Refuse to update onto read-only filesystems or degraded RAID arrays
+            //
+            preflight::check_filesystem_health();
+
+            // Warn about, or refuse to update onto, a disk reporting SMART errors
+            //
+            smart::check(&running_config);
 
             // =============
             // PREREQUSITES
             // =============
 
-            portage::check_and_install_deps(); // This call installs any missing dependencies of this program
+            // Take the options which would leave emerge waiting for input, or fight gentup's own,
+            // out of EMERGE_DEFAULT_OPTS for this run
+            //
+            emergeopts::neutralise(&running_config);
+
+            // On a binary-only host, even the prerequisites must come from binary packages
+            //
+            if options.binary_only {
+                binonly::require_binaries();
+            }
 
-            // Check that elogv is configured - elogv collects post-installation notes for package
-            // updates, so the user is notified about actions they need to take. If elogv is
-            // installed but not configured, this function call will configure elogv
+            // Install any missing tools which this program, or the features enabled in the
+            // config file, run
             //
-            portage::configure_elogv(&running_config);
+            requirements::install(&running_config, &options);
+
+            // Check that elog is configured - portage saves the post-installation notes for package
+            // updates, which gentup reads after the update so the user is notified about actions
+            // they need to take. If elog is not configured, this function call will configure it
+            //
+            portage::configure_elog();
 
             // If the user selected the --optional flag, check and install the optional packages.
             // This is mostly useful to get a newly installed bare-bones Gentoo install into a more
             // complete baseline state
             //
-            if arguments.get("optional") {
+            if options.optional && !options.offline {
                 portage::check_and_install_optional_packages();
             }
 
-            // Check if the last resync was too recent - if not, sync the portage tree
-            // or the user can force a sync anyway by using "gentup --force"
-            // The too recent logic is to avoid abusing the rsync.gentoo.org rotation which
-            // asks that users do not sync more than once per day
+            // Point portage at the installation in another directory, if GENTUP_ROOT is set. This
+            // comes after the prerequisites, which are the tools gentup itself runs on this system
             //
-            if arguments.get("force") || !portage::too_recent() {
-                portage::sync_package_tree();
-            }
-
-            // Update sys-apps/portage and sys-devel/gcc before any other packages
-            // sys-apps/portage is the Gentoo package manager and portage itself advises the user to
-            // update portage first
-            //
-            if portage::package_outdated("sys-apps/portage") {
-                portage::upgrade_package("sys-apps/portage");
-            }
-            if portage::package_outdated("sys-devel/gcc") {
-                portage::upgrade_package("sys-devel/gcc");
+            if let Some(root) = &options.root {
+                if let Err(error) = portage::check_target_root(root) {
+                    eprintln!("{} {}", prompt::revchevrons(Color::Red), error);
+                    ExitCode::ConfigError.exit();
+                }
+                println!(
+                    "{} Updating the Gentoo installation in {}",
+                    prompt::revchevrons(Color::Green),
+                    root
+                );
+                portage::set_target_root(root);
             }
 
-            // Present a list of packages to be updated to the screen
-            // If there are no packages pending updates, we can quit at this stage
-            // unless the user specifically asked for a cleanup to be run
+            // Run only the cleanup stages, after previewing them, if the user selected the
+            // --clean option
             //
-            let pending_updates = portage::get_pending_updates(
-                arguments.get("background") || running_config.background_default,
-            );
-            if !pending_updates && (!arguments.get("cleanup") && !running_config.cleanup_default) {
-                process::exit(0);
+            if arguments.get("clean") {
+                cleanup::run(&running_config, options.trim).exit();
             }
 
-            // Check the news - if there is news, email it to the user
+            // Rebuild the whole world set, or carry on with an unfinished rebuild, if the user
+            // selected the --rebuild-world option
             //
-            println!("{} Checking Gentoo news", prompt::chevrons(Color::Green));
-            portage::check_news(&running_config);
-
-            // ==================
-            // FULL SYSTEM UPDATE
-            // ==================
-
-            if pending_updates {
-                let _ = PackageManager::NoDryRun
-                    .update_all_packages()
-                    .exit_if_failed();
+            if arguments.get("rebuild-world") {
+                rebuild::run(&running_config).exit();
             }
 
-            // =================
-            // POST_UPDATE TASKS
-            // =================
-
-            portage::update_config_files(); // Handle updating package config files
-
-            // =======
-            // CLEANUP
-            // =======
-
-            // List and remove orphaned dependencies.
-            //
-            let (orphans, kernels) = PackageManager::DryRun.depclean(); // DryRun mode only lists orphaned deps
-            if orphans > 0 {
-                // To prevent the issue of depclean removing the currently running kernel immediately after a kernel upgrade
-                // check to see if the running kernel will be depcleaned
-                //
-                if kernels.contains(&linux::running_kernel()) {
-                    if arguments.get("cleanup") || running_config.cleanup_default {
-                        PackageManager::PreserveKernel.depclean(); // depcleans everything excluding old kernel packages
-                    }
-                    println!(
-                        "{} Preserving currently running kernel. Skipping cleanup",
-                        prompt::chevrons(Color::Green)
-                    );
-                    println!("{} All done!!!", prompt::chevrons(Color::Green));
-                    process::exit(0);
-                } else if arguments.get("cleanup") || running_config.cleanup_default
-                /* Change behaviour here - no longer force clean       || kernels.ne("") */
-                {
-                    PackageManager::AllPackages.depclean(); // depcleans everything
-                }
-            }
+            // ======
+            // UPDATE
+            // ======
 
-            // Check for broken Reverse dependencies
+            // Run the update phases: sync, toolchain, pretend, fetch, build, config and cleanup
             //
-            if arguments.get("cleanup") || running_config.cleanup_default {
-                if !PackageManager::DryRun.revdep_rebuild() {
-                    PackageManager::NoDryRun.revdep_rebuild();
-                }
-                portage::find_obsolete_configs(); // Find any obsolete portage configurations from removed packages
-                portage::clean_distfiles(); // Cleanup old distfiles otherwise these will grow indefinitely
-                portage::clean_old_kernels(); // Cleanup unused kernels from /usr/src, /boot, /lib/modules and the grub config
-
-                if arguments.get("trim") || running_config.trim_default {
-                    // A full update creates so many GB of temp files it warrants a trim, but only
-                    // if the user specifies --trim on the command line
-                    linux::call_fstrim();
-                }
-            } else {
-                println!(
-                    "{} Cleanup is disabled. Prolonged skipping of cleanup is not advised",
-                    prompt::chevrons(Color::Yellow)
-                );
-            }
-            println!("{} All done!!!", prompt::chevrons(Color::Green));
+            orchestrator::run(&running_config, &options).exit();
         }
     }
 }
@@ -0,0 +1,295 @@
+// make.conf audit
+// As part of "gentup --doctor", gentup reads /etc/portage/make.conf (or each file in it, if it is a directory) and
+// points out settings which commonly cost time or break updates, each with what to do about it:
+// more make jobs than the memory can feed (2GB per job), CPU_FLAGS_X86 never having been set,
+// EMERGE_DEFAULT_OPTS which fight the options gentup runs emerge with, and variables portage no
+// longer uses
+
+use crate::{emergeopts, linux, linux::OsCall, parallel, portage, prompt, Config, Prompt};
+use crossterm::style::Color;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+// Variables which portage has replaced, with what replaced them
+static DEPRECATED: [(&str, &str); 5] = [
+    ("SYNC", "sync-uri in /etc/portage/repos.conf"),
+    ("PORTDIR", "location in /etc/portage/repos.conf"),
+    (
+        "PORTDIR_OVERLAY",
+        "a repository entry in /etc/portage/repos.conf",
+    ),
+    ("USE_PYTHON", "PYTHON_TARGETS"),
+    (
+        "PORTAGE_ELOG_MAILURI",
+        "the elog checklist gentup emails after each update",
+    ),
+];
+
+// Define a struct to hold something found wrong, and what to do about it
+//
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub problem: String,
+    pub suggestion: String,
+}
+
+// Read make.conf, which may be a directory of files read in order
+//
+pub fn read_make_conf() -> String {
+    let path = portage::target_path("/etc/portage/make.conf");
+    if !Path::new(&path).is_dir() {
+        return fs::read_to_string(&path).unwrap_or_default();
+    }
+    let mut files: Vec<_> = fs::read_dir(&path)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    files.sort();
+    files
+        .iter()
+        .filter_map(|file| fs::read_to_string(file).ok())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Add settings to the end of make.conf, or when it is a directory, to a file of gentup's in it
+//
+pub fn append(settings: &str) -> std::io::Result<()> {
+    let path = portage::target_path("/etc/portage/make.conf");
+    let path = if Path::new(&path).is_dir() {
+        [&path, "/zz-gentup.conf"].concat()
+    } else {
+        path
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file)?;
+    write!(file, "{}", settings)
+}
+
+// The value of the last assignment of a variable, with any quotes removed
+//
+pub fn variable(contents: &str, name: &str) -> Option<String> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .rfind(|(assigned, _)| assigned.trim() == name)
+        .map(|(_, value)| {
+            value
+                .trim()
+                .trim_matches('"')
+                .trim_matches('\'')
+                .to_string()
+        })
+}
+
+// The number of jobs given in MAKEOPTS, e.g 8 from -j8 -l8, --jobs=8 or -j 8
+//
+pub fn make_jobs(makeopts: &str) -> Option<usize> {
+    let mut words = makeopts.split_whitespace();
+    while let Some(word) = words.next() {
+        let number = if word == "-j" || word == "--jobs" {
+            words.next()
+        } else {
+            word.strip_prefix("--jobs=")
+                .or_else(|| word.strip_prefix("-j"))
+        };
+        if let Some(jobs) = number.and_then(|number| number.parse().ok()) {
+            return Some(jobs);
+        }
+    }
+    None
+}
+
+// Audit the contents of make.conf, for a machine with the given CPUs and memory. x86 says whether
+// CPU_FLAGS_X86 applies, and cpu_flags_set whether it is set anywhere
+//
+pub fn audit(
+    contents: &str,
+    cpus: usize,
+    memory_mb: u64,
+    x86: bool,
+    cpu_flags_set: bool,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let tuning = parallel::tune(cpus, memory_mb);
+    match variable(contents, "MAKEOPTS").as_deref().and_then(make_jobs) {
+        Some(jobs) if jobs > tuning.make_jobs && (jobs as u64) * 2048 > memory_mb => {
+            findings.push(Finding {
+                problem: format!(
+                    "MAKEOPTS runs {} make jobs, which need about {}GB of memory, but there is {}MB",
+                    jobs,
+                    jobs * 2,
+                    memory_mb
+                ),
+                suggestion: format!(
+                    "Set MAKEOPTS=\"{}\" to leave 2GB for each job",
+                    tuning.makeopts()
+                ),
+            })
+        }
+        Some(_) => {}
+        None => findings.push(Finding {
+            problem: "MAKEOPTS does not set the number of make jobs".to_string(),
+            suggestion: format!("Set MAKEOPTS=\"{}\"", tuning.makeopts()),
+        }),
+    }
+    if x86 && !cpu_flags_set {
+        findings.push(Finding {
+            problem: "CPU_FLAGS_X86 is not set, so packages cannot use this CPU's instruction set extensions".to_string(),
+            suggestion: "Add the output of cpuid2cpuflags to /etc/portage/package.use as */* CPU_FLAGS_X86: ...".to_string(),
+        });
+    }
+    let default_opts = variable(contents, "EMERGE_DEFAULT_OPTS").unwrap_or_default();
+    for (option, reason) in emergeopts::conflicting(&emergeopts::split(&default_opts)) {
+        findings.push(Finding {
+            problem: format!("EMERGE_DEFAULT_OPTS includes {}, so {}", option, reason),
+            suggestion: format!("Remove {} from EMERGE_DEFAULT_OPTS", option),
+        });
+    }
+    for (name, replacement) in DEPRECATED {
+        if variable(contents, name).is_some() {
+            findings.push(Finding {
+                problem: format!("{} is no longer used by portage", name),
+                suggestion: format!("Remove {}, and use {} instead", name, replacement),
+            });
+        }
+    }
+    findings
+}
+
+// Whether CPU_FLAGS_X86 is set in make.conf or package.use
+//
+fn cpu_flags_set(contents: &str) -> bool {
+    if contents.contains("CPU_FLAGS_X86") {
+        return true;
+    }
+    let package_use = portage::target_path("/etc/portage/package.use");
+    let files: Vec<_> = if Path::new(&package_use).is_dir() {
+        fs::read_dir(&package_use)
+            .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+            .unwrap_or_default()
+    } else {
+        vec![Path::new(&package_use).to_path_buf()]
+    };
+    files.iter().any(|file| {
+        fs::read_to_string(file).is_ok_and(|contents| contents.contains("CPU_FLAGS_X86"))
+    })
+}
+
+// Run cpuid2cpuflags, installing it first if need be, and record its flags in package.use
+//
+fn set_cpu_flags() {
+    if portage::package_is_missing("app-portage/cpuid2cpuflags") {
+        let _ = OsCall::Interactive.execute(
+            "emerge --oneshot app-portage/cpuid2cpuflags",
+            "Installing cpuid2cpuflags",
+        );
+    }
+    let Ok((output, 0)) = OsCall::Quiet.execute("cpuid2cpuflags", "") else {
+        eprintln!(
+            "{} cpuid2cpuflags did not run",
+            prompt::revchevrons(Color::Red)
+        );
+        return;
+    };
+    let package_use = portage::target_path("/etc/portage/package.use");
+    let path = if Path::new(&package_use).is_dir() {
+        [&package_use, "/00cpu-flags"].concat()
+    } else {
+        package_use
+    };
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "*/* {}", output.trim()));
+    match written {
+        Ok(_) => println!(
+            "{} Added \"*/* {}\" to {}",
+            prompt::revchevrons(Color::Green),
+            output.trim(),
+            path
+        ),
+        Err(error) => eprintln!(
+            "{} Could not write {} - {}",
+            prompt::revchevrons(Color::Red),
+            path,
+            error
+        ),
+    }
+}
+
+// Whether CPU_FLAGS_X86 applies to this machine's architecture
+//
+fn is_x86() -> bool {
+    let arch = OsCall::Quiet
+        .execute("portageq envvar ARCH", "")
+        .map(|(output, _)| output.trim().to_string())
+        .unwrap_or_default();
+    arch == "amd64" || arch == "x86"
+}
+
+// Audit this machine's make.conf
+//
+pub fn findings(running_config: &Config) -> Vec<Finding> {
+    let contents = read_make_conf();
+    let cpus = std::thread::available_parallelism()
+        .map(|cpus| cpus.get())
+        .unwrap_or(1);
+    let flags_set = cpu_flags_set(&contents);
+    let mut findings = audit(&contents, cpus, parallel::memory_mb(), is_x86(), flags_set);
+    if running_config.auto_parallelism {
+        // gentup sets the jobs itself, so make.conf's are not used for updates
+        findings.retain(|finding| !finding.problem.starts_with("MAKEOPTS"));
+    }
+    findings
+}
+
+// Offer to set CPU_FLAGS_X86 with cpuid2cpuflags, when it applies and was never set
+//
+pub fn offer_cpu_flags() {
+    if is_x86()
+        && !cpu_flags_set(&read_make_conf())
+        && (linux::is_a_tty() || prompt::answered("cpuflags"))
+        && Prompt::AllowSkip
+            .askuser("cpuflags", "Run cpuid2cpuflags and set CPU_FLAGS_X86")
+            .is_some()
+    {
+        set_cpu_flags();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audits_make_conf() {
+        assert_eq!(make_jobs("-j8 -l8"), Some(8));
+        assert_eq!(make_jobs("--jobs=12"), Some(12));
+        assert_eq!(make_jobs("-l4 -j 6"), Some(6));
+        assert_eq!(make_jobs("-l4"), None);
+
+        let contents = "\
+COMMON_FLAGS=\"-O2 -pipe\"
+MAKEOPTS=\"-j32\"
+EMERGE_DEFAULT_OPTS=\"-av --quiet-build n\"
+PORTDIR=\"/usr/portage\"
+";
+        let problems: Vec<String> = audit(contents, 16, 16384, true, false)
+            .into_iter()
+            .map(|finding| finding.problem)
+            .collect();
+        assert_eq!(problems.len(), 5);
+        assert!(problems[0].starts_with("MAKEOPTS runs 32 make jobs"));
+        assert!(problems[1].starts_with("CPU_FLAGS_X86 is not set"));
+        assert!(problems[2].starts_with("EMERGE_DEFAULT_OPTS includes --ask"));
+        assert!(problems[3].starts_with("EMERGE_DEFAULT_OPTS includes --quiet-build=n"));
+        assert_eq!(problems[4], "PORTDIR is no longer used by portage");
+
+        let tidy = "MAKEOPTS=\"-j8 -l8\"\nCPU_FLAGS_X86=\"aes avx\"\n";
+        assert!(audit(tidy, 8, 32768, true, true).is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+// MQTT publishing
+// When mqtt_broker is set in the config file, gentup publishes each phase transition and the final
+// run report to the broker with mosquitto_pub from app-misc/mosquitto, for Home Assistant
+// dashboards and automations. Messages are JSON, published under the mqtt_topic prefix and the
+// host name, and retained so a dashboard shows the latest state as soon as it subscribes:
+//
+//   gentup/build1/phase   {"event":"phase_start","phase":"build"}
+//   gentup/build1/result  the run report, as POSTed to a webhook
+//
+// Broker credentials and TLS options are not held in the gentup config file. mosquitto_pub reads
+// them from /root/.config/mosquitto_pub, one option per line
+
+use crate::{linux::OsCall, prompt, tempfile::TempFile};
+use crossterm::style::Color;
+
+// Build the mosquitto_pub command line for a broker given as host or host:port. The message is
+// read from a file because the command line is split on whitespace
+//
+pub fn command_line(broker: &str, topic: &str, message_path: &str) -> String {
+    let (host, port) = broker.split_once(':').unwrap_or((broker, "1883"));
+    [
+        "mosquitto_pub -r -q 1 -h ",
+        host,
+        " -p ",
+        port,
+        " -t ",
+        topic,
+        " -f ",
+        message_path,
+    ]
+    .concat()
+}
+
+// Publish a retained message, warning rather than failing if the broker cannot be reached
+//
+pub fn publish(broker: &str, topic: &str, message: &str) {
+    let result = TempFile::create("json", message)
+        .map_err(|error| error.to_string())
+        .and_then(|message_file| {
+            OsCall::Quiet
+                .execute(&command_line(broker, topic, message_file.path()), "")
+                .map_err(|error| error.to_string())
+        });
+    match result {
+        Ok((_, 0)) => {}
+        Ok((output, _)) => eprintln!(
+            "{} Could not publish to {} on {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            topic,
+            broker,
+            output.trim()
+        ),
+        Err(error) => eprintln!(
+            "{} Could not publish to {} on {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            topic,
+            broker,
+            error
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_command_line() {
+        assert_eq!(
+            command_line("mqtt.lan", "gentup/build1/phase", "/tmp/message"),
+            "mosquitto_pub -r -q 1 -h mqtt.lan -p 1883 -t gentup/build1/phase -f /tmp/message"
+        );
+        assert_eq!(
+            command_line("10.0.0.5:8883", "gentup/build1/result", "/tmp/message"),
+            "mosquitto_pub -r -q 1 -h 10.0.0.5 -p 8883 -t gentup/build1/result -f /tmp/message"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+// Gentoo news
+// Reads the unread news items straight from the Gentoo repository, rather than through eselect
+// news, so that each item can be handled on its own. Portage lists the unread items in
+// /var/lib/gentoo/news/news-gentoo.unread when the tree is synced, and each item is a file such as
+// metadata/news/2024-03-22-new-23-profiles/2024-03-22-new-23-profiles.en.txt in the repository,
+// with headers and then the text. The Display-If- headers (GLEP 42) restrict an item to systems
+// with a package installed, a keyword accepted or a profile selected, and items which do not apply
+// to this system are left out
+//
+//   Title: OpenSSL 3.0 upgrade
+//   Posted: 2024-01-02
+//   Display-If-Installed: <dev-libs/openssl-3
+//   Display-If-Profile: default/linux/amd64/*
+
+use crate::{linux::OsCall, portage, prefix};
+use std::fs;
+
+pub static NEWS_PATH: &str = "/var/db/repos/gentoo/metadata/news";
+pub static UNREAD_PATH: &str = "/var/lib/gentoo/news/news-gentoo.unread";
+
+// Define a struct to hold one news item
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct NewsItem {
+    pub name: String, // The directory name, e.g 2024-03-22-new-23-profiles
+    pub title: String,
+    pub posted: String,
+    pub installed: Vec<String>, // Display-If-Installed atoms
+    pub keywords: Vec<String>,  // Display-If-Keyword values
+    pub profiles: Vec<String>,  // Display-If-Profile values
+    pub body: String,
+}
+
+// Define a struct to hold the facts about this system which news items are filtered by
+//
+pub struct System<'a> {
+    pub arch: String,
+    pub profile: String, // Relative to the profiles directory, e.g default/linux/amd64/23.0
+    pub has_version: &'a dyn Fn(&str) -> bool,
+}
+
+impl NewsItem {
+    // Parse a news item file. The headers end at the first blank line
+    //
+    pub fn parse(name: &str, contents: &str) -> NewsItem {
+        let mut item = NewsItem {
+            name: name.to_string(),
+            ..NewsItem::default()
+        };
+        let mut lines = contents.lines();
+        for line in lines.by_ref() {
+            if line.trim().is_empty() {
+                break;
+            }
+            let Some((header, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match header {
+                "Title" => item.title = value,
+                "Posted" => item.posted = value,
+                "Display-If-Installed" => item.installed.push(value),
+                "Display-If-Keyword" => item.keywords.push(value),
+                "Display-If-Profile" => item.profiles.push(value),
+                _ => {}
+            }
+        }
+        item.body = lines.collect::<Vec<&str>>().join("\n").trim().to_string();
+        item
+    }
+
+    // An item applies if, for each kind of Display-If- header it has, any one of them matches
+    //
+    pub fn applies_to(&self, system: &System) -> bool {
+        let profile_matches = |profile: &String| match profile.strip_suffix("/*") {
+            Some(prefix) => system.profile.starts_with(&[prefix, "/"].concat()),
+            None => *profile == system.profile,
+        };
+        (self.installed.is_empty() || self.installed.iter().any(|atom| (system.has_version)(atom)))
+            && (self.keywords.is_empty() || self.keywords.contains(&system.arch))
+            && (self.profiles.is_empty() || self.profiles.iter().any(profile_matches))
+    }
+}
+
+// The selected profile, from the make.profile symbolic link into the repository's profiles
+//
+fn current_profile() -> String {
+    fs::canonicalize(portage::target_path("/etc/portage/make.profile"))
+        .map(|path| {
+            let path = path.to_string_lossy().to_string();
+            match path.split_once("/profiles/") {
+                Some((_, profile)) => profile.to_string(),
+                None => path,
+            }
+        })
+        .unwrap_or_default()
+}
+
+fn has_version(atom: &str) -> bool {
+    let root = portage::portageq_root();
+    matches!(
+        OsCall::Quiet.execute(&["portageq has_version ", &root, " ", atom].concat(), ""),
+        Ok((_, 0))
+    )
+}
+
+// The unread news items which apply to this system, oldest first
+//
+pub fn unread() -> Vec<NewsItem> {
+    let arch = OsCall::Quiet
+        .execute("portageq envvar ARCH", "")
+        .map(|(output, _)| output.trim().to_string())
+        .unwrap_or_default();
+    let system = System {
+        arch,
+        profile: current_profile(),
+        has_version: &has_version,
+    };
+    let mut items: Vec<NewsItem> = fs::read_to_string(portage::target_path(UNREAD_PATH))
+        .unwrap_or_default()
+        .lines()
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let path = format!("{}/{}/{}.en.txt", prefix::path(NEWS_PATH), name, name);
+            fs::read_to_string(path)
+                .ok()
+                .map(|contents| NewsItem::parse(name, &contents))
+        })
+        .filter(|item| item.applies_to(&system))
+        .collect();
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    items
+}
+
+// Format news items as the text of an email
+//
+pub fn to_text(items: &[NewsItem]) -> String {
+    let mut text = String::new();
+    for item in items {
+        text = text
+            + &item.title
+            + "\n"
+            + &"=".repeat(item.title.chars().count())
+            + "\nPosted: "
+            + &item.posted
+            + "\n\n"
+            + &item.body
+            + "\n\n";
+    }
+    text
+}
+
+// Mark a news item as read, once it has been delivered, by taking it off the unread list as
+// eselect news does
+//
+pub fn mark_read(item: &NewsItem) {
+    let path = portage::target_path(UNREAD_PATH);
+    if let Ok(contents) = fs::read_to_string(&path) {
+        let remaining: String = contents
+            .lines()
+            .filter(|name| name.trim() != item.name)
+            .map(|name| [name, "\n"].concat())
+            .collect();
+        let _ = fs::write(&path, remaining);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ITEM: &str = "\
+Title: OpenSSL 3.0 upgrade
+Author: A Developer <dev@gentoo.org>
+Posted: 2024-01-02
+Revision: 1
+News-Item-Format: 2.0
+Display-If-Installed: <dev-libs/openssl-3
+Display-If-Installed: dev-libs/libressl
+Display-If-Profile: default/linux/amd64/*
+
+Systems still on OpenSSL 1.1 need to rebuild
+their dependent packages.
+";
+
+    #[test]
+    fn parses_and_filters_news() {
+        let item = NewsItem::parse("2024-01-02-openssl", ITEM);
+        assert_eq!(item.title, "OpenSSL 3.0 upgrade");
+        assert_eq!(item.posted, "2024-01-02");
+        assert_eq!(
+            item.installed,
+            vec!["<dev-libs/openssl-3", "dev-libs/libressl"]
+        );
+        assert_eq!(
+            item.body,
+            "Systems still on OpenSSL 1.1 need to rebuild\ntheir dependent packages."
+        );
+
+        fn installed(atom: &str) -> bool {
+            atom == "dev-libs/libressl"
+        }
+        fn not_installed(_: &str) -> bool {
+            false
+        }
+        let system = |profile: &str, has_version: &'static dyn Fn(&str) -> bool| System {
+            arch: "amd64".to_string(),
+            profile: profile.to_string(),
+            has_version,
+        };
+        assert!(item.applies_to(&system("default/linux/amd64/23.0", &installed)));
+        assert!(!item.applies_to(&system("default/linux/amd64/23.0", &not_installed)));
+        assert!(!item.applies_to(&system("default/linux/arm64/23.0", &installed)));
+        assert!(NewsItem::parse("unconditional", "Title: All\n\nText")
+            .applies_to(&system("default/linux/arm64/23.0", &not_installed)));
+        assert!(to_text(&[item])
+            .starts_with("OpenSSL 3.0 upgrade\n===================\nPosted: 2024-01-02\n\n"));
+    }
+}
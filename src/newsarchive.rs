@@ -0,0 +1,248 @@
+// News archive
+// Each news item gentup delivers is kept in /var/lib/gentup/news, with its full text and a record
+// of every delivery: when it was emailed and to which address, when it was displayed at the
+// terminal and to which user, and when it was left unread. "gentup --news-history" lists what was
+// delivered, to whom and when, for audits. One file per item, named after it, e.g
+//
+//   Title: OpenSSL 3.0 upgrade
+//   Posted: 2024-01-02
+//   Delivered: 1712312100 email root@example.com
+//   Delivered: 1712398500 terminal alice
+//
+//   Systems still on OpenSSL 1.1 need to rebuild
+//   their dependent packages.
+
+use crate::{config::state_dir, exitcode::ExitCode, news::NewsItem, prompt, report, stats};
+use crossterm::style::Color;
+use std::{env, fmt, fs};
+
+// Define an enum to hold how a news item was delivered
+//
+#[derive(Clone, Debug, PartialEq)]
+pub enum Method {
+    Email(String),    // Emailed to this address
+    Terminal(String), // Displayed at the terminal to this user
+    Unread,           // Listed, but left unread
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Method::Email(address) => write!(f, "email {}", address),
+            Method::Terminal(user) => write!(f, "terminal {}", user),
+            Method::Unread => write!(f, "unread"),
+        }
+    }
+}
+
+// Define a struct to hold a news item as archived
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct ArchivedItem {
+    pub name: String,
+    pub title: String,
+    pub posted: String,
+    pub deliveries: Vec<(u64, Method)>,
+    pub body: String,
+}
+
+impl ArchivedItem {
+    // Parse an archived item. The headers end at the first blank line
+    //
+    pub fn parse(name: &str, contents: &str) -> ArchivedItem {
+        let mut item = ArchivedItem {
+            name: name.to_string(),
+            ..ArchivedItem::default()
+        };
+        let mut lines = contents.lines();
+        for line in lines.by_ref() {
+            if line.trim().is_empty() {
+                break;
+            }
+            let Some((header, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match header {
+                "Title" => item.title = value.to_string(),
+                "Posted" => item.posted = value.to_string(),
+                "Delivered" => {
+                    let mut fields = value.splitn(3, ' ');
+                    let Some(time) = fields.next().and_then(|time| time.parse().ok()) else {
+                        continue;
+                    };
+                    let kind = fields.next();
+                    let recipient = fields.next().unwrap_or_default().to_string();
+                    let method = match kind {
+                        Some("email") => Method::Email(recipient),
+                        Some("terminal") => Method::Terminal(recipient),
+                        _ => Method::Unread,
+                    };
+                    item.deliveries.push((time, method));
+                }
+                _ => {}
+            }
+        }
+        item.body = lines.collect::<Vec<&str>>().join("\n");
+        item
+    }
+
+    // Whether the item has reached a reader, by email or at the terminal
+    //
+    pub fn read(&self) -> bool {
+        self.deliveries
+            .iter()
+            .any(|(_, method)| *method != Method::Unread)
+    }
+}
+
+impl fmt::Display for ArchivedItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Posted: {}", self.posted)?;
+        for (time, method) in &self.deliveries {
+            writeln!(f, "Delivered: {} {}", time, method)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", self.body)
+    }
+}
+
+fn archive_path(name: &str) -> String {
+    [state_dir(), "/news/", name].concat()
+}
+
+// The user at the terminal, who news displayed there was delivered to
+//
+pub fn terminal_user() -> String {
+    ["SUDO_USER", "USER"]
+        .iter()
+        .find_map(|variable| env::var(variable).ok().filter(|user| !user.is_empty()))
+        .unwrap_or("root".to_string())
+}
+
+// Record the delivery of a news item, archiving its text the first time it is delivered. An
+// item left unread on several runs in a row is recorded once
+//
+pub fn record(item: &NewsItem, method: Method) {
+    let path = archive_path(&item.name);
+    let mut archived = match fs::read_to_string(&path) {
+        Ok(contents) => ArchivedItem::parse(&item.name, &contents),
+        Err(_) => ArchivedItem {
+            name: item.name.clone(),
+            title: item.title.clone(),
+            posted: item.posted.clone(),
+            deliveries: Vec::new(),
+            body: item.body.clone(),
+        },
+    };
+    if method == Method::Unread
+        && archived
+            .deliveries
+            .last()
+            .is_some_and(|(_, last)| *last == Method::Unread)
+    {
+        return;
+    }
+    archived.deliveries.push((report::now(), method));
+    if let Err(error) = fs::create_dir_all([state_dir(), "/news"].concat())
+        .and_then(|_| fs::write(&path, archived.to_string()))
+    {
+        eprintln!(
+            "{} Could not archive the news item {}: {}",
+            prompt::revchevrons(Color::Yellow),
+            item.name,
+            error
+        );
+    }
+}
+
+// Every archived news item, oldest first
+//
+pub fn load() -> Vec<ArchivedItem> {
+    let mut items: Vec<ArchivedItem> = fs::read_dir([state_dir(), "/news"].concat())
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    fs::read_to_string(entry.path())
+                        .ok()
+                        .map(|contents| ArchivedItem::parse(&name, &contents))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    items
+}
+
+// List the archived news items and their deliveries, for gentup --news-history
+//
+pub fn show() -> ExitCode {
+    let items = load();
+    if items.is_empty() {
+        println!(
+            "{} No news items have been delivered yet",
+            prompt::revchevrons(Color::Blue)
+        );
+        return ExitCode::NothingToDo;
+    }
+    println!(
+        "{} {} news item(s) delivered, with their text kept in {}/news:\n",
+        prompt::revchevrons(Color::Green),
+        items.len(),
+        state_dir()
+    );
+    for item in &items {
+        println!(
+            "{}  {} [{}]",
+            item.posted,
+            item.title,
+            if item.read() { "read" } else { "unread" }
+        );
+        for (time, method) in &item.deliveries {
+            let delivery = match method {
+                Method::Email(address) => ["emailed to ", address].concat(),
+                Method::Terminal(user) => ["displayed at the terminal to ", user].concat(),
+                Method::Unread => "left unread".to_string(),
+            };
+            println!("    {}  {}", stats::local_time(*time), delivery);
+        }
+    }
+    ExitCode::NothingToDo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_news_deliveries() {
+        let archived = "\
+Title: OpenSSL 3.0 upgrade
+Posted: 2024-01-02
+Delivered: 1712312100 unread
+Delivered: 1712398500 email root@example.com
+
+Systems still on OpenSSL 1.1 need to rebuild
+their dependent packages.
+";
+        let mut item = ArchivedItem::parse("2024-01-02-openssl", archived);
+        assert_eq!(item.title, "OpenSSL 3.0 upgrade");
+        assert_eq!(
+            item.deliveries,
+            vec![
+                (1712312100, Method::Unread),
+                (1712398500, Method::Email("root@example.com".to_string()))
+            ]
+        );
+        assert!(item.read());
+        assert_eq!(item.to_string(), archived);
+
+        item.deliveries.truncate(1);
+        assert!(!item.read());
+        item.deliveries
+            .push((1712400000, Method::Terminal("alice".to_string())));
+        assert!(ArchivedItem::parse("2024-01-02-openssl", &item.to_string()).read());
+    }
+}
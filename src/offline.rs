@@ -0,0 +1,105 @@
+// Offline updates
+// "gentup --offline" updates without touching the network, e.g on a laptop which fetched the
+// sources at the office and builds on the train. The package tree is not synced, nothing is
+// downloaded, and only the pending updates whose distfiles are already in DISTDIR and match their
+// Manifest are built. The rest are left for a later run, as with --max-packages. Whether a
+// package's distfiles are all present is asked of portage itself, by running its fetch with a
+// download command which always fails: files already present are checked against the Manifest,
+// and the fetch only succeeds if none had to be downloaded
+//
+//   FETCHCOMMAND=/bin/false RESUMECOMMAND=/bin/false emerge --fetchonly --nodeps =sys-libs/zlib-1.3.1
+
+use crate::{atom::Package, prompt};
+use crossterm::style::Color;
+use std::{
+    env,
+    process::{Command, Stdio},
+};
+
+// The download command portage is given while offline
+static NO_DOWNLOAD: &str = "/bin/false";
+
+// Whether every distfile of a package is present and verified
+//
+pub fn distfiles_present(package: &Package) -> bool {
+    Command::new("emerge")
+        .args([
+            "--fetchonly",
+            "--nodeps",
+            &["=", &package.to_string()].concat(),
+        ])
+        .env("FETCHCOMMAND", NO_DOWNLOAD)
+        .env("RESUMECOMMAND", NO_DOWNLOAD)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// Split the pending updates into those which can be built offline and those which cannot,
+// keeping emerge's build order in each
+//
+pub fn split_buildable(
+    packages: Vec<Package>,
+    present: impl Fn(&Package) -> bool,
+) -> (Vec<Package>, Vec<Package>) {
+    packages.into_iter().partition(|package| present(package))
+}
+
+// Keep only the pending updates which can be built without downloading anything. Returns the
+// number left for a later run
+//
+pub fn select(pending_updates: &mut Vec<Package>) -> usize {
+    let (buildable, missing) = split_buildable(std::mem::take(pending_updates), distfiles_present);
+    *pending_updates = buildable;
+    if missing.is_empty() {
+        println!(
+            "{} The sources of every pending update are already downloaded",
+            prompt::revchevrons(Color::Blue)
+        );
+        return 0;
+    }
+    println!(
+        "{} Offline: building {} pending updates, and leaving {} whose sources are not downloaded for later:",
+        prompt::revchevrons(Color::Yellow),
+        pending_updates.len(),
+        missing.len()
+    );
+    for package in &missing {
+        println!("    {}", package);
+    }
+    missing.len()
+}
+
+// Stop portage downloading anything for the rest of this run
+//
+pub fn disable_downloads() {
+    env::set_var("FETCHCOMMAND", NO_DOWNLOAD);
+    env::set_var("RESUMECOMMAND", NO_DOWNLOAD);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defers_undownloaded_packages() {
+        let packages: Vec<Package> = [
+            "sys-libs/zlib-1.3.1",
+            "dev-lang/rust-1.77.1",
+            "app-editors/vim-9.1.0",
+        ]
+        .iter()
+        .map(|package| package.parse().unwrap())
+        .collect();
+        let (buildable, missing) = split_buildable(packages, |package| package.name != "rust");
+        assert_eq!(
+            buildable
+                .iter()
+                .map(|package| package.to_string())
+                .collect::<Vec<_>>(),
+            vec!["sys-libs/zlib-1.3.1", "app-editors/vim-9.1.0"]
+        );
+        assert_eq!(missing[0].to_string(), "dev-lang/rust-1.77.1");
+    }
+}
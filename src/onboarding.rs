@@ -0,0 +1,213 @@
+// First run onboarding
+// When there is no configuration file, rather than writing the defaults and exiting, gentup looks
+// at the system and proposes settings to suit it, each with the reason it was chosen:
+//
+//   - solid state storage under / is trimmed after cleanup, spinning disks are not
+//   - a laptop, going by its battery, waits for mains power and keeps the CPU cool, while a server
+//     cleans up after every update
+//   - the make and emerge jobs are set from the CPUs and memory
+//   - without a mail transport, the emailed reports cannot be delivered, which is pointed out
+//   - with a binhost configured, a small machine is pointed at gentup --usepkgonly
+//
+// The user can accept the settings or edit them first, and then carry straight on into the first
+// update. Without a terminal, the proposed settings are saved for review, and gentup exits
+
+use crate::{
+    binonly,
+    config::{config_file, Config},
+    exitcode::ExitCode,
+    linux::{self, OsCall},
+    parallel, prompt, rotational, Prompt,
+};
+use crossterm::style::Color;
+use std::{fs, path::Path, thread};
+
+// Define a struct to hold what was found out about the system
+//
+#[derive(Debug)]
+pub struct System {
+    pub ssd: Option<bool>, // Whether / is on solid state storage, if that could be told
+    pub laptop: bool,      // Whether the system has a battery
+    pub cpus: usize,
+    pub memory_mb: u64,
+    pub mta: bool,     // Whether a mail transport provides /usr/sbin/sendmail
+    pub binhost: bool, // Whether a binhost is configured for portage
+}
+
+impl System {
+    // Look at this system
+    //
+    pub fn detect() -> System {
+        let ssd = linux::mount_for("/")
+            .and_then(|root| rotational::device_name(&root.device))
+            .and_then(|device| rotational::is_rotational(&device))
+            .map(|rotational| !rotational);
+        let laptop = fs::read_dir("/sys/class/power_supply")
+            .map(|supplies| {
+                supplies.flatten().any(|supply| {
+                    fs::read_to_string(supply.path().join("type"))
+                        .is_ok_and(|kind| kind.trim() == "Battery")
+                })
+            })
+            .unwrap_or(false);
+        System {
+            ssd,
+            laptop,
+            cpus: thread::available_parallelism()
+                .map(|cpus| cpus.get())
+                .unwrap_or(1),
+            memory_mb: parallel::memory_mb(),
+            mta: Path::new("/usr/sbin/sendmail").exists(),
+            binhost: binonly::binhost_configured(),
+        }
+    }
+}
+
+// Propose the settings for a system. Returns them with the reason for each, and notes about what
+// the settings cannot fix
+//
+pub fn propose(system: &System) -> (Config, Vec<String>) {
+    let mut config = Config::build_default();
+    let mut reasons = Vec::new();
+    match system.ssd {
+        Some(true) => {
+            config.trim_default = true;
+            reasons.push("trim_default: true - / is on solid state storage".to_string());
+        }
+        Some(false) => reasons.push("trim_default: false - / is on a spinning disk".to_string()),
+        None => {}
+    }
+    if system.laptop {
+        config.battery_minimum = 50;
+        config.temperature_limit = 90;
+        config.load_limit = system.cpus as f32;
+        reasons.push(
+            "battery_minimum: 50 - this is a laptop, so builds wait for mains power".to_string(),
+        );
+        reasons.push("temperature_limit: 90 - builds pause while the CPU runs hot".to_string());
+        reasons.push(format!(
+            "load_limit: {} - builds wait while the laptop is busy",
+            config.load_limit
+        ));
+    } else {
+        config.battery_minimum = 0;
+        config.cleanup_default = true;
+        reasons.push("battery_minimum: 0 - there is no battery".to_string());
+        reasons.push(
+            "cleanup_default: true - orphans and old files are cleaned up after each update"
+                .to_string(),
+        );
+    }
+    config.auto_parallelism = true;
+    let tuning = parallel::tune(system.cpus, system.memory_mb);
+    reasons.push(format!(
+        "auto_parallelism: true - {} CPU(s) and {}MB of memory suit MAKEOPTS=\"{}\" and{}",
+        system.cpus,
+        system.memory_mb,
+        tuning.makeopts(),
+        tuning.emerge_options()
+    ));
+    if !system.mta {
+        reasons.push(
+            "No mail transport is installed, so the update reports emailed to root@localhost will not be delivered until one is, e.g mail-mta/nullmailer"
+                .to_string(),
+        );
+    }
+    if system.binhost && (system.cpus <= 2 || system.memory_mb < 4096) {
+        reasons.push(
+            "A binhost is configured, and this is a small machine, so consider updating with gentup --usepkgonly, which never builds from source"
+                .to_string(),
+        );
+    }
+    (config, reasons)
+}
+
+// Propose, save and return the configuration on the first run
+//
+pub fn run() -> Config {
+    let system = System::detect();
+    let (config, reasons) = propose(&system);
+    println!(
+        "{} No configuration file found. These settings are suggested for this system:\n",
+        prompt::revchevrons(Color::Yellow)
+    );
+    for reason in &reasons {
+        println!("    {}", reason);
+    }
+    println!();
+    if !linux::is_a_tty() {
+        config.save();
+        println!(
+            "{} The suggested settings are saved in {}. Review them, then run gentup again",
+            prompt::revchevrons(Color::Yellow),
+            config_file()
+        );
+        ExitCode::ConfigError.exit();
+    }
+    loop {
+        let answer = Prompt::Options.askuser(
+            "onboarding",
+            "Select a to accept these settings, e to edit them first, or q to quit [a|e|q]",
+        );
+        match answer.as_deref() {
+            Some("a\n") => {
+                config.save();
+                break;
+            }
+            Some("e\n") => {
+                config.save();
+                let _ = OsCall::Interactive
+                    .execute(&["vi ", config_file()].concat(), "Launching editor");
+                break;
+            }
+            _ => continue,
+        }
+    }
+    let _ = Prompt::PressReturn.askuser(
+        "onboarding",
+        &[
+            "The settings are saved in ",
+            config_file(),
+            ". Carry on into the first update",
+        ]
+        .concat(),
+    );
+    Config::load()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suits_settings_to_the_system() {
+        let laptop = System {
+            ssd: Some(true),
+            laptop: true,
+            cpus: 8,
+            memory_mb: 16384,
+            mta: true,
+            binhost: false,
+        };
+        let (config, reasons) = propose(&laptop);
+        assert!(config.trim_default && config.auto_parallelism && !config.cleanup_default);
+        assert_eq!(config.battery_minimum, 50);
+        assert_eq!(config.load_limit, 8.0);
+        assert_eq!(reasons.len(), 5);
+
+        let server = System {
+            ssd: Some(false),
+            laptop: false,
+            cpus: 2,
+            memory_mb: 2048,
+            mta: false,
+            binhost: true,
+        };
+        let (config, reasons) = propose(&server);
+        assert!(!config.trim_default && config.cleanup_default);
+        assert_eq!(config.battery_minimum, 0);
+        assert!(reasons[3].starts_with("auto_parallelism: true - 2 CPU(s) and 2048MB"));
+        assert!(reasons[4].starts_with("No mail transport"));
+        assert!(reasons[5].contains("--usepkgonly"));
+    }
+}
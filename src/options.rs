@@ -0,0 +1,221 @@
+// Runtime options
+// Several behaviours can be chosen on the command line, in the environment or in the config file.
+// These are resolved once, at startup, into a RuntimeOptions struct which the rest of the program
+// consults. The precedence, highest first, is:
+//
+//   1. A command line switch, e.g --cleanup
+//   2. An environment variable, e.g GENTUP_CLEANUP=0 or GENTUP_CLEANUP=1
+//   3. The config file, e.g cleanup_default: true
+//
+// The command line switches can only turn a behaviour on, so the environment is the way to turn
+// off, for one run, a behaviour which the config file turns on. GENTUP_ROOT=<directory> updates
+// the Gentoo installation in that directory rather than the running system, and
+// GENTUP_MAX_PACKAGES=<number> does the same as --max-packages. GENTUP_OFFLINE=1 is the same as
+// --offline, GENTUP_USEPKGONLY=1 the same as --usepkgonly and GENTUP_DRY_RUN=1 the same as
+// --dry-run. --package-env, which can be given more than once, gives build settings for
+// particular packages, added to the package_env lines of the config file. --exclude, which can
+// also be given more than once, leaves a package out of the update, as does each of the packages
+// in GENTUP_EXCLUDE, separated by spaces.
+// GENTUP_QUIET=1 and GENTUP_VERBOSE=1 are the same as --quiet and --verbose
+
+use crate::{
+    args::{ArgCheck, Search},
+    buildenv::Override,
+    prompt::Verbosity,
+    Config,
+};
+use std::env;
+
+// Define a struct to hold the behaviours in effect for this run
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct RuntimeOptions {
+    pub cleanup: bool,               // Perform cleanup tasks after the update
+    pub trim: bool,                  // Perform an fstrim after the cleanup
+    pub background: bool,            // Fetch sources in the background during the update
+    pub force: bool,                 // Sync even if the last sync was too recent
+    pub optional: bool,              // Install the optional packages
+    pub continue_run: bool,          // Continue an interrupted update from its checkpoint
+    pub resume: bool,                // Carry on an interrupted emerge with emerge --resume
+    pub json: bool,                  // Write progress as JSON events
+    pub root: Option<String>,        // Update the Gentoo installation in this directory instead
+    pub max_packages: Option<usize>, // Build at most this many of the pending updates
+    pub offline: bool,               // Build only what has been downloaded, touching no network
+    pub binary_only: bool,           // Install only from binary packages, never building
+    pub package_env: Vec<Override>,  // Build settings for particular packages, for this run
+    pub dry_run: bool,               // Print the commands which would change the system instead
+    pub exclude: Vec<String>,        // Packages left out of the update
+    pub verbosity: Verbosity,        // How much is shown at the terminal
+}
+
+// Interpret the value of an environment variable as a switch
+fn env_switch(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+impl RuntimeOptions {
+    // Resolve the options from the command line, the process environment and the config file
+    //
+    pub fn resolve(running_config: &Config, arguments: &ArgCheck) -> Self {
+        RuntimeOptions::resolve_with(running_config, arguments, |name| env::var(name).ok())
+    }
+
+    // As resolve, but with the environment supplied by the caller
+    //
+    pub fn resolve_with(
+        running_config: &Config,
+        arguments: &ArgCheck,
+        environment: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let option = |flag: &str, variable: &str, default: bool| {
+            if arguments.get(flag) {
+                return true;
+            }
+            environment(variable)
+                .and_then(|value| env_switch(&value))
+                .unwrap_or(default)
+        };
+        RuntimeOptions {
+            cleanup: option("cleanup", "GENTUP_CLEANUP", running_config.cleanup_default),
+            trim: option("trim", "GENTUP_TRIM", running_config.trim_default),
+            background: option(
+                "background",
+                "GENTUP_BACKGROUND",
+                running_config.background_default,
+            ),
+            force: option("force", "GENTUP_FORCE", false),
+            optional: option("optional", "GENTUP_OPTIONAL", false),
+            continue_run: arguments.get("continue"),
+            resume: arguments.get("resume"),
+            json: option("json", "GENTUP_JSON", false),
+            root: environment("GENTUP_ROOT").filter(|root| !root.is_empty() && root != "/"),
+            max_packages: arguments
+                .value("max-packages")
+                .map(String::from)
+                .or_else(|| environment("GENTUP_MAX_PACKAGES"))
+                .and_then(|value| value.trim().parse().ok())
+                .filter(|max| *max > 0),
+            offline: option("offline", "GENTUP_OFFLINE", false),
+            binary_only: option("usepkgonly", "GENTUP_USEPKGONLY", false),
+            package_env: arguments
+                .values("package-env")
+                .into_iter()
+                .filter_map(Override::parse_list)
+                .flatten()
+                .collect(),
+            dry_run: option("dry-run", "GENTUP_DRY_RUN", false),
+            exclude: environment("GENTUP_EXCLUDE")
+                .unwrap_or_default()
+                .split_whitespace()
+                .chain(arguments.values("exclude"))
+                .map(String::from)
+                .collect(),
+            // A switch on the command line outranks the other in the environment
+            verbosity: if arguments.get("verbose") {
+                Verbosity::Verbose
+            } else if option("quiet", "GENTUP_QUIET", false) {
+                Verbosity::Quiet
+            } else if option("verbose", "GENTUP_VERBOSE", false) {
+                Verbosity::Verbose
+            } else {
+                Verbosity::Normal
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ArgumentStruct;
+
+    fn arguments(set: &[&str]) -> ArgCheck {
+        let mut arguments: ArgCheck = [
+            "cleanup",
+            "trim",
+            "background",
+            "force",
+            "continue",
+            "resume",
+            "quiet",
+            "verbose",
+        ]
+        .iter()
+        .map(|long| ArgumentStruct::from("", long, ""))
+        .collect();
+        for flag in set {
+            arguments.setflag_from_long(flag.to_string());
+        }
+        arguments
+    }
+
+    #[test]
+    fn command_line_then_environment_then_config() {
+        let mut running_config = Config::build_default();
+        running_config.cleanup_default = true;
+        running_config.trim_default = true;
+
+        // The config file alone
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), |_| None);
+        assert!(options.cleanup && options.trim && !options.background);
+
+        // The environment overrides the config file, in either direction
+        let environment = |name: &str| match name {
+            "GENTUP_TRIM" => Some("0".to_string()),
+            "GENTUP_BACKGROUND" => Some("yes".to_string()),
+            "GENTUP_CLEANUP" => Some("nonsense".to_string()),
+            _ => None,
+        };
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), environment);
+        assert!(options.cleanup && !options.trim && options.background);
+
+        // The command line overrides the environment
+        let options =
+            RuntimeOptions::resolve_with(&running_config, &arguments(&["--trim"]), environment);
+        assert!(options.trim);
+    }
+
+    #[test]
+    fn chooses_the_verbosity() {
+        let running_config = Config::build_default();
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), |_| None);
+        assert_eq!(options.verbosity, Verbosity::Normal);
+        let options =
+            RuntimeOptions::resolve_with(&running_config, &arguments(&["--verbose"]), |_| None);
+        assert_eq!(options.verbosity, Verbosity::Verbose);
+        let environment = |name: &str| (name == "GENTUP_QUIET").then(|| "1".to_string());
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), environment);
+        assert_eq!(options.verbosity, Verbosity::Quiet);
+        let options =
+            RuntimeOptions::resolve_with(&running_config, &arguments(&["--verbose"]), environment);
+        assert_eq!(options.verbosity, Verbosity::Verbose);
+    }
+
+    #[test]
+    fn continues_or_resumes() {
+        let running_config = Config::build_default();
+        let options =
+            RuntimeOptions::resolve_with(&running_config, &arguments(&["--continue"]), |_| None);
+        assert!(options.continue_run && !options.resume);
+        let options =
+            RuntimeOptions::resolve_with(&running_config, &arguments(&["--resume"]), |_| None);
+        assert!(options.resume && !options.continue_run);
+    }
+
+    #[test]
+    fn limits_the_packages_built() {
+        let running_config = Config::build_default();
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), |_| None);
+        assert_eq!(options.max_packages, None);
+        let environment = |name: &str| (name == "GENTUP_MAX_PACKAGES").then(|| "20".to_string());
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), environment);
+        assert_eq!(options.max_packages, Some(20));
+        let environment = |name: &str| (name == "GENTUP_MAX_PACKAGES").then(|| "0".to_string());
+        let options = RuntimeOptions::resolve_with(&running_config, &arguments(&[]), environment);
+        assert_eq!(options.max_packages, None);
+    }
+}
@@ -0,0 +1,779 @@
+// Run orchestration
+// An update is modelled as a sequence of phases. After each phase completes, a checkpoint is
+// written to disk, so that an interrupted or crashed run can be resumed with gentup --continue from
+// the phase after the last one which completed, rather than starting again from the sync
+
+#[cfg(feature = "custom-phases")]
+use crate::plugin::{PhaseContext, Registry};
+#[cfg(feature = "recovery")]
+use crate::recovery;
+use crate::{
+    actions,
+    atom::Package,
+    bandwidth, binonly, budget, bugreport, builddirs, buildenv, collisions, compiler,
+    config::state_dir,
+    crossdev, distclean, dryrun, elog,
+    events::{self, Event, LogWatcher},
+    exitcode::ExitCode,
+    integrity, kconfig, lastrites,
+    linux::{self, ShellOutResult},
+    logarchive, offline,
+    options::RuntimeOptions,
+    ordering, overlays, parallel,
+    portage::{self, PackageManager},
+    preflight, progress, prompt, removed, report, resume, selfupgrade, signature, stalerun,
+    stats::{self, History},
+    throttle, Config,
+};
+#[cfg(feature = "status-socket")]
+use crate::{linux::OsCall, status};
+use crossterm::style::Color;
+use std::{fs, time::Instant};
+
+// The phases of an update, in the order they run
+//
+#[derive(Clone, Copy, PartialEq)]
+pub enum Phase {
+    Sync,      // Sync the package tree
+    Toolchain, // Update portage and gcc ahead of everything else
+    Pretend,   // Calculate and list the pending updates, and check the news
+    Fetch,     // Download the sources of the pending updates
+    Build,     // Update the @world set
+    Config,    // Merge configuration file changes
+    Cleanup,   // Remove orphans, rebuild reverse dependencies and tidy up
+}
+
+impl Phase {
+    // Every phase, in the order they run
+    pub const ALL: [Phase; 7] = [
+        Phase::Sync,
+        Phase::Toolchain,
+        Phase::Pretend,
+        Phase::Fetch,
+        Phase::Build,
+        Phase::Config,
+        Phase::Cleanup,
+    ];
+
+    // The name of the phase, as stored in the checkpoint file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Sync => "sync",
+            Phase::Toolchain => "toolchain",
+            Phase::Pretend => "pretend",
+            Phase::Fetch => "fetch",
+            Phase::Build => "build",
+            Phase::Config => "config",
+            Phase::Cleanup => "cleanup",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Phase> {
+        Phase::ALL.into_iter().find(|phase| phase.name() == name)
+    }
+
+    // The phase which follows this one, or None if this is the last phase
+    pub fn next(&self) -> Option<Phase> {
+        let position = Phase::ALL.iter().position(|phase| phase == self)?;
+        Phase::ALL.get(position + 1).copied()
+    }
+
+    // The phase before this one, or None if this is the first phase
+    pub fn previous(&self) -> Option<Phase> {
+        let position = Phase::ALL.iter().position(|phase| phase == self)?;
+        position.checked_sub(1).map(|position| Phase::ALL[position])
+    }
+}
+
+// The progress of a run, saved after every completed phase
+//
+pub struct Checkpoint {
+    pub completed: Phase,
+    pub pending_updates: Vec<Package>,
+}
+
+impl Checkpoint {
+    fn path() -> String {
+        [state_dir(), "/checkpoint"].concat()
+    }
+
+    // Load the checkpoint of an interrupted run. The first line holds the name of the last
+    // completed phase, and each following line a package pending an update
+    //
+    pub fn load() -> Option<Checkpoint> {
+        let contents = fs::read_to_string(Checkpoint::path()).ok()?;
+        let mut lines = contents.lines();
+        let completed = Phase::from_name(lines.next()?.trim())?;
+        let pending_updates = lines.filter_map(|line| line.trim().parse().ok()).collect();
+        Some(Checkpoint {
+            completed,
+            pending_updates,
+        })
+    }
+
+    pub fn save(&self) {
+        if dryrun::active() {
+            return;
+        }
+        let mut contents = [self.completed.name(), "\n"].concat();
+        for package in &self.pending_updates {
+            contents = contents + &package.to_string() + "\n";
+        }
+        if let Err(error) =
+            fs::create_dir_all(state_dir()).and_then(|_| fs::write(Checkpoint::path(), contents))
+        {
+            eprintln!(
+                "{} Could not save checkpoint {} - {}",
+                prompt::revchevrons(Color::Red),
+                Checkpoint::path(),
+                error
+            );
+        }
+    }
+
+    pub fn clear() {
+        if !dryrun::active() {
+            let _ = fs::remove_file(Checkpoint::path());
+        }
+    }
+}
+
+// What should happen after a phase has run
+//
+enum Outcome {
+    Continue,  // Carry on with the next phase
+    Finished,  // There is nothing more to do
+    OutOfTime, // The phase was stopped part way through for running past its budget
+}
+
+// Exit with the build failure status if the world update failed, sending the checklist first so
+// that it reaches the user with the bug report bundle
+//
+fn exit_if_build_failed(result: ShellOutResult, running_config: &Config) {
+    if !matches!(result, Ok((_, 0))) {
+        eprintln!(
+            "{} The update failed. Fix the problem, then resume with gentup --continue",
+            prompt::revchevrons(Color::Red)
+        );
+        actions::report(running_config);
+        ExitCode::BuildFailed.exit();
+    }
+}
+
+// The state carried between the phases of a run
+//
+struct Run<'a> {
+    config: Config, // Reloaded between phases if the config file is edited
+    options: &'a RuntimeOptions,
+    pending_updates: Vec<Package>,
+    deferred: usize, // Pending updates left for a later run by --max-packages
+    batches: Vec<(ordering::Tier, Vec<Package>)>, // The batches to merge, with critical_first
+    started: u64,    // Seconds since the epoch
+    stale_tree: bool, // The sync was skipped as too recent
+}
+
+impl Run<'_> {
+    // Merge any new kernel sources on their own, then review the options they introduce, taking
+    // them out of what is left to merge. Sources which fail to merge are left to the main build
+    //
+    fn install_kernel_sources(&mut self) {
+        let sources: Vec<Package> = self
+            .pending_updates
+            .iter()
+            .filter(|package| kconfig::is_kernel_sources(package))
+            .cloned()
+            .collect();
+        if sources.is_empty() || !portage::running_system() {
+            return;
+        }
+        if !matches!(portage::update_selected(&sources), Ok((_, 0))) {
+            return;
+        }
+        kconfig::review_new_sources();
+        self.pending_updates
+            .retain(|package| !kconfig::is_kernel_sources(package));
+        for (_, batch) in &mut self.batches {
+            batch.retain(|package| !kconfig::is_kernel_sources(package));
+        }
+        self.batches.retain(|(_, batch)| !batch.is_empty());
+    }
+
+    // Sync the package tree, checking the repositories first and its signatures after
+    //
+    fn sync(&self) {
+        // A broken overlay configuration otherwise shows up later as confusing emerge errors
+        //
+        overlays::check();
+        bandwidth::limit(&self.config);
+        portage::sync_package_tree(&self.config);
+        signature::check(&self.config);
+    }
+
+    // Work out the pending updates. Binary only, portage is free to consider the ebuilds, so the
+    // updates with no binary package can be listed
+    //
+    fn pretend(&self) -> Vec<portage::Change> {
+        if self.options.binary_only {
+            binonly::allowing_sources(|| portage::get_pending_updates(&self.config))
+        } else {
+            portage::get_pending_updates(&self.config)
+        }
+    }
+
+    // The number of updates pending from the package tree as it is, without listing them
+    //
+    fn count_pending(&self) -> usize {
+        if self.options.binary_only {
+            binonly::allowing_sources(portage::count_pending_updates)
+        } else {
+            portage::count_pending_updates()
+        }
+    }
+
+    // Run a single phase of the update
+    //
+    fn execute(&mut self, phase: Phase) -> Outcome {
+        match phase {
+            Phase::Sync if self.options.offline => {
+                // Nothing is downloaded while offline, so the tree stays as it was last synced
+                //
+                offline::disable_downloads();
+                println!(
+                    "{} Offline: not syncing the package tree",
+                    prompt::revchevrons(Color::Yellow)
+                );
+            }
+            Phase::Sync => {
+                // Check if the last resync was too recent - if not, sync the portage tree
+                // or the user can force a sync anyway by using "gentup --force"
+                // The too recent logic is to avoid abusing the rsync.gentoo.org rotation which
+                // asks that users do not sync more than once per day
+                //
+                // With the sync skipped as too recent, any updates pending are from the package
+                // tree as it was, so the user decides whether to sync first after all, before
+                // anything is merged
+                //
+                if self.options.force
+                    || !portage::too_recent(&self.config)
+                    || portage::sync_stale_tree(self.count_pending())
+                {
+                    self.sync();
+                } else {
+                    self.stale_tree = true;
+                }
+            }
+            Phase::Toolchain if self.options.offline || self.options.binary_only => {
+                // The toolchain is updated with the rest of the pending updates, if its sources
+                // were downloaded or it has binary packages
+            }
+            Phase::Toolchain => {
+                // Update sys-apps/portage and sys-devel/gcc before any other packages
+                // sys-apps/portage is the Gentoo package manager and portage itself advises the
+                // user to update portage first. If the upgrade fails or leaves portage damaged,
+                // it is repaired before anything else is built
+                //
+                if portage::package_outdated("sys-apps/portage") {
+                    selfupgrade::upgrade();
+                }
+                if portage::package_outdated("sys-devel/gcc") {
+                    preflight::before_build(&self.config);
+                    portage::upgrade_package("sys-devel/gcc");
+                }
+            }
+            Phase::Pretend => {
+                // Present a list of packages to be updated to the screen
+                // If there are no packages pending updates, we can quit at this stage
+                // unless the user specifically asked for a cleanup to be run
+                //
+                let changes = self.pretend();
+
+                // Warn about installed packages which are about to be removed from the tree, or
+                // which already have been
+                //
+                lastrites::check();
+                removed::check();
+                self.pending_updates = changes
+                    .iter()
+                    .map(|change| change.package.clone())
+                    .collect();
+                events::emit(Event::PendingUpdates {
+                    packages: self
+                        .pending_updates
+                        .iter()
+                        .map(|package| package.to_string())
+                        .collect(),
+                    security: changes
+                        .iter()
+                        .filter(|change| !change.security.is_empty())
+                        .map(|change| change.package.to_string())
+                        .collect(),
+                });
+                if self.pending_updates.is_empty() {
+                    portage::report_nothing_to_do(self.stale_tree);
+                    if !self.options.cleanup {
+                        return Outcome::Finished;
+                    }
+                }
+
+                // With --max-packages, only the first of the pending updates are built this run
+                //
+                if let Some(max) = self.options.max_packages {
+                    if self.pending_updates.len() > max {
+                        self.deferred = self.pending_updates.len() - max;
+                        self.pending_updates.truncate(max);
+                        println!(
+                            "{} Building the first {} pending updates this run, and leaving the other {} for later runs",
+                            prompt::revchevrons(Color::Yellow),
+                            max,
+                            self.deferred
+                        );
+                    }
+                }
+
+                // Offline, only the pending updates whose sources were downloaded can be built
+                //
+                if self.options.offline {
+                    self.deferred += offline::select(&mut self.pending_updates);
+                }
+
+                // Binary only, the pending updates with no binary package are left for later
+                //
+                if self.options.binary_only {
+                    self.deferred += binonly::select(&mut self.pending_updates);
+                }
+
+                // With critical_first, the toolchain, security fixes and small packages are
+                // merged ahead of the large packages, in batches
+                //
+                if self.config.critical_first && !self.pending_updates.is_empty() {
+                    let history = History::load();
+                    self.batches = ordering::batches(&changes, &self.pending_updates, |cpn| {
+                        history.average_build_time(cpn)
+                    });
+                    ordering::show(&self.batches);
+                }
+
+                // Check the news - if there is news, email it to the user
+                //
+                println!("{} Checking Gentoo news", prompt::chevrons(Color::Green));
+                portage::check_news(&self.config);
+            }
+            Phase::Fetch => {
+                // Download the sources up front, unless they are to be fetched in the background
+                // during the update. Either way, the downloads keep within the bandwidth limit
+                //
+                if self.options.offline {
+                    return Outcome::Continue;
+                }
+                bandwidth::limit(&self.config);
+                if !self.options.background {
+                    portage::fetch_sources(&self.pending_updates);
+                }
+            }
+            Phase::Build => {
+                if !self.pending_updates.is_empty() {
+                    // Make sure PORTAGE_TMPDIR can hold the largest of the pending builds
+                    //
+                    preflight::check_portage_tmpdir(&self.config, &self.pending_updates);
+
+                    // Suggest, or apply, the make and emerge jobs to suit this machine
+                    //
+                    parallel::apply(&self.config);
+
+                    // Write the package_env overrides for this run's build
+                    //
+                    buildenv::apply(&self.config, &self.options.package_env);
+
+                    // Hold off building while on low battery, busy, running hot or in a paused
+                    // time of day, and throttle the build by the time of day, if so configured
+                    //
+                    preflight::before_build(&self.config);
+
+                    // Merge new kernel sources ahead of the rest, and have the options they
+                    // introduce looked at as soon as they are installed
+                    //
+                    if !self.options.resume {
+                        self.install_kernel_sources();
+                    }
+
+                    // Keep the merge history from before the build, to compare the time each
+                    // package takes with its estimate
+                    //
+                    let history = History::load();
+                    let build_started = report::now();
+
+                    // If a package fails to build and there is a user at the terminal, offer them
+                    // ways to recover rather than just exiting
+                    //
+                    let watcher = LogWatcher::start();
+                    let monitor = compiler::Monitor::start();
+                    let sampler = progress::Sampler::start();
+                    let throttler = throttle::Throttler::start(&self.config);
+                    let selected = (self.options.max_packages.is_some()
+                        || self.options.offline
+                        || self.options.binary_only)
+                        && (self.deferred > 0 || self.options.continue_run);
+                    #[allow(unused_mut)]
+                    let mut result = if self.options.resume {
+                        resume::resume()
+                    } else if self.pending_updates.is_empty() {
+                        Ok((String::new(), 0)) // Only kernel sources were pending
+                    } else if !self.batches.is_empty() {
+                        ordering::update(&self.batches)
+                    } else if selected {
+                        portage::update_selected(&self.pending_updates)
+                    } else {
+                        PackageManager::NoDryRun.update_all_packages()
+                    };
+
+                    // The package being built may have been skipped, or the update aborted, from
+                    // the status socket
+                    //
+                    #[cfg(feature = "status-socket")]
+                    while matches!(result, Ok((_, status)) if status != 0)
+                        && status::take_skip_request()
+                    {
+                        result = OsCall::Interactive.execute(
+                            "emerge --resume --skipfirst",
+                            "Skipping the current package",
+                        );
+                    }
+                    if let Some(watcher) = watcher {
+                        watcher.finish(matches!(result, Ok((_, 0))));
+                    }
+                    if let Some(sampler) = sampler {
+                        sampler.finish();
+                    }
+                    if let Some(throttler) = throttler {
+                        throttler.finish();
+                    }
+                    monitor.finish();
+                    stats::report_build_times(&history, build_started);
+                    elog::report(build_started);
+                    if !matches!(result, Ok((_, 0))) {
+                        collisions::explain(build_started);
+                    }
+                    integrity::verify_merged(build_started);
+                    #[cfg(feature = "status-socket")]
+                    status::honour_controls();
+
+                    // A build stopped for running past its budget carries on in the next window
+                    //
+                    if budget::exceeded() && !matches!(result, Ok((_, 0))) {
+                        preflight::remove_tmpdir_redirect();
+                        buildenv::remove();
+                        return Outcome::OutOfTime;
+                    }
+                    // Gather what a bug report needs while the failed build directory is there
+                    //
+                    if matches!(result, Ok((_, status)) if status != 0) {
+                        if let Some(path) = bugreport::create(self.started) {
+                            actions::add(format!(
+                                "A build failed. Attach {} to the bug report or support request",
+                                path
+                            ));
+                        }
+                    }
+                    #[cfg(feature = "recovery")]
+                    let recovered = matches!(result, Ok((_, status)) if status != 0)
+                        && (linux::is_a_tty() || prompt::answered("recovery"))
+                        && recovery::recover_failed_update();
+                    #[cfg(feature = "recovery")]
+                    if !recovered {
+                        exit_if_build_failed(result, &self.config);
+                    }
+                    #[cfg(not(feature = "recovery"))]
+                    exit_if_build_failed(result, &self.config);
+                    preflight::remove_tmpdir_redirect();
+                    buildenv::remove();
+
+                    // Kernel sources merged by the resumed emerge have their new options looked
+                    // at once it finishes
+                    //
+                    if self.options.resume {
+                        kconfig::review_new_sources();
+                    }
+                }
+            }
+            Phase::Config => {
+                portage::update_config_files(); // Handle updating package config files
+
+                // The system is part way through its updates, so cleaning up waits until the
+                // last of them has been built
+                //
+                if self.deferred > 0 {
+                    let remaining = format!(
+                        "{} pending updates are left for the next run{}",
+                        self.deferred,
+                        if self.options.offline {
+                            ", as their sources are not downloaded"
+                        } else if self.options.binary_only {
+                            ", as they have no binary package"
+                        } else {
+                            " with --max-packages"
+                        }
+                    );
+                    println!("{} {}", prompt::revchevrons(Color::Yellow), remaining);
+                    actions::add(remaining);
+                    return Outcome::Finished;
+                }
+            }
+            Phase::Cleanup => return self.cleanup(),
+        }
+        Outcome::Continue
+    }
+
+    // The cleanup phase
+    //
+    fn cleanup(&self) -> Outcome {
+        // List the build directories left behind by crashed builds, and remove them if cleaning
+        // up
+        //
+        builddirs::after_update(self.started, self.options.cleanup);
+
+        // Compress the build logs, elog messages and run reports which are no longer recent
+        //
+        logarchive::compress_old(&self.config);
+
+        // Record the exact versions of the active toolchain, so that it can be restored if
+        // cleanup manages to break it
+        //
+        let toolchain: Vec<String> = portage::active_toolchain()
+            .iter()
+            .map(|atom| portage::installed_version(atom))
+            .collect();
+
+        // List and remove orphaned dependencies.
+        //
+        let (orphans, kernels) = PackageManager::DryRun.depclean(); // DryRun mode only lists orphaned deps
+        events::emit(Event::Orphans { count: orphans });
+        if orphans > 0 {
+            // To prevent the issue of depclean removing the currently running kernel immediately
+            // after a kernel upgrade check to see if the running kernel will be depcleaned. An
+            // installation in another directory is not running any kernel
+            //
+            if portage::running_system() && kernels.contains(&linux::running_kernel()) {
+                if self.options.cleanup {
+                    PackageManager::PreserveKernel.depclean(); // depcleans everything excluding old kernel packages
+                    portage::verify_toolchain(&toolchain);
+                }
+                println!(
+                    "{} Preserving currently running kernel. Skipping cleanup",
+                    prompt::chevrons(Color::Green)
+                );
+                return Outcome::Finished;
+            } else if self.options.cleanup {
+                PackageManager::AllPackages.depclean(); // depcleans everything
+            }
+        }
+
+        // Check for broken Reverse dependencies
+        //
+        if self.options.cleanup {
+            if !PackageManager::DryRun.revdep_rebuild() {
+                PackageManager::NoDryRun.revdep_rebuild();
+            }
+            portage::verify_toolchain(&toolchain); // Make sure depclean and revdep-rebuild left a working toolchain
+            portage::find_obsolete_configs(); // Find any obsolete portage configurations from removed packages
+            distclean::clean(&self.config); // Cleanup old distfiles and binary packages otherwise these will grow indefinitely
+            portage::clean_old_kernels(); // Cleanup unused kernels from /usr/src, /boot, /lib/modules and the grub config
+
+            if self.options.trim && portage::running_system() {
+                // A full update creates so many GB of temp files it warrants a trim, but only
+                // if the user specifies --trim on the command line
+                linux::call_fstrim();
+            }
+        } else {
+            println!(
+                "{} Cleanup is disabled. Prolonged skipping of cleanup is not advised",
+                prompt::chevrons(Color::Yellow)
+            );
+        }
+        Outcome::Continue
+    }
+}
+
+// Packages which, once updated, are not fully in use until the system is rebooted
+static REBOOT_PACKAGES: [&str; 6] = [
+    "sys-kernel/gentoo-kernel",
+    "sys-kernel/gentoo-kernel-bin",
+    "sys-kernel/vanilla-kernel",
+    "sys-kernel/linux-firmware",
+    "sys-libs/glibc",
+    "sys-apps/systemd",
+];
+
+// Stop the update once a phase has run past its budget, leaving the checkpoint for the next
+// window to carry on from
+//
+fn out_of_time(run: &Run, phase: Phase) -> ExitCode {
+    #[cfg(feature = "status-socket")]
+    status::shutdown();
+    let action = format!(
+        "The update stopped when the {} phase ran past its time budget. Carry on with gentup --continue",
+        phase.name()
+    );
+    eprintln!("{} {}", prompt::revchevrons(Color::Yellow), action);
+    actions::add(action);
+    actions::report(&run.config);
+    ExitCode::OutOfTime
+}
+
+// Run the update from the first phase, or when the user asked to continue an interrupted run,
+// from the phase after the last completed one. Returns the exit status describing the outcome
+//
+pub fn run(running_config: &Config, options: &RuntimeOptions) -> ExitCode {
+    report::begin(running_config);
+    running_config.note_changes_since_last_run();
+    let mut run = Run {
+        config: running_config.clone(),
+        options,
+        pending_updates: Vec::new(),
+        deferred: 0,
+        batches: Vec::new(),
+        started: report::now(),
+        stale_tree: false,
+    };
+    #[cfg(feature = "custom-phases")]
+    let mut registry = Registry::from_config(running_config);
+    let mut phase = Some(Phase::Sync);
+
+    // An update which did not finish is reported, and resumed or discarded, rather than a fresh
+    // run starting blind on top of it
+    //
+    let from_checkpoint = stalerun::check(options.continue_run);
+
+    // With --resume, the emerge which stopped part way through is carried on from portage's own
+    // resume list, skipping the sync and the pretend
+    //
+    if options.resume {
+        let remaining = resume::interrupted();
+        if remaining.is_empty() {
+            println!(
+                "{} There is no interrupted emerge to resume. Run gentup without --resume to update",
+                prompt::revchevrons(Color::Yellow)
+            );
+            return ExitCode::NothingToDo;
+        }
+        resume::show(&remaining);
+        Checkpoint::clear();
+        phase = Some(Phase::Build);
+        run.pending_updates = remaining;
+    } else if from_checkpoint {
+        match Checkpoint::load() {
+            Some(checkpoint) => {
+                println!(
+                    "{} Resuming the interrupted update after the {} phase",
+                    prompt::revchevrons(Color::Green),
+                    checkpoint.completed.name()
+                );
+                phase = checkpoint.completed.next();
+                run.pending_updates = checkpoint.pending_updates;
+            }
+            None => println!(
+                "{} There is no interrupted update to continue. Starting from the beginning",
+                prompt::revchevrons(Color::Yellow)
+            ),
+        }
+    } else {
+        Checkpoint::clear();
+    }
+    #[cfg(feature = "status-socket")]
+    status::serve();
+    #[cfg(feature = "http-status")]
+    if !running_config.http_status.is_empty() {
+        crate::http::serve(&running_config.http_status);
+    }
+
+    // Offer to remove the build directories left behind by builds which crashed in earlier runs
+    //
+    builddirs::offer_removal(run.started);
+    while let Some(current) = phase {
+        #[cfg(feature = "status-socket")]
+        status::set_phase(current.name(), &run.pending_updates);
+        stalerun::hold(run.started, current.name());
+        dryrun::set_phase(current.name());
+        let started = Instant::now();
+        events::emit(Event::PhaseStart {
+            phase: current.name(),
+        });
+        let watchdog = budget::Watchdog::start(&run.config.phase_budgets, current.name());
+        let outcome = run.execute(current);
+        if let Some(watchdog) = watchdog {
+            watchdog.finish();
+        }
+        events::emit(Event::PhaseEnd {
+            phase: current.name(),
+            seconds: started.elapsed().as_secs(),
+        });
+        match outcome {
+            Outcome::Finished => break,
+            Outcome::OutOfTime => {
+                // The phase runs again from the start when the update carries on
+                //
+                match current.previous() {
+                    Some(completed) => Checkpoint {
+                        completed,
+                        pending_updates: run.pending_updates.clone(),
+                    }
+                    .save(),
+                    None => Checkpoint::clear(),
+                }
+                return out_of_time(&run, current);
+            }
+            Outcome::Continue => {}
+        }
+        #[cfg(feature = "custom-phases")]
+        {
+            let context = PhaseContext {
+                config: &run.config,
+                pending_updates: &run.pending_updates,
+            };
+            if let Err(error) = registry.run_after(current, &context) {
+                eprintln!("{} {}", prompt::revchevrons(Color::Red), error);
+                ExitCode::Failed.exit();
+            }
+        }
+        Checkpoint {
+            completed: current,
+            pending_updates: run.pending_updates.clone(),
+        }
+        .save();
+        #[cfg(feature = "status-socket")]
+        status::honour_controls();
+        if budget::exceeded() {
+            return out_of_time(&run, current);
+        }
+        // Settings edited during the run take effect between phases, never part way through one
+        if run.config.reload_if_changed() {
+            #[cfg(feature = "custom-phases")]
+            {
+                registry = Registry::from_config(&run.config);
+            }
+        }
+        phase = current.next();
+    }
+    Checkpoint::clear();
+    #[cfg(feature = "status-socket")]
+    status::shutdown();
+    // crossdev targets are updated once the host is, and only when updating the host itself
+    let cross_updated = !portage::running_system() || crossdev::update_targets(&run.config);
+    prompt::console(&format!("{} All done!!!\n", prompt::chevrons(Color::Green)));
+    let exit_code = if !cross_updated {
+        ExitCode::BuildFailed
+    } else if run.pending_updates.is_empty() {
+        ExitCode::NothingToDo
+    } else if portage::running_system()
+        && run
+            .pending_updates
+            .iter()
+            .any(|package| REBOOT_PACKAGES.contains(&package.cpn().as_str()))
+    {
+        actions::add("Reboot to use the updated kernel or system libraries".to_string());
+        ExitCode::RebootRequired
+    } else {
+        ExitCode::UpdatesApplied
+    };
+    actions::report(&run.config);
+    exit_code
+}
@@ -0,0 +1,196 @@
+// Critical-first ordering
+// emerge merges the pending updates in dependency order, so a night's window can be spent inside
+// chromium while the openssl security fix waits behind it, and an interrupted run leaves it
+// unapplied. With critical_first: true in the configuration file, the updates are merged in
+// batches, each a --oneshot emerge of the exact versions pending:
+//
+//   1. the toolchain and core system packages
+//   2. the packages fixing a security advisory
+//   3. the small packages, which built here in under half an hour
+//   4. the large packages, those known to be huge and those slow to build here, quickest first
+//
+// emerge's order is kept within each batch, and a batch pulls in any dependency from a later one
+// which it needs, so each batch leaves the system consistent
+
+use crate::{
+    atom::Package,
+    linux::ShellOutResult,
+    portage::{self, Change},
+    preflight, prompt,
+};
+use crossterm::style::Color;
+
+// Packages which the rest of the system is built with, or which everything runs on
+static CRITICAL_PACKAGES: [&str; 8] = [
+    "sys-apps/portage",
+    "sys-devel/gcc",
+    "sys-devel/binutils",
+    "sys-libs/glibc",
+    "sys-libs/musl",
+    "sys-libs/zlib",
+    "dev-libs/openssl",
+    "dev-lang/python",
+];
+
+// Packages which took at least this long to build here are merged last
+static LARGE_SECONDS: u64 = 1800;
+
+// The batches, in the order they are merged
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tier {
+    Critical,
+    Security,
+    Small,
+    Large,
+}
+
+impl Tier {
+    pub fn description(self) -> &'static str {
+        match self {
+            Tier::Critical => "toolchain and core system",
+            Tier::Security => "security fixes",
+            Tier::Small => "small packages",
+            Tier::Large => "large packages",
+        }
+    }
+}
+
+// The tier of a pending update, given how long it took to build here before, if it has been built
+//
+pub fn tier(change: &Change, build_seconds: Option<u64>) -> Tier {
+    let cpn = change.package.cpn();
+    if CRITICAL_PACKAGES.contains(&cpn.as_str()) {
+        Tier::Critical
+    } else if !change.security.is_empty() {
+        Tier::Security
+    } else if preflight::build_space_mb(&cpn).is_some()
+        || build_seconds.is_some_and(|seconds| seconds >= LARGE_SECONDS)
+    {
+        Tier::Large
+    } else {
+        Tier::Small
+    }
+}
+
+// Split the pending updates, in emerge's order, into the batches to merge, leaving out any which
+// are not to be built this run. The large packages are ordered quickest first
+//
+pub fn batches(
+    changes: &[Change],
+    building: &[Package],
+    build_seconds: impl Fn(&str) -> Option<u64>,
+) -> Vec<(Tier, Vec<Package>)> {
+    let mut batches: Vec<(Tier, Vec<Package>)> = Vec::new();
+    for wanted in [Tier::Critical, Tier::Security, Tier::Small, Tier::Large] {
+        let mut batch: Vec<(u64, Package)> = changes
+            .iter()
+            .filter(|change| building.contains(&change.package))
+            .map(|change| {
+                let seconds = build_seconds(&change.package.cpn());
+                (tier(change, seconds), seconds.unwrap_or(u64::MAX), change)
+            })
+            .filter(|(tier, _, _)| *tier == wanted)
+            .map(|(_, seconds, change)| (seconds, change.package.clone()))
+            .collect();
+        if wanted == Tier::Large {
+            batch.sort_by_key(|(seconds, _)| *seconds);
+        }
+        if !batch.is_empty() {
+            batches.push((
+                wanted,
+                batch.into_iter().map(|(_, package)| package).collect(),
+            ));
+        }
+    }
+    batches
+}
+
+// List the batches the update will be merged in
+//
+pub fn show(batches: &[(Tier, Vec<Package>)]) {
+    println!(
+        "{} Merging the pending updates critical first, in {} batch(es):",
+        prompt::revchevrons(Color::Green),
+        batches.len()
+    );
+    for (number, (tier, packages)) in batches.iter().enumerate() {
+        println!(
+            "    {}. {} ({} package(s))",
+            number + 1,
+            tier.description(),
+            packages.len()
+        );
+    }
+}
+
+// Merge each batch in turn, stopping at the first which fails
+//
+pub fn update(batches: &[(Tier, Vec<Package>)]) -> ShellOutResult {
+    let mut result: ShellOutResult = Ok((String::new(), 0));
+    for (tier, packages) in batches {
+        println!(
+            "{} Merging the {}",
+            prompt::chevrons(Color::Green),
+            tier.description()
+        );
+        result = portage::update_selected(packages);
+        if !matches!(result, Ok((_, 0))) {
+            break;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_critical_updates_first() {
+        let output = "\
+[ebuild     U  ] www-client/chromium-126.0.6478.126 [125.0.6422.141]
+[ebuild     U  ] app-misc/screen-4.9.1 [4.9.0]
+[ebuild     U  ] dev-libs/openssl-3.0.13 [3.0.12]
+[ebuild     U  ] net-misc/curl-8.7.1 [8.6.0]
+[ebuild     U  ] dev-lang/rust-1.77.1 [1.76.0]
+[ebuild     U  ] app-editors/vim-9.1.0 [9.0.2167]
+";
+        let mut changes = portage::parse_changes(output);
+        changes[3].security.push("202404-01".to_string());
+        let building: Vec<Package> = changes
+            .iter()
+            .map(|change| change.package.clone())
+            .filter(|package| package.name != "vim")
+            .collect();
+        let batches = batches(&changes, &building, |cpn| match cpn {
+            "dev-lang/rust" => Some(3600),
+            "app-misc/screen" => Some(60),
+            _ => None,
+        });
+        let names: Vec<(Tier, Vec<String>)> = batches
+            .iter()
+            .map(|(tier, packages)| {
+                (
+                    *tier,
+                    packages
+                        .iter()
+                        .map(|package| package.name.clone())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (Tier::Critical, vec!["openssl".to_string()]),
+                (Tier::Security, vec!["curl".to_string()]),
+                (Tier::Small, vec!["screen".to_string()]),
+                (
+                    Tier::Large,
+                    vec!["rust".to_string(), "chromium".to_string()]
+                ),
+            ]
+        );
+    }
+}
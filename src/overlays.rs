@@ -0,0 +1,267 @@
+// Overlay configuration checks
+// A mistake in repos.conf, or in an overlay's metadata/layout.conf, only shows up later as emerge
+// errors about missing eclasses, unknown repositories or the wrong ebuild being picked, which are
+// hard to trace back to their cause. Before syncing, the repositories portage knows about are
+// checked for:
+//
+//   a location which does not exist
+//   an overlay without metadata/layout.conf, or whose layout.conf sets no masters, e.g
+//       masters = gentoo
+//   masters which name a repository that is not configured
+//   overlays sharing a priority, so which one's ebuilds are used is left to chance
+//   the same package in more than one overlay
+//
+// The configuration is read from portageq repos_config, which merges all of repos.conf
+
+use crate::{linux::OsCall, prompt};
+use crossterm::style::Color;
+use std::{fs, path::Path};
+
+// How many packages in more than one overlay are listed
+static OVERLAPS_SHOWN: usize = 5;
+
+// Define a struct to hold the settings of one repository from repos.conf
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct Repository {
+    pub name: String,
+    pub location: String,
+    pub priority: Option<i32>,
+}
+
+// Parse the output of "portageq repos_config /" into the main repository and every repository
+//
+pub fn parse_repositories(output: &str) -> (String, Vec<Repository>) {
+    let mut main_repo = "gentoo".to_string();
+    let mut repositories: Vec<Repository> = Vec::new();
+    let mut in_default = false;
+    for line in output.lines().map(|line| line.trim()) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            in_default = name == "DEFAULT";
+            if !in_default {
+                repositories.push(Repository {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if in_default {
+            if key == "main-repo" {
+                main_repo = value.to_string();
+            }
+            continue;
+        }
+        let Some(repository) = repositories.last_mut() else {
+            continue;
+        };
+        match key {
+            "location" => repository.location = value.to_string(),
+            "priority" => repository.priority = value.parse().ok(),
+            _ => {}
+        }
+    }
+    (main_repo, repositories)
+}
+
+// The masters set in a metadata/layout.conf, if any are
+//
+pub fn layout_masters(layout: &str) -> Option<Vec<String>> {
+    layout.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "masters").then(|| value.split_whitespace().map(String::from).collect())
+    })
+}
+
+// The packages in a repository, e.g app-misc/foo, found as the directories in its category
+// directories
+//
+fn packages(location: &str) -> Vec<String> {
+    let mut packages = Vec::new();
+    let Ok(categories) = fs::read_dir(location) else {
+        return packages;
+    };
+    for category in categories.flatten() {
+        let category_name = category.file_name().to_string_lossy().to_string();
+        if !(category_name.contains('-') || category_name == "virtual") {
+            continue; // eclass, licenses, metadata, profiles and so on
+        }
+        let Ok(entries) = fs::read_dir(category.path()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                packages.push([&category_name, "/", &entry.file_name().to_string_lossy()].concat());
+            }
+        }
+    }
+    packages.sort();
+    packages
+}
+
+// Find the problems with the configured repositories
+//
+pub fn problems(main_repo: &str, repositories: &[Repository]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let overlays: Vec<&Repository> = repositories
+        .iter()
+        .filter(|repository| repository.name != main_repo)
+        .collect();
+    for repository in repositories {
+        if !Path::new(&repository.location).is_dir() {
+            problems.push(format!(
+                "Repository {} has location {}, which does not exist",
+                repository.name, repository.location
+            ));
+        }
+    }
+    for overlay in &overlays {
+        if !Path::new(&overlay.location).is_dir() {
+            continue;
+        }
+        let layout_path = [&overlay.location, "/metadata/layout.conf"].concat();
+        let Ok(layout) = fs::read_to_string(&layout_path) else {
+            problems.push(format!(
+                "Overlay {} has no {} - add one with masters = {}",
+                overlay.name, layout_path, main_repo
+            ));
+            continue;
+        };
+        match layout_masters(&layout) {
+            None => problems.push(format!(
+                "Overlay {} sets no masters in {} - add masters = {}",
+                overlay.name, layout_path, main_repo
+            )),
+            Some(masters) => {
+                for master in masters {
+                    if !repositories
+                        .iter()
+                        .any(|repository| repository.name == master)
+                    {
+                        problems.push(format!(
+                            "Overlay {} has master {}, which is not a configured repository",
+                            overlay.name, master
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    for (position, overlay) in overlays.iter().enumerate() {
+        for other in &overlays[position + 1..] {
+            if overlay.priority.is_some() && overlay.priority == other.priority {
+                problems.push(format!(
+                    "Overlays {} and {} both have priority {}, so which one's ebuilds are used is left to chance",
+                    overlay.name,
+                    other.name,
+                    overlay.priority.unwrap_or(0)
+                ));
+            }
+        }
+    }
+    let contents: Vec<(&str, Vec<String>)> = overlays
+        .iter()
+        .map(|overlay| (overlay.name.as_str(), packages(&overlay.location)))
+        .collect();
+    let mut overlaps = Vec::new();
+    for (position, (name, packages)) in contents.iter().enumerate() {
+        for (other_name, other_packages) in &contents[position + 1..] {
+            for package in packages
+                .iter()
+                .filter(|package| other_packages.binary_search(package).is_ok())
+            {
+                overlaps.push(format!(
+                    "{} is in both overlays {} and {}",
+                    package, name, other_name
+                ));
+            }
+        }
+    }
+    let total = overlaps.len();
+    problems.extend(overlaps.into_iter().take(OVERLAPS_SHOWN));
+    if total > OVERLAPS_SHOWN {
+        problems.push(format!(
+            "... and {} more packages in more than one overlay",
+            total - OVERLAPS_SHOWN
+        ));
+    }
+    problems
+}
+
+// Warn about any problems with the repository configuration, before syncing
+//
+pub fn check() {
+    let output = match OsCall::Quiet.execute("portageq repos_config /", "") {
+        Ok((output, 0)) => output,
+        _ => return,
+    };
+    let (main_repo, repositories) = parse_repositories(&output);
+    for problem in problems(&main_repo, &repositories) {
+        eprintln!("{} {}", prompt::revchevrons(Color::Yellow), problem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_overlays() {
+        let root = std::env::temp_dir().join(format!("gentup-overlays-{}", std::process::id()));
+        let location = |name: &str| root.join(name).to_string_lossy().to_string();
+        for (name, package) in [
+            ("gentoo", "app-misc/foo"),
+            ("guru", "app-misc/foo"),
+            ("local", "app-misc/foo"),
+            ("local", "virtual/bar"),
+            ("nolayout", "dev-util/baz"),
+        ] {
+            fs::create_dir_all(root.join(name).join(package)).unwrap();
+        }
+        fs::create_dir_all(root.join("guru/metadata")).unwrap();
+        fs::write(root.join("guru/metadata/layout.conf"), "masters = gentoo\n").unwrap();
+        fs::create_dir_all(root.join("local/metadata")).unwrap();
+        fs::write(
+            root.join("local/metadata/layout.conf"),
+            "masters = gentoo musl\nthin-manifests = true\n",
+        )
+        .unwrap();
+
+        let output = format!(
+            "[DEFAULT]\nmain-repo = gentoo\n\n\
+            [gentoo]\nlocation = {}\npriority = -1000\n\n\
+            [guru]\nlocation = {}\npriority = 50\n\n\
+            [local]\nlocation = {}\npriority = 50\n\n\
+            [nolayout]\nlocation = {}\n\n\
+            [gone]\nlocation = {}\n",
+            location("gentoo"),
+            location("guru"),
+            location("local"),
+            location("nolayout"),
+            location("gone"),
+        );
+        let (main_repo, repositories) = parse_repositories(&output);
+        assert_eq!(main_repo, "gentoo");
+        assert_eq!(repositories.len(), 5);
+        assert_eq!(repositories[1].priority, Some(50));
+
+        let problems = problems(&main_repo, &repositories);
+        assert_eq!(problems.len(), 5);
+        assert!(problems[0].starts_with("Repository gone has location"));
+        assert!(problems[1].starts_with("Overlay local has master musl"));
+        assert!(problems[2].starts_with("Overlay nolayout has no"));
+        assert!(problems[3].starts_with("Overlays guru and local both have priority 50"));
+        assert_eq!(
+            problems[4],
+            "app-misc/foo is in both overlays guru and local"
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,131 @@
+// Build parallelism
+// Works out how many jobs to run from the number of CPUs and the amount of memory, rather than
+// relying on whatever happens to be in make.conf, which is often copied from another machine or
+// left from an old one. Each make job is allowed 2GB of memory, since large C++ packages use that
+// much per compiler process, and emerge runs one package per four make jobs in parallel, with both
+// held back once the load average reaches the number of CPUs. With auto_parallelism: true in the
+// configuration file the suggestion is applied to the world update, otherwise it is only shown
+
+use crate::{portage, prompt, Config};
+use crossterm::style::Color;
+use std::{env, fs, sync::OnceLock, thread};
+
+// The emerge options applied to the world update, once decided
+static UPDATE_OPTIONS: OnceLock<String> = OnceLock::new();
+
+// Define a struct to hold the suggested parallelism
+//
+#[derive(Debug, PartialEq)]
+pub struct Tuning {
+    pub make_jobs: usize,  // For MAKEOPTS -j
+    pub jobs: usize,       // For emerge --jobs
+    pub load_average: f32, // For MAKEOPTS -l and emerge --load-average
+}
+
+impl Tuning {
+    pub fn makeopts(&self) -> String {
+        format!("-j{} -l{}", self.make_jobs, self.load_average)
+    }
+
+    pub fn emerge_options(&self) -> String {
+        format!(" --jobs {} --load-average {}", self.jobs, self.load_average)
+    }
+}
+
+// Suggest the parallelism for a machine with the given number of CPUs and megabytes of memory
+//
+pub fn tune(cpus: usize, memory_mb: u64) -> Tuning {
+    let cpus = cpus.max(1);
+    let by_memory = (memory_mb / 2048).max(1) as usize;
+    let make_jobs = cpus.min(by_memory);
+    Tuning {
+        make_jobs,
+        jobs: (make_jobs / 4).max(1),
+        load_average: cpus as f32,
+    }
+}
+
+// The total memory, in megabytes, from /proc/meminfo
+//
+pub fn memory_mb() -> u64 {
+    fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix("MemTotal:"))
+                .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+        })
+        .map(|kilobytes| kilobytes / 1024)
+        .unwrap_or(0)
+}
+
+// Show the suggested parallelism beside what make.conf has, and apply it to the world update if
+// the configuration asks for it
+//
+pub fn apply(running_config: &Config) {
+    let cpus = thread::available_parallelism()
+        .map(|cpus| cpus.get())
+        .unwrap_or(1);
+    let memory = memory_mb();
+    let tuning = tune(cpus, memory);
+    let current = portage::make_conf_variable("MAKEOPTS").unwrap_or_default();
+    if running_config.auto_parallelism {
+        println!(
+            "{} Building with MAKEOPTS=\"{}\" and{} for {} CPU(s) and {}MB of memory",
+            prompt::revchevrons(Color::Green),
+            tuning.makeopts(),
+            tuning.emerge_options(),
+            cpus,
+            memory
+        );
+        // MAKEOPTS in the environment overrides make.conf
+        env::set_var("MAKEOPTS", tuning.makeopts());
+        let _ = UPDATE_OPTIONS.set(tuning.emerge_options());
+    } else if current.split_whitespace().next() != Some(&format!("-j{}", tuning.make_jobs)) {
+        println!(
+            "{} For {} CPU(s) and {}MB of memory, MAKEOPTS=\"{}\" and emerge{} are suggested. make.conf has MAKEOPTS=\"{}\"",
+            prompt::revchevrons(Color::Yellow),
+            cpus,
+            memory,
+            tuning.makeopts(),
+            tuning.emerge_options(),
+            current
+        );
+    }
+}
+
+// The options to add to the world update command line
+//
+pub fn update_options() -> &'static str {
+    UPDATE_OPTIONS
+        .get()
+        .map(|options| options.as_str())
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_parallelism() {
+        // Plenty of memory: one make job per CPU
+        assert_eq!(
+            tune(16, 65536),
+            Tuning {
+                make_jobs: 16,
+                jobs: 4,
+                load_average: 16.0
+            }
+        );
+        // Short of memory: 2GB per make job
+        let tuning = tune(16, 8192);
+        assert_eq!(tuning.make_jobs, 4);
+        assert_eq!(tuning.jobs, 1);
+        assert_eq!(tuning.makeopts(), "-j4 -l16");
+        assert_eq!(tuning.emerge_options(), " --jobs 1 --load-average 16");
+        // Never less than one job
+        assert_eq!(tune(0, 512).make_jobs, 1);
+    }
+}
@@ -0,0 +1,151 @@
+// Custom phases
+// Site-specific steps can be inserted into the update after any of the built-in phases, for
+// example rebuilding containers once the host has been updated. Each step implements the
+// CustomPhase trait, and is registered either in code with Registry::register, or in the config
+// file by naming one of the built-in custom phases:
+//
+//   custom_phase: build command /usr/local/bin/rebuild-containers
+//   custom_phase: cleanup preserved-rebuild
+
+use crate::{atom::Package, linux::OsCall, orchestrator::Phase, prompt, Config};
+use crossterm::style::Color;
+
+// The information made available to a custom phase
+//
+pub struct PhaseContext<'a> {
+    pub config: &'a Config,
+    pub pending_updates: &'a [Package],
+}
+
+// Define the interface every custom phase implements
+//
+pub trait CustomPhase {
+    fn name(&self) -> String; // Displayed to the user when the phase runs
+    fn run(&self, context: &PhaseContext) -> Result<(), String>;
+}
+
+// Runs an external command, passing it the pending updates in the GENTUP_PENDING_UPDATES
+// environment variable
+//
+struct Command {
+    command_line: String,
+}
+
+impl CustomPhase for Command {
+    fn name(&self) -> String {
+        ["command ", &self.command_line].concat()
+    }
+
+    fn run(&self, context: &PhaseContext) -> Result<(), String> {
+        let pending_updates: Vec<String> = context
+            .pending_updates
+            .iter()
+            .map(|package| package.to_string())
+            .collect();
+        std::env::set_var("GENTUP_PENDING_UPDATES", pending_updates.join(" "));
+        match OsCall::Interactive.execute(&self.command_line, "Running custom phase") {
+            Ok((_, 0)) => Ok(()),
+            Ok((_, status)) => Err(format!("exited with status {}", status)),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+// Runs emerge against a package set, such as @preserved-rebuild or @module-rebuild
+//
+struct EmergeSet {
+    set: &'static str,
+}
+
+impl CustomPhase for EmergeSet {
+    fn name(&self) -> String {
+        ["emerge ", self.set].concat()
+    }
+
+    fn run(&self, _context: &PhaseContext) -> Result<(), String> {
+        match OsCall::Interactive.execute(
+            &["emerge --quiet-build y -v ", self.set].concat(),
+            "Rebuilding package set",
+        ) {
+            Ok((_, 0)) => Ok(()),
+            Ok((_, status)) => Err(format!("exited with status {}", status)),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+// Construct the built-in custom phase with the given name, as used in the config file
+//
+pub fn builtin(name: &str, argument: &str) -> Option<Box<dyn CustomPhase>> {
+    match name {
+        "command" if !argument.is_empty() => Some(Box::new(Command {
+            command_line: argument.to_string(),
+        })),
+        "preserved-rebuild" => Some(Box::new(EmergeSet {
+            set: "@preserved-rebuild",
+        })),
+        "module-rebuild" => Some(Box::new(EmergeSet {
+            set: "@module-rebuild",
+        })),
+        _ => None,
+    }
+}
+
+// Holds the custom phases, each against the built-in phase it runs after
+//
+#[derive(Default)]
+pub struct Registry {
+    phases: Vec<(Phase, Box<dyn CustomPhase>)>,
+}
+
+impl Registry {
+    // Build the registry from the custom_phase entries in the config file
+    //
+    pub fn from_config(running_config: &Config) -> Self {
+        let mut registry = Registry::default();
+        for entry in &running_config.custom_phases {
+            let Some(after) = Phase::from_name(&entry.after) else {
+                println!(
+                    "{} Ignoring custom phase {}: there is no {} phase",
+                    prompt::revchevrons(Color::Red),
+                    entry.name,
+                    entry.after
+                );
+                continue;
+            };
+            match builtin(&entry.name, &entry.argument) {
+                Some(custom_phase) => registry.register(after, custom_phase),
+                None => println!(
+                    "{} Ignoring unknown custom phase {}",
+                    prompt::revchevrons(Color::Red),
+                    entry.name
+                ),
+            }
+        }
+        registry
+    }
+
+    pub fn register(&mut self, after: Phase, custom_phase: Box<dyn CustomPhase>) {
+        self.phases.push((after, custom_phase));
+    }
+
+    // Run each custom phase registered after the given phase, in the order they were registered.
+    // Returns the error of the first custom phase which fails
+    //
+    pub fn run_after(&self, phase: Phase, context: &PhaseContext) -> Result<(), String> {
+        for (after, custom_phase) in &self.phases {
+            if *after != phase {
+                continue;
+            }
+            println!(
+                "{} Custom phase: {}",
+                prompt::chevrons(Color::Green),
+                custom_phase.name()
+            );
+            custom_phase.run(context).map_err(|error| {
+                format!("custom phase {} failed: {}", custom_phase.name(), error)
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,36 @@
+#[cfg(feature = "mail")]
+use crate::mail;
 use crate::{
-    config::PACKAGE_FILE_PATH, linux, linux::CouldFail, linux::OsCall, linux::ShellOutResult, mail,
-    portage, prompt, Config,
+    actions,
+    atom::{Package, Version},
+    backend::{Backend, Emerge},
+    budget, changeddeps,
+    config::package_file,
+    configmerge, eixdb,
+    exitcode::ExitCode,
+    glsa, linux,
+    linux::CouldFail,
+    linux::OsCall,
+    linux::ShellOutResult,
+    news,
+    newsarchive::{self, Method},
+    parallel, portage, prefix,
+    prompt::{self, Verbosity},
+    stats, treestate, Config, Prompt,
+};
+use crossterm::{
+    cursor, execute,
+    style::{Color, SetForegroundColor},
+    terminal::{self, ClearType},
 };
-use crossterm::{cursor, execute, style::Color};
 use filetime::FileTime;
-use gethostname::gethostname;
 use std::{
+    cmp::Ordering,
+    env,
     fs::{self, File, OpenOptions},
     io::{self, Seek, SeekFrom, Write},
     path::Path,
-    process,
+    sync::OnceLock,
 };
 use terminal_spinners::{SpinnerBuilder, LINE};
 
@@ -25,18 +46,24 @@ pub enum PackageManager {
 // Describe orphaned packages
 pub type Orphans = (i32, String);
 
-// Deal with the different things we can do with the system's package manager
+// Deal with the different things we can do with the system's package manager. Each operation
+// has a _with variant which takes the Backend to run against, so that it can be tested
 impl PackageManager {
     //
     // Perform an update of the @world set (full system update)
     //
     pub fn update_all_packages(self) -> ShellOutResult {
         match self {
-            PackageManager::NoDryRun => OsCall::Interactive.execute(
-                "emerge --quiet-build y -uNDv --autounmask n --with-bdeps y --changed-use --complete-graph @world",
-                "Updating world set",
-            ),
-            PackageManager::DryRun => OsCall::Spinner.execute("emerge -puDv @world", "Checking for updates"),
+            // The pending updates are only calculated again once something they depend on changes
+            PackageManager::DryRun => treestate::cached_pretend(|| Emerge.pretend_update()),
+            _ => self.update_all_packages_with(&Emerge),
+        }
+    }
+
+    pub fn update_all_packages_with(self, backend: &dyn Backend) -> ShellOutResult {
+        match self {
+            PackageManager::NoDryRun => backend.update(),
+            PackageManager::DryRun => backend.pretend_update(),
             _ => Ok((String::new(), 0)),
         }
     }
@@ -47,65 +74,37 @@ impl PackageManager {
     // will remove it.
     //
     pub fn depclean(self) -> Orphans {
-        let mut kernels = String::new();
+        self.depclean_with(&Emerge)
+    }
+
+    pub fn depclean_with(self, backend: &dyn Backend) -> Orphans {
         match self {
             PackageManager::DryRun => {
-                if let Ok((output, _)) = OsCall::Spinner
-                    .execute("emerge -p --depclean", "Checking for orphaned dependencies")
-                    .exit_if_failed()
-                {
-                    let lines = output.split('\n');
-                    for line in lines {
-                        if line.contains("gentoo-kernel") || line.contains("gentoo-sources") {
-                            kernels = linux::stripchar(line.to_string());
-                        }
-                        if line.starts_with("Number to remove") {
-                            let mut words = line.split_whitespace();
-                            let mut word: Option<&str> = Some("");
-                            for _counter in 0..=3 {
-                                word = words.next();
-                            }
-                            match word {
-                                Some(word) => {
-                                    let numdep = word.parse().unwrap();
-                                    let mut _depcolor = Color::Green;
-                                    if numdep == 0 {
-                                        _depcolor = Color::Blue;
-                                    } else {
-                                        _depcolor = Color::Yellow;
-                                    }
-                                    println!(
-                                        "{} Found {} dependencies to clean",
-                                        prompt::revchevrons(_depcolor),
-                                        numdep
-                                    );
-                                    return (numdep, kernels);
-                                }
-                                None => {
-                                    println!(
-                                        "{} There are no orphamed dependencies",
-                                        prompt::revchevrons(Color::Green)
-                                    );
-                                    return (0, kernels);
-                                }
-                            }
+                if let Ok((output, _)) = backend.pretend_depclean().exit_if_failed() {
+                    if let Some((numdep, kernels)) = parse_depclean(&output) {
+                        if numdep == 0 {
+                            println!(
+                                "{} There are no orphaned dependencies",
+                                prompt::revchevrons(Color::Blue)
+                            );
+                        } else {
+                            println!(
+                                "{} Found {} dependencies to clean",
+                                prompt::revchevrons(Color::Yellow),
+                                numdep
+                            );
                         }
+                        return (numdep, kernels);
                     }
                 }
-
                 (0, String::new())
             }
             PackageManager::PreserveKernel => {
-                let _ = OsCall::Interactive.execute(
-                "emerge --depclean --exclude sys-kernel/gentoo-kernel-bin --exclude sys-kernel/gentoo-sources",
-                "Removing orphaned dependencies",
-            ).exit_if_failed();
+                let _ = backend.depclean(true).exit_if_failed();
                 (0, String::new())
             }
             PackageManager::AllPackages => {
-                let _ = OsCall::Interactive
-                    .execute("emerge --depclean", "Removing all orphaned dependencies")
-                    .exit_if_failed();
+                let _ = backend.depclean(false).exit_if_failed();
                 (0, String::new())
             }
             _ => (0, String::new()),
@@ -120,21 +119,19 @@ impl PackageManager {
     // linked at run-time
     //
     pub fn revdep_rebuild(self) -> bool {
+        self.revdep_rebuild_with(&Emerge)
+    }
+
+    pub fn revdep_rebuild_with(self, backend: &dyn Backend) -> bool {
         match self {
             PackageManager::DryRun => {
-                if let Ok((output, _)) = OsCall::Spinner
-                    .execute("revdep-rebuild -ip", "Checking reverse dependencies")
-                    .exit_if_failed()
-                {
-                    let lines = output.split('\n');
-                    for line in lines {
-                        if line.starts_with("Your system is consistent") {
-                            println!(
-                                "{} No broken reverse dependencies were found",
-                                prompt::revchevrons(Color::Blue)
-                            );
-                            return true;
-                        }
+                if let Ok((output, _)) = backend.pretend_revdep().exit_if_failed() {
+                    if parse_revdep(&output) {
+                        println!(
+                            "{} No broken reverse dependencies were found",
+                            prompt::revchevrons(Color::Blue)
+                        );
+                        return true;
                     }
                 }
                 println!(
@@ -144,9 +141,7 @@ impl PackageManager {
                 false
             }
             PackageManager::NoDryRun => {
-                let _ = OsCall::Interactive
-                    .execute("revdep-rebuild", "Rebuilding reverse dependencies")
-                    .exit_if_failed();
+                let _ = backend.revdep_rebuild().exit_if_failed();
                 true
             }
             _ => false,
@@ -154,37 +149,388 @@ impl PackageManager {
     }
 }
 
-// List and fetch pending updates. Returns "true" if there are any pending updates
-// Returns false if there are no pending updates.
+// Parse the output of emerge -p --depclean. Returns the number of orphaned dependencies and the
+// version of any kernel package among them, or None if emerge did not report a count
 //
-pub fn get_pending_updates(background_fetch: bool) -> bool {
-    match PackageManager::DryRun.update_all_packages() {
-        Ok((output, _)) => {
-            let mut pending_updates = Vec::new();
-            for line in output.split('\n') {
-                if line.starts_with("[ebuild") {
-                    let mut words = line.split(']');
-                    let _word = words.next();
-                    let _word = words.next();
-                    match _word {
-                        Some(_word) => {
-                            let word = _word.split_whitespace().next().unwrap_or("");
-                            pending_updates.push(word);
-                        }
-                        None => {
-                            break;
-                        }
-                    }
+pub fn parse_depclean(output: &str) -> Option<Orphans> {
+    let mut kernels = String::new();
+    for line in output.lines() {
+        if line.contains("gentoo-kernel") || line.contains("gentoo-sources") {
+            kernels = linux::stripchar(line.to_string());
+        }
+        if line.starts_with("Number to remove") {
+            let numdep = line
+                .split_whitespace()
+                .nth(3)
+                .and_then(|word| word.parse().ok())
+                .unwrap_or(0);
+            return Some((numdep, kernels));
+        }
+    }
+    None
+}
+
+// Parse the output of revdep-rebuild -ip. Returns true if the system is consistent
+//
+pub fn parse_revdep(output: &str) -> bool {
+    output
+        .lines()
+        .any(|line| line.starts_with("Your system is consistent"))
+}
+
+// The change emerge is going to make to a package
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Upgrade,
+    Downgrade,
+    New,
+    Rebuild,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Upgrade => "upgrade",
+            Action::Downgrade => "downgrade",
+            Action::New => "new",
+            Action::Rebuild => "rebuild",
+        }
+    }
+
+    pub fn colour(self) -> Color {
+        match self {
+            Action::Upgrade => Color::Green,
+            Action::Downgrade => Color::Red,
+            Action::New => Color::Cyan,
+            Action::Rebuild => Color::Yellow,
+        }
+    }
+}
+
+// Define a struct to hold a package pending an update, with the version it replaces
+//
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub package: Package,
+    pub installed: Option<Version>,
+    pub action: Action,
+    pub security: Vec<String>, // The GLSAs which the change fixes, filled in by glsa::tag
+}
+
+// Parse the output of emerge -puDv @world into the changes it would make. Each line gives the
+// flags, the new package, and the installed version it replaces in brackets, if any, e.g
+// [ebuild     U  ] dev-libs/openssl-3.0.13:0/3::gentoo [3.0.12:0/3::gentoo] USE="asm" 15,470 KiB
+//
+pub fn parse_changes(output: &str) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for line in output.lines() {
+        let Some((flags, rest)) = line
+            .strip_prefix("[ebuild")
+            .and_then(|line| line.split_once(']'))
+        else {
+            continue;
+        };
+        let mut words = rest.split_whitespace();
+        let Some(Ok(package)) = words.next().map(|word| word.parse::<Package>()) else {
+            continue;
+        };
+        let installed = words
+            .next()
+            .filter(|word| word.starts_with('['))
+            .and_then(|word| word.trim_matches(['[', ']']).split(':').next())
+            .and_then(|version| version.parse::<Version>().ok());
+        let action = match (&package.version, &installed) {
+            _ if flags.contains('N') => Action::New,
+            (Some(new), Some(old)) => match new.cmp(old) {
+                Ordering::Greater => Action::Upgrade,
+                Ordering::Less => Action::Downgrade,
+                Ordering::Equal => Action::Rebuild,
+            },
+            (_, None) => Action::New,
+            _ => Action::Rebuild,
+        };
+        changes.push(Change {
+            package,
+            installed,
+            action,
+            security: Vec::new(),
+        });
+    }
+    changes
+}
+
+// Parse the output of emerge -puDv @world into a list of the packages pending an update
+//
+pub fn parse_pending_updates(output: &str) -> Vec<Package> {
+    parse_changes(output)
+        .into_iter()
+        .map(|change| change.package)
+        .collect()
+}
+
+pub static EMERGE_LOG: &str = "/var/log/emerge.log";
+
+// Define a struct to hold a merge recorded in emerge.log
+//
+#[derive(Debug, PartialEq)]
+pub struct EmergeLogEntry {
+    pub timestamp: u64,
+    pub completed: bool, // false when the merge started, true when it completed
+    pub number: usize,   // The position of the package in the merge list
+    pub total: usize,    // The length of the merge list
+    pub package: String,
+}
+
+// Parse a line of emerge.log which records the start or completion of a merge, e.g
+// 1712345678:  >>> emerge (3 of 10) sys-devel/gcc-13.2.1_p20240113-r1 to /
+// 1712349999:  ::: completed emerge (3 of 10) sys-devel/gcc-13.2.1_p20240113-r1 to /
+//
+pub fn parse_emerge_log_line(line: &str) -> Option<EmergeLogEntry> {
+    let (timestamp, message) = line.split_once(':')?;
+    let timestamp = timestamp.trim().parse().ok()?;
+    let message = message.trim();
+    let (completed, rest) = if let Some(rest) = message.strip_prefix(">>> emerge (") {
+        (false, rest)
+    } else {
+        (true, message.strip_prefix("::: completed emerge (")?)
+    };
+    let (counts, rest) = rest.split_once(") ")?;
+    let (number, total) = counts.split_once(" of ")?;
+    Some(EmergeLogEntry {
+        timestamp,
+        completed,
+        number: number.trim().parse().ok()?,
+        total: total.trim().parse().ok()?,
+        package: rest.split_whitespace().next()?.to_string(),
+    })
+}
+
+// Returns slot atoms for the toolchain currently in use: the selected gcc, the selected python,
+// portage itself and the C library. These must never be removed by a cleanup action
+//
+pub fn active_toolchain() -> Vec<String> {
+    let mut toolchain = vec!["sys-apps/portage".to_string()];
+    if let Ok((output, 0)) = OsCall::Quiet.execute("gcc-config -c", "") {
+        // e.g x86_64-pc-linux-gnu-13 selects sys-devel/gcc:13
+        if let Some(slot) = output.trim().rsplit('-').next() {
+            toolchain.push(["sys-devel/gcc:", slot].concat());
+        }
+    }
+    if let Ok((output, 0)) = OsCall::Quiet.execute("eselect python show", "") {
+        // e.g python3.12 selects dev-lang/python:3.12
+        if let Some(slot) = output.trim().strip_prefix("python") {
+            toolchain.push(["dev-lang/python:", slot].concat());
+        }
+    }
+    for libc in ["sys-libs/glibc", "sys-libs/musl"] {
+        if !installed_version(libc).is_empty() {
+            toolchain.push(libc.to_string());
+        }
+    }
+    toolchain
+}
+
+// Returns the exact installed version of the best match for an atom, e.g sys-devel/gcc:13 returns
+// sys-devel/gcc-13.2.1_p20240113-r1, or an empty string if nothing matching is installed
+//
+pub fn installed_version(atom: &str) -> String {
+    let command_line = ["portageq best_version ", &portageq_root(), " ", atom].concat();
+    match OsCall::Quiet.execute(&command_line, "") {
+        Ok((output, _)) => output.trim().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+// The installed packages, e.g dev-python/baz-1.4, read from the package database
+//
+pub fn installed_packages() -> Vec<Package> {
+    let mut packages = Vec::new();
+    let Ok(categories) = fs::read_dir(target_path("/var/db/pkg")) else {
+        return packages;
+    };
+    for category in categories.flatten() {
+        let Ok(entries) = fs::read_dir(category.path()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let cpv = [
+                category.file_name().to_string_lossy(),
+                "/".into(),
+                entry.file_name().to_string_lossy(),
+            ]
+            .concat();
+            if let Ok(package) = cpv.parse::<Package>() {
+                if package.version.is_some() {
+                    packages.push(package);
                 }
             }
-            let num_updates = pending_updates.len();
+        }
+    }
+    packages
+}
+
+// Returns the --exclude arguments which stop emerge from touching the active toolchain
+//
+pub fn toolchain_excludes() -> String {
+    active_toolchain()
+        .iter()
+        .map(|atom| [" --exclude ", atom].concat())
+        .collect()
+}
+
+// The packages left out of the update on the command line, e.g --exclude www-client/firefox
+static EXCLUDED: OnceLock<Vec<String>> = OnceLock::new();
+
+// Leave packages out of every update from here on. Each is a category and name, a name alone, or a
+// whole category, e.g www-client/firefox, firefox or www-client/*
+//
+pub fn set_excluded(atoms: &[String]) {
+    let _ = EXCLUDED.set(atoms.to_vec());
+}
+
+// Whether a package is one of those left out of the update
+//
+pub fn is_excluded(package: &Package, atoms: &[String]) -> bool {
+    let cpn = package.cpn();
+    atoms.iter().any(|atom| {
+        *atom == cpn
+            || *atom == package.name
+            || atom
+                .strip_suffix("/*")
+                .is_some_and(|category| category == package.category)
+    })
+}
+
+// Returns the --exclude arguments which stop emerge updating the packages left out on the command
+// line
+//
+pub fn user_excludes() -> String {
+    EXCLUDED
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|atom| [" --exclude ", atom].concat())
+        .collect()
+}
+
+// Drop the packages left out on the command line from the pending changes, listing those dropped
+//
+fn drop_excluded(changes: &mut Vec<Change>) {
+    let Some(atoms) = EXCLUDED.get().filter(|atoms| !atoms.is_empty()) else {
+        return;
+    };
+    let (excluded, kept): (Vec<Change>, Vec<Change>) = std::mem::take(changes)
+        .into_iter()
+        .partition(|change| is_excluded(&change.package, atoms));
+    *changes = kept;
+    for change in &excluded {
+        println!(
+            "{} Leaving {} out of the update, as excluded on the command line",
+            prompt::revchevrons(Color::Yellow),
+            change.package
+        );
+    }
+}
+
+// After cleanup, check that the toolchain still works: gcc-config has a valid compiler selected,
+// and python and portage still run. If anything is broken, reinstall the versions which were
+// installed before the cleanup from binary packages
+//
+pub fn verify_toolchain(installed_before: &[String]) {
+    let checks = [
+        ("gcc-config -c", "gcc-config"),
+        ("gcc --version", "gcc"),
+        ("python --version", "python"),
+        ("emerge --version", "portage"),
+    ];
+    let mut broken = Vec::new();
+    for (command, name) in checks {
+        if !matches!(OsCall::Quiet.execute(command, ""), Ok((_, 0))) {
+            broken.push(name);
+        }
+    }
+    if broken.is_empty() {
+        println!(
+            "{} The active toolchain is intact",
+            prompt::revchevrons(Color::Blue)
+        );
+        return;
+    }
+    eprintln!(
+        "{} The toolchain is broken after cleanup: {} no longer resolves. Restoring from binary packages",
+        prompt::revchevrons(Color::Red),
+        broken.join(", ")
+    );
+    let atoms: Vec<String> = installed_before
+        .iter()
+        .filter(|package| !package.is_empty())
+        .map(|package| ["=", package].concat())
+        .collect();
+    let _ = OsCall::Interactive
+        .execute(
+            &["emerge --oneshot --usepkgonly ", &atoms.join(" ")].concat(),
+            "Restoring the toolchain",
+        )
+        .exit_if_failed();
+}
+
+// Report whether any updates are pending, for --check. Nothing on the system is changed, not even
+// the package tree, and the output is a single line, for use by configuration management tools
+//
+pub fn check_pending_updates() -> ExitCode {
+    match treestate::cached_pretend(|| OsCall::Quiet.execute("emerge -puDv @world", "")) {
+        Ok((output, 0)) => {
+            let mut changes = parse_changes(&output);
+            if changes.is_empty() {
+                println!("ok: up to date");
+                return ExitCode::NothingToDo;
+            }
+            glsa::tag(&glsa::load(), &mut changes);
+            let packages: Vec<String> = changes
+                .iter()
+                .map(|change| change.package.to_string())
+                .collect();
+            let security = changes
+                .iter()
+                .filter(|change| !change.security.is_empty())
+                .count();
+            println!(
+                "changed: {} update(s) pending, {} security: {}",
+                packages.len(),
+                security,
+                packages.join(" ")
+            );
+            ExitCode::UpdatesPending
+        }
+        _ => {
+            println!("failed: emerge could not calculate the pending updates");
+            ExitCode::Failed
+        }
+    }
+}
+
+// List pending updates, tagging those which fix a security advisory, and warn about installed
+// packages which are vulnerable with no fix pending. Returns the changes pending, which are empty
+// if there are no pending updates.
+//
+pub fn get_pending_updates(running_config: &Config) -> Vec<Change> {
+    match PackageManager::DryRun.update_all_packages() {
+        Ok((output, _)) => {
+            let mut changes = parse_changes(&output);
+            changeddeps::add_rebuilds(running_config, &mut changes);
+            drop_excluded(&mut changes);
+            let advisories = glsa::load();
+            glsa::tag(&advisories, &mut changes);
+            glsa::report_unfixed(&advisories, &changes);
+            let num_updates = changes.len();
             match num_updates {
                 0 => {
                     println!(
                         "{} There are no pending updates",
                         prompt::revchevrons(Color::Blue)
                     );
-                    return false;
+                    return changes;
                 }
                 1 => {
                     println!(
@@ -200,30 +546,51 @@ pub fn get_pending_updates(background_fetch: bool) -> bool {
                     );
                 }
             }
-            portage::package_list(&pending_updates);
-            if !background_fetch {
-                portage::fetch_sources(&pending_updates);
+            let security = changes
+                .iter()
+                .filter(|change| !change.security.is_empty())
+                .count();
+            if security > 0 {
+                println!(
+                    "{} {} of them fix security advisories",
+                    prompt::revchevrons(Color::Red),
+                    security
+                );
             }
-            true
+            portage::package_list(&changes, running_config.group_by_category);
+            changes
         }
         Err(_) => {
             eprintln!("{} Error calling emerge", prompt::revchevrons(Color::Red));
-            false
+            Vec::new()
         }
     }
 }
 
-// This function checks if the last portage sync was too recent (<=24 hours ago)
+// The timestamp of the Gentoo repository, the time the package tree was generated
+static TREE_TIMESTAMP_PATH: &str = "/var/db/repos/gentoo/metadata/timestamp";
+
+// This function checks if the last portage sync was too recent (<=24 hours ago). The tree's
+// metadata/timestamp keeps the time the tree was generated. With rsync that is close to the time
+// of the sync, but a webrsync snapshot is made once a day and may already be most of a day old when
+// it is fetched, so with webrsync the sync is too recent while no newer snapshot can exist yet
 //
-pub fn too_recent() -> bool {
-    let portage_metadata = fs::metadata("/var/db/repos/gentoo/metadata/timestamp").unwrap();
-    let filestamp = FileTime::from_last_modification_time(&portage_metadata).seconds();
-    let nowutc = chrono::offset::Utc::now();
-    let nowstamp = nowutc.timestamp();
-    if nowstamp - filestamp < (24 * 60 * 60) {
+pub fn too_recent(running_config: &Config) -> bool {
+    let Some(filestamp) = tree_timestamp() else {
+        return false; // A tree which was never synced needs syncing
+    };
+    let nowstamp = chrono::offset::Utc::now().timestamp();
+    let recent = if running_config.sync_method == "webrsync" {
+        snapshot_is_current(filestamp, nowstamp)
+    } else {
+        nowstamp - filestamp < (24 * 60 * 60)
+    };
+    if recent {
         println!(
-            "{} Last sync was too recent: Skipping sync phase",
-            prompt::revchevrons(Color::Yellow)
+            "{} The package tree is from {}, and was last synced at {}: the last sync was too recent, so skipping the sync phase. gentup --force syncs anyway",
+            prompt::revchevrons(Color::Yellow),
+            tree_age(),
+            last_synced()
         );
         true
     } else {
@@ -231,10 +598,145 @@ pub fn too_recent() -> bool {
     }
 }
 
+// The time the package tree was generated, from its metadata/timestamp in the installation being
+// updated
+//
+fn tree_timestamp() -> Option<i64> {
+    let portage_metadata = fs::metadata(target_path(TREE_TIMESTAMP_PATH)).ok()?;
+    Some(FileTime::from_last_modification_time(&portage_metadata).seconds())
+}
+
+// A time, and how long before now it was, e.g "2024-04-05 10:15, 3h 02m 10s ago"
+//
+pub fn describe_age(then: i64, now: i64) -> String {
+    format!(
+        "{}, {} ago",
+        stats::local_time(then.max(0) as u64),
+        stats::format_duration((now - then).max(0) as u64)
+    )
+}
+
+// When the package tree is from, and how old it is
+//
+pub fn tree_age() -> String {
+    match tree_timestamp() {
+        Some(filestamp) => describe_age(filestamp, chrono::offset::Utc::now().timestamp()),
+        None => "an unknown time".to_string(),
+    }
+}
+
+// The time of the last completed sync of the Gentoo repository, from emerge.log, e.g
+//
+//   1712345600:  === Sync completed for gentoo
+//
+pub fn last_sync(log: &str) -> Option<i64> {
+    log.lines()
+        .rev()
+        .filter(|line| line.trim_end().ends_with("=== Sync completed for gentoo"))
+        .find_map(|line| line.split_once(':')?.0.trim().parse().ok())
+}
+
+// When the Gentoo repository was last synced, and how long ago
+//
+pub fn last_synced() -> String {
+    match fs::read_to_string(target_path(EMERGE_LOG))
+        .ok()
+        .and_then(|log| last_sync(&log))
+    {
+        Some(synced) => describe_age(synced, chrono::offset::Utc::now().timestamp()),
+        None => "an unknown time".to_string(),
+    }
+}
+
+// The number of updates pending from the package tree as it is, without listing them
+//
+pub fn count_pending_updates() -> usize {
+    match PackageManager::DryRun.update_all_packages() {
+        Ok((output, _)) => {
+            let mut changes = parse_changes(&output);
+            drop_excluded(&mut changes);
+            changes.len()
+        }
+        Err(_) => 0,
+    }
+}
+
+// What an answer to the stale-tree prompt chooses: Some(true) to sync first, Some(false) to go
+// ahead with the package tree as it is, as skipping the prompt also does, or None to ask again
+//
+pub fn stale_tree_choice(answer: Option<&str>) -> Option<bool> {
+    match answer {
+        Some("f\n") => Some(true),
+        Some("p\n") | None => Some(false),
+        _ => None,
+    }
+}
+
+// With the sync skipped as too recent, ask whether to go ahead with the updates pending from the
+// package tree as it is, or to sync it first anyway. Unattended, the update goes ahead, unless the
+// stale-tree prompt is answered in the config file. Returns true to sync first, which is never
+// needed with nothing pending
+//
+pub fn sync_stale_tree(pending: usize) -> bool {
+    if pending == 0 {
+        return false;
+    }
+    println!(
+        "{} These {} update(s) are pending from the package tree as it was at {}, as the sync was skipped",
+        prompt::revchevrons(Color::Yellow),
+        pending,
+        tree_age()
+    );
+    if !linux::is_a_tty() && !prompt::answered("stale-tree") {
+        println!(
+            "{} Going ahead with the package tree as it is. \"answer: stale-tree f\" in the config file syncs first",
+            prompt::revchevrons(Color::Yellow)
+        );
+        return false;
+    }
+    loop {
+        let answer = Prompt::Options.askuser(
+            "stale-tree",
+            "Select p to proceed with the package tree as it is, f to sync it first anyway, or q to quit [p|f|q]",
+        );
+        // Anything else typed at the terminal is asked again. Answers from the config file are
+        // p or f, as they are checked when it is read
+        if let Some(sync_first) = stale_tree_choice(answer.as_deref()) {
+            return sync_first;
+        }
+    }
+}
+
+// Explain that nothing is pending, and whether that is from a freshly synced package tree or one
+// whose sync was skipped as too recent
+//
+pub fn report_nothing_to_do(stale_tree: bool) {
+    if stale_tree {
+        println!(
+            "{} Nothing to do from the package tree as it was at {}. The sync was skipped as too recent, so newer updates may exist: gentup --force syncs anyway",
+            prompt::revchevrons(Color::Blue),
+            tree_age()
+        );
+    } else {
+        println!(
+            "{} Nothing to do: the system is up to date with the package tree from {}",
+            prompt::revchevrons(Color::Blue),
+            tree_age()
+        );
+    }
+}
+
+// Snapshots are published shortly after midnight UTC, so one made on the current UTC day is the
+// newest there is
+//
+pub fn snapshot_is_current(snapshot: i64, now: i64) -> bool {
+    snapshot.div_euclid(24 * 60 * 60) == now.div_euclid(24 * 60 * 60)
+}
+
 // This function checks that a named package is installed.
 //
 pub fn package_is_missing(package: &str) -> bool {
-    match OsCall::Quiet.execute(&["equery l ", package].concat(), "") {
+    match Emerge.query_installed(package) {
         Ok((_, return_code)) => {
             if return_code != 0 {
                 println!();
@@ -254,23 +756,38 @@ pub fn package_is_missing(package: &str) -> bool {
                 prompt::revchevrons(Color::Red),
                 returned_error
             );
-            process::exit(1);
+            ExitCode::Failed.exit();
         }
     }
 }
 
-// This function updates the package tree metadata for Gentoo Linux
+// This function updates the package tree metadata for Gentoo Linux, with emerge --sync or, for
+// hosts where rsync is blocked, emerge-webrsync, then the eix database if the tree has changed
 //
-pub fn sync_package_tree() {
-    let _ = OsCall::Spinner
-        .execute("eix-sync", "Syncing package tree")
-        .exit_if_failed();
+pub fn sync_package_tree(running_config: &Config) {
+    let synced = if running_config.sync_method == "webrsync" {
+        Emerge.webrsync()
+    } else {
+        Emerge.sync()
+    };
+    if !matches!(synced, Ok((_, 0))) {
+        eprintln!(
+            "{} The package tree sync failed",
+            prompt::revchevrons(Color::Red)
+        );
+        ExitCode::SyncFailed.exit();
+    }
+    treestate::refresh_eix();
 }
 
 // This function calls eix to check if the named package is due an upgrade
 //
 pub fn package_outdated(package: &str) -> bool {
-    match OsCall::Quiet.execute(&["eix -u ", package].concat(), "") {
+    package_outdated_with(&Emerge, package)
+}
+
+pub fn package_outdated_with(backend: &dyn Backend, package: &str) -> bool {
+    match backend.query_outdated(package) {
         Ok((_, return_status)) => {
             if return_status != 0 {
                 return false;
@@ -288,11 +805,39 @@ pub fn package_outdated(package: &str) -> bool {
                 prompt::revchevrons(Color::Red),
                 returned_error
             );
-            process::exit(1);
+            ExitCode::Failed.exit();
         }
     }
 }
 
+// The --quiet-build option for the updates, which shows the full build output with --verbose
+//
+pub fn quiet_build() -> &'static str {
+    match prompt::verbosity() {
+        Verbosity::Verbose => " --quiet-build n",
+        _ => " --quiet-build y",
+    }
+}
+
+// Update only the given pending packages, each at the exact version pending. The packages are in
+// emerge's build order, so the dependencies of each are either already installed or come earlier
+// in the list. They are merged as --oneshot, so the world file is left alone
+//
+pub fn update_selected(packages: &[Package]) -> ShellOutResult {
+    let mut command = [
+        "emerge",
+        quiet_build(),
+        " -1v --autounmask n",
+        parallel::update_options(),
+        &user_excludes(),
+    ]
+    .concat();
+    for package in packages {
+        command = command + " =" + &package.to_string();
+    }
+    OsCall::Interactive.execute(&command, "Updating the selected packages")
+}
+
 // This function performs an update of the named package
 //
 pub fn upgrade_package(package: &str) {
@@ -304,74 +849,216 @@ pub fn upgrade_package(package: &str) {
         .exit_if_failed();
 }
 
-// After package installs there are sometimes messages to the user advising them of actions they
-// need to take. These are collected into elog files and displayed here
-// TODO - remove this function in favour of configuring elog to email the user instead
+// The directory holding the Gentoo installation being updated, when it is not the running system
+static TARGET_ROOT: OnceLock<String> = OnceLock::new();
+
+// Update the Gentoo installation in the given directory, such as a build chroot or a container's
+// root filesystem, instead of the running system. Every portage command run from here on
+// inherits ROOT, SYSROOT and PORTAGE_CONFIGROOT, so emerge installs into the directory, builds
+// against its libraries, and uses its /etc/portage and world file
 //
-pub fn elog_viewer() {
-    let _ = OsCall::Interactive.execute("elogv", "Checking for new ebuild logs");
+pub fn set_target_root(root: &str) {
+    env::set_var("ROOT", root);
+    env::set_var("SYSROOT", root);
+    env::set_var("PORTAGE_CONFIGROOT", root);
+    let _ = TARGET_ROOT.set(root.trim_end_matches('/').to_string());
+}
+
+// The directory being updated, if it is not the running system
+//
+pub fn target_root() -> Option<&'static str> {
+    TARGET_ROOT.get().map(|root| root.as_str())
+}
+
+// The root portageq is asked about, the installation being updated, e.g / or /mnt/gentoo/
+//
+pub fn portageq_root() -> String {
+    [target_root().unwrap_or(""), "/"].concat()
+}
+
+// Check that a directory holds a Gentoo installation which can be updated
+//
+pub fn check_target_root(root: &str) -> Result<(), String> {
+    for required in ["/etc/portage", "/var/db/pkg"] {
+        let path = [root.trim_end_matches('/'), required].concat();
+        if !Path::new(&path).is_dir() {
+            return Err([
+                root,
+                " is not a Gentoo installation - ",
+                &path,
+                " is missing",
+            ]
+            .concat());
+        }
+    }
+    Ok(())
+}
+
+// The location of a file of the installation being updated, e.g /etc/portage/make.conf
+//
+pub fn target_path(path: &str) -> String {
+    [target_root().unwrap_or(""), &prefix::path(path)].concat()
+}
+
+// Whether the update is of the running system itself, rather than an installation in another
+// directory or a Gentoo Prefix, neither of which has kernels or filesystems of its own to look
+// after
+//
+pub fn running_system() -> bool {
+    target_root().is_none() && !prefix::active()
+}
+
+// Returns the value of a variable set in /etc/portage/make.conf, with any quotes removed. If the
+// variable is assigned more than once, the last assignment wins, as it does for portage
+//
+pub fn make_conf_variable(variable: &str) -> Option<String> {
+    let contents = fs::read_to_string(target_path("/etc/portage/make.conf")).ok()?;
+    let mut value = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if let Some((name, assigned)) = line.split_once('=') {
+            if name.trim() == variable {
+                value = Some(
+                    assigned
+                        .trim()
+                        .trim_matches('"')
+                        .trim_matches('\'')
+                        .to_string(),
+                );
+            }
+        }
+    }
+    value
+}
+
+// Look up a variable in portage's configuration, as set in make.conf, the profile or the
+// environment
+//
+pub fn portage_variable(variable: &str) -> String {
+    OsCall::Quiet
+        .execute(&["portageq envvar ", variable].concat(), "")
+        .map(|(output, _)| output.trim().to_string())
+        .unwrap_or_default()
+}
+
+// The directory portage builds packages in, PORTAGE_TMPDIR, /var/tmp unless make.conf sets it
+//
+pub fn portage_tmpdir() -> String {
+    make_conf_variable("PORTAGE_TMPDIR").unwrap_or("/var/tmp".to_string())
+}
+
+// The directory portage writes its build and elog logs to, PORT_LOGDIR, in the installation being
+// updated
+//
+pub fn port_logdir() -> String {
+    target_path(&make_conf_variable("PORT_LOGDIR").unwrap_or("/var/log/portage".to_string()))
 }
 
 // This function calls the portage config sanity checker
 //
 pub fn find_obsolete_configs() {
-    let _ = OsCall::Interactive
-        .execute("eix-test-obsolete", "Checking obsolete configs")
-        .exit_if_failed();
+    println!(
+        "{} Checking obsolete configs",
+        prompt::chevrons(Color::Green)
+    );
+    let result = eixdb::query("eix-test-obsolete");
+    if let Ok((output, _)) = &result {
+        print!("{}", output);
+    }
+    let _ = result.exit_if_failed();
 }
 
 // This function cleans up old kernels
 //
 pub fn clean_old_kernels() {
+    if !running_system() {
+        return; // The kernels in /boot belong to the running system
+    }
     let _ = OsCall::Interactive
         .execute("eclean-kernel -a", "Cleaning old kernels")
         .exit_if_failed();
 }
 
-// This function removes old unused package tarballs
-//
-pub fn clean_distfiles() {
-    let _ = OsCall::Interactive
-        .execute("eclean -d distfiles", "Cleaning unused distfiles")
-        .exit_if_failed();
-}
-
 // eix_update resynchronises the eix database with the state of the currently installed packages
 //
 pub fn eix_update() {
-    let _ = OsCall::Spinner
-        .execute("eix-update", "Initialising package database")
-        .exit_if_failed();
+    if !eixdb::rebuild() {
+        eprintln!(
+            "{} The eix database could not be built",
+            prompt::revchevrons(Color::Red)
+        );
+        ExitCode::Failed.exit();
+    }
 }
 
 // handle_news checks to see if there is unread news and emails it if required
 //
+#[cfg_attr(not(feature = "mail"), allow(unused_variables))]
 pub fn check_news(running_config: &Config) -> u32 {
-    let mut count: u32 = 0;
-    if let Ok((output, _)) = OsCall::Quiet
-        .execute("eselect news count new", "")
-        .exit_if_failed()
-    {
-        count = output.trim().parse().unwrap_or(0);
-        if count == 0 {
-            println!("{} No unread news", prompt::revchevrons(Color::Blue));
-        } else {
+    let items = news::unread();
+    if items.is_empty() {
+        println!("{} No unread news", prompt::revchevrons(Color::Blue));
+        return 0;
+    }
+    println!(
+        "{} There are {} news item(s) to read",
+        prompt::revchevrons(Color::Yellow),
+        items.len(),
+    );
+    for item in &items {
+        println!("    {}  {}", item.posted, item.title);
+    }
+    // The news is only marked read once it has been emailed, or displayed to a user at the
+    // terminal, so that it is not lost when mail delivery fails
+    //
+    #[cfg(feature = "mail")]
+    match mail::send_email(
+        running_config,
+        String::from("gentoo-news"),
+        news::to_text(&items),
+    ) {
+        Ok(_) => {
+            for item in &items {
+                news::mark_read(item);
+                newsarchive::record(item, Method::Email(running_config.email_address.clone()));
+            }
             println!(
-                "{} There are {} news item(s) to read",
-                prompt::revchevrons(Color::Yellow),
-                count,
+                "{} News sent by email to {}",
+                prompt::revchevrons(Color::Green),
+                running_config.email_address
             );
-            if let Ok((output, _)) = OsCall::Quiet.execute("eselect news read", "News listing") {
-                mail::send_email(running_config, String::from("gentoo-news"), output);
-                println!(
-                    "{} News sent by email to {}",
-                    prompt::revchevrons(Color::Green),
-                    running_config.email_address
-                );
-            }
+            return items.len() as u32;
         }
+        Err(error) => eprintln!(
+            "{} The news could not be emailed: {}",
+            prompt::revchevrons(Color::Yellow),
+            error
+        ),
     }
-    count
+    if (linux::is_a_tty() || prompt::answered("news"))
+        && Prompt::AllowSkip
+            .askuser("news", "Display the news")
+            .is_some()
+    {
+        println!("\n{}", news::to_text(&items));
+        for item in &items {
+            news::mark_read(item);
+            newsarchive::record(item, Method::Terminal(newsarchive::terminal_user()));
+        }
+    } else {
+        println!(
+            "{} The news has been left unread. Read it with: eselect news read",
+            prompt::revchevrons(Color::Yellow)
+        );
+        for item in &items {
+            newsarchive::record(item, Method::Unread);
+            actions::add(format!(
+                "Read the news item \"{}\" with: eselect news read {}",
+                item.title, item.name
+            ));
+        }
+    }
+    items.len() as u32
 }
 
 // dispatch_conf handles pending changes to package configuration files
@@ -384,82 +1071,42 @@ pub fn check_news(running_config: &Config) -> u32 {
 // This will require "not a tty" detection, and not running dispatch-conf if it is not attached to
 // a tty, and some slight logic change to add --dispatch to the command line argument checker
 //
+// The updates which need no decision are merged first, so dispatch-conf is only run for the rest
+//
 pub fn update_config_files() {
+    if configmerge::merge_trivial() == 0 {
+        return;
+    }
     let _ = OsCall::Interactive
         .execute("dispatch-conf", "Merge config file changes")
         .exit_if_failed();
 }
 
-// Checks and corrects the ELOG configuration in make.conf
+// Checks the ELOG configuration in make.conf, and if there is none, has portage save the messages
+// from each package for gentup to read after the update
 //
-pub fn configure_elogv(running_config: &Config) {
-    let makeconf = fs::read_to_string("/etc/portage/make.conf");
-    let hostname = gethostname()
-        .into_string()
-        .unwrap_or("localhost".to_string());
+pub fn configure_elog() {
+    let makeconf = fs::read_to_string(target_path("/etc/portage/make.conf"));
     if let Ok(contents) = makeconf {
         for eachline in contents.lines() {
             if eachline.contains("PORTAGE_ELOG_SYSTEM") {
                 return;
             }
         }
-        println!("{} Configuring elogv", prompt::chevrons(Color::Yellow));
+        println!("{} Configuring elog", prompt::chevrons(Color::Yellow));
         let mut file = OpenOptions::new()
             .append(true)
-            .open("/etc/portage/make.conf")
+            .open(target_path("/etc/portage/make.conf"))
             .unwrap();
         file.seek(SeekFrom::End(0)).unwrap();
         let _ = writeln!(file, "# Logging");
         let _ = writeln!(file, "PORTAGE_ELOG_CLASSES=\"warn error log\"");
-        let _ = writeln!(file, "PORTAGE_ELOG_SYSTEM=\"mail_summary save\"");
-        let _ = writeln!(
-            file,
-            "PORTAGE_ELOG_MAILURI=\"{} /usr/bin/sendmail\"",
-            running_config.email_address
-        );
-        let _ = writeln!(file, "PORTAGE_ELOG_MAILFROM=\"root@{}\"", hostname);
-        let _ = writeln!(
-            file,
-            "PORTAGE_ELOG_MAILSUBJECT=\"gentup elog summary from {}\"",
-            hostname
-        );
-    }
-}
-
-// This functions installs hard dependencies of this program if they are missing
-//
-pub fn check_and_install_deps() {
-    let packages_to_check = [
-        ["app-portage/eix", "/usr/bin/eix", "eix-update"],
-        ["app-portage/gentoolkit", "/usr/bin/equery", ""],
-        ["app-portage/elogv", "/usr/bin/elogv", ""],
-        ["app-admin/eclean-kernel", "/usr/bin/eclean-kernel", ""],
-    ];
-
-    for package in packages_to_check {
-        if !Path::new(&package[1]).exists() {
-            println!(
-                "{} This updater requires the {} package.",
-                prompt::revchevrons(Color::Yellow),
-                &package[0]
-            );
-            let _ = OsCall::Spinner
-                .execute(
-                    &["emerge --quiet -v ", package[0]].concat(),
-                    &["Installing ", package[0]].concat(),
-                )
-                .exit_if_failed();
-            if !&package[2].eq("") {
-                let _ = OsCall::Spinner
-                    .execute(package[2], "Post installation configuration")
-                    .exit_if_failed();
-            }
-        }
+        let _ = writeln!(file, "PORTAGE_ELOG_SYSTEM=\"save\"");
     }
 }
 
 // This function checks and installs a list of optional packages - the list is taken from
-// the config file in config::PACKAGE_FILE_PATH, and although this list of packages is hardcoded
+// the file given by config::package_file, and although this list of packages is hardcoded
 // here, there is an option for the user to edit this file with the --setup command line option
 //
 pub fn check_and_install_optional_packages() {
@@ -481,9 +1128,9 @@ pub fn check_and_install_optional_packages() {
         "dev-vcs/git",
     ];
 
-    // If PACKAGE_FILE_PATH does not exist, create it with the above contents
-    if !Path::new(PACKAGE_FILE_PATH).exists() {
-        let path = Path::new(PACKAGE_FILE_PATH);
+    // If the package file does not exist, create it with the above contents
+    if !Path::new(package_file()).exists() {
+        let path = Path::new(package_file());
         let display = path.display();
         let mut file = match File::create(path) {
             Err(why) => panic!("couldn't create {}: {}", display, why),
@@ -497,23 +1144,29 @@ pub fn check_and_install_optional_packages() {
         }
     }
 
-    // Read PACKAGE_FILE_PATH into a Vector of strings
+    // Read the package file into a Vector of strings
     let packages_to_check_string =
-        fs::read_to_string(PACKAGE_FILE_PATH).expect("Error in reading the file");
+        fs::read_to_string(package_file()).expect("Error in reading the file");
     let mut counter = 0;
     let packages_to_check: Vec<&str> = packages_to_check_string.lines().collect();
     for check in &packages_to_check {
         counter += 1;
-        println!(
-            "{} Checking prerequsite package : {} of {} - {}                    ",
-            prompt::revchevrons(Color::Green),
+        let progress = format!(
+            "Checking prerequsite package : {} of {} - {}",
             counter,
             packages_to_check.len(),
             check
         );
+        let _ = execute!(io::stdout(), terminal::Clear(ClearType::CurrentLine));
+        println!(
+            "{} {}",
+            prompt::revchevrons(Color::Green),
+            linux::fit_line(&progress, 4)
+        );
         let _ = execute!(io::stdout(), cursor::MoveUp(1));
         if portage::package_is_missing(check) {
-            println!("                                                      ");
+            let _ = execute!(io::stdout(), terminal::Clear(ClearType::CurrentLine));
+            println!();
             println!(
                 "{} This program requires {} to be installed. Installing...",
                 prompt::revchevrons(Color::Yellow),
@@ -529,93 +1182,375 @@ pub fn check_and_install_optional_packages() {
                 .exit_if_failed();
         }
     }
-    println!("                                                                   ");
-    let _ = execute!(io::stdout(), cursor::MoveUp(1));
+    let _ = execute!(io::stdout(), terminal::Clear(ClearType::CurrentLine));
 }
 
 // This function downloads a specified list of package source tarballs from the package repo
 //
-pub fn fetch_sources(package_vec: &Vec<&str>) {
+pub fn fetch_sources(package_vec: &[Package]) {
     let mut count = 0;
     let total = package_vec.len();
     for ebuild_to_fetch in package_vec {
+        if budget::exceeded() {
+            break; // The rest are fetched when the update carries on
+        }
         count += 1;
-        let text = [
-            " Downloading ",
+        let progress = [
+            "Downloading ",
             &count.to_string(),
             " of ",
             &total.to_string(),
             ": ",
-            ebuild_to_fetch,
+            &ebuild_to_fetch.to_string(),
+        ]
+        .concat();
+        let command = [
+            "emerge --fetchonly --nodeps =",
+            &ebuild_to_fetch.to_string(),
         ]
         .concat();
+        // With --verbose, emerge's output is shown as the sources download
+        if prompt::verbosity() == Verbosity::Verbose {
+            let _ = OsCall::Spinner
+                .execute(&command, &progress)
+                .exit_if_failed();
+            continue;
+        }
+        let text = [" ", &linux::fit_line(&progress, 2)].concat();
         let handle = SpinnerBuilder::new().spinner(&LINE).text(text).start();
-        let _ = OsCall::Quiet
-            .execute(
-                &["emerge --fetchonly --nodeps =", ebuild_to_fetch].concat(),
-                "",
-            )
-            .exit_if_failed();
+        let _ = OsCall::Quiet.execute(&command, "").exit_if_failed();
         handle.done();
     }
 }
 
-// Shortens a package name for more aesthetic display to user
-// e.g sys-cluster/kube-scheduler-1.29.1::gentoo to sys-cluster/kube-scheduler
+// Pretty prints a table of the pending changes, with the package name, the installed and new
+// versions, and the kind of change, tagging security updates
 //
-pub fn shortname(packagename: &str) -> String {
-    let mut position = packagename.len();
-    let mut _previous = ' ';
-    for (i, c) in packagename.chars().enumerate() {
-        if c.is_numeric() && _previous == '-' {
-            position = i;
-            break;
-        }
-        _previous = c;
+pub fn package_list(changes: &[Change], group_by_category: bool) {
+    println!();
+    let (width, _height) = linux::termsize();
+    for row in package_table(changes, group_by_category, width) {
+        println!("{}", row);
     }
-    packagename[0..position - 1].to_string()
+    println!();
 }
 
-// Calculates the longest length of shortened package names in a vector of absolute package names
+// The narrowest the package name column is allowed to become on a narrow terminal
+static MIN_NAME_WIDTH: usize = 12;
+
+// Shorten text to the given number of characters, ending it with an ellipsis if anything was cut
 //
-pub fn longest(vec_of_strings: &Vec<&str>) -> u16 {
-    let mut longest_length = 0;
-    let mut _thislen = 0;
-    for string_to_consider in vec_of_strings {
-        let shortened_string = shortname(string_to_consider);
-        _thislen = shortened_string.len() as u16;
-        if _thislen > longest_length {
-            longest_length = _thislen;
-        }
+pub fn ellipsise(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
     }
-    longest_length
+    let mut shortened: String = text.chars().take(width.saturating_sub(1)).collect();
+    shortened.push('…');
+    shortened
 }
 
-// Pretty prints a list of packages
+// Lay out the table of pending changes, sorted by name, one row per package with the columns
+// aligned. When grouping by category, each category is headed by the number of its packages, e.g
+// dev-python (14), and the packages beneath it are listed by name alone. If the rows would be
+// wider than the terminal, long names are ellipsised to fit, down to a minimum width below which
+// the rows are left to wrap. Changes which fix a security advisory are tagged SECURITY
 //
-pub fn package_list(plist: &Vec<&str>) {
-    println!();
-    let spaces: u16 = 4;
-    let max_length = longest(plist);
-    let (width, _height) = linux::termsize();
-    let width = width as u16;
-    let number_of_items_per_line = width / (max_length + spaces);
-    let mut counter = 0;
-    for item in plist {
-        let shortitem = shortname(item);
-        print!("{shortitem}    ");
-        counter += 1;
-        if counter >= number_of_items_per_line {
-            println!();
-            counter = 0;
-            continue;
+pub fn package_table(changes: &[Change], group_by_category: bool, width: usize) -> Vec<String> {
+    let version_text = |version: &Option<Version>| match version {
+        Some(version) => version.to_string(),
+        None => "-".to_string(),
+    };
+    let mut sorted: Vec<&Change> = changes.iter().collect();
+    sorted.sort_by_key(|change| change.package.cpn());
+    let rows: Vec<(&str, String, String, String, &Change)> = sorted
+        .iter()
+        .map(|change| {
+            (
+                change.package.category.as_str(),
+                if group_by_category {
+                    change.package.name.clone()
+                } else {
+                    change.package.cpn()
+                },
+                version_text(&change.installed),
+                version_text(&change.package.version),
+                *change,
+            )
+        })
+        .collect();
+    let installed_width = rows.iter().map(|row| row.2.len()).max().unwrap_or(0);
+    let new_width = rows.iter().map(|row| row.3.len()).max().unwrap_or(0);
+    let action_width = rows
+        .iter()
+        .map(|row| row.4.action.name().len())
+        .max()
+        .unwrap_or(0);
+    let security_width = if changes.iter().any(|change| !change.security.is_empty()) {
+        " SECURITY".len()
+    } else {
+        0
+    };
+    let indent = if group_by_category { "    " } else { "  " };
+    let other_columns =
+        indent.len() + 2 + installed_width + 4 + new_width + 2 + action_width + security_width;
+    let name_width = rows
+        .iter()
+        .map(|row| row.1.len())
+        .max()
+        .unwrap_or(0)
+        .min(width.saturating_sub(other_columns).max(MIN_NAME_WIDTH));
+    let mut table = Vec::new();
+    let mut current_category = "";
+    for (category, name, installed, new, change) in &rows {
+        if group_by_category && *category != current_category {
+            current_category = category;
+            let count = rows.iter().filter(|row| row.0 == *category).count();
+            table.push(format!("  {} ({})", category, count));
         }
-        for _filler in 0..=(max_length - (shortitem.len() as u16)) {
-            print!(" ");
+        let mut row = format!(
+            "{}{:<name_width$}  {:>installed_width$} -> {:<new_width$}  {}{}{}",
+            indent,
+            ellipsise(name, name_width),
+            installed,
+            new,
+            SetForegroundColor(change.action.colour()),
+            change.action.name(),
+            SetForegroundColor(Color::Grey),
+        );
+        if !change.security.is_empty() {
+            row = format!(
+                "{}{} {}SECURITY{}",
+                row,
+                " ".repeat(action_width - change.action.name().len()),
+                SetForegroundColor(Color::Red),
+                SetForegroundColor(Color::Grey),
+            );
+        }
+        table.push(row);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+
+    static PRETEND_UPDATE: &str = "\
+These are the packages that would be merged, in order:
+
+Calculating dependencies... done!
+[ebuild     U  ] dev-libs/openssl-3.0.13:0/3::gentoo [3.0.12:0/3::gentoo] USE=\"asm\" 15,470 KiB
+[ebuild     U  ] sys-devel/gcc-13.2.1_p20240210:13::gentoo [13.2.1_p20240113-r1:13::gentoo] 0 KiB
+[ebuild  N     ] dev-python/trove-classifiers-2024.1.31::gentoo  PYTHON_TARGETS=\"python3_11\" 15 KiB
+
+Total: 3 packages (2 upgrades, 1 new), Size of downloads: 15,485 KiB
+";
+
+    static PRETEND_DEPCLEAN: &str = "\
+Calculating dependencies... done!
+>>> These are the packages that would be unmerged:
+
+ sys-kernel/gentoo-kernel-bin
+    selected: 6.6.13
+   protected: none
+     omitted: 6.6.21
+
+All selected packages: =sys-kernel/gentoo-kernel-bin-6.6.13
+
+>>> To ignore dependencies, use --nodeps
+Number to remove:     1
+";
+
+    #[test]
+    fn parses_pending_updates() {
+        let pending_updates: Vec<String> = parse_pending_updates(PRETEND_UPDATE)
+            .iter()
+            .map(|package| package.to_string())
+            .collect();
+        assert_eq!(
+            pending_updates,
+            vec![
+                "dev-libs/openssl-3.0.13:0/3::gentoo",
+                "sys-devel/gcc-13.2.1_p20240210:13::gentoo",
+                "dev-python/trove-classifiers-2024.1.31::gentoo",
+            ]
+        );
+        assert!(parse_pending_updates("Calculating dependencies... done!\n").is_empty());
+    }
+
+    #[test]
+    fn matches_excluded_packages() {
+        let packages = parse_pending_updates(PRETEND_UPDATE);
+        let atoms = vec!["sys-devel/gcc".to_string(), "dev-python/*".to_string()];
+        let excluded: Vec<bool> = packages
+            .iter()
+            .map(|package| is_excluded(package, &atoms))
+            .collect();
+        assert_eq!(excluded, vec![false, true, true]);
+        assert!(is_excluded(&packages[0], &["openssl".to_string()]));
+        assert!(!is_excluded(
+            &packages[0],
+            &["dev-libs/openssl-compat".to_string()]
+        ));
+    }
+
+    #[test]
+    fn parses_changes_and_tabulates_them() {
+        let output = "\
+[ebuild     U  ] dev-libs/openssl-3.0.13:0/3::gentoo [3.0.12:0/3::gentoo] USE=\"asm\" 15,470 KiB
+[ebuild     UD ] sys-libs/zlib-1.2.13-r1:0/1::gentoo [1.3.1:0/1::gentoo] 0 KiB
+[ebuild  N     ] dev-python/trove-classifiers-2024.1.31::gentoo  PYTHON_TARGETS=\"python3_11\" 15 KiB
+[ebuild   R    ] app-editors/vim-9.1.0:0::gentoo [9.1.0:0::gentoo] USE=\"-X*\" 0 KiB
+";
+        let actions: Vec<Action> = parse_changes(output)
+            .iter()
+            .map(|change| change.action)
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Upgrade,
+                Action::Downgrade,
+                Action::New,
+                Action::Rebuild
+            ]
+        );
+        let table = package_table(&parse_changes(output), false, 80);
+        assert!(table[0].starts_with("  app-editors/vim                9.1.0 -> 9.1.0      "));
+        assert!(table[0].contains("rebuild"));
+        assert!(table[1].starts_with("  dev-libs/openssl              3.0.12 -> 3.0.13     "));
+        assert!(table[2].starts_with("  dev-python/trove-classifiers       - -> 2024.1.31  "));
+        let table = package_table(&parse_changes(output), true, 80);
+        assert_eq!(table[0], "  app-editors (1)");
+        assert!(table[1].starts_with("    vim                 9.1.0 -> 9.1.0      "));
+        assert_eq!(table.len(), 8);
+    }
+
+    #[test]
+    fn fits_package_table_to_narrow_terminals() {
+        let output = "\
+[ebuild     U  ] dev-python/sphinxcontrib-applehelp-1.0.8::gentoo [1.0.7::gentoo] 0 KiB
+[ebuild     U  ] sys-libs/zlib-1.3.1:0/1::gentoo [1.3:0/1::gentoo] 0 KiB
+";
+        let changes = parse_changes(output);
+        let visible = |row: &str| {
+            row.replace(&SetForegroundColor(Color::Green).to_string(), "")
+                .replace(&SetForegroundColor(Color::Grey).to_string(), "")
+                .chars()
+                .count()
+        };
+
+        // Wide enough for every name
+        let table = package_table(&changes, false, 120);
+        assert!(table[0].starts_with("  dev-python/sphinxcontrib-applehelp  1.0.7 -> 1.0.8"));
+        assert!(table.iter().all(|row| visible(row) == 61));
+
+        // Long names are ellipsised so that each row fits
+        let table = package_table(&changes, false, 50);
+        assert!(table[0].starts_with("  dev-python/sphinxcontr…  1.0.7 -> 1.0.8"));
+        assert!(table[1].starts_with("  sys-libs/zlib              1.3 -> 1.3.1"));
+        assert!(table.iter().all(|row| visible(row) == 50));
+
+        // Narrower than the other columns leaves the minimum name width, without panicking
+        for width in [0, 1, 10, 30] {
+            let table = package_table(&changes, false, width);
+            assert!(table[0].starts_with("  dev-python/…  1.0.7 -> 1.0.8"));
         }
+        assert_eq!(ellipsise("sys-libs/zlib", 13), "sys-libs/zlib");
+        assert_eq!(ellipsise("sys-libs/zlib", 5), "sys-…");
+        assert_eq!(ellipsise("sys-libs/zlib", 0), "…");
+    }
+
+    #[test]
+    fn webrsync_snapshot_is_current_until_the_next_day() {
+        // 2024-04-05 00:45 UTC, the time of that day's snapshot
+        let snapshot = 1712277900;
+        assert!(snapshot_is_current(snapshot, snapshot + 20 * 60 * 60));
+        assert!(!snapshot_is_current(snapshot, snapshot + 24 * 60 * 60));
+        assert!(!snapshot_is_current(snapshot - 60 * 60, snapshot));
     }
-    if counter > 0 {
-        println!();
+
+    #[test]
+    fn parses_depclean_count_and_kernels() {
+        assert_eq!(
+            parse_depclean(PRETEND_DEPCLEAN),
+            Some((1, "6613".to_string()))
+        );
+        assert_eq!(parse_depclean("Calculating dependencies... done!\n"), None);
+    }
+
+    #[test]
+    fn depclean_dry_run_uses_pretend_output() {
+        let backend = MockBackend {
+            pretend_depclean_output: PRETEND_DEPCLEAN.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            PackageManager::DryRun.depclean_with(&backend),
+            (1, "6613".to_string())
+        );
+        assert_eq!(*backend.calls.borrow(), vec!["pretend_depclean"]);
+    }
+
+    #[test]
+    fn depclean_preserves_kernel_when_asked() {
+        let backend = MockBackend::default();
+        PackageManager::PreserveKernel.depclean_with(&backend);
+        PackageManager::AllPackages.depclean_with(&backend);
+        assert_eq!(
+            *backend.calls.borrow(),
+            vec![
+                "depclean preserve_kernel=true",
+                "depclean preserve_kernel=false"
+            ]
+        );
+    }
+
+    #[test]
+    fn revdep_rebuild_detects_consistent_system() {
+        let consistent = MockBackend {
+            pretend_revdep_output: "Your system is consistent\n".to_string(),
+            ..Default::default()
+        };
+        assert!(PackageManager::DryRun.revdep_rebuild_with(&consistent));
+        let broken = MockBackend {
+            pretend_revdep_output: "Dynamic linking on your system is inconsistent...\n"
+                .to_string(),
+            ..Default::default()
+        };
+        assert!(!PackageManager::DryRun.revdep_rebuild_with(&broken));
+    }
+
+    #[test]
+    fn package_outdated_follows_backend_status() {
+        let backend = MockBackend {
+            outdated: vec!["sys-apps/portage".to_string()],
+            ..Default::default()
+        };
+        assert!(package_outdated_with(&backend, "sys-apps/portage"));
+        assert!(!package_outdated_with(&backend, "sys-devel/gcc"));
+    }
+
+    #[test]
+    fn describes_a_stale_tree() {
+        let then = 1712312100;
+        assert!(describe_age(then, then + 3 * 3600 + 2 * 60 + 10).ends_with(", 3h 02m 10s ago"));
+        assert!(describe_age(then, then - 60).ends_with(", 0s ago"));
+
+        let log = "\
+1712300000:  === Sync completed for gentoo
+1712300500:  *** emerge --oneshot sys-apps/portage
+1712312100:  >>> Starting rsync with rsync://rsync.gentoo.org/gentoo-portage
+1712312160:  === Sync completed for gentoo
+1712312170:  === Sync completed for guru
+";
+        assert_eq!(last_sync(log), Some(1712312160));
+        assert_eq!(last_sync("1712300500:  *** emerge --sync\n"), None);
+
+        assert_eq!(stale_tree_choice(Some("f\n")), Some(true));
+        assert_eq!(stale_tree_choice(Some("p\n")), Some(false));
+        assert_eq!(stale_tree_choice(None), Some(false));
+        assert_eq!(stale_tree_choice(Some("x\n")), None);
     }
-    println!();
 }
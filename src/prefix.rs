@@ -0,0 +1,80 @@
+// Gentoo Prefix
+// A Gentoo Prefix is a Gentoo installation in a directory of the user's choosing, the EPREFIX,
+// on top of another operating system or another distribution, often on servers the user does not
+// administer, e.g
+//
+//   /home/alice/gentoo/etc/portage/make.conf
+//   /home/alice/gentoo/var/db/pkg
+//
+// gentup run from a prefix shell finds the prefix from EPREFIX in the environment, or from where
+// emerge is installed, and from then on reads and writes every file under it, its own
+// configuration and state included. A prefix belongs to its user, so root is not needed, and the
+// steps which look after the host itself, its kernels, filesystems and reboots, are left out
+
+use std::{env, path::Path, sync::OnceLock};
+
+// The EPREFIX, or empty if this is not a Gentoo Prefix
+static EPREFIX: OnceLock<String> = OnceLock::new();
+
+// The prefix emerge is installed in, from its path, e.g /home/alice/gentoo from
+// /home/alice/gentoo/usr/bin/emerge. None if emerge is installed in /usr/bin
+//
+pub fn eprefix_of(emerge: &str) -> Option<&str> {
+    emerge
+        .strip_suffix("/usr/bin/emerge")
+        .map(|prefix| prefix.trim_end_matches('/'))
+        .filter(|prefix| !prefix.is_empty())
+}
+
+// Look for the prefix this is run from
+//
+fn detect() -> String {
+    let from_environment = env::var("EPREFIX")
+        .ok()
+        .map(|prefix| prefix.trim_end_matches('/').to_string())
+        .filter(|prefix| !prefix.is_empty());
+    let prefix = from_environment.or_else(|| {
+        env::var("PATH")
+            .unwrap_or_default()
+            .split(':')
+            .map(|directory| [directory.trim_end_matches('/'), "/emerge"].concat())
+            .find(|emerge| Path::new(emerge).exists())
+            .and_then(|emerge| eprefix_of(&emerge).map(String::from))
+    });
+    prefix
+        .filter(|prefix| Path::new(&[prefix, "/etc/portage"].concat()).is_dir())
+        .unwrap_or_default()
+}
+
+// The EPREFIX of the Gentoo Prefix being updated, if it is one
+//
+pub fn eprefix() -> Option<&'static str> {
+    Some(EPREFIX.get_or_init(detect).as_str()).filter(|prefix| !prefix.is_empty())
+}
+
+// Whether this is a Gentoo Prefix
+//
+pub fn active() -> bool {
+    eprefix().is_some()
+}
+
+// The location of a file within the prefix, or on the system if this is not a prefix
+//
+pub fn path(path: &str) -> String {
+    [eprefix().unwrap_or(""), path].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_prefix_from_emerge() {
+        assert_eq!(
+            eprefix_of("/home/alice/gentoo/usr/bin/emerge"),
+            Some("/home/alice/gentoo")
+        );
+        assert_eq!(eprefix_of("/usr/bin/emerge"), None);
+        assert_eq!(eprefix_of("/opt/emerge"), None);
+    }
+}
@@ -0,0 +1,575 @@
+// Preflight checks
+// These run before anything is synced or built, so that a problem with the system is reported
+// immediately rather than being discovered hours into a full update
+
+use crate::{
+    atom::Package,
+    config::MountThreshold,
+    exitcode::ExitCode,
+    linux::{self, OsCall},
+    portage, prompt, throttle, Config, Prompt,
+};
+use crossterm::style::Color;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+// hwmon driver names which report CPU package or core temperatures
+static CPU_SENSORS: [&str; 5] = ["coretemp", "k10temp", "zenpower", "cpu_thermal", "acpitz"];
+
+// Packages known to need a large amount of space in PORTAGE_TMPDIR to build, with an approximate
+// requirement in MB
+static HUGE_PACKAGES: [(&str, u64); 12] = [
+    ("www-client/chromium", 16384),
+    ("dev-lang/rust", 12288),
+    ("app-office/libreoffice", 10240),
+    ("www-client/firefox", 8192),
+    ("mail-client/thunderbird", 8192),
+    ("dev-qt/qtwebengine", 8192),
+    ("net-libs/webkit-gtk", 6144),
+    ("dev-lang/ghc", 6144),
+    ("sys-devel/llvm", 5120),
+    ("llvm-core/llvm", 5120),
+    ("sys-devel/gcc", 4096),
+    ("dev-java/openjdk", 4096),
+];
+
+// Files used to temporarily build huge packages on disk when PORTAGE_TMPDIR is too small
+pub static NOTMPFS_DIR: &str = "/var/tmp/notmpfs";
+static NOTMPFS_ENV_FILE: &str = "/etc/portage/env/gentup-notmpfs.conf";
+static NOTMPFS_PACKAGE_ENV_FILE: &str = "/etc/portage/package.env/gentup-notmpfs";
+
+// Paths which an update writes to, and which must therefore be on healthy, writable filesystems
+static WRITTEN_PATHS: [&str; 6] = ["/", "/etc", "/usr", "/var", "/var/tmp", "/boot"];
+
+// Define a struct to hold the free space and free inodes measured on a path
+//
+pub struct MountUsage {
+    pub path: String,
+    pub mount_point: String,
+    pub free_mb: u64,
+    pub free_inodes: Option<u64>, // Some filesystems, such as btrfs, do not report inode counts
+}
+
+// The mount point, free space and free inodes of a path, from the line df printed for it. btrfs
+// and other filesystems which allocate inodes as they go report 0 inodes in total, and so none
+// free, so their free inodes are not known rather than 0
+//
+fn parse_df_line(path: &str, line: &str) -> Option<MountUsage> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let count = fields.len();
+    let free_bytes: u64 = fields[count - 3].parse().ok()?;
+    let total_inodes: u64 = fields[count - 1].parse().unwrap_or(0);
+    Some(MountUsage {
+        path: path.to_string(),
+        mount_point: fields[..count - 3].join(" "),
+        free_mb: free_bytes / (1024 * 1024),
+        free_inodes: if total_inodes > 0 {
+            fields[count - 2].parse().ok()
+        } else {
+            None
+        },
+    })
+}
+
+// Query df for the mount point, free space and free inodes of a path. Each path is queried on its
+// own, so one df cannot measure does not leave the others matched with the wrong lines, and is
+// given to df as a single argument, so a path with spaces in it is measured as it is
+//
+fn mount_usage(path: &str) -> Option<MountUsage> {
+    let output = linux::untranslated(&mut Command::new("df"))
+        .args(["--output=target,avail,iavail,itotal", "-B1", path])
+        .output()
+        .ok()?;
+    parse_df_line(
+        path,
+        String::from_utf8_lossy(&output.stdout).lines().nth(1)?,
+    )
+}
+
+// The configured paths which are short of free space or free inodes, with what was measured
+//
+pub fn short_of_space(running_config: &Config) -> Vec<(&MountThreshold, MountUsage)> {
+    let mut offenders = Vec::new();
+    for threshold in &running_config.mount_thresholds {
+        if !Path::new(&threshold.path).exists() {
+            continue;
+        }
+        let Some(measured) = mount_usage(&threshold.path) else {
+            continue;
+        };
+        let low_space = measured.free_mb < threshold.min_free_mb;
+        let low_inodes = match measured.free_inodes {
+            Some(free_inodes) => free_inodes < threshold.min_free_inodes,
+            None => false,
+        };
+        if low_space || low_inodes {
+            offenders.push((threshold, measured));
+        }
+    }
+    offenders
+}
+
+// Check that each configured path has enough free space and free inodes to survive an update.
+// If any do not, display a table of the offending mounts and exit
+//
+pub fn check_disk_space(running_config: &Config) {
+    let offenders = short_of_space(running_config);
+    if offenders.is_empty() {
+        println!(
+            "{} Free disk space and inodes are sufficient",
+            prompt::revchevrons(Color::Blue)
+        );
+        return;
+    }
+    eprintln!(
+        "{} Insufficient free space to safely perform an update:\n",
+        prompt::revchevrons(Color::Red)
+    );
+    eprintln!(
+        "{:12} {:12} {:>10} {:>10} {:>12} {:>12}",
+        "Path", "Mounted on", "Free MB", "Needed MB", "Free inodes", "Needed inodes"
+    );
+    for (threshold, measured) in offenders {
+        let free_inodes = match measured.free_inodes {
+            Some(free_inodes) => free_inodes.to_string(),
+            None => "-".to_string(),
+        };
+        eprintln!(
+            "{:12} {:12} {:>10} {:>10} {:>12} {:>12}",
+            measured.path,
+            measured.mount_point,
+            measured.free_mb,
+            threshold.min_free_mb,
+            free_inodes,
+            threshold.min_free_inodes
+        );
+    }
+    eprintln!(
+        "\n{} Free up some space, or adjust the mount_threshold entries with gentup --setup",
+        prompt::revchevrons(Color::Red)
+    );
+    ExitCode::PreflightFailed.exit();
+}
+
+// Parse /proc/mdstat and return a description of each degraded or failed software RAID array.
+// A healthy array shows a status like [2/2] [UU], a degraded one [2/1] [U_], and failed member
+// devices are marked with (F)
+//
+fn degraded_md_arrays() -> Vec<String> {
+    let mut degraded = Vec::new();
+    let Ok(mdstat) = fs::read_to_string("/proc/mdstat") else {
+        return degraded;
+    };
+    let mut array = String::new();
+    for line in mdstat.lines() {
+        if line.starts_with("md") && line.contains(" : ") {
+            array = line.split_whitespace().next().unwrap_or("").to_string();
+            if line.contains("(F)") {
+                degraded.push(format!("/dev/{} has a failed member device", array));
+            }
+        } else if line.contains("blocks") {
+            if let Some(status) = line.split_whitespace().last() {
+                if status.starts_with('[') && status.contains('_') {
+                    degraded.push(format!("/dev/{} is degraded {}", array, status));
+                }
+            }
+        }
+    }
+    degraded
+}
+
+// Check that the filesystems an update writes to are mounted read-write, and that the RAID arrays
+// beneath them are healthy. Updating onto a read-only remounted or degraded filesystem only makes
+// matters worse, so exit with an explanation if a problem is found
+//
+pub fn check_filesystem_health() {
+    let mut problems = Vec::new();
+    let mut btrfs_mounts: Vec<String> = Vec::new();
+    for path in WRITTEN_PATHS {
+        let Some(entry) = linux::mount_for(path) else {
+            continue;
+        };
+        if entry.has_option("ro") {
+            problems.push(format!(
+                "{} is on {}, which is mounted read-only",
+                path, entry.mount_point
+            ));
+        }
+        if entry.fstype == "btrfs" && !btrfs_mounts.contains(&entry.mount_point) {
+            if entry.has_option("degraded") {
+                problems.push(format!(
+                    "btrfs filesystem {} is mounted degraded",
+                    entry.mount_point
+                ));
+            }
+            btrfs_mounts.push(entry.mount_point);
+        }
+    }
+    for mount_point in &btrfs_mounts {
+        // btrfs device stats -c exits non-zero if any device has recorded errors
+        if let Ok((_, status)) =
+            OsCall::Quiet.execute(&["btrfs device stats -c ", mount_point].concat(), "")
+        {
+            if status != 0 {
+                problems.push(format!(
+                    "btrfs filesystem {} has device errors, see btrfs device stats {}",
+                    mount_point, mount_point
+                ));
+            }
+        }
+    }
+    problems.extend(degraded_md_arrays());
+    if problems.is_empty() {
+        return;
+    }
+    for problem in &problems {
+        eprintln!("{} {}", prompt::revchevrons(Color::Red), problem);
+    }
+    eprintln!(
+        "{} Not updating: writing to an unhealthy or read-only filesystem risks making things worse. \
+        Repair the filesystem or array first",
+        prompt::revchevrons(Color::Red)
+    );
+    ExitCode::PreflightFailed.exit();
+}
+
+// Returns the filesystem type and free space in MB of the filesystem holding a path
+//
+pub fn filesystem_of(path: &str) -> Option<(String, u64)> {
+    let (output, _) = OsCall::Quiet
+        .execute(&["df --output=fstype,avail -B1 ", path].concat(), "")
+        .ok()?;
+    let line = output.lines().nth(1)?;
+    let mut fields = line.split_whitespace();
+    let fstype = fields.next()?.to_string();
+    let free_bytes: u64 = fields.next()?.parse().ok()?;
+    Some((fstype, free_bytes / (1024 * 1024)))
+}
+
+// The space in MB which the named package, e.g www-client/chromium, is known to need to build, if
+// it is one of the huge packages
+//
+pub fn build_space_mb(cpn: &str) -> Option<u64> {
+    HUGE_PACKAGES
+        .iter()
+        .find(|(huge_package, _)| *huge_package == cpn)
+        .map(|(_, needed_mb)| *needed_mb)
+}
+
+// Check that PORTAGE_TMPDIR is large enough to build each of the pending packages which are known
+// to need a lot of build space. When PORTAGE_TMPDIR is a tmpfs, packages which will not fit are
+// temporarily redirected to build on disk, if so configured. Otherwise the user is warned
+//
+pub fn check_portage_tmpdir(running_config: &Config, pending_updates: &[Package]) {
+    remove_tmpdir_redirect(); // Remove anything left over from a previous run which did not finish
+    let tmpdir = portage::portage_tmpdir();
+    let Some((fstype, free_mb)) = filesystem_of(&tmpdir) else {
+        return;
+    };
+    let mut too_large = Vec::new();
+    for package in pending_updates {
+        let name = package.cpn();
+        for (huge_package, needed_mb) in HUGE_PACKAGES {
+            if name == huge_package && needed_mb > free_mb {
+                too_large.push((name.clone(), needed_mb));
+            }
+        }
+    }
+    if too_large.is_empty() {
+        return;
+    }
+    for (package, needed_mb) in &too_large {
+        println!(
+            "{} {} needs around {} MB to build, but PORTAGE_TMPDIR {} ({}) has {} MB free",
+            prompt::revchevrons(Color::Yellow),
+            package,
+            needed_mb,
+            tmpdir,
+            fstype,
+            free_mb
+        );
+    }
+    if fstype != "tmpfs" || !running_config.tmpfs_redirect {
+        return;
+    }
+    if !Path::new(&portage::target_path("/etc/portage/package.env")).is_dir() {
+        println!(
+            "{} /etc/portage/package.env is not a directory, so these packages cannot be redirected to disk",
+            prompt::revchevrons(Color::Yellow)
+        );
+        return;
+    }
+    let _ = fs::create_dir_all(NOTMPFS_DIR);
+    if let Some((fstype, _)) = filesystem_of(NOTMPFS_DIR) {
+        if fstype == "tmpfs" {
+            println!(
+                "{} {} is also on tmpfs, so these packages cannot be redirected to disk",
+                prompt::revchevrons(Color::Yellow),
+                NOTMPFS_DIR
+            );
+            return;
+        }
+    }
+    let _ = fs::create_dir_all(portage::target_path("/etc/portage/env"));
+    let redirected = File::create(portage::target_path(NOTMPFS_ENV_FILE))
+        .and_then(|mut env_file| writeln!(env_file, "PORTAGE_TMPDIR=\"{}\"", NOTMPFS_DIR))
+        .and_then(|_| {
+            let mut package_env_file =
+                File::create(portage::target_path(NOTMPFS_PACKAGE_ENV_FILE))?;
+            for (package, _) in &too_large {
+                writeln!(package_env_file, "{} gentup-notmpfs.conf", package)?;
+            }
+            Ok(())
+        });
+    match redirected {
+        Ok(_) => println!(
+            "{} These packages will be built in {} for this update",
+            prompt::revchevrons(Color::Green),
+            NOTMPFS_DIR
+        ),
+        Err(error) => {
+            eprintln!(
+                "{} Could not redirect packages to build on disk: {}",
+                prompt::revchevrons(Color::Red),
+                error
+            );
+            remove_tmpdir_redirect();
+        }
+    }
+}
+
+// Remove the temporary PORTAGE_TMPDIR redirection for huge packages
+//
+pub fn remove_tmpdir_redirect() {
+    let _ = fs::remove_file(portage::target_path(NOTMPFS_PACKAGE_ENV_FILE));
+    let _ = fs::remove_file(portage::target_path(NOTMPFS_ENV_FILE));
+}
+
+// Returns the 1-minute load average from /proc/loadavg
+//
+fn load_average() -> Option<f32> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+// Returns the highest CPU temperature in degrees Celsius reported by any CPU hwmon sensor, or None
+// if the system has no recognisable CPU sensors
+//
+fn cpu_temperature() -> Option<u32> {
+    let mut hottest = None;
+    for hwmon in fs::read_dir("/sys/class/hwmon").ok()?.flatten() {
+        let name = fs::read_to_string(hwmon.path().join("name")).unwrap_or_default();
+        if !CPU_SENSORS.contains(&name.trim()) {
+            continue;
+        }
+        let Ok(entries) = fs::read_dir(hwmon.path()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let filename = entry.file_name().to_string_lossy().to_string();
+            if !(filename.starts_with("temp") && filename.ends_with("_input")) {
+                continue;
+            }
+            // hwmon reports temperatures in millidegrees
+            if let Ok(reading) = fs::read_to_string(entry.path()) {
+                if let Ok(millidegrees) = reading.trim().parse::<u32>() {
+                    let degrees = millidegrees / 1000;
+                    if degrees > hottest.unwrap_or(0) {
+                        hottest = Some(degrees);
+                    }
+                }
+            }
+        }
+    }
+    hottest
+}
+
+// Describe the state of the system's power supplies
+//
+enum PowerSource {
+    Mains,
+    Battery(u32), // Running on battery, with the lowest charge percentage of any battery
+    Unknown,      // No power supply information, e.g. a desktop or server without ACPI batteries
+}
+
+// Reads /sys/class/power_supply to determine whether the system is running from mains power or
+// from battery
+//
+fn power_source() -> PowerSource {
+    let Ok(supplies) = fs::read_dir("/sys/class/power_supply") else {
+        return PowerSource::Unknown;
+    };
+    let mut on_mains = false;
+    let mut lowest_charge = None;
+    for supply in supplies.flatten() {
+        let path = supply.path();
+        let read = |attribute: &str| {
+            fs::read_to_string(path.join(attribute))
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        match read("type").as_str() {
+            "Mains" | "USB" if read("online") == "1" => on_mains = true,
+            "Battery" => {
+                if let Ok(capacity) = read("capacity").parse::<u32>() {
+                    if capacity < lowest_charge.unwrap_or(u32::MAX) {
+                        lowest_charge = Some(capacity);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    match (on_mains, lowest_charge) {
+        (false, Some(charge)) => PowerSource::Battery(charge),
+        (true, _) => PowerSource::Mains,
+        (false, None) => PowerSource::Unknown,
+    }
+}
+
+// Laptops running on a low battery should not start a long build. If there is a user at the
+// terminal, or the battery prompt is answered in the config file, ask whether to carry on.
+// Otherwise wait for mains power to return
+//
+pub fn check_power_supply(running_config: &Config) {
+    if running_config.battery_minimum == 0 {
+        return;
+    }
+    let mut waiting = false;
+    loop {
+        match power_source() {
+            PowerSource::Battery(charge) if charge < running_config.battery_minimum => {
+                if linux::is_a_tty() || prompt::answered("battery") {
+                    println!(
+                        "{} Running on battery at {}% charge, below the configured minimum of {}%",
+                        prompt::revchevrons(Color::Yellow),
+                        charge,
+                        running_config.battery_minimum
+                    );
+                    let _ = Prompt::PressReturn.askuser("battery", "Build on battery power anyway");
+                    return;
+                }
+                if !waiting {
+                    println!(
+                        "{} Running on battery at {}% charge. Waiting for mains power",
+                        prompt::revchevrons(Color::Yellow),
+                        charge
+                    );
+                    waiting = true;
+                }
+                thread::sleep(Duration::from_secs(60));
+            }
+            _ => {
+                if waiting {
+                    println!(
+                        "{} Mains power restored, resuming",
+                        prompt::revchevrons(Color::Green)
+                    );
+                }
+                return;
+            }
+        }
+    }
+}
+
+// Run all of the checks which must pass immediately before a heavy build is started
+//
+pub fn before_build(running_config: &Config) {
+    check_power_supply(running_config);
+    wait_for_quiet_system(running_config);
+    throttle::before_build(running_config);
+}
+
+// Before a heavy build, check the load average and CPU temperature against the configured limits.
+// Depending on the configuration, either wait for the system to calm down, or exit
+//
+pub fn wait_for_quiet_system(running_config: &Config) {
+    if running_config.load_limit <= 0.0 && running_config.temperature_limit == 0 {
+        return;
+    }
+    let mut waiting = false;
+    loop {
+        let mut reasons = Vec::new();
+        if running_config.load_limit > 0.0 {
+            if let Some(load) = load_average() {
+                if load > running_config.load_limit {
+                    reasons.push(format!(
+                        "load average {:.2} exceeds {:.2}",
+                        load, running_config.load_limit
+                    ));
+                }
+            }
+        }
+        if running_config.temperature_limit > 0 {
+            if let Some(temperature) = cpu_temperature() {
+                if temperature > running_config.temperature_limit {
+                    reasons.push(format!(
+                        "CPU temperature {}C exceeds {}C",
+                        temperature, running_config.temperature_limit
+                    ));
+                }
+            }
+        }
+        if reasons.is_empty() {
+            if waiting {
+                println!(
+                    "{} System is quiet again, resuming",
+                    prompt::revchevrons(Color::Green)
+                );
+            }
+            return;
+        }
+        if !running_config.wait_when_busy {
+            eprintln!(
+                "{} Not starting the build: {}",
+                prompt::revchevrons(Color::Red),
+                reasons.join(", ")
+            );
+            ExitCode::PreflightFailed.exit();
+        }
+        println!(
+            "{} Waiting before building: {}",
+            prompt::revchevrons(Color::Yellow),
+            reasons.join(", ")
+        );
+        waiting = true;
+        thread::sleep(Duration::from_secs(60));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_df_lines() {
+        let ext4 = parse_df_line("/var", "/var          52428800000  3120456  3276800").unwrap();
+        assert_eq!(ext4.mount_point, "/var");
+        assert_eq!(ext4.free_mb, 50000);
+        assert_eq!(ext4.free_inodes, Some(3120456));
+
+        let btrfs = parse_df_line("/", "/             10485760000        0        0").unwrap();
+        assert_eq!(btrfs.free_inodes, None);
+
+        let spaced = parse_df_line("/mnt/my disk", "/mnt/my disk  1048576  10  20").unwrap();
+        assert_eq!(spaced.mount_point, "/mnt/my disk");
+        assert!(parse_df_line("/", "df: /: No such file or directory").is_none());
+
+        // A threshold path with a space in it is measured, not split into two paths
+        let path = std::env::temp_dir().join(format!("gentup df {}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let measured = mount_usage(&path.to_string_lossy());
+        let _ = fs::remove_dir(&path);
+        assert_eq!(measured.unwrap().path, path.to_string_lossy());
+    }
+}
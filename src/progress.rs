@@ -0,0 +1,257 @@
+// Live compile progress
+// emerge --quiet-build shows nothing while a package builds, which for qtwebengine or chromium can
+// be hours of silence. While the world update runs, the build log of each package being built is
+// sampled every few seconds for the progress markers its build system writes, e.g
+//
+//   [1234/5678] Building CXX object src/core/CMakeFiles/core.dir/render.cpp.o    (ninja)
+//   [ 45%] Building C object lib/CMakeFiles/lib.dir/parse.c.o                     (CMake make)
+//
+// and the progress is shown in the terminal title beside the package name, and by the status
+// socket and page. A build system which writes no markers, such as autotools, is shown by how fast
+// its build log is growing instead
+
+use crate::{
+    atom::Package,
+    linux,
+    portage::{self, EMERGE_LOG},
+    preflight,
+};
+use crossterm::{execute, terminal::SetTitle};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How often the build logs are sampled
+static INTERVAL: Duration = Duration::from_secs(2);
+
+// How much of the end of a build log is searched for a progress marker
+static TAIL_BYTES: u64 = 64 * 1024;
+
+// The progress of each package being built, as last sampled, for the status socket and page
+static CURRENT: Mutex<String> = Mutex::new(String::new());
+
+// The progress a build log shows
+//
+#[derive(Debug, PartialEq)]
+pub enum CompileProgress {
+    Steps(u64, u64), // ninja's count of build steps, done and total
+    Percent(u64),    // CMake's percentage through the targets
+    Growing(u64),    // No markers, so the growth of the build log in bytes per second
+}
+
+impl fmt::Display for CompileProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileProgress::Steps(done, total) => write!(
+                f,
+                "compiling object {}/{} ({}%)",
+                done,
+                total,
+                (done * 100).checked_div(*total).unwrap_or(0)
+            ),
+            CompileProgress::Percent(percent) => write!(f, "{}%", percent),
+            CompileProgress::Growing(rate) => write!(f, "build log growing {} KB/s", rate / 1024),
+        }
+    }
+}
+
+// Find the latest progress marker in the end of a build log
+//
+pub fn parse_markers(tail: &str) -> Option<CompileProgress> {
+    for line in tail.lines().rev() {
+        let Some((marker, _)) = line
+            .trim_start()
+            .strip_prefix('[')
+            .and_then(|line| line.split_once(']'))
+        else {
+            continue;
+        };
+        if let Some((done, total)) = marker.split_once('/') {
+            if let (Ok(done), Ok(total)) = (done.trim().parse(), total.trim().parse()) {
+                return Some(CompileProgress::Steps(done, total));
+            }
+        } else if let Some(percent) = marker.trim().strip_suffix('%') {
+            if let Ok(percent) = percent.trim().parse() {
+                return Some(CompileProgress::Percent(percent));
+            }
+        }
+    }
+    None
+}
+
+// The packages which emerge.log shows have started building since the given offset, and not yet
+// finished
+//
+pub fn building(log: &str) -> Vec<String> {
+    let mut packages: Vec<String> = Vec::new();
+    for entry in log.lines().filter_map(portage::parse_emerge_log_line) {
+        if entry.completed {
+            packages.retain(|package| *package != entry.package);
+        } else if !packages.contains(&entry.package) {
+            packages.push(entry.package);
+        }
+    }
+    packages
+}
+
+// The build log of a package being built, in PORTAGE_TMPDIR or where huge packages are built on
+// disk instead
+//
+fn build_log(tmpdir: &str, package: &str) -> Option<String> {
+    [tmpdir, preflight::NOTMPFS_DIR]
+        .iter()
+        .map(|directory| [directory, "/portage/", package, "/temp/build.log"].concat())
+        .find(|path| fs::metadata(path).is_ok())
+}
+
+// The size of a file, and the text at its end
+//
+fn tail(path: &str) -> Option<(u64, String)> {
+    let mut file = fs::File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(size.saturating_sub(TAIL_BYTES)))
+        .ok()?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).ok()?;
+    Some((size, String::from_utf8_lossy(&bytes).to_string()))
+}
+
+// The progress of each package being built, as the text to show
+//
+pub fn current() -> String {
+    CURRENT
+        .lock()
+        .map(|current| current.clone())
+        .unwrap_or_default()
+}
+
+fn show(text: &str) {
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = text.to_string();
+    }
+    if linux::is_a_tty() {
+        let title = if text.is_empty() {
+            "gentup".to_string()
+        } else {
+            ["gentup: ", text].concat()
+        };
+        let _ = execute!(io::stdout(), SetTitle(title));
+    }
+}
+
+// Samples the build logs of the packages being built until stopped
+//
+pub struct Sampler {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Sampler {
+    // Start sampling, following emerge.log from its current end to see which packages are being
+    // built
+    //
+    pub fn start() -> Option<Sampler> {
+        let emerge_log = portage::target_path(EMERGE_LOG);
+        let log_start = fs::metadata(&emerge_log).ok()?.len();
+        let tmpdir = portage::portage_tmpdir();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            let mut sizes: HashMap<String, u64> = HashMap::new();
+            while !stopped.load(Ordering::Relaxed) {
+                let log = fs::File::open(&emerge_log)
+                    .and_then(|mut file| {
+                        file.seek(SeekFrom::Start(log_start))?;
+                        let mut log = String::new();
+                        file.read_to_string(&mut log)?;
+                        Ok(log)
+                    })
+                    .unwrap_or_default();
+                let mut shown = Vec::new();
+                for package in building(&log) {
+                    let name = package
+                        .parse::<Package>()
+                        .map(|parsed| parsed.cpn())
+                        .unwrap_or(package.clone());
+                    let Some((size, text)) =
+                        build_log(&tmpdir, &package).and_then(|path| tail(&path))
+                    else {
+                        shown.push(name);
+                        continue;
+                    };
+                    let progress = parse_markers(&text).or_else(|| {
+                        let previous = sizes.get(&package).copied()?;
+                        Some(CompileProgress::Growing(
+                            size.saturating_sub(previous) / INTERVAL.as_secs(),
+                        ))
+                    });
+                    sizes.insert(package, size);
+                    match progress {
+                        Some(progress) => shown.push(format!("{} {}", name, progress)),
+                        None => shown.push(name),
+                    }
+                }
+                show(&shown.join(", "));
+                thread::sleep(INTERVAL);
+            }
+            show("");
+        });
+        Some(Sampler { stop, handle })
+    }
+
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_progress_markers() {
+        let ninja = "\
+[1233/5678] Building CXX object src/core/CMakeFiles/core.dir/paint.cpp.o
+[1234/5678] Building CXX object src/core/CMakeFiles/core.dir/render.cpp.o
+warning: unused variable 'x' [-Wunused-variable]
+";
+        let progress = parse_markers(ninja);
+        assert_eq!(progress, Some(CompileProgress::Steps(1234, 5678)));
+        assert_eq!(
+            progress.unwrap().to_string(),
+            "compiling object 1234/5678 (21%)"
+        );
+        assert_eq!(
+            parse_markers("[ 45%] Building C object lib/CMakeFiles/lib.dir/parse.c.o\n"),
+            Some(CompileProgress::Percent(45))
+        );
+        assert_eq!(
+            parse_markers("checking for gcc... gcc\nmake[1]: Entering directory\n"),
+            None
+        );
+        assert_eq!(
+            CompileProgress::Growing(20480).to_string(),
+            "build log growing 20 KB/s"
+        );
+
+        let log = "\
+1712350000:  >>> emerge (1 of 3) dev-qt/qtwebengine-6.7.2 to /
+1712350001:  >>> emerge (2 of 3) sys-libs/zlib-1.3.1 to /
+1712350090:  ::: completed emerge (2 of 3) sys-libs/zlib-1.3.1 to /
+1712350091:  >>> emerge (3 of 3) dev-libs/openssl-3.0.13 to /
+";
+        assert_eq!(
+            building(log),
+            vec!["dev-qt/qtwebengine-6.7.2", "dev-libs/openssl-3.0.13"]
+        );
+    }
+}
@@ -1,10 +1,146 @@
-use crate::Prompt::*;
+use crate::{exitcode::ExitCode, Prompt::*};
 use crossterm::style::{Color, SetForegroundColor};
 use std::{
+    fs::{File, OpenOptions},
     io::{self, stdout, Write},
-    process,
+    os::fd::{AsRawFd, FromRawFd},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex, OnceLock,
+    },
 };
 
+// How much is shown at the terminal. Quiet shows only the errors, the prompts and the summary at
+// the end, and Verbose streams the full output of every command, even those shown with a spinner
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Verbosity {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+// The terminal, while stdout is silenced by --quiet
+static CONSOLE: Mutex<Option<File>> = Mutex::new(None);
+
+// Set how much is shown from here on. When quiet, stdout is pointed at /dev/null, which silences
+// our own output and that of the commands run, and the terminal is kept for the prompts and the
+// summary. Errors go to stderr, which is left alone
+//
+pub fn set_verbosity(verbosity: Verbosity) -> io::Result<()> {
+    if verbosity == Verbosity::Quiet {
+        let _ = stdout().flush();
+        let null = OpenOptions::new().write(true).open("/dev/null")?;
+        // SAFETY: dup and dup2 only manipulate the process's file descriptor table, and the
+        // descriptor returned by dup is owned by nothing else
+        let console = unsafe {
+            let saved = libc::dup(libc::STDOUT_FILENO);
+            if saved < 0 || libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            File::from_raw_fd(saved)
+        };
+        if let Ok(mut output) = CONSOLE.lock() {
+            *output = Some(console);
+        }
+    }
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    }
+}
+
+// Write to the terminal whatever the verbosity, for the prompts and the summary
+//
+pub fn console(text: &str) {
+    let _ = stdout().flush();
+    match CONSOLE.lock().as_deref_mut() {
+        Ok(Some(console)) => {
+            let _ = console.write_all(text.as_bytes());
+            let _ = console.flush();
+        }
+        _ => {
+            print!("{}", text);
+            let _ = stdout().flush();
+        }
+    }
+}
+
+// Answers given in advance to prompts, by the name of the prompt, from the answer: lines of the
+// config file. A prompt with an answer here is not asked at the terminal, so a run can be left
+// unattended through some prompts but still stop at others
+static ANSWERS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+// The prompts which can be answered in advance, and the answers each takes, as typed without the
+// newline. An empty answer is pressing return, s skips, and None takes any answer, for the
+// prompts which ask for a value. Any of them can also be answered q, to stop the run there
+static ANSWERABLE: [(&str, Option<&[&str]>); 14] = [
+    ("battery", Some(&[""])),
+    ("ccache-dir", None),
+    ("ccache-size", None),
+    ("cleanup", Some(&["", "s"])),
+    ("cpuflags", Some(&["", "s"])),
+    ("features", Some(&["", "s"])),
+    ("kernel-config", Some(&["a", "r", "l"])),
+    ("news", Some(&["", "s"])),
+    ("onboarding", Some(&["a", "e"])),
+    ("rebuild", Some(&["", "s"])),
+    ("recovery", Some(&["r", "s", "m"])),
+    ("stale-builds", Some(&["", "s"])),
+    ("stale-run", Some(&["r", "d", "u"])),
+    ("stale-tree", Some(&["p", "f"])),
+];
+
+// Whether the named prompt can be answered in advance with this answer
+//
+pub fn accepts(name: &str, answer: &str) -> bool {
+    ANSWERABLE
+        .iter()
+        .find(|(prompt, _)| *prompt == name)
+        .is_some_and(|(_, answers)| match answers {
+            Some(answers) => answer == "q" || answers.contains(&answer),
+            None => true,
+        })
+}
+
+// The names of the prompts which can be answered in advance, as a list for the config file
+//
+pub fn answerable() -> String {
+    let names: Vec<&str> = ANSWERABLE.iter().map(|(name, _)| *name).collect();
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => [&rest.join(", "), " or ", last].concat(),
+        None => String::new(),
+    }
+}
+
+pub fn set_answers(answers: &[(String, String)]) {
+    let _ = ANSWERS.set(answers.to_vec());
+}
+
+// Whether the named prompt has been answered in the config file
+//
+pub fn answered(name: &str) -> bool {
+    predefined_answer(name).is_some()
+}
+
+fn predefined_answer(name: &str) -> Option<String> {
+    ANSWERS
+        .get()?
+        .iter()
+        .find(|(prompt, _)| prompt == name)
+        .map(|(_, answer)| [answer, "\n"].concat())
+}
+
 // Prompt the user to continue, skip, quit etc
 #[derive(PartialEq)]
 pub enum Prompt {
@@ -13,30 +149,55 @@ pub enum Prompt {
     Options,
 }
 impl Prompt {
-    pub fn askuser(self, prompt: &str) -> Option<String> {
+    // Ask the user a question, unless the named prompt has been answered in the config file. The
+    // answer is returned as typed, including the newline
+    //
+    pub fn askuser(self, name: &str, prompt: &str) -> Option<String> {
+        if let Some(answer) = predefined_answer(name) {
+            // An answer the prompt does not take would otherwise be asked again for ever
+            if !accepts(name, answer.trim_end()) {
+                eprintln!(
+                    "{} \"{}\" in the config file is not an answer the {} prompt takes",
+                    revchevrons(Color::Red),
+                    answer.trim_end(),
+                    name
+                );
+                ExitCode::ConfigError.exit();
+            }
+            println!(
+                "{} {}: answered \"{}\" from the config file",
+                chevrons(Color::Green),
+                prompt,
+                answer.trim_end()
+            );
+            return Prompt::handle(answer);
+        }
         match self {
-            AllowSkip => println!(
-                "{} {}: Press return to continue, s to skip, q to quit",
+            AllowSkip => console(&format!(
+                "{} {}: Press return to continue, s to skip, q to quit\n",
                 chevrons(Color::Green),
                 prompt
-            ),
-            PressReturn => println!(
-                "{} {}: Press return to continue, or q to quit",
+            )),
+            PressReturn => console(&format!(
+                "{} {}: Press return to continue, or q to quit\n",
                 chevrons(Color::Green),
                 prompt
-            ),
-            Options => {
-                print!("{} {}: ", chevrons(Color::Green), prompt);
-                let _ = stdout().flush();
-            }
+            )),
+            Options => console(&format!("{} {}: ", chevrons(Color::Green), prompt)),
         }
         let mut user_input = String::new();
         io::stdin()
             .read_line(&mut user_input)
             .expect("Failed to read line");
+        Prompt::handle(user_input)
+    }
+
+    // Act on the answers common to every prompt, quit and skip
+    //
+    fn handle(user_input: String) -> Option<String> {
         if user_input.eq("q\n") {
             println!("{} Quitting at user request", chevrons(Color::Green));
-            process::exit(0);
+            ExitCode::Aborted.exit();
         }
         if user_input.eq("s\n") {
             println!("{} Skipping at user request", chevrons(Color::Green));
@@ -53,3 +214,21 @@ pub fn chevrons(colour: Color) -> String {
 pub fn revchevrons(colour: Color) -> String {
     SetForegroundColor(colour).to_string() + "<<<" + &SetForegroundColor(Color::Grey).to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_preset_answers() {
+        assert!(accepts("battery", ""));
+        assert!(accepts("stale-tree", "f"));
+        assert!(accepts("recovery", "q"));
+        assert!(accepts("ccache-size", "50G"));
+        assert!(!accepts("recovery", "b"));
+        assert!(!accepts("stale-tree", "s"));
+        assert!(!accepts("setup", "c"));
+        assert!(answerable().starts_with("battery, ccache-dir, "));
+        assert!(answerable().ends_with("stale-run or stale-tree"));
+    }
+}
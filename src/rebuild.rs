@@ -0,0 +1,223 @@
+// World rebuild
+// "gentup --rebuild-world" rebuilds every installed package, as emerge -e @world does, for the
+// rare changes which need it: a profile migration such as 23.0, a new CFLAGS, or a toolchain
+// switch. Such a rebuild takes many hours, and an emerge -e which fails or is interrupted part way
+// through starts from the beginning again. Instead, the build order from emerge -pe @world is saved
+// as the plan, and the packages are rebuilt in chunks, each recorded as done once it has built.
+// Running gentup --rebuild-world again after a failure carries on from the first package not yet
+// rebuilt, from the same plan. The state lives in /var/lib/gentup:
+//
+//   rebuild-plan   every package to rebuild, in build order, one per line
+//   rebuild-done   the packages rebuilt so far
+
+use crate::{
+    config::state_dir,
+    exitcode::ExitCode,
+    linux::OsCall,
+    parallel,
+    portage::{self, PackageManager},
+    preflight, prompt, Config, Prompt,
+};
+use crossterm::style::Color;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+// How many packages are rebuilt by each emerge
+static CHUNK_SIZE: usize = 20;
+
+fn plan_path() -> String {
+    [state_dir(), "/rebuild-plan"].concat()
+}
+
+fn done_path() -> String {
+    [state_dir(), "/rebuild-done"].concat()
+}
+
+fn read_lines(path: &str) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+// The packages of the plan still to be rebuilt, in build order
+//
+pub fn remaining(plan: &[String], done: &[String]) -> Vec<String> {
+    plan.iter()
+        .filter(|package| !done.contains(package))
+        .cloned()
+        .collect()
+}
+
+// The emerge command line which rebuilds one chunk of packages, each at its exact version. The
+// dependencies of each were rebuilt by an earlier chunk, so emerge is not asked to rebuild them
+// again, and the world file is left alone
+//
+pub fn chunk_command(chunk: &[String]) -> String {
+    let mut command = [
+        "emerge --quiet-build y --oneshot --usepkg n",
+        parallel::update_options(),
+    ]
+    .concat();
+    for package in chunk {
+        command = command + " =" + package;
+    }
+    command
+}
+
+// Work out the build order of the whole rebuild, and save it as the plan
+//
+fn make_plan() -> Option<Vec<String>> {
+    let (output, status) = OsCall::Spinner
+        .execute("emerge -pe @world", "Working out the rebuild order")
+        .ok()?;
+    let plan: Vec<String> = portage::parse_changes(&output)
+        .iter()
+        .map(|change| change.package.cpv())
+        .collect();
+    if status != 0 || plan.is_empty() {
+        for line in output.lines().filter(|line| line.starts_with("!!!")) {
+            eprintln!("  {}", line);
+        }
+        return None;
+    }
+    let mut contents = plan.join("\n");
+    contents.push('\n');
+    if let Err(error) = fs::create_dir_all(state_dir())
+        .and_then(|_| fs::write(plan_path(), contents))
+        .and_then(|_| fs::write(done_path(), ""))
+    {
+        eprintln!(
+            "{} Could not save the rebuild plan {} - {}",
+            prompt::revchevrons(Color::Red),
+            plan_path(),
+            error
+        );
+        return None;
+    }
+    Some(plan)
+}
+
+// Record a chunk of packages as rebuilt
+//
+fn record_done(chunk: &[String]) {
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(done_path())
+        .and_then(|mut file| writeln!(file, "{}", chunk.join("\n")));
+    if let Err(error) = result {
+        eprintln!(
+            "{} Could not record the rebuild progress in {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            done_path(),
+            error
+        );
+    }
+}
+
+// Rebuild the world set, carrying on from an earlier rebuild which did not finish
+//
+pub fn run(running_config: &Config) -> ExitCode {
+    let mut plan = read_lines(&plan_path());
+    let mut done = read_lines(&done_path());
+    if plan.is_empty() {
+        let Some(new_plan) = make_plan() else {
+            eprintln!(
+                "{} emerge could not work out the rebuild",
+                prompt::revchevrons(Color::Red)
+            );
+            return ExitCode::Failed;
+        };
+        println!(
+            "{} Every installed package will be rebuilt: {} packages, in chunks of {}",
+            prompt::revchevrons(Color::Yellow),
+            new_plan.len(),
+            CHUNK_SIZE
+        );
+        if Prompt::AllowSkip
+            .askuser("rebuild", "Start rebuilding the world set")
+            .is_none()
+        {
+            let _ = fs::remove_file(plan_path());
+            let _ = fs::remove_file(done_path());
+            return ExitCode::Aborted;
+        }
+        plan = new_plan;
+        done.clear();
+    }
+    let remaining = remaining(&plan, &done);
+    println!(
+        "{} {} of {} packages rebuilt, {} to go",
+        prompt::revchevrons(Color::Green),
+        plan.len() - remaining.len(),
+        plan.len(),
+        remaining.len()
+    );
+    parallel::apply(running_config);
+    for chunk in remaining.chunks(CHUNK_SIZE) {
+        // Hold off building while on low battery, busy or running hot, if so configured
+        preflight::before_build(running_config);
+        let status = format!(
+            "Rebuilding {} to {}",
+            chunk.first().map(|package| package.as_str()).unwrap_or(""),
+            chunk.last().map(|package| package.as_str()).unwrap_or("")
+        );
+        match OsCall::Interactive.execute(&chunk_command(chunk), &status) {
+            Ok((_, 0)) => record_done(chunk),
+            _ => {
+                eprintln!(
+                    "{} The rebuild failed. Fix the problem, then carry on with gentup --rebuild-world",
+                    prompt::revchevrons(Color::Red)
+                );
+                return ExitCode::BuildFailed;
+            }
+        }
+    }
+    let _ = fs::remove_file(plan_path());
+    let _ = fs::remove_file(done_path());
+    println!(
+        "{} The world set has been rebuilt",
+        prompt::revchevrons(Color::Green)
+    );
+    // Anything left linking against libraries which no longer exist is found and rebuilt
+    if !PackageManager::DryRun.revdep_rebuild() {
+        PackageManager::NoDryRun.revdep_rebuild();
+    }
+    ExitCode::UpdatesApplied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_the_rebuild() {
+        let output = "\
+[ebuild   R    ] sys-libs/zlib-1.3.1:0/1::gentoo  USE=\"-minizip\" 0 KiB
+[ebuild   R    ] dev-libs/openssl-3.0.13:0/3::gentoo  USE=\"asm\" 0 KiB
+[ebuild   R    ] sys-devel/gcc-13.2.1_p20240113-r1:13::gentoo  0 KiB
+";
+        let plan: Vec<String> = portage::parse_changes(output)
+            .iter()
+            .map(|change| change.package.cpv())
+            .collect();
+        let done = vec!["sys-libs/zlib-1.3.1".to_string()];
+        let remaining = remaining(&plan, &done);
+        assert_eq!(
+            remaining,
+            vec![
+                "dev-libs/openssl-3.0.13",
+                "sys-devel/gcc-13.2.1_p20240113-r1"
+            ]
+        );
+        assert_eq!(
+            chunk_command(&remaining),
+            "emerge --quiet-build y --oneshot --usepkg n =dev-libs/openssl-3.0.13 =sys-devel/gcc-13.2.1_p20240113-r1"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// Guided recovery from a failed package build
+// Rather than exiting as soon as emerge fails, find the package which failed, show the end of its
+// build log, and let the user decide how to carry on
+
+use crate::{
+    bugreport::{find_failed_build, FailedBuild},
+    linux::{CouldFail, OsCall},
+    portage::{self, PackageManager},
+    prompt, Prompt,
+};
+use crossterm::style::Color;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+// The number of lines of the failed build log to display
+static LOG_TAIL_LINES: usize = 30;
+
+// How many times an answer to the recovery prompt from the config file is acted on, so that
+// retrying a build which keeps failing cannot go on for ever unattended
+static PRESET_ATTEMPTS: usize = 3;
+
+// Display the last few lines of the build log of the failed package
+//
+fn show_log_tail(failed: &FailedBuild) {
+    let Ok(contents) = fs::read_to_string(&failed.build_log) else {
+        return;
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    println!(
+        "{} Last {} lines of {}:\n",
+        prompt::revchevrons(Color::Yellow),
+        LOG_TAIL_LINES,
+        failed.build_log.display()
+    );
+    for line in &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..] {
+        println!("{}", line);
+    }
+    println!();
+}
+
+// Add the failed version of the package to package.mask, so that the next update will choose a
+// different version. The user is told where the mask was written so that they can remove it later
+//
+fn mask_package(package: &str) {
+    let mask_path = if Path::new(&portage::target_path("/etc/portage/package.mask")).is_dir() {
+        portage::target_path("/etc/portage/package.mask/gentup")
+    } else {
+        portage::target_path("/etc/portage/package.mask")
+    };
+    let masked = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&mask_path)
+        .and_then(|mut file| {
+            writeln!(
+                file,
+                "# Masked by gentup after a failed build\n={}",
+                package
+            )
+        });
+    match masked {
+        Ok(_) => println!(
+            "{} Masked ={} in {}. Remove this entry once the build is fixed",
+            prompt::revchevrons(Color::Yellow),
+            package,
+            mask_path
+        ),
+        Err(error) => eprintln!(
+            "{} Could not write to {}: {}",
+            prompt::revchevrons(Color::Red),
+            mask_path,
+            error
+        ),
+    }
+}
+
+// Write a bug report template, pre-filled with emerge --info, and open it in an editor
+//
+fn bug_report(failed: &FailedBuild) {
+    let report_path = portage::target_path(&format!(
+        "/var/tmp/gentup-bugreport-{}.txt",
+        failed.package.replace('/', "_")
+    ));
+    let emerge_info = match OsCall::Spinner.execute(
+        &["emerge --info =", &failed.package].concat(),
+        "Collecting emerge --info",
+    ) {
+        Ok((output, _)) => output,
+        Err(_) => String::new(),
+    };
+    let written = File::create(&report_path).and_then(|mut file| {
+        writeln!(
+            file,
+            "Summary: ={} fails to build\n\
+            \n\
+            Steps to reproduce:\n\
+            emerge -1v ={}\n\
+            \n\
+            Build log: {}\n\
+            (attach this file to the bug report)\n\
+            \n\
+            emerge --info:\n\
+            {}",
+            failed.package,
+            failed.package,
+            failed.build_log.display(),
+            emerge_info
+        )
+    });
+    match written {
+        Ok(_) => {
+            let _ =
+                OsCall::Interactive.execute(&["vi ", &report_path].concat(), "Launching editor");
+            println!(
+                "{} Bug report template saved in {}",
+                prompt::revchevrons(Color::Green),
+                report_path
+            );
+        }
+        Err(error) => eprintln!(
+            "{} Could not create {}: {}",
+            prompt::revchevrons(Color::Red),
+            report_path,
+            error
+        ),
+    }
+}
+
+// Called when the world update fails and there is a user at the terminal, or the recovery prompt
+// is answered in the config file. Loop offering choices until emerge completes, returning true,
+// or the user quits. An answer from the config file is only acted on PRESET_ATTEMPTS times,
+// returning false if the update still fails
+//
+pub fn recover_failed_update() -> bool {
+    let mut attempts = 0;
+    loop {
+        if prompt::answered("recovery") {
+            if attempts == PRESET_ATTEMPTS {
+                eprintln!(
+                    "{} The update still fails after {} attempts with the recovery answer from the config file",
+                    prompt::revchevrons(Color::Red),
+                    attempts
+                );
+                return false;
+            }
+            attempts += 1;
+        }
+        let failed = find_failed_build();
+        match &failed {
+            Some(failed) => {
+                println!(
+                    "{} {} failed to build",
+                    prompt::revchevrons(Color::Red),
+                    failed.package
+                );
+                show_log_tail(failed);
+            }
+            None => println!(
+                "{} The update failed, but the failed build log could not be found",
+                prompt::revchevrons(Color::Red)
+            ),
+        }
+        let answer = Prompt::Options.askuser(
+            "recovery",
+            "Select r to retry, s to skip the failed package and continue, m to mask the failed version, b to write a bug report, or q to quit [r|s|m|b|q]",
+        );
+        let result = match answer.as_deref() {
+            Some("r\n") => {
+                OsCall::Interactive.execute("emerge --resume", "Retrying the failed update")
+            }
+            None => OsCall::Interactive
+                .execute("emerge --resume --skipfirst", "Skipping the failed package"),
+            Some("m\n") => {
+                if let Some(failed) = &failed {
+                    mask_package(&failed.package);
+                }
+                PackageManager::NoDryRun.update_all_packages()
+            }
+            Some("b\n") => {
+                if let Some(failed) = &failed {
+                    bug_report(failed);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        match result {
+            Ok((_, 0)) => return true,
+            Ok(_) => continue,
+            Err(_) => {
+                let _ = result.exit_if_failed();
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// Packages removed from the repositories
+// After the sync, each installed package is looked for in every configured repository. One which
+// is in none of them has been removed upstream, and will never be updated again. Each is listed,
+// and added to the run report, with why it was removed when that can be found:
+//
+//   - the last rites reason, from the package.mask entry which announced the removal. These
+//     entries disappear with the package, so lastrites.rs keeps them in /var/lib/gentup/last-rites
+//   - otherwise the commit which removed it, for repositories synced with git, e.g
+//     "3f1c2a9 2024-05-02 app-misc/foo: treeclean"
+
+use crate::{
+    atom::Package,
+    config::state_dir,
+    doctor,
+    events::{self, Event},
+    linux::OsCall,
+    prompt,
+};
+use crossterm::style::Color;
+use std::{path::Path, process::Command};
+
+// The file keeping the last rites reason of each installed package due to be removed
+//
+pub fn last_rites_path() -> String {
+    [state_dir(), "/last-rites"].concat()
+}
+
+// The paths of the configured repositories
+//
+fn repositories() -> Vec<String> {
+    let names = OsCall::Quiet
+        .execute("portageq get_repos /", "")
+        .map(|(output, _)| output)
+        .unwrap_or_default();
+    if names.trim().is_empty() {
+        return vec!["/var/db/repos/gentoo".to_string()];
+    }
+    OsCall::Quiet
+        .execute(&["portageq get_repo_path / ", names.trim()].concat(), "")
+        .map(|(output, _)| output.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// Parse the kept last rites reasons, one package to a line with its reason after a tab
+//
+pub fn parse_last_rites(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(cpn, reason)| (cpn.to_string(), reason.to_string()))
+        .collect()
+}
+
+// The installed packages, e.g app-misc/foo, which none of the repositories has
+//
+pub fn missing(installed: &[String], in_repository: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut missing: Vec<String> = installed
+        .iter()
+        .filter(|cpn| cpn.parse::<Package>().is_ok() && !in_repository(cpn))
+        .cloned()
+        .collect();
+    missing.sort();
+    missing
+}
+
+// The commit which removed a package from a repository synced with git, if its history is there
+//
+fn removal_commit(repository: &str, cpn: &str) -> Option<String> {
+    if !Path::new(repository).join(".git").exists() {
+        return None;
+    }
+    let output = Command::new("git")
+        .args([
+            "-C",
+            repository,
+            "log",
+            "-1",
+            "--format=%h %as %s",
+            "--",
+            cpn,
+        ])
+        .output()
+        .ok()?;
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !commit.is_empty()).then_some(commit)
+}
+
+// Report the installed packages which are no longer in any repository
+//
+pub fn check() {
+    let repositories = repositories();
+    let mut installed: Vec<String> = doctor::installed_packages().into_iter().collect();
+    installed.sort();
+    let gone = missing(&installed, |cpn| {
+        repositories
+            .iter()
+            .any(|repository| Path::new(repository).join(cpn).is_dir())
+    });
+    if gone.is_empty() {
+        return;
+    }
+    let last_rites =
+        parse_last_rites(&std::fs::read_to_string(last_rites_path()).unwrap_or_default());
+    println!(
+        "{} {} installed package(s) are no longer in any repository, and will not be updated:",
+        prompt::revchevrons(Color::Yellow),
+        gone.len()
+    );
+    let mut packages = Vec::new();
+    for cpn in gone {
+        let reason = last_rites
+            .iter()
+            .find(|(rite, _)| *rite == cpn)
+            .map(|(_, reason)| reason.clone())
+            .or_else(|| {
+                repositories
+                    .iter()
+                    .find_map(|repository| removal_commit(repository, &cpn))
+                    .map(|commit| ["removed in ", &commit].concat())
+            })
+            .unwrap_or_default();
+        if reason.is_empty() {
+            println!("    {}", cpn);
+        } else {
+            println!("    {} - {}", cpn, reason);
+        }
+        packages.push((cpn, reason));
+    }
+    println!(
+        "{} Look for a replacement, or keep them in a local repository",
+        prompt::revchevrons(Color::Yellow)
+    );
+    events::emit(Event::RemovedPackages { packages });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_removed_packages() {
+        let installed: Vec<String> = ["app-misc/foo", "sys-libs/zlib", "dev-python/baz"]
+            .iter()
+            .map(|cpn| cpn.to_string())
+            .collect();
+        assert_eq!(
+            missing(&installed, |cpn| cpn == "sys-libs/zlib"),
+            vec!["app-misc/foo", "dev-python/baz"]
+        );
+        assert_eq!(
+            parse_last_rites("app-misc/foo\tUnmaintained upstream. Use app-misc/bar instead.\n"),
+            vec![(
+                "app-misc/foo".to_string(),
+                "Unmaintained upstream. Use app-misc/bar instead.".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+// Run report
+// A summary of each update run is built up from the events it reports (see events.rs), whether or
+// not --json is in effect. When the run exits, however it exits, the summary is delivered as a
+// JSON document to each of the destinations configured, such as a webhook or an MQTT broker
+
+use crate::{
+    compiler,
+    events::Event,
+    events::{json_string, removed_json},
+    version::{self, VERSION},
+    Config,
+};
+use gethostname::gethostname;
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Define a struct to hold the summary of a run
+//
+#[derive(Default)]
+pub struct RunReport {
+    pub hostname: String,
+    pub started: u64,                            // Seconds since the epoch
+    pub phases: Vec<(&'static str, u64)>,        // Each phase completed, with the seconds it took
+    pub tree_verification: Option<&'static str>, // None if the tree was not synced
+    pub pending_updates: Vec<String>,
+    pub security_updates: Vec<String>, // Those of the pending updates which fix a GLSA
+    pub failed: Vec<String>,
+    pub bug_report: Option<String>, // The bundle gathered for the failed build
+    pub cross_targets: Vec<(String, usize, bool)>, // Each crossdev target, its updates and success
+    pub removed_packages: Vec<(String, String)>, // Installed packages in no repository, and why
+    pub orphans: Option<i32>,
+    pub actions: Vec<String>, // The post-update checklist
+    pub ccache: Option<(u64, u64)>,
+    pub distcc: Option<(u64, u64)>,
+    pub exit_code: i32,
+    pub description: &'static str,
+    pub webhook_url: String,
+    pub webhook_auth: String,
+    pub mqtt_broker: String,
+    pub mqtt_topic: String,
+}
+
+static REPORT: Mutex<Option<RunReport>> = Mutex::new(None); // None unless an update is running
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl RunReport {
+    // Fold an event into the report
+    //
+    pub fn record(&mut self, event: &Event) {
+        match event {
+            Event::PhaseEnd { phase, seconds } => self.phases.push((phase, *seconds)),
+            Event::TreeVerification { status } => self.tree_verification = Some(status),
+            Event::PendingUpdates { packages, security } => {
+                self.pending_updates = packages.clone();
+                self.security_updates = security.clone();
+            }
+            Event::PackageFailed { package } => self.failed.push(package.clone()),
+            Event::BugReport { path, .. } => self.bug_report = Some(path.clone()),
+            Event::Orphans { count } => self.orphans = Some(*count),
+            Event::CrossTarget {
+                target,
+                packages,
+                succeeded,
+            } => self
+                .cross_targets
+                .push((target.clone(), packages.len(), *succeeded)),
+            Event::RemovedPackages { packages } => self.removed_packages = packages.clone(),
+            Event::ActionsRequired { actions } => self.actions = actions.clone(),
+            Event::CompilerStats { ccache, distcc } => {
+                self.ccache = *ccache;
+                self.distcc = *distcc;
+            }
+            Event::Exit { code, description } => {
+                self.exit_code = *code;
+                self.description = description;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |items: &[String]| {
+            let quoted: Vec<String> = items.iter().map(|item| json_string(item)).collect();
+            ["[", &quoted.join(","), "]"].concat()
+        };
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(phase, seconds)| {
+                format!(
+                    "{{\"phase\":{},\"seconds\":{}}}",
+                    json_string(phase),
+                    seconds
+                )
+            })
+            .collect();
+        let cross_targets: Vec<String> = self
+            .cross_targets
+            .iter()
+            .map(|(target, updates, succeeded)| {
+                format!(
+                    "{{\"target\":{},\"updates\":{},\"succeeded\":{}}}",
+                    json_string(target),
+                    updates,
+                    succeeded
+                )
+            })
+            .collect();
+        format!(
+            "{{\"host\":{},\"version\":{},\"build\":{},\"started\":{},\"finished\":{},\"exit_code\":{},\"result\":{},\
+            \"tree_verification\":{},\"pending_updates\":{},\"security_updates\":{},\"failed\":{},\"bug_report\":{},\"cross_targets\":[{}],\"removed_packages\":[{}],\"orphans\":{},\"actions\":{},\"ccache\":{},\"distcc\":{},\"phases\":[{}]}}",
+            json_string(&self.hostname),
+            json_string(VERSION),
+            version::to_json(),
+            self.started,
+            now(),
+            self.exit_code,
+            json_string(self.description),
+            self.tree_verification
+                .map(json_string)
+                .unwrap_or("null".to_string()),
+            list(&self.pending_updates),
+            list(&self.security_updates),
+            list(&self.failed),
+            self.bug_report
+                .as_deref()
+                .map(json_string)
+                .unwrap_or("null".to_string()),
+            cross_targets.join(","),
+            removed_json(&self.removed_packages),
+            self.orphans
+                .map(|orphans| orphans.to_string())
+                .unwrap_or("null".to_string()),
+            list(&self.actions),
+            self.ccache
+                .map(|(hits, misses)| format!(
+                    "{{\"hits\":{},\"misses\":{},\"hit_rate\":{}}}",
+                    hits,
+                    misses,
+                    compiler::percentage(hits, hits + misses)
+                ))
+                .unwrap_or("null".to_string()),
+            self.distcc
+                .map(|(local, remote)| format!(
+                    "{{\"local\":{},\"remote\":{},\"remote_percentage\":{}}}",
+                    local,
+                    remote,
+                    compiler::percentage(remote, local + remote)
+                ))
+                .unwrap_or("null".to_string()),
+            phases.join(",")
+        )
+    }
+
+    // The MQTT topic for one kind of message about this host, e.g gentup/build1/phase
+    //
+    pub fn topic(&self, name: &str) -> String {
+        [&self.mqtt_topic, "/", &self.hostname, "/", name].concat()
+    }
+}
+
+// The report of the update running, as it stands
+//
+pub fn snapshot() -> Option<String> {
+    REPORT.lock().ok()?.as_ref().map(|report| report.to_json())
+}
+
+// Start the report of an update run
+//
+pub fn begin(running_config: &Config) {
+    if let Ok(mut report) = REPORT.lock() {
+        *report = Some(RunReport {
+            hostname: gethostname()
+                .into_string()
+                .unwrap_or("localhost".to_string()),
+            started: now(),
+            webhook_url: running_config.webhook_url.clone(),
+            webhook_auth: running_config.webhook_auth.clone(),
+            mqtt_broker: running_config.mqtt_broker.clone(),
+            mqtt_topic: running_config.mqtt_topic.clone(),
+            ..RunReport::default()
+        });
+    }
+}
+
+// Called with every event reported. Phase transitions are also published to the MQTT broker
+//
+pub fn record(event: &Event) {
+    if let Ok(mut report) = REPORT.lock() {
+        if let Some(report) = report.as_mut() {
+            report.record(event);
+            #[cfg(feature = "mqtt")]
+            if !report.mqtt_broker.is_empty()
+                && matches!(event, Event::PhaseStart { .. } | Event::PhaseEnd { .. })
+            {
+                crate::mqtt::publish(
+                    &report.mqtt_broker,
+                    &report.topic("phase"),
+                    &event.to_json(),
+                );
+            }
+        }
+    }
+}
+
+// Deliver the report of the run, if one is running, as the program exits
+//
+pub fn finish() {
+    let Some(report) = REPORT.lock().ok().and_then(|mut report| report.take()) else {
+        return;
+    };
+    crate::logarchive::save_run(report.started, &report.to_json());
+    #[cfg(feature = "webhook")]
+    if !report.webhook_url.is_empty() {
+        crate::webhook::post(&report.webhook_url, &report.webhook_auth, &report.to_json());
+    }
+    #[cfg(feature = "mqtt")]
+    if !report.mqtt_broker.is_empty() {
+        crate::mqtt::publish(
+            &report.mqtt_broker,
+            &report.topic("result"),
+            &report.to_json(),
+        );
+    }
+    #[cfg(not(any(feature = "mqtt", feature = "webhook")))]
+    let _ = report;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarises_events() {
+        let mut report = RunReport {
+            hostname: "build1".to_string(),
+            ..RunReport::default()
+        };
+        report.record(&Event::PhaseEnd {
+            phase: "sync",
+            seconds: 12,
+        });
+        report.record(&Event::PendingUpdates {
+            packages: vec!["sys-libs/zlib-1.3.1".to_string()],
+            security: Vec::new(),
+        });
+        report.record(&Event::CrossTarget {
+            target: "armv7a-unknown-linux-gnueabihf".to_string(),
+            packages: vec!["sys-libs/zlib-1.3.1".to_string()],
+            succeeded: false,
+        });
+        report.record(&Event::RemovedPackages {
+            packages: vec![("app-misc/foo".to_string(), "Dead upstream".to_string())],
+        });
+        report.record(&Event::Exit {
+            code: 1,
+            description: "Updates were applied",
+        });
+        let json = report.to_json();
+        assert!(json.starts_with("{\"host\":\"build1\","));
+        assert!(json.contains(&format!(
+            "\"build\":{{\"version\":\"{}\",\"commit\":\"{}\",",
+            VERSION,
+            version::GIT_COMMIT
+        )));
+        assert!(json.contains(
+            "\"exit_code\":1,\"result\":\"Updates were applied\",\"tree_verification\":null,"
+        ));
+        assert!(json.contains(
+            "\"pending_updates\":[\"sys-libs/zlib-1.3.1\"],\"security_updates\":[],\"failed\":[],\"bug_report\":null,"
+        ));
+        assert!(json.contains(
+            "\"cross_targets\":[{\"target\":\"armv7a-unknown-linux-gnueabihf\",\"updates\":1,\"succeeded\":false}],\
+            \"removed_packages\":[{\"package\":\"app-misc/foo\",\"reason\":\"Dead upstream\"}]"
+        ));
+        assert!(
+            json.ends_with("\"orphans\":null,\"actions\":[],\"ccache\":null,\"distcc\":null,\"phases\":[{\"phase\":\"sync\",\"seconds\":12}]}")
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Tool requirements
+// gentup runs other programs for much of its work, and installs any which are missing before an
+// update starts. Each entry in the table below names a package, the oldest version which has the
+// options gentup passes, the commands gentup runs from it, and the features which use it, so that
+// a tool is only required when the configuration enables something which needs it. A subsystem
+// which runs a new program adds an entry here, e.g
+//
+//   app-misc/mosquitto  any version  mosquitto_pub  when mqtt_broker is set
+//
+// The mail command is not installed here, as it needs the local mail transport setting up first
+
+use crate::{
+    atom::{Package, Version},
+    linux::{CouldFail, OsCall},
+    options::RuntimeOptions,
+    portage, prompt, Config,
+};
+use crossterm::style::Color;
+use std::{env, path::Path};
+
+// Define a struct to hold one requirement
+//
+pub struct Requirement {
+    pub package: &'static str,
+    pub minimum: &'static str, // The oldest usable version, or empty for any version
+    pub commands: &'static [&'static str],
+    pub post_install: &'static str, // A command to run once the package is installed
+    pub used_for: &'static str,
+    pub needed: fn(&Config, &RuntimeOptions) -> bool,
+}
+
+pub static REQUIREMENTS: &[Requirement] = &[
+    Requirement {
+        package: "app-portage/eix",
+        minimum: "0.36",
+        commands: &["eix", "eix-update", "eix-test-obsolete"],
+        post_install: "eix-update",
+        used_for: "searching the package tree",
+        needed: |_, _| true,
+    },
+    Requirement {
+        package: "app-portage/gentoolkit",
+        minimum: "0.6.0",
+        commands: &["equery", "eclean", "revdep-rebuild"],
+        post_install: "",
+        used_for: "reverse dependency rebuilds and distfile cleaning",
+        needed: |_, _| true,
+    },
+    Requirement {
+        package: "app-admin/eclean-kernel",
+        minimum: "",
+        commands: &["eclean-kernel"],
+        post_install: "",
+        used_for: "removing old kernels during cleanup",
+        needed: |_, options| options.cleanup && options.root.is_none(),
+    },
+    Requirement {
+        package: "sys-apps/util-linux",
+        minimum: "",
+        commands: &["fstrim"],
+        post_install: "",
+        used_for: "trimming filesystems",
+        needed: |_, options| options.trim,
+    },
+    Requirement {
+        package: "app-crypt/gemato",
+        minimum: "",
+        commands: &["gemato"],
+        post_install: "",
+        used_for: "require_signed_tree",
+        needed: |running_config, _| running_config.require_signed_tree,
+    },
+    Requirement {
+        package: "net-misc/curl",
+        minimum: "7.71.0", // The first with --retry-all-errors
+        commands: &["curl"],
+        post_install: "",
+        used_for: "webhook_url",
+        needed: |running_config, _| {
+            cfg!(feature = "webhook") && !running_config.webhook_url.is_empty()
+        },
+    },
+    Requirement {
+        package: "app-misc/mosquitto",
+        minimum: "",
+        commands: &["mosquitto_pub"],
+        post_install: "",
+        used_for: "mqtt_broker",
+        needed: |running_config, _| {
+            cfg!(feature = "mqtt") && !running_config.mqtt_broker.is_empty()
+        },
+    },
+];
+
+// The requirements of the features enabled for this run
+//
+pub fn required<'a>(
+    running_config: &'a Config,
+    options: &'a RuntimeOptions,
+) -> impl Iterator<Item = &'static Requirement> + 'a {
+    REQUIREMENTS
+        .iter()
+        .filter(move |requirement| (requirement.needed)(running_config, options))
+}
+
+// Why a requirement is not met, given the installed version of its package (e.g
+// app-portage/eix-0.36.7, or empty if it is not installed) and a test for whether a command can be
+// found
+//
+pub fn unmet(
+    requirement: &Requirement,
+    installed: &str,
+    found: impl Fn(&str) -> bool,
+) -> Option<String> {
+    if installed.is_empty() {
+        return Some(format!("{} is not installed", requirement.package));
+    }
+    if !requirement.minimum.is_empty() {
+        let minimum = requirement.minimum.parse::<Version>().ok()?;
+        let version = installed.parse::<Package>().ok()?.version?;
+        if version < minimum {
+            return Some(format!(
+                "{} is installed, but {} or later is needed",
+                installed, requirement.minimum
+            ));
+        }
+    }
+    requirement
+        .commands
+        .iter()
+        .find(|command| !found(command))
+        .map(|command| format!("{} is installed, but {} is missing", installed, command))
+}
+
+// Whether a command is in a directory on PATH
+//
+pub fn on_path(command: &str) -> bool {
+    env::var("PATH")
+        .unwrap_or("/usr/bin:/bin:/usr/sbin:/sbin".to_string())
+        .split(':')
+        .any(|directory| Path::new(directory).join(command).exists())
+}
+
+// Install or upgrade any package required by the features enabled for this run
+//
+pub fn install(running_config: &Config, options: &RuntimeOptions) {
+    for requirement in required(running_config, options) {
+        let installed = portage::installed_version(requirement.package);
+        let Some(reason) = unmet(requirement, &installed, on_path) else {
+            continue;
+        };
+        println!(
+            "{} This updater requires {} for {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            requirement.package,
+            requirement.used_for,
+            reason
+        );
+        if options.offline {
+            println!(
+                "{} Not installing {} while offline",
+                prompt::revchevrons(Color::Yellow),
+                requirement.package
+            );
+            continue;
+        }
+        let atom = if requirement.minimum.is_empty() {
+            requirement.package.to_string()
+        } else {
+            [">=", requirement.package, "-", requirement.minimum].concat()
+        };
+        let _ = OsCall::Spinner
+            .execute(
+                &["emerge --quiet -v ", &atom].concat(),
+                &["Installing ", requirement.package].concat(),
+            )
+            .exit_if_failed();
+        if !requirement.post_install.is_empty() {
+            let _ = OsCall::Spinner
+                .execute(requirement.post_install, "Post installation configuration")
+                .exit_if_failed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_requirements() {
+        let mut running_config = Config::build_default();
+        let mut options = RuntimeOptions::default();
+        let packages = |running_config: &Config, options: &RuntimeOptions| {
+            required(running_config, options)
+                .map(|requirement| requirement.package)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            packages(&running_config, &options),
+            vec!["app-portage/eix", "app-portage/gentoolkit"]
+        );
+        options.cleanup = true;
+        running_config.require_signed_tree = true;
+        assert_eq!(
+            packages(&running_config, &options),
+            vec![
+                "app-portage/eix",
+                "app-portage/gentoolkit",
+                "app-admin/eclean-kernel",
+                "app-crypt/gemato"
+            ]
+        );
+
+        let eix = &REQUIREMENTS[0];
+        assert_eq!(unmet(eix, "app-portage/eix-0.36.7", |_| true), None);
+        assert_eq!(
+            unmet(eix, "", |_| true).as_deref(),
+            Some("app-portage/eix is not installed")
+        );
+        assert_eq!(
+            unmet(eix, "app-portage/eix-0.35.2-r1", |_| true).as_deref(),
+            Some("app-portage/eix-0.35.2-r1 is installed, but 0.36 or later is needed")
+        );
+        assert_eq!(
+            unmet(eix, "app-portage/eix-0.36.7", |command| command
+                != "eix-update")
+            .as_deref(),
+            Some("app-portage/eix-0.36.7 is installed, but eix-update is missing")
+        );
+    }
+}
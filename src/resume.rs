@@ -0,0 +1,145 @@
+// Resuming an interrupted emerge
+// When emerge dies part way through the world update, portage keeps the packages it had still to
+// merge in its mtimedb, the list emerge --resume carries on with. "gentup --resume" looks for that
+// list, and when it finds one, runs emerge --resume rather than syncing and working out the
+// pending updates all over again, then carries on with the phases after the build, merging
+// configuration files and cleaning up. The mtimedb is JSON, e.g
+//
+//   "resume": {
+//       "mergelist": [
+//           ["ebuild", "/", "www-client/firefox-128.0.3", "merge"],
+//
+// and only the resume list itself is read from it, not the backup of the one before
+
+use crate::{
+    atom::Package,
+    linux::{OsCall, ShellOutResult},
+    portage, prompt,
+};
+use crossterm::style::Color;
+use std::fs;
+
+static MTIMEDB_PATH: &str = "/var/cache/edb/mtimedb";
+
+// The text of the JSON object or array which starts at the beginning of the text, up to the brace
+// or bracket which closes it, skipping over any inside strings
+//
+fn enclosed(text: &str, open: char, close: char) -> Option<&str> {
+    if !text.starts_with(open) {
+        return None;
+    }
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (position, character) in text.char_indices() {
+        if quoted {
+            match character {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+            continue;
+        }
+        match character {
+            '"' => quoted = true,
+            _ if character == open => depth += 1,
+            _ if character == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[..=position]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// The packages still to be merged, from the contents of the mtimedb. Only the resume object is
+// looked in, so that the mergelist of resume_backup after it is never taken for its own
+//
+pub fn resume_list(mtimedb: &str) -> Vec<Package> {
+    let Some(resume) = mtimedb
+        .split_once("\"resume\":")
+        .and_then(|(_, rest)| enclosed(rest.trim_start(), '{', '}'))
+    else {
+        return Vec::new();
+    };
+    let Some(mergelist) = resume
+        .split_once("\"mergelist\":")
+        .and_then(|(_, rest)| enclosed(rest.trim_start(), '[', ']'))
+    else {
+        return Vec::new();
+    };
+    mergelist
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .filter_map(|string| string.parse().ok())
+        .collect()
+}
+
+// The packages an interrupted emerge had still to merge, if there was one
+//
+pub fn interrupted() -> Vec<Package> {
+    resume_list(&fs::read_to_string(portage::target_path(MTIMEDB_PATH)).unwrap_or_default())
+}
+
+// List what the interrupted emerge had still to merge
+//
+pub fn show(remaining: &[Package]) {
+    println!(
+        "{} Resuming the interrupted update, with {} package(s) still to merge:",
+        prompt::revchevrons(Color::Green),
+        remaining.len()
+    );
+    for package in remaining {
+        println!("    {}", package);
+    }
+}
+
+// Carry on with the interrupted emerge
+//
+pub fn resume() -> ShellOutResult {
+    OsCall::Interactive.execute("emerge --resume", "Resuming the interrupted update")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_resume_list() {
+        let mtimedb = r#"{
+	"info": {},
+	"resume": {
+		"favorites": ["@world"],
+		"mergelist": [
+			["ebuild", "/", "dev-libs/openssl-3.0.13", "merge"],
+			["ebuild", "/", "www-client/firefox-128.0.3", "merge"]
+		],
+		"myopts": {"--deep": true}
+	},
+	"resume_backup": {
+		"mergelist": [["ebuild", "/", "app-misc/screen-4.9.1", "merge"]]
+	}
+}"#;
+        let remaining: Vec<String> = resume_list(mtimedb)
+            .iter()
+            .map(|package| package.to_string())
+            .collect();
+        assert_eq!(
+            remaining,
+            vec!["dev-libs/openssl-3.0.13", "www-client/firefox-128.0.3"]
+        );
+        assert!(resume_list("{\"resume_backup\": {\"mergelist\": []}}").is_empty());
+
+        // A resume list which is finished has no mergelist of its own, whatever the backup holds
+        let finished = r#"{
+	"resume": {"favorites": ["@world"], "myopts": {"--exclude": ["a \"quoted\" }"]}},
+	"resume_backup": {"mergelist": [["ebuild", "/", "app-misc/screen-4.9.1", "merge"]]}
+}"#;
+        assert!(resume_list(finished).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+// Rotational storage detection
+// Works out from sysfs whether the mounted filesystems are backed by spinning disks or by solid
+// state storage, and whether the storage accepts discards, so that only the filesystems which can
+// be trimmed are trimmed, one at a time. The kernel reports rotational status in
+// /sys/class/block/<device>/queue/rotational for whole disks, and discard support as a non-zero
+// queue/discard_max_bytes. A partition takes the status of the disk it is on, and a device-mapper
+// or md device, which is stacked on other block devices listed in its slaves directory, counts as
+// solid state, or as accepting discards, only when every device beneath it does. Network and
+// virtual filesystems, which fstrim -a complains about, are never trimmed
+
+use crate::linux;
+use std::{fs, path::Path};
+
+static SYSFS_BLOCK: &str = "/sys/class/block";
+
+// Filesystem types which are never trimmed, as they are not on a local block device or cannot
+// pass discards down to one. fuse.sshfs and the like are matched by their fuse prefix
+static UNTRIMMABLE_FSTYPES: [&str; 12] = [
+    "nfs", "nfs4", "cifs", "smb3", "9p", "fuse", "tmpfs", "ramfs", "overlay", "squashfs",
+    "iso9660", "udf",
+];
+
+// The devices a device-mapper or md device is stacked on, from its slaves directory
+//
+fn slaves_of(device: &Path) -> Vec<String> {
+    fs::read_dir(device.join("slaves"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Read a queue attribute of a device. A partition has no queue of its own, but sits in the
+// directory of its disk
+//
+fn queue_attribute(device: &Path, attribute: &str) -> Option<String> {
+    let read = |directory: &Path| {
+        fs::read_to_string(directory.join("queue").join(attribute))
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+    read(device).or_else(|| read(device.parent()?))
+}
+
+// Returns whether the named block device, e.g sda1, nvme0n1p2 or dm-0, is rotational, looking it
+// up under the given sysfs block class directory. None if the device is unknown
+//
+pub fn is_rotational_in(sysfs_block: &Path, name: &str) -> Option<bool> {
+    let device = fs::canonicalize(sysfs_block.join(name)).ok()?;
+    let slaves = slaves_of(&device);
+    if !slaves.is_empty() {
+        let mut rotational = false;
+        for slave in slaves {
+            rotational |= is_rotational_in(sysfs_block, &slave)?;
+        }
+        return Some(rotational);
+    }
+    queue_attribute(&device, "rotational").map(|value| value == "1")
+}
+
+// Returns whether the named block device accepts discards, looking it up under the given sysfs
+// block class directory. None if the device is unknown
+//
+pub fn supports_discard_in(sysfs_block: &Path, name: &str) -> Option<bool> {
+    let device = fs::canonicalize(sysfs_block.join(name)).ok()?;
+    let slaves = slaves_of(&device);
+    if !slaves.is_empty() {
+        let mut discard = true;
+        for slave in slaves {
+            discard &= supports_discard_in(sysfs_block, &slave)?;
+        }
+        return Some(discard);
+    }
+    queue_attribute(&device, "discard_max_bytes").map(|value| value != "0")
+}
+
+pub fn is_rotational(name: &str) -> Option<bool> {
+    is_rotational_in(Path::new(SYSFS_BLOCK), name)
+}
+
+// The kernel name of the block device behind a device path from the mount table. Paths such as
+// /dev/mapper/root and /dev/disk/by-uuid/... are symbolic links to the real device node
+//
+pub fn device_name(device: &str) -> Option<String> {
+    if !device.starts_with("/dev/") {
+        return None;
+    }
+    let resolved = fs::canonicalize(device).ok()?;
+    Some(resolved.file_name()?.to_string_lossy().to_string())
+}
+
+// Whether a filesystem type could be trimmed, going by UNTRIMMABLE_FSTYPES
+//
+pub fn trimmable_fstype(fstype: &str) -> bool {
+    let base = fstype.split('.').next().unwrap_or(fstype);
+    !UNTRIMMABLE_FSTYPES.contains(&base)
+}
+
+// The number of bytes fstrim -v reports trimming, from output such as
+// "/: 20.5 GiB (22017253376 bytes) trimmed"
+//
+pub fn trimmed_bytes(output: &str) -> Option<u64> {
+    let (_, rest) = output.split_once('(')?;
+    let (bytes, _) = rest.split_once(" bytes")?;
+    bytes.trim().parse().ok()
+}
+
+// The mount points of the writable filesystems on solid state storage which accepts discards, one
+// for each device
+//
+pub fn trimmable_mounts() -> Vec<String> {
+    let mut devices = Vec::new();
+    let mut mount_points = Vec::new();
+    for entry in linux::mounts() {
+        if entry.has_option("ro") || !trimmable_fstype(&entry.fstype) {
+            continue;
+        }
+        let Some(name) = device_name(&entry.device) else {
+            continue;
+        };
+        if devices.contains(&name) {
+            continue; // Already trimmed through another mount, e.g a bind mount
+        }
+        if is_rotational(&name) == Some(false)
+            && supports_discard_in(Path::new(SYSFS_BLOCK), &name) == Some(true)
+        {
+            devices.push(name);
+            mount_points.push(entry.mount_point);
+        }
+    }
+    mount_points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn follows_partitions_and_stacked_devices() {
+        let sysfs = std::env::temp_dir().join(format!("gentup-sysfs-{}", std::process::id()));
+        let devices = sysfs.join("devices");
+        let block = sysfs.join("block");
+        let disk = |name: &str, rotational: &str| {
+            fs::create_dir_all(devices.join(name).join("queue")).unwrap();
+            fs::write(devices.join(name).join("queue/rotational"), rotational).unwrap();
+            let discard = if name == "sda" {
+                "0\n"
+            } else {
+                "2199023255040\n"
+            };
+            fs::write(devices.join(name).join("queue/discard_max_bytes"), discard).unwrap();
+            symlink(devices.join(name), block.join(name)).unwrap();
+        };
+        fs::create_dir_all(&block).unwrap();
+        disk("sda", "1\n");
+        disk("nvme0n1", "0\n");
+        for (partition, parent) in [("sda1", "sda"), ("nvme0n1p2", "nvme0n1")] {
+            fs::create_dir_all(devices.join(parent).join(partition)).unwrap();
+            symlink(devices.join(parent).join(partition), block.join(partition)).unwrap();
+        }
+        // dm-0 is on the SSD alone, and md0 mirrors the SSD and the hard disk
+        for (stacked, slaves) in [
+            ("dm-0", vec!["nvme0n1p2"]),
+            ("md0", vec!["nvme0n1p2", "sda1"]),
+        ] {
+            disk(stacked, "0\n");
+            fs::create_dir_all(devices.join(stacked).join("slaves")).unwrap();
+            for slave in slaves {
+                fs::write(devices.join(stacked).join("slaves").join(slave), "").unwrap();
+            }
+        }
+        assert_eq!(is_rotational_in(&block, "sda1"), Some(true));
+        assert_eq!(is_rotational_in(&block, "nvme0n1p2"), Some(false));
+        assert_eq!(is_rotational_in(&block, "dm-0"), Some(false));
+        assert_eq!(is_rotational_in(&block, "md0"), Some(true));
+        assert_eq!(is_rotational_in(&block, "sdz"), None);
+        assert_eq!(supports_discard_in(&block, "sda1"), Some(false));
+        assert_eq!(supports_discard_in(&block, "nvme0n1p2"), Some(true));
+        assert_eq!(supports_discard_in(&block, "dm-0"), Some(true));
+        assert_eq!(supports_discard_in(&block, "md0"), Some(false));
+        assert!(trimmable_fstype("ext4"));
+        assert!(!trimmable_fstype("nfs4"));
+        assert!(!trimmable_fstype("fuse.sshfs"));
+        assert_eq!(
+            trimmed_bytes("/: 20.5 GiB (22017253376 bytes) trimmed on /dev/nvme0n1p2\n"),
+            Some(22017253376)
+        );
+        assert_eq!(
+            trimmed_bytes("fstrim: /boot: the discard operation is not supported"),
+            None
+        );
+        let _ = fs::remove_dir_all(&sysfs);
+    }
+}
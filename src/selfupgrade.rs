@@ -0,0 +1,344 @@
+// Upgrading portage itself
+// sys-apps/portage is upgraded before anything else, and a world update run with a broken package
+// manager can only make things worse. After the upgrade, portage is checked:
+//
+//   - no -MERGING- directory was left in /var/db/pkg/sys-apps by an interrupted merge
+//   - the files of the installed version match the record of them in its CONTENTS, as qcheck does
+//   - emerge still runs
+//
+// If the upgrade failed or portage is damaged, the lock files a killed emerge leaves behind are
+// removed, as long as no other emerge is running, and the installed version is merged again,
+// from its binary package if PKGDIR holds one. The update only carries on if portage is then
+// sound; otherwise the user is told how to repair it by hand
+
+use crate::{
+    actions,
+    exitcode::ExitCode,
+    integrity,
+    linux::{OsCall, ShellOutResult},
+    portage, prompt,
+};
+use crossterm::style::Color;
+use std::{fs, path::Path};
+
+static PACKAGE: &str = "sys-apps/portage";
+
+// The names in the package database's sys-apps category left by merges of portage which never
+// finished, e.g -MERGING-portage-3.0.63
+//
+pub fn half_merged(names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| name.starts_with("-MERGING-portage-"))
+        .cloned()
+        .collect()
+}
+
+// The installed version of portage, e.g portage-3.0.63-r1, from the names in the package
+// database's sys-apps category
+//
+pub fn installed(names: &[String]) -> Option<String> {
+    names
+        .iter()
+        .find(|name| {
+            name.strip_prefix("portage-")
+                .is_some_and(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .cloned()
+}
+
+// Whether a file in PKGDIR is a binary package of the given version, in either the old layout,
+// e.g sys-apps/portage-3.0.63.tbz2, or the new one, e.g sys-apps/portage/portage-3.0.63-1.gpkg.tar
+//
+pub fn is_binpkg_of(file_name: &str, version: &str) -> bool {
+    let Some(rest) = file_name.strip_prefix(version) else {
+        return false;
+    };
+    let rest = rest
+        .strip_prefix('-')
+        .map(|build| build.trim_start_matches(|c: char| c.is_ascii_digit()))
+        .unwrap_or(rest);
+    [".tbz2", ".xpak", ".gpkg.tar"].contains(&rest)
+}
+
+// Whether the command line of a process, as in /proc/<pid>/cmdline, is that of emerge
+//
+pub fn is_emerge(cmdline: &str) -> bool {
+    cmdline
+        .split('\0')
+        .take(2) // emerge itself, or the python interpreter running it
+        .any(|argument| argument.rsplit('/').next() == Some("emerge"))
+}
+
+// The names in the package database's sys-apps category
+//
+fn sys_apps() -> Vec<String> {
+    fs::read_dir(portage::target_path("/var/db/pkg/sys-apps"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Whether an emerge other than ours is running
+//
+fn other_emerge_running() -> bool {
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+    processes.flatten().any(|process| {
+        let name = process.file_name().to_string_lossy().to_string();
+        name.parse::<u32>()
+            .is_ok_and(|pid| pid != std::process::id())
+            && fs::read_to_string(process.path().join("cmdline"))
+                .is_ok_and(|cmdline| is_emerge(&cmdline))
+    })
+}
+
+// Describe what is wrong with the installed portage, if anything
+//
+fn problems() -> Vec<String> {
+    let names = sys_apps();
+    let mut found: Vec<String> = half_merged(&names)
+        .iter()
+        .map(|name| ["An interrupted merge left /var/db/pkg/sys-apps/", name].concat())
+        .collect();
+    match installed(&names) {
+        None => found.push("No version of sys-apps/portage is recorded as installed".to_string()),
+        Some(version) => {
+            if let Ok(contents) = fs::read_to_string(portage::target_path(
+                &["/var/db/pkg/sys-apps/", &version, "/CONTENTS"].concat(),
+            )) {
+                let damage = integrity::verify(
+                    &integrity::parse_contents(&contents),
+                    portage::target_root().unwrap_or(""),
+                    &["/etc".to_string()],
+                );
+                let damaged = damage.missing.len() + damage.modified.len();
+                if damaged > 0 {
+                    found.push(format!(
+                        "{} of the files of sys-apps/{} are missing or damaged",
+                        damaged, version
+                    ));
+                }
+            }
+        }
+    }
+    if !matches!(OsCall::Quiet.execute("emerge --version", ""), Ok((_, 0))) {
+        found.push("emerge no longer runs".to_string());
+    }
+    found
+}
+
+// Remove the lock files left by an emerge which was killed, unless another emerge is running and
+// holds them. Returns the number removed
+//
+fn clear_stale_locks() -> usize {
+    if other_emerge_running() {
+        println!(
+            "{} Another emerge is running, so its lock files are left alone",
+            prompt::revchevrons(Color::Yellow)
+        );
+        return 0;
+    }
+    let tmpdir = portage::portage_tmpdir();
+    let mut locks: Vec<String> = [
+        "/var/db/.pkg.portage_lockfile",
+        "/var/lib/portage/.world.portage_lockfile",
+    ]
+    .iter()
+    .map(|lock| portage::target_path(lock))
+    .collect();
+    if let Ok(entries) = fs::read_dir([&tmpdir, "/portage/sys-apps"].concat()) {
+        locks.extend(
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with(".portage-") && name.ends_with(".portage_lockfile"))
+                .map(|name| [&tmpdir, "/portage/sys-apps/", &name].concat()),
+        );
+    }
+    let mut removed = 0;
+    for lock in locks {
+        if fs::remove_file(&lock).is_ok() {
+            println!(
+                "{} Removed the stale lock file {}",
+                prompt::revchevrons(Color::Green),
+                lock
+            );
+            removed += 1;
+        }
+    }
+    removed
+}
+
+// Whether PKGDIR holds a binary package of the given version of portage
+//
+fn binpkg_available(version: &str) -> bool {
+    let pkgdir = OsCall::Quiet
+        .execute("portageq pkgdir", "")
+        .ok()
+        .filter(|(_, status)| *status == 0)
+        .map(|(output, _)| output.trim().to_string())
+        .filter(|pkgdir| !pkgdir.is_empty())
+        .or_else(|| portage::make_conf_variable("PKGDIR"))
+        .unwrap_or("/var/cache/binpkgs".to_string());
+    [
+        [&pkgdir, "/sys-apps"].concat(),
+        [&pkgdir, "/sys-apps/portage"].concat(),
+    ]
+    .iter()
+    .filter_map(|directory| fs::read_dir(Path::new(directory)).ok())
+    .flat_map(|entries| entries.flatten())
+    .any(|entry| is_binpkg_of(&entry.file_name().to_string_lossy(), version))
+}
+
+// Merge the installed version of portage again, from its binary package if there is one
+//
+fn remerge() -> bool {
+    let Some(version) = installed(&sys_apps()) else {
+        return false;
+    };
+    let atom = ["=sys-apps/", &version].concat();
+    let command = if binpkg_available(&version) {
+        ["emerge --quiet -1v --usepkgonly ", &atom].concat()
+    } else {
+        ["emerge --quiet -1v ", &atom].concat()
+    };
+    matches!(
+        OsCall::Interactive.execute(&command, "Repairing portage"),
+        Ok((_, 0))
+    )
+}
+
+// Report that portage could not be repaired, and stop before the world update
+//
+fn give_up(found: &[String]) -> ! {
+    for problem in found {
+        eprintln!("{} {}", prompt::revchevrons(Color::Red), problem);
+    }
+    let action = "Portage is broken and gentup could not repair it. Reinstall sys-apps/portage \
+                  from a binary package or a stage3 tarball, following \
+                  https://wiki.gentoo.org/wiki/Fix_my_Gentoo, before updating again"
+        .to_string();
+    eprintln!("{} {}", prompt::revchevrons(Color::Red), action);
+    actions::add(action);
+    ExitCode::Failed.exit();
+}
+
+// Upgrade portage, and repair it if the upgrade failed or left it damaged. Exits if portage
+// cannot be made sound, or if the upgrade failed and could not be retried
+//
+pub fn upgrade() {
+    let result: ShellOutResult = OsCall::Interactive.execute(
+        &["emerge --quiet -1v ", PACKAGE].concat(),
+        "Upgrading package",
+    );
+    let upgraded = matches!(result, Ok((_, 0)));
+    let found = problems();
+    if upgraded && found.is_empty() {
+        return;
+    }
+    if found.is_empty() {
+        // The upgrade failed but left the installed portage alone. A lock file left by an emerge
+        // which was killed is worth one more try
+        //
+        eprintln!(
+            "{} The upgrade of {} failed, but the installed version is intact",
+            prompt::revchevrons(Color::Yellow),
+            PACKAGE
+        );
+        if clear_stale_locks() > 0
+            && matches!(
+                OsCall::Interactive.execute(
+                    &["emerge --quiet -1v ", PACKAGE].concat(),
+                    "Upgrading package"
+                ),
+                Ok((_, 0))
+            )
+        {
+            return;
+        }
+        eprintln!(
+            "{} {} could not be upgraded. Please check.",
+            prompt::revchevrons(Color::Red),
+            PACKAGE
+        );
+        ExitCode::BuildFailed.exit();
+    }
+    eprintln!(
+        "{} The upgrade of {} left it damaged:",
+        prompt::revchevrons(Color::Red),
+        PACKAGE
+    );
+    for problem in &found {
+        eprintln!("    {}", problem);
+    }
+    if found
+        .iter()
+        .any(|problem| problem == "emerge no longer runs")
+    {
+        give_up(&found);
+    }
+    clear_stale_locks();
+    remerge();
+    let remaining = problems();
+    if !remaining.is_empty() {
+        give_up(&remaining);
+    }
+    println!(
+        "{} Portage was repaired, and the update carries on",
+        prompt::revchevrons(Color::Green)
+    );
+    if !upgraded {
+        actions::add(
+            [
+                "The upgrade of ",
+                PACKAGE,
+                " failed and the installed version was merged again. Try the upgrade again later",
+            ]
+            .concat(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_portage_installation() {
+        let names: Vec<String> = [
+            "pciutils-3.12.0",
+            "-MERGING-portage-3.0.65",
+            "portage-utils-0.97",
+            "portage-3.0.63-r1",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+        assert_eq!(half_merged(&names), vec!["-MERGING-portage-3.0.65"]);
+        assert_eq!(installed(&names), Some("portage-3.0.63-r1".to_string()));
+        assert_eq!(installed(&names[..3]), None);
+
+        assert!(is_binpkg_of("portage-3.0.63-r1.tbz2", "portage-3.0.63-r1"));
+        assert!(is_binpkg_of(
+            "portage-3.0.63-r1-2.gpkg.tar",
+            "portage-3.0.63-r1"
+        ));
+        assert!(!is_binpkg_of(
+            "portage-3.0.63-r2.gpkg.tar",
+            "portage-3.0.63"
+        ));
+        assert!(!is_binpkg_of("portage-3.0.63.1.tbz2", "portage-3.0.63"));
+
+        assert!(is_emerge(
+            "/usr/bin/python3.12\0/usr/lib/python-exec/python3.12/emerge\0-uDN\0"
+        ));
+        assert!(is_emerge("emerge\0--sync\0"));
+        assert!(!is_emerge("/usr/bin/python3.12\0/usr/bin/emaint\0"));
+    }
+}
@@ -0,0 +1,182 @@
+// Package tree signature verification
+// Checks that the Gentoo repository which was just synced can be authenticated, so that an update
+// is never built from a tampered tree without anyone noticing. How the tree is signed depends on
+// the sync method in repos.conf: an rsync mirror carries a signed Manifest tree which gemato
+// verifies, emerge-webrsync checks the GPG signature of the snapshot it downloads, and git checks
+// the signature on the commit it pulls. The merged repos.conf is read from portageq, e.g
+//
+//   [gentoo]
+//   location = /var/db/repos/gentoo
+//   sync-type = rsync
+//   sync-rsync-verify-metamanifest = yes
+//   sync-openpgp-key-path = /usr/share/openpgp-keys/gentoo-release.asc
+
+use crate::{
+    events::{self, Event},
+    exitcode::ExitCode,
+    linux::OsCall,
+    prompt, Config,
+};
+use crossterm::style::Color;
+use std::collections::HashMap;
+
+static DEFAULT_KEY_PATH: &str = "/usr/share/openpgp-keys/gentoo-release.asc";
+
+// The outcome of verifying the package tree
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    Verified,   // The signatures were checked and are good
+    Unverified, // Nothing checked the signatures, as verification is disabled or unavailable
+    Failed,     // The signatures were checked and are bad or missing
+}
+
+impl Verification {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verification::Verified => "verified",
+            Verification::Unverified => "unverified",
+            Verification::Failed => "failed",
+        }
+    }
+}
+
+// Parse the output of "portageq repos_config /" into the settings of the named repository, with
+// those of the DEFAULT section beneath them
+//
+pub fn parse_repos_config(output: &str, repository: &str) -> HashMap<String, String> {
+    let mut defaults = HashMap::new();
+    let mut settings = HashMap::new();
+    let mut section = String::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = name.to_string();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let entry = (key.trim().to_string(), value.trim().to_string());
+        if section == "DEFAULT" {
+            defaults.insert(entry.0, entry.1);
+        } else if section == repository {
+            settings.insert(entry.0, entry.1);
+        }
+    }
+    for (key, value) in defaults {
+        settings.entry(key).or_insert(value);
+    }
+    settings
+}
+
+fn enabled(settings: &HashMap<String, String>, key: &str) -> bool {
+    settings
+        .get(key)
+        .is_some_and(|value| matches!(value.to_lowercase().as_str(), "yes" | "true" | "1"))
+}
+
+// Verify the gentoo repository after a sync. For rsync, gemato checks the Manifest tree against
+// the release key. The other methods verify as they sync, failing the sync if the signature is
+// bad, so a completed sync is verified when the setting which makes them check is on
+//
+pub fn verify(sync_method: &str) -> Verification {
+    let settings = match OsCall::Quiet.execute("portageq repos_config /", "") {
+        Ok((output, 0)) => parse_repos_config(&output, "gentoo"),
+        _ => return Verification::Unverified,
+    };
+    // gentup's webrsync sync method overrides the sync-type in repos.conf
+    let sync_type = if sync_method == "webrsync" {
+        "webrsync"
+    } else {
+        settings.get("sync-type").map(String::as_str).unwrap_or("")
+    };
+    match sync_type {
+        "rsync" => {
+            let location = settings
+                .get("location")
+                .map(String::as_str)
+                .unwrap_or("/var/db/repos/gentoo");
+            let key_path = settings
+                .get("sync-openpgp-key-path")
+                .map(String::as_str)
+                .unwrap_or(DEFAULT_KEY_PATH);
+            match OsCall::Spinner.execute(
+                &["gemato verify -K ", key_path, " ", location].concat(),
+                "Verifying the package tree signatures",
+            ) {
+                Ok((_, 0)) => Verification::Verified,
+                Ok(_) => Verification::Failed,
+                Err(_) => Verification::Unverified, // gemato is not installed
+            }
+        }
+        "webrsync" if enabled(&settings, "sync-webrsync-verify-signature") => {
+            Verification::Verified
+        }
+        "git" if enabled(&settings, "sync-git-verify-commit-signature") => Verification::Verified,
+        _ => Verification::Unverified,
+    }
+}
+
+// Verify the package tree, report the result, and if the configuration requires a signed tree,
+// refuse to go on with one which could not be authenticated
+//
+pub fn check(running_config: &Config) {
+    let verification = verify(&running_config.sync_method);
+    events::emit(Event::TreeVerification {
+        status: verification.name(),
+    });
+    match verification {
+        Verification::Verified => println!(
+            "{} The package tree signatures are verified",
+            prompt::revchevrons(Color::Green)
+        ),
+        Verification::Unverified => eprintln!(
+            "{} The package tree signatures were not verified - check the sync settings in repos.conf",
+            prompt::revchevrons(Color::Yellow)
+        ),
+        Verification::Failed => eprintln!(
+            "{} The package tree failed signature verification",
+            prompt::revchevrons(Color::Red)
+        ),
+    }
+    if verification != Verification::Verified && running_config.require_signed_tree {
+        eprintln!(
+            "{} Refusing to update from a package tree which could not be authenticated",
+            prompt::revchevrons(Color::Red)
+        );
+        ExitCode::SyncFailed.exit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_repository_sync_settings() {
+        let output = "\
+[DEFAULT]
+main-repo = gentoo
+sync-rsync-verify-metamanifest = no
+
+[gentoo]
+location = /var/db/repos/gentoo
+sync-type = rsync
+sync-rsync-verify-metamanifest = yes
+
+[guru]
+location = /var/db/repos/guru
+sync-type = git
+";
+        let settings = parse_repos_config(output, "gentoo");
+        assert_eq!(settings["sync-type"], "rsync");
+        assert_eq!(settings["main-repo"], "gentoo");
+        assert!(enabled(&settings, "sync-rsync-verify-metamanifest"));
+        assert!(!enabled(&settings, "sync-webrsync-verify-signature"));
+        assert_eq!(parse_repos_config(output, "guru")["sync-type"], "git");
+    }
+}
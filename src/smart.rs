@@ -0,0 +1,261 @@
+// Storage health
+// Updating onto a dying disk is how systems are lost: a build which writes gigabytes to a drive
+// already remapping sectors can be what finishes it, leaving a half-merged system behind. Before
+// an update, the disks beneath /, /usr and /var are asked for their SMART health, with smartctl,
+// or with nvme-cli for NVMe drives when smartctl is not installed:
+//
+//   smartctl -H -A /dev/sda
+//   nvme smart-log /dev/nvme0n1
+//
+// A disk is reported when it fails its own health assessment, or has reallocated, pending or
+// uncorrectable sectors, media errors, a critical warning, or too little spare capacity left.
+// storage_health in the configuration file chooses whether that is a warning (the default), stops
+// the update (abort), or the disks are not checked at all (off). Partitions, device-mapper and md
+// devices are traced back to the disks they are on
+
+use crate::{
+    exitcode::ExitCode, linux, linux::OsCall, prefix, prompt, requirements, rotational, Config,
+};
+use crossterm::style::Color;
+use std::{fs, path::Path};
+
+// The paths whose disks are checked
+static CHECKED_PATHS: [&str; 3] = ["/", "/usr", "/var"];
+
+// ATA attributes which count sectors the disk has failed to read or had to remap
+static FAILING_ATTRIBUTES: [&str; 4] = [
+    "Reallocated_Sector_Ct",
+    "Current_Pending_Sector",
+    "Offline_Uncorrectable",
+    "Reported_Uncorrect",
+];
+
+// An NVMe drive with less spare capacity left than this percentage is reported, even before it
+// reaches the threshold the drive itself warns at
+static LOW_SPARE_PERCENT: u64 = 20;
+
+static SYSFS_BLOCK: &str = "/sys/class/block";
+
+// The whole disks a block device is on, e.g sda for sda2, or both members of a mirrored md array
+//
+fn disks_of(name: &str) -> Vec<String> {
+    let Ok(device) = fs::canonicalize(Path::new(SYSFS_BLOCK).join(name)) else {
+        return Vec::new();
+    };
+    let slaves: Vec<String> = fs::read_dir(device.join("slaves"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if !slaves.is_empty() {
+        return slaves.iter().flat_map(|slave| disks_of(slave)).collect();
+    }
+    let disk = if device.join("partition").exists() {
+        device.parent()
+    } else {
+        Some(device.as_path())
+    };
+    disk.and_then(|disk| disk.file_name())
+        .map(|disk| vec![disk.to_string_lossy().to_string()])
+        .unwrap_or_default()
+}
+
+// The disks beneath the checked paths, each once
+//
+fn checked_disks() -> Vec<String> {
+    let mut disks: Vec<String> = Vec::new();
+    for path in CHECKED_PATHS {
+        let Some(name) =
+            linux::mount_for(path).and_then(|entry| rotational::device_name(&entry.device))
+        else {
+            continue;
+        };
+        for disk in disks_of(&name) {
+            if !disks.contains(&disk) {
+                disks.push(disk);
+            }
+        }
+    }
+    disks
+}
+
+// The leading number of a value such as "100%", "0x04" or "1,024", if it has one
+//
+fn number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    let digits: String = value
+        .chars()
+        .filter(|character| *character != ',')
+        .take_while(|character| character.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+// The problems reported in the output of smartctl -H -A, for an ATA or an NVMe disk, or of
+// nvme smart-log
+//
+pub fn problems(report: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut spare: Option<u64> = None;
+    let mut spare_threshold: Option<u64> = None;
+    for line in report.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // ATA attribute rows: ID# ATTRIBUTE_NAME FLAG VALUE WORST THRESH TYPE UPDATED WHEN_FAILED
+        // RAW_VALUE
+        if fields.len() >= 10 && FAILING_ATTRIBUTES.contains(&fields[1]) {
+            if let Some(count) = number(fields[9]).filter(|count| *count > 0) {
+                problems.push(format!("{} is {}", fields[1], count));
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase().replace(' ', "_");
+        let value = value.trim();
+        match key.as_str() {
+            "smart_overall-health_self-assessment_test_result" | "smart_health_status"
+                if !matches!(value, "PASSED" | "OK") =>
+            {
+                problems.push(format!("the disk's own health assessment is {}", value));
+            }
+            "critical_warning" => {
+                if let Some(warning) = number(value).filter(|warning| *warning > 0) {
+                    problems.push(format!("critical warning {:#04x}", warning));
+                }
+            }
+            "media_and_data_integrity_errors" | "media_errors" => {
+                if let Some(errors) = number(value).filter(|errors| *errors > 0) {
+                    problems.push(format!("{} media error(s)", errors));
+                }
+            }
+            "available_spare" => spare = number(value),
+            "available_spare_threshold" => spare_threshold = number(value),
+            _ => {}
+        }
+    }
+    if let Some(spare) = spare {
+        let threshold = spare_threshold.unwrap_or(0).max(LOW_SPARE_PERCENT);
+        if spare < threshold {
+            problems.push(format!("only {}% spare capacity left", spare));
+        }
+    }
+    problems
+}
+
+// Ask a disk for its SMART report. None if neither smartctl, nor nvme-cli for an NVMe drive, is
+// installed
+//
+fn report(disk: &str) -> Option<String> {
+    let device = ["/dev/", disk].concat();
+    let command = if requirements::on_path("smartctl") {
+        ["smartctl -H -A ", &device].concat()
+    } else if disk.starts_with("nvme") && requirements::on_path("nvme") {
+        ["nvme smart-log ", &device].concat()
+    } else {
+        return None;
+    };
+    OsCall::Quiet
+        .execute(&command, "")
+        .ok()
+        .map(|(output, _)| output)
+}
+
+// Each disk beneath the checked paths which reports problems, with the problems
+//
+pub fn unhealthy_disks() -> Vec<(String, Vec<String>)> {
+    checked_disks()
+        .into_iter()
+        .filter_map(|disk| {
+            let problems = problems(&report(&disk)?);
+            (!problems.is_empty()).then(|| (["/dev/", &disk].concat(), problems))
+        })
+        .collect()
+}
+
+// Check the disks an update writes to, warning about, or stopping for, a disk which is failing
+//
+pub fn check(running_config: &Config) {
+    // A Gentoo Prefix belongs to its user, who cannot read the disks
+    if running_config.storage_health == "off" || prefix::active() {
+        return;
+    }
+    let unhealthy = unhealthy_disks();
+    if unhealthy.is_empty() {
+        return;
+    }
+    let abort = running_config.storage_health == "abort";
+    let colour = if abort { Color::Red } else { Color::Yellow };
+    for (disk, problems) in &unhealthy {
+        eprintln!(
+            "{} {} is failing: {}",
+            prompt::revchevrons(colour),
+            disk,
+            problems.join(", ")
+        );
+    }
+    if abort {
+        eprintln!(
+            "{} Not updating: writing an update onto a failing disk risks losing the system. Back up and replace the disk first",
+            prompt::revchevrons(Color::Red)
+        );
+        ExitCode::PreflightFailed.exit();
+    }
+    eprintln!(
+        "{} Back up this system and replace the disk soon. Set storage_health: abort to stop updates until it is replaced",
+        prompt::revchevrons(Color::Yellow)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_smart_reports() {
+        let ata = "\
+SMART overall-health self-assessment test result: PASSED
+ID# ATTRIBUTE_NAME          FLAG     VALUE WORST THRESH TYPE      UPDATED  WHEN_FAILED RAW_VALUE
+  5 Reallocated_Sector_Ct   0x0033   098   098   010    Pre-fail  Always       -       24
+  9 Power_On_Hours          0x0032   091   091   000    Old_age   Always       -       41234
+197 Current_Pending_Sector  0x0012   100   100   000    Old_age   Always       -       0
+";
+        assert_eq!(problems(ata), vec!["Reallocated_Sector_Ct is 24"]);
+
+        let nvme = "\
+SMART overall-health self-assessment test result: PASSED
+Critical Warning:                   0x00
+Available Spare:                    100%
+Available Spare Threshold:          10%
+Media and Data Integrity Errors:    0
+";
+        assert!(problems(nvme).is_empty());
+
+        let nvme_cli = "\
+critical_warning                        : 0x1
+available_spare                         : 8%
+available_spare_threshold               : 10%
+media_errors                            : 1,024
+";
+        assert_eq!(
+            problems(nvme_cli),
+            vec![
+                "critical warning 0x01",
+                "1024 media error(s)",
+                "only 8% spare capacity left"
+            ]
+        );
+
+        let failed = "SMART overall-health self-assessment test result: FAILED!\n";
+        assert_eq!(
+            problems(failed),
+            vec!["the disk's own health assessment is FAILED!"]
+        );
+    }
+}
@@ -0,0 +1,329 @@
+// Stale runs
+// An update which crashed, was killed, or lost power part way through can leave the system half
+// updated, and a fresh run started blind on top of it can make matters worse. While an update
+// runs, it holds a lock file, /var/lib/gentup/lock, giving its process, when it started and the
+// phase it is in, e.g
+//
+//   pid: 4242
+//   started: 1712345600
+//   phase: build
+//
+// The lock is removed however the update exits through gentup, so a lock left behind by a process
+// which is no longer running belongs to a run which did not finish. At startup, what that run was
+// doing is reported: the phase it was in, the last phase its checkpoint records as completed, and
+// from emerge.log the packages it merged and any it was part way through merging. The user is
+// then offered to resume from the checkpoint, to discard it and start again, or, when snapper has
+// a snapshot from before the run, to roll the system back to it. Unattended, the update resumes,
+// unless the stale-run prompt is answered in the config file. A lock held by a process which is
+// still running means another update is in progress, and this one stops
+
+use crate::{
+    actions,
+    config::state_dir,
+    exitcode::ExitCode,
+    linux::{self, OsCall},
+    orchestrator::Checkpoint,
+    portage, prompt, stats, Prompt,
+};
+use crossterm::style::Color;
+use std::{fs, process};
+
+// Define a struct to hold the lock of an update run
+//
+#[derive(Debug, PartialEq)]
+pub struct Lock {
+    pub pid: u32,
+    pub started: u64, // Seconds since the epoch
+    pub phase: String,
+}
+
+impl Lock {
+    fn path() -> String {
+        [state_dir(), "/lock"].concat()
+    }
+
+    pub fn parse(contents: &str) -> Option<Lock> {
+        let (mut pid, mut started, mut phase) = (None, None, String::new());
+        for line in contents.lines() {
+            match line.split_once(": ") {
+                Some(("pid", value)) => pid = value.trim().parse().ok(),
+                Some(("started", value)) => started = value.trim().parse().ok(),
+                Some(("phase", value)) => phase = value.trim().to_string(),
+                _ => {}
+            }
+        }
+        Some(Lock {
+            pid: pid?,
+            started: started?,
+            phase,
+        })
+    }
+
+    pub fn load() -> Option<Lock> {
+        Lock::parse(&fs::read_to_string(Lock::path()).ok()?)
+    }
+
+    // Whether the process which holds the lock is a gentup still running
+    //
+    fn held(&self) -> bool {
+        self.pid != process::id()
+            && fs::read_to_string(format!("/proc/{}/cmdline", self.pid))
+                .is_ok_and(|cmdline| cmdline.contains("gentup"))
+    }
+}
+
+// Take, or update, the lock of this run as it enters a phase
+//
+pub fn hold(started: u64, phase: &str) {
+    let contents = format!(
+        "pid: {}\nstarted: {}\nphase: {}\n",
+        process::id(),
+        started,
+        phase
+    );
+    if let Err(error) =
+        fs::create_dir_all(state_dir()).and_then(|_| fs::write(Lock::path(), contents))
+    {
+        eprintln!(
+            "{} Could not write the lock file {} - {}",
+            prompt::revchevrons(Color::Red),
+            Lock::path(),
+            error
+        );
+    }
+}
+
+// Remove the lock, if this process holds it, as the program exits
+//
+pub fn release() {
+    if Lock::load().is_some_and(|lock| lock.pid == process::id()) {
+        let _ = fs::remove_file(Lock::path());
+    }
+}
+
+// The packages a run merged, from emerge.log, and those it started merging but did not complete.
+// With --jobs, several may have been part way through
+//
+pub fn merges_since(log: &str, started: u64) -> (Vec<String>, Vec<String>) {
+    let mut merged = Vec::new();
+    let mut merging = Vec::new();
+    for entry in log.lines().filter_map(portage::parse_emerge_log_line) {
+        if entry.timestamp < started {
+            continue;
+        }
+        if entry.completed {
+            merging.retain(|package| *package != entry.package);
+            merged.push(entry.package);
+        } else {
+            merging.push(entry.package);
+        }
+    }
+    (merged, merging)
+}
+
+// The newest snapper snapshot taken before a time, from snapper --csvout --iso list --columns
+// number,date. Dates in ISO format compare in time order as text. Snapshot 0 is the live system
+//
+pub fn snapshot_before(listing: &str, before: &str) -> Option<String> {
+    listing
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(','))
+        .filter(|(number, date)| *number != "0" && !date.is_empty() && *date < before)
+        .last()
+        .map(|(number, _)| number.to_string())
+}
+
+// The snapper snapshot of the root filesystem to roll back to, if there is one from before the run
+//
+fn rollback_snapshot(started: u64) -> Option<String> {
+    let (listing, status) = OsCall::Quiet
+        .execute("snapper --csvout --iso list --columns number,date", "")
+        .ok()?;
+    if status != 0 {
+        return None;
+    }
+    let before = chrono::DateTime::from_timestamp(started as i64, 0)?
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    snapshot_before(&listing, &before)
+}
+
+// Roll the system back to a snapper snapshot, which takes effect at the next boot
+//
+fn rollback(snapshot: &str) -> ! {
+    match OsCall::Interactive.execute(
+        &["snapper rollback ", snapshot].concat(),
+        "Rolling back the system",
+    ) {
+        Ok((_, 0)) => {
+            Checkpoint::clear();
+            let _ = fs::remove_file(Lock::path());
+            println!(
+                "{} The system is rolled back to snapshot {}. Reboot to use it",
+                prompt::revchevrons(Color::Yellow),
+                snapshot
+            );
+            ExitCode::RebootRequired.exit();
+        }
+        _ => {
+            eprintln!(
+                "{} Could not roll back to snapshot {}",
+                prompt::revchevrons(Color::Red),
+                snapshot
+            );
+            ExitCode::Failed.exit();
+        }
+    }
+}
+
+// Look for an update which is still running, or one which did not finish. Returns whether this
+// run should resume from the checkpoint
+//
+pub fn check(resume: bool) -> bool {
+    let Some(lock) = Lock::load() else {
+        return resume;
+    };
+    if lock.held() {
+        eprintln!(
+            "{} Another update, process {}, has been running since {}, and is in the {} phase",
+            prompt::revchevrons(Color::Red),
+            lock.pid,
+            stats::local_time(lock.started),
+            lock.phase
+        );
+        ExitCode::Failed.exit();
+    }
+    println!(
+        "{} The update started at {} did not finish. It crashed or was killed in the {} phase",
+        prompt::revchevrons(Color::Yellow),
+        stats::local_time(lock.started),
+        lock.phase
+    );
+    let checkpoint = Checkpoint::load();
+    match &checkpoint {
+        Some(checkpoint) => println!(
+            "{} It completed the {} phase, with {} updates pending",
+            prompt::revchevrons(Color::Yellow),
+            checkpoint.completed.name(),
+            checkpoint.pending_updates.len()
+        ),
+        None => println!(
+            "{} It completed no phase",
+            prompt::revchevrons(Color::Yellow)
+        ),
+    }
+    let (merged, merging) = merges_since(
+        &fs::read_to_string(portage::target_path(portage::EMERGE_LOG)).unwrap_or_default(),
+        lock.started,
+    );
+    if !merged.is_empty() {
+        println!(
+            "{} It merged {} package(s):",
+            prompt::revchevrons(Color::Yellow),
+            merged.len()
+        );
+        for package in &merged {
+            println!("    {}", package);
+        }
+    }
+    for package in &merging {
+        println!(
+            "{} It was part way through merging {}, which may be left half installed",
+            prompt::revchevrons(Color::Red),
+            package
+        );
+    }
+    if resume {
+        return true;
+    }
+    if !linux::is_a_tty() && !prompt::answered("stale-run") {
+        println!(
+            "{} Resuming the update from its checkpoint",
+            prompt::revchevrons(Color::Yellow)
+        );
+        actions::add(format!(
+            "The update started at {} did not finish, and was resumed. Check the packages it was merging",
+            stats::local_time(lock.started)
+        ));
+        return true;
+    }
+    let snapshot = rollback_snapshot(lock.started);
+    let question = match &snapshot {
+        Some(snapshot) => format!(
+            "Select r to resume, d to discard the checkpoint and start again, u to roll back to snapper snapshot {}, or q to quit [r|d|u|q]",
+            snapshot
+        ),
+        None => {
+            "Select r to resume, d to discard the checkpoint and start again, or q to quit [r|d|q]"
+                .to_string()
+        }
+    };
+    loop {
+        match Prompt::Options.askuser("stale-run", &question).as_deref() {
+            Some("r\n") => return true,
+            Some("d\n") => return false,
+            Some("u\n") => match &snapshot {
+                Some(snapshot) => rollback(snapshot),
+                // A preset answer would otherwise be given again and again
+                None if prompt::answered("stale-run") => {
+                    eprintln!(
+                        "{} \"answer: stale-run u\" cannot be acted on, as there is no snapper snapshot from before the update",
+                        prompt::revchevrons(Color::Red)
+                    );
+                    ExitCode::ConfigError.exit();
+                }
+                None => continue,
+            },
+            // Anything else typed at the terminal is asked again
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_unfinished_runs() {
+        let lock = Lock::parse("pid: 4242\nstarted: 1712345600\nphase: build\n").unwrap();
+        assert_eq!(
+            lock,
+            Lock {
+                pid: 4242,
+                started: 1712345600,
+                phase: "build".to_string()
+            }
+        );
+        assert_eq!(Lock::parse("phase: build\n"), None);
+
+        let log = "\
+1712345000:  ::: completed emerge (1 of 1) sys-libs/zlib-1.3 to /
+1712345700:  >>> emerge (1 of 3) sys-libs/zlib-1.3.1 to /
+1712345750:  ::: completed emerge (1 of 3) sys-libs/zlib-1.3.1 to /
+1712345800:  >>> emerge (2 of 3) sys-devel/gcc-13.2.1_p20240113-r1 to /
+";
+        assert_eq!(
+            merges_since(log, 1712345600),
+            (
+                vec!["sys-libs/zlib-1.3.1".to_string()],
+                vec!["sys-devel/gcc-13.2.1_p20240113-r1".to_string()]
+            )
+        );
+
+        let listing = "\
+number,date
+0,
+1,2024-04-04 03:00:00
+2,2024-04-05 03:00:00
+3,2024-04-05 11:00:00
+";
+        assert_eq!(
+            snapshot_before(listing, "2024-04-05 10:40:00"),
+            Some("2".to_string())
+        );
+        assert_eq!(snapshot_before(listing, "2024-04-01 00:00:00"), None);
+    }
+}
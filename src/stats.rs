@@ -0,0 +1,517 @@
+// Merge statistics
+// Reads the merge history straight from emerge.log, rather than relying on qlop from
+// app-portage/portage-utils, to report what has been merged and unmerged, how long each package
+// takes to build on average, and the total time spent compiling. "gentup --stats" displays these,
+// and the averages are used to estimate how long an update has left to run. After each build, the
+// time each package actually took is compared with the estimate from its earlier builds, and the
+// comparison is kept in /var/lib/gentup/build-times, so that packages whose build time has
+// exploded, e.g after a USE flag change, stand out. "gentup --since-boot" and "gentup --since
+// 2024-04-05" list every package changed since the system booted, or since a date, to answer
+// "something broke, what was updated?"
+
+use crate::{atom::Package, config::state_dir, logarchive, portage, prompt};
+use crossterm::style::Color;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+// Define a struct to hold one completed merge
+//
+#[derive(Debug, PartialEq)]
+pub struct Merge {
+    pub package: String, // The full name, e.g sys-libs/zlib-1.3.1
+    pub started: u64,
+    pub finished: u64,
+}
+
+impl Merge {
+    pub fn seconds(&self) -> u64 {
+        self.finished.saturating_sub(self.started)
+    }
+
+    // The category and name, e.g sys-libs/zlib
+    pub fn cpn(&self) -> String {
+        match self.package.parse::<Package>() {
+            Ok(package) => package.cpn(),
+            Err(_) => self.package.clone(),
+        }
+    }
+}
+
+// A change made to the installed packages
+//
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Installed(String),
+    Replaced(String, String), // The old version, and the one which replaced it
+    Removed(String),
+}
+
+// Define a struct to hold the merge history
+//
+#[derive(Debug, Default)]
+pub struct History {
+    pub merges: Vec<Merge>,
+    pub unmerges: Vec<(u64, String)>, // When, and which package
+}
+
+impl History {
+    // Parse the contents of emerge.log. A merge is recorded when it starts and again when it
+    // completes, and unmerges as e.g
+    // 1712350000:  >>> unmerge success: sys-libs/zlib-1.3
+    //
+    pub fn parse(contents: &str) -> History {
+        let mut history = History::default();
+        let mut started = HashMap::new();
+        for line in contents.lines() {
+            if let Some(entry) = portage::parse_emerge_log_line(line) {
+                if !entry.completed {
+                    started.insert(entry.package, entry.timestamp);
+                } else if let Some(start) = started.remove(&entry.package) {
+                    history.merges.push(Merge {
+                        package: entry.package,
+                        started: start,
+                        finished: entry.timestamp,
+                    });
+                }
+            } else if let Some((timestamp, message)) = line.split_once(':') {
+                if let (Ok(timestamp), Some(package)) = (
+                    timestamp.trim().parse(),
+                    message.trim().strip_prefix(">>> unmerge success: "),
+                ) {
+                    history
+                        .unmerges
+                        .push((timestamp, package.trim().to_string()));
+                }
+            }
+        }
+        history
+    }
+
+    // Load the history from emerge.log. The history is empty if the log cannot be read
+    //
+    pub fn load() -> History {
+        fs::read_to_string(portage::target_path(portage::EMERGE_LOG))
+            .map(|contents| History::parse(&contents))
+            .unwrap_or_default()
+    }
+
+    // The average time taken to build the named package, e.g sys-devel/gcc, if it has been built
+    // before
+    //
+    pub fn average_build_time(&self, cpn: &str) -> Option<u64> {
+        let times: Vec<u64> = self
+            .merges
+            .iter()
+            .filter(|merge| merge.cpn() == cpn)
+            .map(|merge| merge.seconds())
+            .collect();
+        if times.is_empty() {
+            return None;
+        }
+        Some(times.iter().sum::<u64>() / times.len() as u64)
+    }
+
+    // The changes made since a time, in seconds since the epoch, in the order they were made. A
+    // package is unmerged while its replacement merges, so an unmerge of the same package during a
+    // merge is the old version being replaced
+    //
+    pub fn changes_since(&self, since: u64) -> Vec<(u64, Change)> {
+        let mut replaced = Vec::new();
+        let mut changes = Vec::new();
+        for merge in self.merges.iter().filter(|merge| merge.finished >= since) {
+            let old = self.unmerges.iter().position(|(when, package)| {
+                (merge.started..=merge.finished).contains(when)
+                    && package
+                        .parse::<Package>()
+                        .is_ok_and(|package| package.cpn() == merge.cpn())
+            });
+            let change = match old {
+                Some(index) => {
+                    replaced.push(index);
+                    Change::Replaced(self.unmerges[index].1.clone(), merge.package.clone())
+                }
+                None => Change::Installed(merge.package.clone()),
+            };
+            changes.push((merge.finished, change));
+        }
+        for (index, (when, package)) in self.unmerges.iter().enumerate() {
+            if *when >= since && !replaced.contains(&index) {
+                changes.push((*when, Change::Removed(package.clone())));
+            }
+        }
+        changes.sort_by_key(|(when, _)| *when);
+        changes
+    }
+
+    pub fn total_build_time(&self) -> u64 {
+        self.merges.iter().map(|merge| merge.seconds()).sum()
+    }
+
+    // The average build time of every package, longest first
+    //
+    pub fn averages(&self) -> Vec<(String, u64, usize)> {
+        let mut totals: HashMap<String, (u64, usize)> = HashMap::new();
+        for merge in &self.merges {
+            let total = totals.entry(merge.cpn()).or_default();
+            total.0 += merge.seconds();
+            total.1 += 1;
+        }
+        let mut averages: Vec<(String, u64, usize)> = totals
+            .into_iter()
+            .map(|(cpn, (seconds, count))| (cpn, seconds / count as u64, count))
+            .collect();
+        averages.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        averages
+    }
+}
+
+// Define a struct to hold the estimated and actual build time of a package built in this run
+//
+#[derive(Debug, PartialEq)]
+pub struct BuildTime {
+    pub finished: u64,
+    pub package: String,
+    pub estimate: Option<u64>, // None for a package never built before
+    pub actual: u64,
+}
+
+impl BuildTime {
+    // A build which took more than twice as long as estimated, and at least five minutes longer
+    pub fn exploded(&self) -> bool {
+        self.estimate
+            .is_some_and(|estimate| self.actual > estimate * 2 && self.actual > estimate + 300)
+    }
+
+    // The record kept in the build-times file, e.g 1712350000 sys-devel/gcc-13.2.1 4500 5100
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.finished,
+            self.package,
+            self.estimate
+                .map(|estimate| estimate.to_string())
+                .unwrap_or("-".to_string()),
+            self.actual
+        )
+    }
+
+    fn from_line(line: &str) -> Option<BuildTime> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            return None;
+        }
+        Some(BuildTime {
+            finished: fields[0].parse().ok()?,
+            package: fields[1].to_string(),
+            estimate: fields[2].parse().ok(),
+            actual: fields[3].parse().ok()?,
+        })
+    }
+}
+
+fn build_times_path() -> String {
+    [state_dir(), "/build-times"].concat()
+}
+
+// Compare the packages merged since the build started with the estimates from the history as it
+// was before the build
+//
+pub fn build_times(before: &History, after: &History, since: u64) -> Vec<BuildTime> {
+    after
+        .merges
+        .iter()
+        .filter(|merge| merge.started >= since)
+        .map(|merge| BuildTime {
+            finished: merge.finished,
+            package: merge.package.clone(),
+            estimate: before.average_build_time(&merge.cpn()),
+            actual: merge.seconds(),
+        })
+        .collect()
+}
+
+// Display the estimated and actual build times of the packages built by this run, then add them
+// to the build-times file
+//
+pub fn report_build_times(before: &History, since: u64) {
+    let times = build_times(before, &History::load(), since);
+    if times.is_empty() {
+        return;
+    }
+    println!(
+        "\n{} Build times, estimated and actual:",
+        prompt::revchevrons(Color::Green)
+    );
+    for time in &times {
+        println!(
+            "  {:<50} {:>14} {:>14}{}",
+            time.package,
+            time.estimate
+                .map(format_duration)
+                .unwrap_or("-".to_string()),
+            format_duration(time.actual),
+            if time.exploded() {
+                "  much slower than before"
+            } else {
+                ""
+            }
+        );
+    }
+    println!();
+    let lines: String = times.iter().map(|time| time.to_line() + "\n").collect();
+    let _ = fs::create_dir_all(state_dir()).and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(build_times_path())?
+            .write_all(lines.as_bytes())
+    });
+}
+
+// Format a number of seconds for display, e.g 1h 02m 03s
+//
+pub fn format_duration(seconds: u64) -> String {
+    match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+// Format a time in seconds since the epoch for display, e.g 2024-04-05 10:15
+//
+pub fn local_time(seconds: u64) -> String {
+    chrono::DateTime::from_timestamp(seconds as i64, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+// Display the merge statistics, for gentup --stats
+//
+pub fn show() {
+    let history = History::load();
+    if history.merges.is_empty() {
+        println!(
+            "{} There is no merge history in {}",
+            prompt::revchevrons(Color::Yellow),
+            portage::target_path(portage::EMERGE_LOG)
+        );
+        return;
+    }
+    println!(
+        "{} {} merges and {} unmerges recorded in {}",
+        prompt::revchevrons(Color::Green),
+        history.merges.len(),
+        history.unmerges.len(),
+        portage::target_path(portage::EMERGE_LOG)
+    );
+    println!(
+        "{} Total time spent compiling: {}\n",
+        prompt::revchevrons(Color::Green),
+        format_duration(history.total_build_time())
+    );
+
+    println!("Longest average build times:");
+    for (cpn, seconds, count) in history.averages().iter().take(15) {
+        println!(
+            "  {:<40} {:>14}  ({} merge{})",
+            cpn,
+            format_duration(*seconds),
+            count,
+            if *count == 1 { "" } else { "s" }
+        );
+    }
+
+    let exploded: Vec<BuildTime> = fs::read_to_string(build_times_path())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(BuildTime::from_line)
+        .filter(|time| time.exploded())
+        .collect();
+    if !exploded.is_empty() {
+        println!("\nBuilds much slower than their estimate:");
+        for time in exploded.iter().rev().take(15) {
+            println!(
+                "  {:<50} {:>14} estimated, {:>14} actual",
+                time.package,
+                format_duration(time.estimate.unwrap_or_default()),
+                format_duration(time.actual)
+            );
+        }
+    }
+
+    println!("\nMost recent merges:");
+    for merge in history.merges.iter().rev().take(15) {
+        println!(
+            "  {}  {:<50} {:>14}",
+            local_time(merge.finished),
+            merge.package,
+            format_duration(merge.seconds())
+        );
+    }
+
+    let runs = logarchive::recent_runs(15);
+    if !runs.is_empty() {
+        println!("\nMost recent update runs:");
+        for run in runs {
+            println!(
+                "  {}  {:<50} {:>14}",
+                local_time(run.started),
+                run.result,
+                format_duration(run.finished.saturating_sub(run.started))
+            );
+        }
+    }
+}
+
+// When the system booted, in seconds since the epoch, from the btime line of /proc/stat
+//
+pub fn boot_time() -> Option<u64> {
+    fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse().ok())
+}
+
+// Parse the date given to --since, e.g 2024-04-05 or 2024-04-05T10:15, in local time
+//
+pub fn parse_since(date: &str) -> Option<u64> {
+    let time = chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M")
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        })
+        .ok()?;
+    let local = time.and_local_timezone(chrono::Local).earliest()?;
+    u64::try_from(local.timestamp()).ok()
+}
+
+// Display every package changed since a time, and the update runs which changed them, for
+// gentup --since-boot and gentup --since
+//
+pub fn show_changes_since(since: u64, description: &str) {
+    let changes = History::load().changes_since(since);
+    if changes.is_empty() {
+        println!(
+            "{} No packages have changed since {} ({})",
+            prompt::revchevrons(Color::Green),
+            description,
+            local_time(since)
+        );
+        return;
+    }
+    println!(
+        "{} {} package change(s) since {} ({}):\n",
+        prompt::revchevrons(Color::Yellow),
+        changes.len(),
+        description,
+        local_time(since)
+    );
+    for (when, change) in &changes {
+        let (verb, packages) = match change {
+            Change::Installed(package) => ("installed", package.to_string()),
+            Change::Replaced(old, new) => ("updated", format!("{} -> {}", old, new)),
+            Change::Removed(package) => ("removed", package.to_string()),
+        };
+        println!("  {}  {:<10} {}", local_time(*when), verb, packages);
+    }
+    let runs: Vec<logarchive::RunSummary> = logarchive::recent_runs(usize::MAX)
+        .into_iter()
+        .filter(|run| run.finished >= since)
+        .collect();
+    if !runs.is_empty() {
+        println!("\nUpdate runs since {}:", description);
+        for run in runs.iter().rev() {
+            println!("  {}  {}", local_time(run.started), run.result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_merge_history() {
+        let log = "\
+1700000000: Started emerge on: Nov 14, 2023 22:13:20
+1700000010:  >>> emerge (1 of 2) sys-devel/gcc-13.2.1_p20240113-r1 to /
+1700000020:  >>> emerge (2 of 2) sys-libs/zlib-1.3.1 to /
+1700000080:  ::: completed emerge (2 of 2) sys-libs/zlib-1.3.1 to /
+1700003610:  ::: completed emerge (1 of 2) sys-devel/gcc-13.2.1_p20240113-r1 to /
+1700003620:  >>> unmerge success: sys-libs/zlib-1.3
+1700100000:  >>> emerge (1 of 1) sys-devel/gcc-13.2.1_p20240210 to /
+1700105400:  ::: completed emerge (1 of 1) sys-devel/gcc-13.2.1_p20240210 to /
+";
+        let history = History::parse(log);
+        assert_eq!(history.merges.len(), 3);
+        assert_eq!(
+            history.unmerges,
+            vec![(1700003620, "sys-libs/zlib-1.3".to_string())]
+        );
+        assert_eq!(history.average_build_time("sys-devel/gcc"), Some(4500));
+        assert_eq!(history.average_build_time("dev-lang/rust"), None);
+        assert_eq!(history.total_build_time(), 3600 + 60 + 5400);
+        assert_eq!(
+            history.averages()[0],
+            ("sys-devel/gcc".to_string(), 4500, 2)
+        );
+        assert_eq!(format_duration(4500), "1h 15m 00s");
+
+        // The second gcc build, compared with the history before it started
+        let before = History::parse(&log.lines().take(6).collect::<Vec<&str>>().join("\n"));
+        let times = build_times(&before, &history, 1700100000);
+        assert_eq!(
+            times,
+            vec![BuildTime {
+                finished: 1700105400,
+                package: "sys-devel/gcc-13.2.1_p20240210".to_string(),
+                estimate: Some(3600),
+                actual: 5400,
+            }]
+        );
+        assert!(!times[0].exploded());
+
+        let upgrade = "\
+1700200000:  >>> emerge (1 of 1) sys-libs/zlib-1.3.1-r1 to /
+1700200050:  >>> unmerge success: sys-libs/zlib-1.3.1
+1700200060:  ::: completed emerge (1 of 1) sys-libs/zlib-1.3.1-r1 to /
+";
+        let history = History::parse(&[log, upgrade].concat());
+        assert_eq!(
+            history.changes_since(1700100000),
+            vec![
+                (
+                    1700105400,
+                    Change::Installed("sys-devel/gcc-13.2.1_p20240210".to_string())
+                ),
+                (
+                    1700200060,
+                    Change::Replaced(
+                        "sys-libs/zlib-1.3.1".to_string(),
+                        "sys-libs/zlib-1.3.1-r1".to_string()
+                    )
+                ),
+            ]
+        );
+        assert_eq!(
+            history.changes_since(1700003000)[1],
+            (1700003620, Change::Removed("sys-libs/zlib-1.3".to_string()))
+        );
+        assert!(parse_since("2024-04-05").is_some());
+        assert!(parse_since("2024-04-05T10:15").is_some());
+        assert_eq!(parse_since("yesterday"), None);
+        assert_eq!(
+            BuildTime::from_line(&times[0].to_line()).as_ref(),
+            Some(&times[0])
+        );
+    }
+}
@@ -0,0 +1,422 @@
+// Status and control socket
+// While an update runs, gentup listens on a Unix domain socket so that dashboards, scripts or
+// "gentup --status" can see how far it has got, and nudge it. Each connection sends one verb on a
+// line of its own and receives one line of JSON in reply:
+//
+//   status  - the current phase, the package being built and how far its compile has got,
+//             progress counts and an ETA
+//   pause   - hold the update at the end of the current phase (or build) until resumed
+//   resume  - release a paused update
+//   skip    - stop building the current package and carry on with the rest of the update
+//   abort   - stop the running build and exit. The update can be resumed with --continue
+//
+// For example: echo status | socat - UNIX-CONNECT:/run/gentup.sock
+//
+// In a Gentoo Prefix, the socket is under the EPREFIX, e.g /home/alice/gentoo/run/gentup.sock.
+// skip and abort only signal the processes of this update's own build, found by walking the
+// process tree down from gentup, so other emerges running on the machine are left alone
+
+use crate::{
+    atom::Package, events::json_string, exitcode::ExitCode, portage, prefix, prompt,
+    stats::History, throttle,
+};
+use crossterm::style::Color;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub static SOCKET_PATH: &str = "/run/gentup.sock";
+
+// Where the socket is, under the EPREFIX in a Gentoo Prefix, whose user cannot write to /run
+//
+fn socket_path() -> String {
+    prefix::path(SOCKET_PATH)
+}
+
+// The state shared between the update and the socket listener
+//
+struct State {
+    phase: String,
+    pending_updates: Vec<String>, // The category and name of each package to be updated
+    build_started: u64,           // Seconds since the epoch, or 0 if the build has not started
+    paused: bool,
+    skip: bool,
+    abort: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    phase: String::new(),
+    pending_updates: Vec::new(),
+    build_started: 0,
+    paused: false,
+    skip: false,
+    abort: false,
+});
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// Build progress as recorded in emerge.log since the build started: the package currently being
+// merged, how many have completed and how many there are in total
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct Progress {
+    pub package: String,
+    pub package_started: u64,
+    pub completed: usize,
+    pub total: usize,
+    pub finished: Vec<String>, // The packages completed so far
+}
+
+pub fn parse_emerge_log(contents: &str, since: u64) -> Progress {
+    let mut progress = Progress::default();
+    for entry in contents.lines().filter_map(portage::parse_emerge_log_line) {
+        if entry.timestamp < since {
+            continue;
+        }
+        progress.total = entry.total;
+        if entry.completed {
+            progress.completed = entry.number;
+            if progress.package == entry.package {
+                progress.package.clear();
+            }
+            progress.finished.push(entry.package);
+        } else {
+            progress.package = entry.package;
+            progress.package_started = entry.timestamp;
+        }
+    }
+    progress
+}
+
+// Record the phase the update has reached
+//
+pub fn set_phase(phase: &str, pending_updates: &[Package]) {
+    if let Ok(mut state) = STATE.lock() {
+        state.phase = phase.to_string();
+        state.pending_updates = pending_updates.iter().map(|each| each.cpn()).collect();
+        if phase == "build" {
+            state.build_started = now();
+        }
+    }
+}
+
+// Estimate the seconds the build has left to run. Packages built before are assumed to take
+// their average time again, and the rest the average of the packages built so far in this run
+//
+fn estimate_remaining(state: &State, progress: &Progress, history: &History) -> Option<u64> {
+    let finished: Vec<String> = progress
+        .finished
+        .iter()
+        .filter_map(|package| package.parse::<Package>().ok())
+        .map(|package| package.cpn())
+        .collect();
+    let run_average = match progress.completed {
+        0 => None,
+        completed => Some((now() - state.build_started) / completed as u64),
+    };
+    let mut remaining = 0;
+    for cpn in state
+        .pending_updates
+        .iter()
+        .filter(|cpn| !finished.contains(cpn))
+    {
+        remaining += history.average_build_time(cpn).or(run_average)?;
+    }
+    // The package being built is already part way through
+    if !progress.package.is_empty() {
+        remaining = remaining.saturating_sub(now().saturating_sub(progress.package_started));
+    }
+    Some(remaining)
+}
+
+// Define a struct to hold the status of the update at a moment in time
+//
+pub struct Snapshot {
+    pub phase: String,
+    pub package: String, // The package being built, if any
+    pub compile: String, // How far the packages being built have got
+    pub completed: usize,
+    pub total: usize,
+    pub eta: Option<u64>, // Seconds, once the build has started
+    pub paused: bool,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"phase\":{},\"package\":{},\"compile\":{},\"completed\":{},\"total\":{},\"eta_seconds\":{},\"paused\":{}}}",
+            json_string(&self.phase),
+            json_string(&self.package),
+            json_string(&self.compile),
+            self.completed,
+            self.total,
+            self.eta
+                .map(|seconds| seconds.to_string())
+                .unwrap_or("null".to_string()),
+            self.paused
+        )
+    }
+}
+
+// Take a snapshot of the status of the update
+//
+pub fn snapshot() -> Option<Snapshot> {
+    let state = STATE.lock().ok()?;
+    let mut progress = Progress {
+        total: state.pending_updates.len(),
+        ..Progress::default()
+    };
+    let mut eta = None;
+    if state.build_started > 0 {
+        if let Ok(contents) = fs::read_to_string(portage::target_path(portage::EMERGE_LOG)) {
+            let logged = parse_emerge_log(&contents, state.build_started);
+            if logged.total > 0 {
+                progress = logged;
+            }
+            eta = estimate_remaining(&state, &progress, &History::parse(&contents));
+        }
+    }
+    Some(Snapshot {
+        phase: state.phase.clone(),
+        package: progress.package,
+        compile: crate::progress::current(),
+        completed: progress.completed,
+        total: progress.total,
+        eta,
+        paused: state.paused,
+    })
+}
+
+// The value of a variable in the contents of /proc/PID/environ
+//
+fn environment_variable<'a>(environ: &'a str, name: &str) -> &'a str {
+    environ
+        .split('\0')
+        .find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+        .unwrap_or_default()
+}
+
+// Whether a process runs ebuild.sh for the package, e.g sys-libs/zlib-1.3.1, or for any package
+// if which one is being built is not known
+//
+fn builds(pid: u32, package: &str) -> bool {
+    let proc = Path::new("/proc").join(pid.to_string());
+    let cmdline = fs::read(proc.join("cmdline")).unwrap_or_default();
+    if !String::from_utf8_lossy(&cmdline).contains("ebuild.sh") {
+        return false;
+    }
+    if package.is_empty() {
+        return true;
+    }
+    let environ = fs::read(proc.join("environ")).unwrap_or_default();
+    let environ = String::from_utf8_lossy(&environ);
+    [
+        environment_variable(&environ, "CATEGORY"),
+        "/",
+        environment_variable(&environ, "PF"),
+    ]
+    .concat()
+        == package
+}
+
+// Whether a process is emerge
+//
+fn is_emerge(pid: u32) -> bool {
+    fs::read_to_string(Path::new("/proc").join(pid.to_string()).join("comm"))
+        .is_ok_and(|comm| comm.trim_end() == "emerge")
+}
+
+// Send SIGTERM to the processes of this update's build which match
+//
+fn terminate(matches: impl Fn(u32) -> bool) {
+    for pid in throttle::descendants()
+        .into_iter()
+        .filter(|pid| matches(*pid))
+    {
+        // SAFETY: kill only sends a signal, to a process of the build gentup started
+        unsafe {
+            libc::kill(pid as i32, libc::SIGTERM);
+        }
+    }
+}
+
+// Act on a verb received from the socket, returning the reply
+//
+fn handle(verb: &str) -> String {
+    let reply = "{\"ok\":true}".to_string();
+    match verb {
+        "status" => {
+            return match snapshot() {
+                Some(snapshot) => snapshot.to_json(),
+                None => "{\"error\":\"status unavailable\"}".to_string(),
+            }
+        }
+        "pause" | "resume" => {
+            if let Ok(mut state) = STATE.lock() {
+                state.paused = verb == "pause";
+            }
+        }
+        "skip" => {
+            if let Ok(mut state) = STATE.lock() {
+                state.skip = true;
+            }
+            // Terminate the ebuild of the package being built. emerge then fails, and the update
+            // resumes without the package
+            let package = snapshot()
+                .map(|snapshot| snapshot.package)
+                .unwrap_or_default();
+            terminate(|pid| builds(pid, &package));
+        }
+        "abort" => {
+            if let Ok(mut state) = STATE.lock() {
+                state.abort = true;
+            }
+            terminate(is_emerge);
+        }
+        _ => return format!("{{\"error\":{}}}", json_string("unknown verb")),
+    }
+    reply
+}
+
+fn serve_connection(mut stream: UnixStream) {
+    let mut verb = String::new();
+    if BufReader::new(&stream).read_line(&mut verb).is_ok() {
+        let _ = writeln!(stream, "{}", handle(verb.trim()));
+    }
+}
+
+// Start listening on the socket in the background. The update carries on without the socket if
+// it cannot be created
+//
+pub fn serve() {
+    let path = socket_path();
+    let _ = fs::remove_file(&path); // Left behind by a run which did not exit cleanly
+    if let Some(directory) = Path::new(&path).parent() {
+        let _ = fs::create_dir_all(directory);
+    }
+    match UnixListener::bind(&path) {
+        Ok(listener) => {
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    serve_connection(stream);
+                }
+            });
+        }
+        Err(error) => eprintln!(
+            "{} Could not create the status socket {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            path,
+            error
+        ),
+    }
+}
+
+pub fn shutdown() {
+    let _ = fs::remove_file(socket_path());
+}
+
+// Returns true, and clears the request, if the user asked to skip the package being built
+//
+pub fn take_skip_request() -> bool {
+    match STATE.lock() {
+        Ok(mut state) => std::mem::take(&mut state.skip),
+        Err(_) => false,
+    }
+}
+
+// Called between phases: exit if the update was aborted, and wait while it is paused
+//
+pub fn honour_controls() {
+    let mut announced = false;
+    loop {
+        let (paused, abort) = match STATE.lock() {
+            Ok(state) => (state.paused, state.abort),
+            Err(_) => return,
+        };
+        if abort {
+            eprintln!(
+                "{} Update aborted from the status socket. Resume it with gentup --continue",
+                prompt::revchevrons(Color::Red)
+            );
+            shutdown();
+            ExitCode::Aborted.exit();
+        }
+        if !paused {
+            return;
+        }
+        if !announced {
+            println!(
+                "{} Update paused from the status socket. Waiting to be resumed",
+                prompt::revchevrons(Color::Yellow)
+            );
+            announced = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Print the status of a running update, as for "gentup --status"
+//
+pub fn query() {
+    match UnixStream::connect(socket_path()) {
+        Ok(mut stream) => {
+            let mut reply = String::new();
+            if writeln!(stream, "status").is_ok()
+                && BufReader::new(&stream).read_line(&mut reply).is_ok()
+            {
+                print!("{}", reply);
+            }
+        }
+        Err(_) => {
+            eprintln!(
+                "{} No update is running",
+                prompt::revchevrons(Color::Yellow)
+            );
+            ExitCode::Failed.exit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_emerge_log_progress() {
+        let log = "\
+1700000000:  >>> emerge (1 of 3) app-misc/old-1.0 to /
+1700000100:  >>> emerge (1 of 2) sys-libs/zlib-1.3.1 to /
+1700000200:  ::: completed emerge (1 of 2) sys-libs/zlib-1.3.1 to /
+1700000201:  >>> emerge (2 of 2) sys-devel/gcc-13.2.1_p20240113-r1 to /
+";
+        assert_eq!(
+            parse_emerge_log(log, 1700000050),
+            Progress {
+                package: "sys-devel/gcc-13.2.1_p20240113-r1".to_string(),
+                package_started: 1700000201,
+                completed: 1,
+                total: 2,
+                finished: vec!["sys-libs/zlib-1.3.1".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn finds_the_build_processes() {
+        let environ = "PATH=/usr/bin\0CATEGORY=sys-libs\0PF=zlib-1.3.1\0PFX=no\0";
+        assert_eq!(environment_variable(environ, "CATEGORY"), "sys-libs");
+        assert_eq!(environment_variable(environ, "PF"), "zlib-1.3.1");
+        assert_eq!(environment_variable(environ, "P"), "");
+    }
+}
@@ -0,0 +1,259 @@
+// Temporary files
+// Several subsystems hand text to another program through a file, because OsCall splits command
+// lines on whitespace: the body of an email, a webhook report and its Authorization header, an
+// MQTT message. TempFile and TempDir create these readable only by root, with a name no other
+// process can have claimed first, in the directory set by temp_dir in the config file, e.g
+//
+//   /tmp/gentup.4321.0.eml
+//
+// They are removed when dropped. Every one still in existence is also removed when the program
+// exits through ExitCode::exit, or is killed by SIGINT, SIGTERM or SIGHUP, so that nothing is
+// left behind whichever way a run ends. Removing files is not safe inside a signal handler, so the
+// handler only passes the signal down a pipe, to a thread which removes them and then ends the
+// process as the signal would have
+
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicI32, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+};
+
+static DEFAULT_DIRECTORY: &str = "/tmp";
+
+static DIRECTORY: OnceLock<String> = OnceLock::new();
+
+// Makes each name unique within this process
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// The temporary files and directories which have not been removed yet
+static LIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// The write end of the pipe which passes a signal to the thread that cleans up, -1 until it is
+// set up
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+pub fn set_directory(directory: &str) {
+    let _ = DIRECTORY.set(directory.to_string());
+}
+
+fn directory() -> &'static str {
+    DIRECTORY
+        .get()
+        .map(|directory| directory.as_str())
+        .unwrap_or(DEFAULT_DIRECTORY)
+}
+
+// A name for a new temporary file or directory, e.g gentup.4321.0.eml
+//
+fn unique_path(directory: &str, suffix: &str) -> PathBuf {
+    let mut name = format!(
+        "gentup.{}.{}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    if !suffix.is_empty() {
+        name = name + "." + suffix;
+    }
+    Path::new(directory).join(name)
+}
+
+fn register(path: &Path) {
+    if let Ok(mut live) = LIVE.lock() {
+        live.push(path.to_path_buf());
+    }
+}
+
+fn remove(path: &Path) {
+    if let Ok(mut live) = LIVE.lock() {
+        live.retain(|known| known != path);
+    }
+    let _ = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+}
+
+// Remove every temporary file and directory still in existence
+//
+pub fn remove_all() {
+    if let Ok(mut live) = LIVE.lock() {
+        for path in live.drain(..) {
+            let _ = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+        }
+    }
+}
+
+// Pass the signal on to the cleanup thread. write is async-signal-safe, where removing files,
+// which allocates and takes the lock on the list, is not
+//
+extern "C" fn on_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    // SAFETY: writes the one byte from the stack, to a descriptor which is either the pipe or -1
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+}
+
+// Wait for a signal to come down the pipe, remove the temporary files, then end the process as
+// the signal would have done without the handler
+//
+fn clean_up_after_signal(read_end: libc::c_int) {
+    let mut byte = 0u8;
+    loop {
+        // SAFETY: reads at most one byte into the local
+        let count = unsafe { libc::read(read_end, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        if count == 1 {
+            break;
+        }
+        if count < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        return;
+    }
+    remove_all();
+    let signal = libc::c_int::from(byte);
+    // SAFETY: restoring the default action and raising the signal again ends the process
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+    }
+}
+
+// Remove the temporary files if the program is killed
+//
+pub fn remove_on_signals() {
+    let mut pipe = [0; 2];
+    // SAFETY: pipe2 fills in the two descriptors, which are closed on exec so that the commands
+    // run do not inherit them
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return;
+    }
+    SIGNAL_PIPE.store(pipe[1], Ordering::Relaxed);
+    thread::spawn(move || clean_up_after_signal(pipe[0]));
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: on_signal only writes the signal to the pipe
+        unsafe {
+            libc::signal(
+                signal,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+// Define a struct to hold a temporary file, which is removed when dropped
+//
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    // Create a temporary file holding some text, in the configured directory
+    //
+    pub fn create(suffix: &str, contents: &str) -> io::Result<TempFile> {
+        TempFile::create_in(directory(), suffix, contents)
+    }
+
+    pub fn create_in(directory: &str, suffix: &str, contents: &str) -> io::Result<TempFile> {
+        fs::create_dir_all(directory)?;
+        let path = unique_path(directory, suffix);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        register(&path);
+        let temp_file = TempFile { path };
+        file.write_all(contents.as_bytes())?;
+        Ok(temp_file)
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
+
+// Define a struct to hold a temporary directory, which is removed with everything in it when
+// dropped
+//
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn create() -> io::Result<TempDir> {
+        TempDir::create_in(directory())
+    }
+
+    pub fn create_in(directory: &str) -> io::Result<TempDir> {
+        fs::create_dir_all(directory)?;
+        let path = unique_path(directory, "");
+        DirBuilder::new().mode(0o700).create(&path)?;
+        register(&path);
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn removes_temporary_files() {
+        let base = std::env::temp_dir().join(format!("gentup-temp-{}", process::id()));
+        let base = base.to_str().unwrap();
+
+        let file = TempFile::create_in(base, "eml", "Subject: test\n").unwrap();
+        let path = file.path().to_string();
+        assert!(path.ends_with(".eml"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Subject: test\n");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(file);
+        assert!(!Path::new(&path).exists());
+
+        let other = TempFile::create_in(base, "json", "{}").unwrap();
+        let directory = TempDir::create_in(base).unwrap();
+        assert_ne!(other.path(), directory.path());
+        fs::write(Path::new(directory.path()).join("message"), "hello").unwrap();
+        let paths = [other.path().to_string(), directory.path().to_string()];
+        remove_all();
+        assert!(paths.iter().all(|path| !Path::new(path).exists()));
+        drop(other);
+        drop(directory);
+        let _ = fs::remove_dir_all(base);
+    }
+}
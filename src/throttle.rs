@@ -0,0 +1,372 @@
+// Time of day throttling
+// A workstation can keep updating during the day without becoming unusable, by limiting the
+// builds while someone is likely to be at it and letting them run at full speed otherwise. Each
+// throttle: line in the configuration file gives the days, the time of day and the limit, e.g
+//
+//   throttle: mon-fri 09:00-17:30 19 2     build at niceness 19 with 2 make jobs
+//   throttle: mon,wed 12:00-13:00 pause    hold the builds entirely
+//   throttle: daily 23:00-06:00 5 8        a window may run past midnight
+//
+// Outside every window the builds run as make.conf and the parallelism settings have them. The
+// number of make jobs is fixed when emerge starts, so it applies to the builds started inside a
+// window. While the update runs, the niceness of every build process is changed as windows open
+// and close, and the builds are stopped and continued for a pause window
+
+use crate::{linux, portage, prompt, Config};
+use chrono::{Datelike, Timelike, Weekday};
+use crossterm::style::Color;
+use std::{
+    fmt, fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How often the schedule is checked during the build
+static INTERVAL: Duration = Duration::from_secs(5);
+
+static DAY_NAMES: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Mon),
+    ("tue", Weekday::Tue),
+    ("wed", Weekday::Wed),
+    ("thu", Weekday::Thu),
+    ("fri", Weekday::Fri),
+    ("sat", Weekday::Sat),
+    ("sun", Weekday::Sun),
+];
+
+// What a window does to the builds
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Pause,                                    // Hold the builds until the window closes
+    Slow { niceness: i32, make_jobs: usize }, // Run the builds at this niceness and parallelism
+}
+
+// Define a struct to hold one throttle: line from the configuration file
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    pub days: String, // As written, e.g mon-fri
+    pub weekdays: Vec<Weekday>,
+    pub start: u32, // Minutes after midnight
+    pub end: u32,   // Earlier than the start for a window which runs past midnight
+    pub limit: Limit,
+}
+
+fn parse_weekdays(days: &str) -> Option<Vec<Weekday>> {
+    if days == "daily" {
+        return Some(DAY_NAMES.iter().map(|(_, day)| *day).collect());
+    }
+    let index = |name: &str| DAY_NAMES.iter().position(|(day, _)| *day == name);
+    let mut weekdays = Vec::new();
+    for item in days.split(',') {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let (first, last) = (index(first)?, index(last)?);
+        let mut day = first;
+        loop {
+            weekdays.push(DAY_NAMES[day].1);
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Some(weekdays)
+}
+
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+impl Window {
+    // Parse the value of a throttle: line, e.g mon-fri 09:00-17:30 19 2
+    //
+    pub fn parse(text: &str) -> Option<Window> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let (start, end) = fields.get(1)?.split_once('-')?;
+        let limit = match fields[2..] {
+            ["pause"] => Limit::Pause,
+            [niceness, make_jobs] => Limit::Slow {
+                niceness: niceness
+                    .parse()
+                    .ok()
+                    .filter(|niceness| (-20..20).contains(niceness))?,
+                make_jobs: make_jobs.parse().ok().filter(|jobs| *jobs > 0)?,
+            },
+            _ => return None,
+        };
+        Some(Window {
+            days: fields[0].to_string(),
+            weekdays: parse_weekdays(fields[0])?,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            limit,
+        })
+    }
+
+    // Whether the window is open on the given day at the given minute after midnight
+    //
+    pub fn contains(&self, weekday: Weekday, minute: u32) -> bool {
+        if self.start < self.end {
+            self.weekdays.contains(&weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            // The part after midnight belongs to the window which opened the day before
+            (self.weekdays.contains(&weekday) && minute >= self.start)
+                || (self.weekdays.contains(&weekday.pred()) && minute < self.end)
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:02}:{:02}-{:02}:{:02}",
+            self.days,
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )?;
+        match self.limit {
+            Limit::Pause => write!(f, " pause"),
+            Limit::Slow {
+                niceness,
+                make_jobs,
+            } => write!(f, " {} {}", niceness, make_jobs),
+        }
+    }
+}
+
+// The limit in force on the given day and minute, from the first window which is open
+//
+pub fn limit_at(windows: &[Window], weekday: Weekday, minute: u32) -> Option<Limit> {
+    windows
+        .iter()
+        .find(|window| window.contains(weekday, minute))
+        .map(|window| window.limit)
+}
+
+// The limit in force now
+//
+fn limit_now(windows: &[Window]) -> Option<Limit> {
+    let now = chrono::Local::now();
+    limit_at(windows, now.weekday(), now.hour() * 60 + now.minute())
+}
+
+// The niceness of builds outside every window
+//
+fn usual_niceness() -> i32 {
+    portage::make_conf_variable("PORTAGE_NICENESS")
+        .and_then(|niceness| niceness.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// Before a build starts, wait while a pause window is open, then set the niceness and make jobs
+// of any window which is. PORTAGE_NICENESS and MAKEOPTS given to emerge override make.conf, and
+// are given to the commands gentup runs rather than set in its own environment, which the
+// threads already running may be reading
+//
+pub fn before_build(running_config: &Config) {
+    let windows = &running_config.throttle_windows;
+    if windows.is_empty() {
+        return;
+    }
+    let mut waiting = false;
+    while limit_now(windows) == Some(Limit::Pause) {
+        if !waiting {
+            println!(
+                "{} Builds are paused at this time of day. Waiting for the pause to end",
+                prompt::revchevrons(Color::Yellow)
+            );
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(60));
+    }
+    match limit_now(windows) {
+        Some(Limit::Slow {
+            niceness,
+            make_jobs,
+        }) => {
+            println!(
+                "{} Builds are throttled at this time of day: niceness {} and {} make job(s)",
+                prompt::revchevrons(Color::Yellow),
+                niceness,
+                make_jobs
+            );
+            linux::set_command_variable("PORTAGE_NICENESS", Some(niceness.to_string()));
+            linux::set_command_variable("MAKEOPTS", Some(format!("-j{}", make_jobs)));
+        }
+        _ => {
+            linux::set_command_variable("PORTAGE_NICENESS", None);
+            linux::set_command_variable("MAKEOPTS", None);
+        }
+    }
+}
+
+// The parent of a process, from the contents of its /proc/<pid>/stat. The command name, in
+// brackets, may hold spaces, so the fields are counted from the closing bracket
+//
+pub fn parent_of(stat: &str) -> Option<u32> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+// The processes started, directly or not, by this one: emerge, and every build beneath it
+//
+pub fn descendants() -> Vec<u32> {
+    let mut parents: Vec<(u32, u32)> = Vec::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+                continue;
+            };
+            if let Some(parent) = fs::read_to_string(entry.path().join("stat"))
+                .ok()
+                .and_then(|stat| parent_of(&stat))
+            {
+                parents.push((pid, parent));
+            }
+        }
+    }
+    let mut found = vec![std::process::id()];
+    let mut index = 0;
+    while index < found.len() {
+        let parent = found[index];
+        found.extend(
+            parents
+                .iter()
+                .filter(|(_, of)| *of == parent)
+                .map(|(pid, _)| *pid),
+        );
+        index += 1;
+    }
+    found.remove(0);
+    found
+}
+
+// Apply a change of limit to the build processes already running
+//
+fn apply(limit: Option<Limit>, usual: i32) {
+    let (niceness, stopped) = match limit {
+        Some(Limit::Pause) => (usual, true),
+        Some(Limit::Slow { niceness, .. }) => (niceness, false),
+        None => (usual, false),
+    };
+    for pid in descendants() {
+        // SAFETY: setpriority and kill only change the scheduling of, and signal, the processes of
+        // the build gentup started, which are continued again before the build ends
+        unsafe {
+            libc::setpriority(libc::PRIO_PROCESS, pid, niceness);
+            libc::kill(
+                pid as libc::pid_t,
+                if stopped {
+                    libc::SIGSTOP
+                } else {
+                    libc::SIGCONT
+                },
+            );
+        }
+    }
+}
+
+// Follows the schedule while the update builds, until stopped
+//
+pub struct Throttler {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Throttler {
+    pub fn start(running_config: &Config) -> Option<Throttler> {
+        if running_config.throttle_windows.is_empty() {
+            return None;
+        }
+        let windows = running_config.throttle_windows.clone();
+        let usual = usual_niceness();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            let mut current = limit_now(&windows);
+            while !stopped.load(Ordering::Relaxed) {
+                thread::sleep(INTERVAL);
+                let limit = limit_now(&windows);
+                if limit != current {
+                    let message = match limit {
+                        Some(Limit::Pause) => "Pausing the build for the time of day".to_string(),
+                        Some(Limit::Slow { niceness, .. }) => {
+                            format!("Throttling the build to niceness {}", niceness)
+                        }
+                        None => "Building at full speed again".to_string(),
+                    };
+                    println!("{} {}", prompt::revchevrons(Color::Yellow), message);
+                    apply(limit, usual);
+                    current = limit;
+                }
+            }
+            // Never leave a build stopped
+            if current == Some(Limit::Pause) {
+                apply(None, usual);
+            }
+        });
+        Some(Throttler { stop, handle })
+    }
+
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_schedule() {
+        let work = Window::parse("mon-fri 09:00-17:30 19 2").unwrap();
+        assert_eq!(work.weekdays.len(), 5);
+        assert_eq!(
+            work.limit,
+            Limit::Slow {
+                niceness: 19,
+                make_jobs: 2
+            }
+        );
+        assert_eq!(work.to_string(), "mon-fri 09:00-17:30 19 2");
+        let lunch = Window::parse("mon,wed 12:00-13:00 pause").unwrap();
+        let night = Window::parse("fri-sun 23:00-06:00 5 8").unwrap();
+        assert_eq!(
+            night.weekdays,
+            vec![Weekday::Fri, Weekday::Sat, Weekday::Sun]
+        );
+        assert_eq!(Window::parse("mon-fri 09:00-25:00 19 2"), None);
+        assert_eq!(Window::parse("someday 09:00-17:00 19 2"), None);
+        assert_eq!(Window::parse("daily 09:00-17:00 19"), None);
+
+        let windows = vec![lunch, work, night];
+        assert_eq!(
+            limit_at(&windows, Weekday::Mon, 12 * 60 + 30),
+            Some(Limit::Pause)
+        );
+        assert_eq!(
+            limit_at(&windows, Weekday::Tue, 12 * 60 + 30),
+            Some(windows[1].limit)
+        );
+        assert_eq!(limit_at(&windows, Weekday::Tue, 18 * 60), None);
+        // Sunday night's window runs into Monday morning, but not Friday's into Thursday's
+        assert!(windows[2].contains(Weekday::Mon, 5 * 60));
+        assert!(!windows[2].contains(Weekday::Fri, 5 * 60));
+
+        assert_eq!(
+            parent_of("4242 (cc1plus (x)) R 4241 4242 4000 0 -1 4194560"),
+            Some(4241)
+        );
+    }
+}
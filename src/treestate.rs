@@ -0,0 +1,262 @@
+// Package tree state
+// Records which state each repository was in when gentup last brought the eix database up to date,
+// so that eix-update, which reads every ebuild in every repository and takes minutes, only runs
+// after a sync which actually changed something. A timer running gentup more often than the
+// mirrors update otherwise pays for a full eix-update on every run. The state of a repository is
+// the generation time of the tree for rsync and webrsync, the commit checked out for git, and the
+// modification time of the directory for anything else. /var/lib/gentup/tree-state holds one line
+// per repository, e.g
+//
+//   gentoo Tue, 16 Apr 2024 00:45:01 +0000
+//   guru 5d1c7c0a3c9bb8e1f0f1e7b4a9d4b2b1d3d1e5f0
+//
+// The same state, together with the latest change to /etc/portage and portage's count of merges,
+// is the key to a cache of the pretend update in /var/lib/gentup/pretend-cache. While none of them
+// has changed, emerge would calculate the same pending updates, so --estimate, --check and the
+// update itself reuse the result rather than each spending minutes working it out again
+
+use crate::{
+    config::state_dir,
+    linux::{OsCall, ShellOutResult},
+    portage, prompt,
+};
+use crossterm::style::Color;
+use std::{fs, path::Path, time::UNIX_EPOCH};
+
+pub static EIX_CACHE: &str = "/var/cache/eix/portage.eix";
+
+// Portage increments the number in this file with every package merged
+static MERGE_COUNTER: &str = "/var/cache/edb/counter";
+
+// Separates the key from the emerge output in the pretend cache
+static CACHE_SEPARATOR: &str = "\n%%\n";
+
+fn state_file() -> String {
+    [state_dir(), "/tree-state"].concat()
+}
+
+fn pretend_cache_file() -> String {
+    [state_dir(), "/pretend-cache"].concat()
+}
+
+// The commit checked out in a git repository, read from .git without running git
+//
+fn git_head(repository: &Path) -> Option<String> {
+    let git = repository.join(".git");
+    let head = fs::read_to_string(git.join("HEAD")).ok()?;
+    let Some(reference) = head.trim().strip_prefix("ref: ") else {
+        return Some(head.trim().to_string()); // A detached HEAD holds the commit itself
+    };
+    if let Ok(commit) = fs::read_to_string(git.join(reference)) {
+        return Some(commit.trim().to_string());
+    }
+    fs::read_to_string(git.join("packed-refs"))
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let (commit, name) = line.split_once(' ')?;
+            (name == reference).then(|| commit.to_string())
+        })
+}
+
+// The state of one repository, which changes whenever a sync brings in anything new
+//
+pub fn repository_stamp(repository: &Path) -> String {
+    if let Ok(timestamp) = fs::read_to_string(repository.join("metadata/timestamp.chk")) {
+        return timestamp.trim().to_string();
+    }
+    if let Some(commit) = git_head(repository) {
+        return commit;
+    }
+    fs::metadata(repository)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs().to_string())
+        .unwrap_or_default()
+}
+
+// The state of every repository portage knows about, one line per repository
+//
+pub fn tree_state() -> String {
+    let repositories = OsCall::Quiet
+        .execute("portageq get_repos /", "")
+        .map(|(output, _)| output)
+        .unwrap_or_default();
+    let mut names: Vec<&str> = repositories.split_whitespace().collect();
+    names.sort();
+    names
+        .iter()
+        .filter_map(|name| {
+            let (path, _) = OsCall::Quiet
+                .execute(&["portageq get_repo_path / ", name].concat(), "")
+                .ok()?;
+            Some(format!(
+                "{} {}\n",
+                name,
+                repository_stamp(Path::new(path.trim()))
+            ))
+        })
+        .collect()
+}
+
+// Whether the repositories have changed since the state last recorded
+//
+pub fn changed_since_last_run(state: &str) -> bool {
+    state.is_empty() || fs::read_to_string(state_file()).map_or(true, |recorded| recorded != state)
+}
+
+// Bring the eix database up to date after a sync, unless no repository has changed since it was
+// last brought up to date
+//
+pub fn refresh_eix() {
+    let state = tree_state();
+    if !changed_since_last_run(&state) && Path::new(&portage::target_path(EIX_CACHE)).exists() {
+        println!(
+            "{} The package tree has not changed since the last run. Skipping eix-update",
+            prompt::revchevrons(Color::Blue)
+        );
+        return;
+    }
+    portage::eix_update();
+    if let Err(error) =
+        fs::create_dir_all(state_dir()).and_then(|_| fs::write(state_file(), &state))
+    {
+        eprintln!(
+            "{} Could not record the package tree state in {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            state_file(),
+            error
+        );
+    }
+}
+
+// The latest modification time, in nanoseconds, of a directory or anything beneath it. Symbolic
+// links are not followed, so make.profile changes only when a different profile is selected
+//
+pub fn latest_change(path: &Path) -> u128 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    let mut latest = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos())
+        .unwrap_or(0);
+    if metadata.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                latest = latest.max(latest_change(&entry.path()));
+            }
+        }
+    }
+    latest
+}
+
+// Everything the pending updates depend on: the repositories, the portage configuration, and the
+// packages installed
+//
+pub fn pretend_key() -> String {
+    format!(
+        "{}etc-portage {}\nmerges {}",
+        tree_state(),
+        latest_change(Path::new(&portage::target_path("/etc/portage"))),
+        fs::read_to_string(portage::target_path(MERGE_COUNTER))
+            .unwrap_or_default()
+            .trim()
+    )
+}
+
+// The emerge output kept in a pretend cache, if it was kept under the given key
+//
+pub fn cached_output(cache: &str, key: &str) -> Option<String> {
+    let (cached_key, output) = cache.split_once(CACHE_SEPARATOR)?;
+    (cached_key == key).then(|| output.to_string())
+}
+
+// Run the pretend update, or reuse its result from an earlier run if nothing it depends on has
+// changed since. Only a successful result is kept
+//
+pub fn cached_pretend(pretend: impl FnOnce() -> ShellOutResult) -> ShellOutResult {
+    let key = pretend_key();
+    if let Some(output) = fs::read_to_string(pretend_cache_file())
+        .ok()
+        .and_then(|cache| cached_output(&cache, &key))
+    {
+        return Ok((output, 0));
+    }
+    let result = pretend();
+    if let Ok((output, 0)) = &result {
+        let _ = fs::create_dir_all(state_dir()).and_then(|_| {
+            fs::write(
+                pretend_cache_file(),
+                [&key, CACHE_SEPARATOR, output].concat(),
+            )
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_repositories() {
+        let repos = std::env::temp_dir().join(format!("gentup-repos-{}", std::process::id()));
+        let gentoo = repos.join("gentoo");
+        fs::create_dir_all(gentoo.join("metadata")).unwrap();
+        fs::write(
+            gentoo.join("metadata/timestamp.chk"),
+            "Tue, 16 Apr 2024 00:45:01 +0000\n",
+        )
+        .unwrap();
+        assert_eq!(repository_stamp(&gentoo), "Tue, 16 Apr 2024 00:45:01 +0000");
+
+        let guru = repos.join("guru");
+        fs::create_dir_all(guru.join(".git/refs/heads")).unwrap();
+        fs::write(guru.join(".git/HEAD"), "ref: refs/heads/master\n").unwrap();
+        fs::write(
+            guru.join(".git/packed-refs"),
+            "# pack-refs with: peeled fully-peeled sorted\n\
+            5d1c7c0a3c9bb8e1f0f1e7b4a9d4b2b1d3d1e5f0 refs/heads/master\n",
+        )
+        .unwrap();
+        assert_eq!(
+            repository_stamp(&guru),
+            "5d1c7c0a3c9bb8e1f0f1e7b4a9d4b2b1d3d1e5f0"
+        );
+        fs::write(
+            guru.join(".git/refs/heads/master"),
+            "0123456789abcdef0123456789abcdef01234567\n",
+        )
+        .unwrap();
+        assert_eq!(
+            repository_stamp(&guru),
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+
+        let local = repos.join("local");
+        fs::create_dir_all(&local).unwrap();
+        assert!(repository_stamp(&local).parse::<u64>().is_ok());
+
+        // Any change beneath a directory is its latest change
+        fs::create_dir_all(local.join("package.use")).unwrap();
+        let before = latest_change(&local);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(local.join("package.use/custom"), "dev-lang/rust clippy").unwrap();
+        assert!(latest_change(&local) > before);
+
+        let cache = "gentoo 1\netc-portage 2\nmerges 3\n%%\n[ebuild     U  ] sys-libs/zlib-1.3.1\n";
+        assert_eq!(
+            cached_output(cache, "gentoo 1\netc-portage 2\nmerges 3").as_deref(),
+            Some("[ebuild     U  ] sys-libs/zlib-1.3.1\n")
+        );
+        assert_eq!(
+            cached_output(cache, "gentoo 1\netc-portage 2\nmerges 4"),
+            None
+        );
+        let _ = fs::remove_dir_all(&repos);
+    }
+}
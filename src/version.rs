@@ -1,2 +1,47 @@
 // Still in alpha!
 pub const VERSION: &str = "0.5.1a";
+
+// Build metadata
+// Recorded by build.rs, so that --version and the run reports identify the exact binary in use
+
+use crate::events::json_string;
+
+pub const GIT_COMMIT: &str = env!("GENTUP_GIT_COMMIT");
+pub const BUILD_DATE: &str = env!("GENTUP_BUILD_DATE");
+pub const FEATURES: &str = env!("GENTUP_FEATURES"); // Comma separated, e.g fleet,mail
+pub const TARGET: &str = env!("GENTUP_TARGET");
+
+// The build details displayed by --version
+//
+pub fn details() -> String {
+    format!(
+        "gentup version {}\ncommit:   {}\nbuilt:    {}\nfeatures: {}\ntarget:   {}",
+        VERSION,
+        GIT_COMMIT,
+        BUILD_DATE,
+        if FEATURES.is_empty() {
+            "none"
+        } else {
+            FEATURES
+        },
+        TARGET
+    )
+}
+
+// The build details as a JSON object, for the run report
+//
+pub fn to_json() -> String {
+    let features: Vec<String> = FEATURES
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(json_string)
+        .collect();
+    format!(
+        "{{\"version\":{},\"commit\":{},\"date\":{},\"features\":[{}],\"target\":{}}}",
+        json_string(VERSION),
+        json_string(GIT_COMMIT),
+        json_string(BUILD_DATE),
+        features.join(","),
+        json_string(TARGET)
+    )
+}
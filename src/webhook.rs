@@ -0,0 +1,53 @@
+// Webhook delivery
+// POSTs the JSON run report to the configured webhook_url, for central dashboards and chat-ops
+// bots. The request is made with curl, which retries transient failures, and carries the
+// webhook_auth value, if set, as the Authorization header, e.g "Bearer 0123456789abcdef"
+
+use crate::{linux::OsCall, prompt, tempfile::TempFile};
+use crossterm::style::Color;
+
+pub fn post(url: &str, auth: &str, body: &str) {
+    let mut headers = String::from("Content-Type: application/json\n");
+    if !auth.is_empty() {
+        headers = headers + "Authorization: " + auth + "\n";
+    }
+    // The files are readable only by root, as the headers may hold a credential
+    let result = TempFile::create("json", body)
+        .and_then(|body_file| Ok((body_file, TempFile::create("headers", &headers)?)))
+        .map_err(|error| error.to_string())
+        .and_then(|(body_file, headers_file)| {
+            OsCall::Quiet
+                .execute(
+                    &[
+                        "curl -fsS --retry 3 --retry-delay 10 --retry-all-errors --max-time 60 -H @",
+                        headers_file.path(),
+                        " --data-binary @",
+                        body_file.path(),
+                        " ",
+                        url,
+                    ]
+                    .concat(),
+                    "",
+                )
+                .map_err(|error| error.to_string())
+        });
+    match result {
+        Ok((_, 0)) => println!(
+            "{} Run report sent to {}",
+            prompt::revchevrons(Color::Green),
+            url
+        ),
+        Ok((_, status)) => eprintln!(
+            "{} Could not send the run report to {} - curl exited with status {}",
+            prompt::revchevrons(Color::Yellow),
+            url,
+            status
+        ),
+        Err(error) => eprintln!(
+            "{} Could not send the run report to {} - {}",
+            prompt::revchevrons(Color::Yellow),
+            url,
+            error
+        ),
+    }
+}